@@ -0,0 +1,130 @@
+//! A limit on how long a check may take.
+//!
+//! Each request has its own timeout, but an instance that answers slowly enough could still keep
+//! a worker busy for many minutes by making us follow page after page of peers. On the other
+//! hand, some of the time is spent waiting on purpose: for the Crawl-delay, before retrying a
+//! request, or for the `--max-rps` budget. That time is the price of being polite, so it doesn't
+//! count towards the limit; see [`sleep()`].
+use slog::{error, Logger};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+/// How long the checker has deliberately waited so far, in milliseconds. Set by [`sleep()`].
+static WAITED_MILLIS: AtomicU64 = AtomicU64::new(0);
+
+/// Sleep for the `duration`, and push the deadline back by as much.
+pub fn sleep(duration: Duration) {
+    extend(duration);
+    std::thread::sleep(duration);
+}
+
+/// Push the deadline back by `duration`, which was spent waiting on purpose.
+pub fn extend(duration: Duration) {
+    let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+    // Waits in parallel threads add up, which only makes the deadline more lenient.
+    let _ = WAITED_MILLIS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |waited| {
+        Some(waited.saturating_add(millis))
+    });
+}
+
+/// How much time the check has spent waiting on purpose.
+fn waited() -> Duration {
+    Duration::from_millis(WAITED_MILLIS.load(Ordering::Relaxed))
+}
+
+/// How much of the `limit` is left to a check that began at `started`, not counting the
+/// deliberate waits. `None` if the deadline is too far in the future to ever come.
+fn remaining(started: Instant, limit: Duration) -> Option<Duration> {
+    let deadline = started.checked_add(limit)?.checked_add(waited())?;
+    Some(deadline.saturating_duration_since(Instant::now()))
+}
+
+fn log_giving_up(logger: &Logger, limit: Duration) {
+    error!(
+        logger,
+        "The check took longer than {}s, not counting {}s of waiting between requests; giving up",
+        limit.as_secs(),
+        waited().as_secs()
+    );
+}
+
+/// Call `on_timeout` and exit once the check spent more than `limit` doing something other than
+/// deliberately waiting.
+///
+/// Whatever was reported by then stands. If the state wasn't reported yet, the Orchestrator marks
+/// the instance dead, as it does whenever a checker exits with an error without saying anything.
+pub fn give_up_after(logger: Logger, limit: Duration, on_timeout: impl FnOnce() + Send + 'static) {
+    let started = Instant::now();
+    std::thread::spawn(move || {
+        loop {
+            match remaining(started, limit) {
+                None => return,
+                Some(remaining) if remaining.is_zero() => break,
+                Some(remaining) => std::thread::sleep(remaining),
+            }
+        }
+        log_giving_up(&logger, limit);
+        on_timeout();
+        // Holding the lock ensures that we don't exit in the middle of a message.
+        let _stdout = std::io::stdout().lock();
+        std::process::exit(1);
+    });
+}
+
+/// Wait for the `result` of a check that runs on another thread, giving up once it spent more
+/// than `limit` doing something other than deliberately waiting. `None` if the check ran out of
+/// time or never sent its result.
+///
+/// This is [`give_up_after`] for checks that run in-process, which can't just exit: the check is
+/// left to finish in the background, and the caller takes whatever was reported by then.
+pub fn wait_for<T>(logger: &Logger, limit: Duration, result: &Receiver<T>) -> Option<T> {
+    let started = Instant::now();
+    loop {
+        let timeout = match remaining(started, limit) {
+            None => return result.recv().ok(),
+            Some(remaining) if remaining.is_zero() => break,
+            Some(remaining) => remaining,
+        };
+        match result.recv_timeout(timeout) {
+            Ok(value) => return Some(value),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+    log_giving_up(logger, limit);
+    None
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deliberate_waits_push_the_deadline_back() {
+        // Other tests might be waiting at the same time, so this can only check a lower bound.
+        let before = waited();
+        sleep(Duration::from_millis(20));
+        assert!(waited() >= before + Duration::from_millis(20));
+    }
+
+    #[test]
+    fn in_process_checks_are_waited_for_until_the_deadline() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let (sender, receiver) = std::sync::mpsc::channel();
+        sender.send("done").unwrap();
+        assert_eq!(
+            wait_for(&logger, Duration::from_secs(60), &receiver),
+            Some("done")
+        );
+
+        // The sender is kept, so only the deadline ends the wait.
+        let started = Instant::now();
+        assert_eq!(
+            wait_for(&logger, Duration::from_millis(50), &receiver),
+            None
+        );
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+}
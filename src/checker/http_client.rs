@@ -1,15 +1,76 @@
-//! HTTP client that automatically checks requests against robots.txt.
+//! HTTP client that automatically checks requests against robots.txt, and waits between requests
+//! as long as robots.txt asks.
+use crate::checker::{deadline, rate_limiter, tls};
+use rustls::pki_types::CertificateDer;
 use slog::{error, info, Logger};
-use std::time::Duration;
+use std::collections::HashSet;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 use ureq::Agent;
 use url::{Host, Url};
 
 /// The string to be matched against "User-agent" in robots.txt
 const USER_AGENT_TOKEN: &str = "MinoruFediverseCrawler";
 
-/// The string to be sent with each HTTP request.
+/// The string to be sent with each HTTP request, unless [`set_contact_url()`] was called.
 const USER_AGENT_FULL: &str = "Minoru's Fediverse Crawler (+https://nodes.fediverse.party)";
 
+/// The name of the crawler in [`USER_AGENT_FULL`], without the contact URL.
+const USER_AGENT_NAME: &str = "Minoru's Fediverse Crawler";
+
+/// Set by [`set_contact_url()`].
+static USER_AGENT: OnceLock<String> = OnceLock::new();
+
+/// The longest Crawl-delay we honor. The waits don't count towards the check's deadline, but they
+/// do keep a worker busy, and a check can take dozens of requests when the peers list has many
+/// pages.
+const MAX_CRAWL_DELAY: Duration = Duration::from_secs(30);
+
+/// How long to wait before retrying a request that failed because of a transient network error.
+const TRANSIENT_ERROR_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// The `Accept` header for ordinary JSON APIs.
+pub const ACCEPT_JSON: &str = "application/json";
+
+/// The `Accept` header for NodeInfo documents. The well-known document is JRD, and some servers
+/// refuse to serve it as plain JSON.
+pub const ACCEPT_NODEINFO: &str = "application/jrd+json, application/json";
+
+/// The `Accept` header for the XRD flavour of host-meta.
+pub const ACCEPT_XRD: &str = "application/xrd+xml, application/xml";
+
+/// Only this much of robots.txt is parsed, and the rest is ignored. RFC 9309 asks crawlers to
+/// parse at least 500 KiB.
+const ROBOTS_TXT_SIZE_LIMIT: u64 = 512 * 1024;
+
+/// The first bytes of a gzipped file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The `Accept-Encoding` header sent with each request. ureq decodes gzip and Brotli by itself;
+/// deflate is decoded by [`read_body`].
+const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+
+/// Time limits for HTTP requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Overall limit for a request made by the agent, including reading the response body.
+    pub agent: Duration,
+
+    /// Limit for a single request, after which it's abandoned.
+    pub request: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            agent: Duration::from_secs(30),
+            request: Duration::from_secs(10),
+        }
+    }
+}
+
 /// A redirection from one URL to another.
 #[derive(Debug)]
 pub struct Redirection {
@@ -45,6 +106,24 @@ pub enum HttpClientError {
 
     /// Error parsing a URL with the `url` crate.
     UrlParseError(url::ParseError),
+
+    /// Error setting up TLS.
+    TlsConfigError(rustls::Error),
+
+    /// The server refused to talk to us because it only speaks HTTP/2, which ureq doesn't support.
+    Http2Only(Url),
+
+    /// The TLS handshake failed, e.g. because the certificate is expired or issued for another
+    /// host. Unlike a refused connection, this is usually fixable by the instance's admin.
+    // The fields are put into a box to avoid clippy::result_large_err warning.
+    TlsError(Box<ureq::Error>),
+
+    /// The response body is larger than the given number of bytes, either as sent or after
+    /// decompression.
+    BodyTooLarge(u64),
+
+    /// The redirects went in a circle and came back to this URL.
+    RedirectLoop(Url),
 }
 
 impl std::fmt::Display for HttpClientError {
@@ -77,6 +156,23 @@ impl std::fmt::Display for HttpClientError {
             HttpClientError::UrlParseError(err) => {
                 write!(f, "error parsing URL: {}", err)
             }
+            HttpClientError::TlsConfigError(err) => {
+                write!(f, "error setting up TLS: {}", err)
+            }
+            HttpClientError::Http2Only(url) => {
+                write!(
+                    f,
+                    "{} is only served over HTTP/2, which we don't support",
+                    url
+                )
+            }
+            HttpClientError::TlsError(err) => write!(f, "TLS handshake failed: {}", err),
+            HttpClientError::BodyTooLarge(limit) => {
+                write!(f, "the response body is larger than {} bytes", limit)
+            }
+            HttpClientError::RedirectLoop(url) => {
+                write!(f, "redirects loop back to {}", url)
+            }
         }
     }
 }
@@ -91,6 +187,11 @@ impl std::error::Error for HttpClientError {
             HttpClientError::UreqError(err) => err.source(),
             HttpClientError::UreqStdError(err) => err.source(),
             HttpClientError::UrlParseError(err) => err.source(),
+            HttpClientError::TlsConfigError(err) => err.source(),
+            HttpClientError::Http2Only(_) => None,
+            HttpClientError::TlsError(err) => err.source(),
+            HttpClientError::BodyTooLarge(_) => None,
+            HttpClientError::RedirectLoop(_) => None,
         }
     }
 }
@@ -98,38 +199,182 @@ impl std::error::Error for HttpClientError {
 pub struct HttpClient {
     logger: Logger,
     inner: Agent,
+    request_timeout: Duration,
     robots_txt: String,
+    certificate_expiry: tls::CertificateExpiry,
+    /// How long to wait between requests, as asked by robots.txt.
+    crawl_delay: Option<Duration>,
+    /// When the last request was made. Requests can be made from multiple threads, and they wait
+    /// for each other while holding this lock.
+    last_request: Mutex<Option<Instant>>,
+}
+
+/// A resolver that knows the addresses of one host in advance, and resolves everything else as
+/// usual.
+struct PreResolved {
+    host: String,
+    addresses: Vec<IpAddr>,
+}
+
+impl ureq::Resolver for PreResolved {
+    fn resolve(&self, netloc: &str) -> std::io::Result<Vec<SocketAddr>> {
+        if let Some((host, port)) = netloc.rsplit_once(':') {
+            if host == self.host {
+                if let Ok(port) = port.parse() {
+                    return Ok(self
+                        .addresses
+                        .iter()
+                        .map(|ip| SocketAddr::new(*ip, port))
+                        .collect());
+                }
+            }
+        }
+        netloc.to_socket_addrs().map(|addrs| addrs.collect())
+    }
 }
 
 impl HttpClient {
-    pub fn new(logger: Logger, host: Host) -> Result<Self, HttpClientError> {
-        let inner = ureq::AgentBuilder::new()
+    /// Create a client for the `host`.
+    ///
+    /// If `addresses` is non-empty, the `host` is not resolved; these addresses are used instead.
+    /// Likewise, robots.txt is only fetched if `robots_txt` is `None`.
+    ///
+    /// If the `host` is a Tor onion service and `tor_proxy` ("host:port" of a SOCKS5 proxy) is
+    /// given, requests go through the proxy, which also resolves the host. Such hosts are checked
+    /// over plain HTTP; see [`scheme`].
+    ///
+    /// The `extra_roots` are trusted in addition to the usual CAs. This applies to all hosts, not
+    /// just the `host`, since redirects and peers lists can lead elsewhere.
+    pub fn new(
+        logger: Logger,
+        host: Host,
+        addresses: &[IpAddr],
+        tor_proxy: Option<&str>,
+        robots_txt: Option<String>,
+        extra_roots: &[CertificateDer<'static>],
+        timeouts: Timeouts,
+    ) -> Result<Self, HttpClientError> {
+        let (tls_config, certificate_expiry) = tls::client_config(&host.to_string(), extra_roots)
+            .map_err(HttpClientError::TlsConfigError)?;
+        let mut builder = ureq::AgentBuilder::new()
             // We'll handle redirects ourselves
             .redirects(0)
-            .timeout(Duration::from_secs(30))
-            .user_agent(USER_AGENT_FULL)
-            .build();
-        let robots_txt = {
-            let url = format!("https://{}/robots.txt", host);
-            let url = Url::parse(&url).map_err(HttpClientError::UrlParseError)?;
-            info!(logger, "Fetching robots.txt");
-            get_with_type_ignoring_404(&logger, &inner, &url, None)?
-                .into_string()
-                .map_err(HttpClientError::UreqStdError)?
+            .timeout(timeouts.agent)
+            .user_agent(user_agent())
+            .tls_config(tls_config);
+        if !addresses.is_empty() {
+            builder = builder.resolver(PreResolved {
+                host: host.to_string(),
+                addresses: addresses.to_vec(),
+            });
+        }
+        if let (true, Some(tor_proxy)) = (is_onion(&host), tor_proxy) {
+            info!(logger, "Connecting through the Tor proxy at {}", tor_proxy);
+            let proxy = ureq::Proxy::new(format!("socks5://{}", tor_proxy))
+                .map_err(|e| HttpClientError::UreqError(Box::new(e)))?;
+            builder = builder.proxy(proxy);
+        }
+        let inner = builder.build();
+        let mut last_request = None;
+        let robots_txt = match robots_txt {
+            Some(robots_txt) => {
+                info!(logger, "Using the robots.txt that was given");
+                robots_txt
+            }
+            None => {
+                let url = format!("{}://{}/robots.txt", scheme(&host), host);
+                let url = Url::parse(&url).map_err(HttpClientError::UrlParseError)?;
+                info!(logger, "Fetching robots.txt");
+                last_request = Some(Instant::now());
+                let response = get_with_type_ignoring_404(
+                    &logger,
+                    &inner,
+                    timeouts.request,
+                    &url,
+                    None,
+                    // The body is truncated instead; see `robots_txt_from_response`.
+                    u64::MAX,
+                )?;
+                robots_txt_from_response(&logger, response)?
+            }
         };
+        let crawl_delay = crawl_delay(&robots_txt);
+        if let Some(delay) = crawl_delay {
+            info!(
+                logger,
+                "robots.txt asks to wait {:.1}s between requests",
+                delay.as_secs_f64()
+            );
+        }
         Ok(Self {
             logger,
             inner,
+            request_timeout: timeouts.request,
             robots_txt,
+            certificate_expiry,
+            crawl_delay,
+            last_request: Mutex::new(last_request),
         })
     }
 
-    pub fn get(&self, url: &Url) -> Result<ureq::Response, HttpClientError> {
+    /// When the host's certificate expires. Only known after the first request to the host.
+    pub fn certificate_expiry(&self) -> Option<SystemTime> {
+        self.certificate_expiry.get()
+    }
+
+    /// Fetch a JSON document of at most `body_limit` bytes.
+    pub fn get(&self, url: &Url, body_limit: u64) -> Result<ureq::Response, HttpClientError> {
+        self.get_with_accept(url, ACCEPT_JSON, body_limit)
+    }
+
+    /// Fetch a document of at most `body_limit` bytes, asking for one of the types listed in
+    /// `accept`.
+    ///
+    /// The limit is only checked against `Content-Length` here; the body should be read with
+    /// [`read_body`], which enforces the limit while reading.
+    pub fn get_with_accept(
+        &self,
+        url: &Url,
+        accept: &str,
+        body_limit: u64,
+    ) -> Result<ureq::Response, HttpClientError> {
         if !self.allowed_by_robots_txt(url.as_str()) {
             return Err(HttpClientError::ForbiddenByRobotsTxt(url.to_owned()));
         }
 
-        match get_with_type_ignoring_404(&self.logger, &self.inner, url, Some("application/json")) {
+        // The lock is held until the request is sent, so that concurrent requests are spaced out
+        // too.
+        let mut last_request = self.last_request.lock().ok();
+        if let (Some(delay), Some(Some(last_request))) = (self.crawl_delay, last_request.as_deref())
+        {
+            if let Some(wait) = delay.checked_sub(last_request.elapsed()) {
+                deadline::sleep(wait);
+            }
+        }
+        if let Some(last_request) = last_request.as_deref_mut() {
+            *last_request = Some(Instant::now());
+        }
+        drop(last_request);
+
+        // The retry is spaced out from the first attempt at least as much as robots.txt asks.
+        let retry_delay = self
+            .crawl_delay
+            .map_or(TRANSIENT_ERROR_RETRY_DELAY, |delay| {
+                delay.max(TRANSIENT_ERROR_RETRY_DELAY)
+            });
+        let response = with_transient_error_retry(&self.logger, retry_delay, || {
+            with_accept_fallback(&self.logger, accept, |accept| {
+                get_with_type_ignoring_404(
+                    &self.logger,
+                    &self.inner,
+                    self.request_timeout,
+                    url,
+                    accept,
+                    body_limit,
+                )
+            })
+        });
+        match response {
             Ok(r) if r.status() == 404 => {
                 let ureq_err = ureq::Error::Status(404, r);
                 Err(HttpClientError::UreqError(Box::new(ureq_err)))
@@ -145,31 +390,351 @@ impl HttpClient {
     }
 }
 
+/// Returns `true` if the `host` is a Tor onion service, which can only be reached through Tor.
+pub fn is_onion(host: &Host) -> bool {
+    match host {
+        Host::Domain(domain) => domain.ends_with(".onion"),
+        Host::Ipv4(_) | Host::Ipv6(_) => false,
+    }
+}
+
+/// The URL scheme with which the `host` is checked.
+///
+/// Onion services are checked over plain HTTP, since that's what most of them serve. Tor already
+/// encrypts the connection, and the onion address proves that it leads to the right server. If
+/// the service redirects to HTTPS, that's followed, and its certificate isn't checked (see
+/// [`tls::client_config`]).
+pub fn scheme(host: &Host) -> &'static str {
+    if is_onion(host) {
+        "http"
+    } else {
+        "https"
+    }
+}
+
+/// The Crawl-delay that robots.txt sets for us, or for all crawlers if it doesn't mention us. It's
+/// capped at [`MAX_CRAWL_DELAY`].
+fn crawl_delay(robots_txt: &str) -> Option<Duration> {
+    let mut finder = CrawlDelayFinder::default();
+    robotstxt::parse_robotstxt(robots_txt, &mut finder);
+    finder
+        .ours
+        .or(finder.everyones)
+        .map(|delay| delay.min(MAX_CRAWL_DELAY))
+}
+
+/// Collects the Crawl-delay directives from the groups that apply to us.
+#[derive(Default)]
+struct CrawlDelayFinder {
+    /// The current group of rules applies to [`USER_AGENT_TOKEN`].
+    group_is_ours: bool,
+    /// The current group of rules applies to all user-agents.
+    group_is_everyones: bool,
+    /// The last line was a User-agent one, so the next one adds to the same group.
+    in_user_agents: bool,
+    ours: Option<Duration>,
+    everyones: Option<Duration>,
+}
+
+impl robotstxt::RobotsParseHandler for CrawlDelayFinder {
+    fn handle_robots_start(&mut self) {}
+
+    fn handle_robots_end(&mut self) {}
+
+    fn handle_user_agent(&mut self, _line_num: u32, user_agent: &str) {
+        if !self.in_user_agents {
+            self.group_is_ours = false;
+            self.group_is_everyones = false;
+        }
+        self.in_user_agents = true;
+
+        // Like the rest of robots.txt rules, only the product token is matched, e.g.
+        // "MinoruFediverseCrawler/1.0" is the same as "MinoruFediverseCrawler".
+        let token = user_agent
+            .split(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            .next()
+            .unwrap_or_default();
+        if user_agent.starts_with('*') {
+            self.group_is_everyones = true;
+        } else if token.eq_ignore_ascii_case(USER_AGENT_TOKEN) {
+            self.group_is_ours = true;
+        }
+    }
+
+    fn handle_allow(&mut self, _line_num: u32, _value: &str) {
+        self.in_user_agents = false;
+    }
+
+    fn handle_disallow(&mut self, _line_num: u32, _value: &str) {
+        self.in_user_agents = false;
+    }
+
+    fn handle_sitemap(&mut self, _line_num: u32, _value: &str) {
+        self.in_user_agents = false;
+    }
+
+    fn handle_unknown_action(&mut self, _line_num: u32, action: &str, value: &str) {
+        self.in_user_agents = false;
+        if !action.eq_ignore_ascii_case("crawl-delay") {
+            return;
+        }
+        let Some(delay) = value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        else {
+            return;
+        };
+        if self.group_is_ours {
+            self.ours.get_or_insert(delay);
+        }
+        if self.group_is_everyones {
+            self.everyones.get_or_insert(delay);
+        }
+    }
+}
+
+/// The contents of robots.txt, or an empty string if the server responded with something else.
+///
+/// Some servers respond to any path with an HTML page (e.g. an error page with a 200 status).
+/// Feeding that to the robots.txt parser could make it find rules that aren't there, so we treat
+/// it as if there was no robots.txt at all.
+fn robots_txt_from_response(
+    logger: &Logger,
+    response: ureq::Response,
+) -> Result<String, HttpClientError> {
+    // ureq reports "text/plain" if the `Content-Type` header is missing.
+    let content_type = response.content_type();
+    if !content_type.eq_ignore_ascii_case("text/plain") {
+        info!(
+            logger,
+            "robots.txt was served as {} rather than plain text; ignoring it", content_type
+        );
+        return Ok(String::new());
+    }
+    read_truncated_body(response, ROBOTS_TXT_SIZE_LIMIT)
+}
+
+/// Read the response body like [`read_body`] does, but rather than failing on bodies larger than
+/// `limit` bytes, ignore everything after the last full line that fits into the limit. A line that
+/// was cut short could mean something else entirely: "Disallow: /private" would become
+/// "Disallow: /".
+fn read_truncated_body(response: ureq::Response, limit: u64) -> Result<String, HttpClientError> {
+    let deflated = response
+        .header("content-encoding")
+        .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("deflate"));
+    read_truncated(response.into_reader(), deflated, limit).map_err(HttpClientError::UreqStdError)
+}
+
+/// See [`read_truncated_body`].
+fn read_truncated(reader: impl Read, deflated: bool, limit: u64) -> std::io::Result<String> {
+    // One byte past the limit tells a body of exactly `limit` bytes from a larger one.
+    let read_limit = limit.saturating_add(1);
+    let mut body = vec![];
+    reader.take(read_limit).read_to_end(&mut body)?;
+    let mut truncated = u64::try_from(body.len()).map_or(true, |len| len > limit);
+
+    let compressed = deflated || body.starts_with(&GZIP_MAGIC);
+    if compressed {
+        let mut decoded = vec![];
+        let mut decoder: Box<dyn Read> = if deflated {
+            Box::new(flate2::read::ZlibDecoder::new(body.as_slice()))
+        } else {
+            Box::new(flate2::read::GzDecoder::new(body.as_slice()))
+        };
+        // A compressed stream that was cut short fails at the end, but everything before that
+        // is still good.
+        let result = (&mut decoder).take(read_limit).read_to_end(&mut decoded);
+        drop(decoder);
+        if let (Err(e), false) = (result, truncated) {
+            return Err(e);
+        }
+        truncated = truncated || u64::try_from(decoded.len()).map_or(true, |len| len > limit);
+        body = decoded;
+    }
+
+    if truncated {
+        let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+        body.truncate(limit);
+        let full_lines = body
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(0, |newline| newline.saturating_add(1));
+        body.truncate(full_lines);
+    }
+    // The cut could also land in the middle of a character.
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Make a request with the `Accept` header, and if the server refuses it, retry once without.
+///
+/// Some servers respond with 406 Not Acceptable or 415 Unsupported Media Type to our `Accept`,
+/// even though the resource is JSON. The retry's response is only used if it looks like JSON.
+fn with_accept_fallback(
+    logger: &Logger,
+    accept: &str,
+    mut get: impl FnMut(Option<&str>) -> Result<ureq::Response, HttpClientError>,
+) -> Result<ureq::Response, HttpClientError> {
+    const NOT_ACCEPTABLE: u16 = 406;
+    const UNSUPPORTED_MEDIA_TYPE: u16 = 415;
+
+    let error = match get(Some(accept)) {
+        Err(HttpClientError::UreqError(error))
+            if matches!(
+                error.as_ref(),
+                ureq::Error::Status(NOT_ACCEPTABLE | UNSUPPORTED_MEDIA_TYPE, _)
+            ) =>
+        {
+            error
+        }
+        result => return result,
+    };
+
+    info!(
+        logger,
+        "Server refused our Accept header, retrying without it"
+    );
+    match get(None) {
+        Ok(response) if is_json_content_type(response.content_type()) => Ok(response),
+        Ok(response) => {
+            info!(
+                logger,
+                "Without the Accept header, {} was served as {}; giving up",
+                response.get_url(),
+                response.content_type()
+            );
+            Err(HttpClientError::UreqError(error))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Make a request, and if it fails because of a transient network error, retry once after `delay`.
+///
+/// Only network errors are retried. HTTP errors, like 503, are the server's answer, and retrying
+/// them straight away would just put more load on a server that's already struggling.
+fn with_transient_error_retry(
+    logger: &Logger,
+    delay: Duration,
+    mut get: impl FnMut() -> Result<ureq::Response, HttpClientError>,
+) -> Result<ureq::Response, HttpClientError> {
+    match get() {
+        Err(HttpClientError::UreqError(error)) if is_transient(&error) => {
+            info!(
+                logger,
+                "Request failed with a transient error, retrying in {:.1}s: {}",
+                delay.as_secs_f64(),
+                error
+            );
+            deadline::sleep(delay);
+            get()
+        }
+        result => result,
+    }
+}
+
+/// Returns `true` if the request failed because of a network problem that might go away by
+/// itself, like a dropped connection.
+///
+/// Timeouts are not retried: a server that is too slow to answer once is likely to be just as
+/// slow a second later, and retrying would only double the time the check takes. On Unix, a read
+/// that times out fails with `WouldBlock` rather than `TimedOut`, so that isn't retried either.
+/// Neither are DNS failures, which the standard library doesn't tell apart from non-existent
+/// domains.
+fn is_transient(error: &ureq::Error) -> bool {
+    use std::io::ErrorKind::*;
+
+    if !matches!(
+        error.kind(),
+        ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io
+    ) {
+        return false;
+    }
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<std::io::Error>() {
+            return matches!(
+                error.kind(),
+                ConnectionReset | ConnectionAborted | BrokenPipe | UnexpectedEof | Interrupted
+            );
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Returns `true` if the server aborted the TLS handshake because it doesn't speak any protocol
+/// that we offered via ALPN, i.e. HTTP/1.1 (see [`tls::client_config`]).
+fn is_http2_only(error: &ureq::Error) -> bool {
+    matches!(
+        tls_error(error),
+        Some(rustls::Error::AlertReceived(
+            rustls::AlertDescription::NoApplicationProtocol
+        ))
+    )
+}
+
+/// The TLS error that made the request fail, if any.
+fn tls_error(error: &ureq::Error) -> Option<&rustls::Error> {
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        // `std::io::Error` reports the source of the error it wraps, skipping the error itself.
+        let tls_error = error.downcast_ref::<rustls::Error>().or_else(|| {
+            error
+                .downcast_ref::<std::io::Error>()
+                .and_then(|error| error.get_ref())
+                .and_then(|error| error.downcast_ref::<rustls::Error>())
+        });
+        if tls_error.is_some() {
+            return tls_error;
+        }
+        source = error.source();
+    }
+    None
+}
+
 fn get_with_type_ignoring_404(
     logger: &Logger,
     agent: &Agent,
+    timeout: Duration,
     url: &Url,
     acceptable_type: Option<&str>,
+    body_limit: u64,
 ) -> Result<ureq::Response, HttpClientError> {
     // Our redirect policy is:
     // - follow redirects as long as they point to the same hostname:port, and schema didn't
     //   change
     // - stop after 10 redirects
+    // - fail as soon as a redirect leads to a URL we've already been to
     const REDIRECTS_LIMIT: u8 = 10;
     let mut redirects_left = REDIRECTS_LIMIT;
     let mut current_url = url.to_owned();
+    let mut visited = HashSet::from([current_url.clone()]);
     let mut response;
     loop {
         let mut request = agent
             .get(current_url.as_str())
-            .timeout(Duration::from_secs(10));
+            .timeout(timeout)
+            .set("Accept-Encoding", ACCEPT_ENCODING);
         if let Some(t) = acceptable_type {
             request = request.set("Accept", t);
         }
 
+        rate_limiter::acquire();
         match request.call() {
             Ok(r) => response = r,
             Err(ureq::Error::Status(404, r)) => response = r,
+            Err(e) if is_http2_only(&e) => {
+                info!(
+                    logger,
+                    "The server rejected HTTP/1.1 during the TLS handshake; it probably only speaks HTTP/2"
+                );
+                return Err(HttpClientError::Http2Only(current_url));
+            }
+            Err(e) if tls_error(&e).is_some() => {
+                return Err(HttpClientError::TlsError(Box::new(e)))
+            }
             Err(e) => return Err(HttpClientError::UreqError(Box::new(e))),
         }
         if !is_redirect(response.status()) {
@@ -183,7 +748,7 @@ fn get_with_type_ignoring_404(
             .and_then(|h| Url::parse(h).ok())
             .ok_or_else(|| HttpClientError::NoLocationHeader(current_url.clone()))?;
 
-        if !is_same_origin(&to, &current_url) {
+        if !is_same_origin(&to, &current_url) && !is_https_upgrade(&current_url, &to) {
             error!(
                 logger,
                 "Redirect points to {} which is of different origin that {}; stopping here",
@@ -193,6 +758,9 @@ fn get_with_type_ignoring_404(
             break;
         }
 
+        if !visited.insert(to.clone()) {
+            return Err(HttpClientError::RedirectLoop(to));
+        }
         current_url = to;
 
         redirects_left = redirects_left.saturating_sub(1);
@@ -201,9 +769,24 @@ fn get_with_type_ignoring_404(
         }
     }
     redirect_into_error(url, &response)?;
+    if declares_body_larger_than(&response, body_limit) {
+        return Err(HttpClientError::BodyTooLarge(body_limit));
+    }
     Ok(response)
 }
 
+/// Returns `true` if the successful `response` announces a body larger than `limit` bytes. This
+/// lets us refuse the body without downloading it.
+///
+/// Error pages are let through, so that e.g. a large 404 page is still reported as a 404.
+fn declares_body_larger_than(response: &ureq::Response, limit: u64) -> bool {
+    (200..300).contains(&response.status())
+        && response
+            .header("content-length")
+            .and_then(|length| length.trim().parse::<u64>().ok())
+            .is_some_and(|length| length > limit)
+}
+
 fn is_temporary_redirect(status: u16) -> bool {
     const FOUND: u16 = 302;
     const SEE_OTHER: u16 = 303;
@@ -248,15 +831,506 @@ fn redirect_into_error(from: &Url, response: &ureq::Response) -> Result<(), Http
     )
 }
 
+/// Read the response body as a string, decompressing it if it's deflated or gzipped.
+///
+/// ureq only decodes gzip and Brotli, so `Content-Encoding: deflate` is handled here. Also, some
+/// servers store documents pre-compressed and serve them without saying so in `Content-Encoding`,
+/// so ureq doesn't decode them. We recognize such bodies by gzip's magic bytes.
+///
+/// The body may not be larger than `limit` bytes, neither before nor after decompression.
+pub fn read_body(response: ureq::Response, limit: u64) -> Result<String, HttpClientError> {
+    let deflated = response
+        .header("content-encoding")
+        .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("deflate"));
+    let mut body = vec![];
+    LimitedReader::new(response.into_reader(), limit)
+        .read_to_end(&mut body)
+        .map_err(into_body_error)?;
+    let body = if deflated {
+        inflate(&body, limit).map_err(into_body_error)?
+    } else {
+        body
+    };
+    decode_body(body, limit).map_err(into_body_error)
+}
+
+/// Decompress a body served with `Content-Encoding: deflate`, which is zlib-wrapped.
+fn inflate(body: &[u8], limit: u64) -> std::io::Result<Vec<u8>> {
+    let mut inflated = vec![];
+    LimitedReader::new(flate2::read::ZlibDecoder::new(body), limit).read_to_end(&mut inflated)?;
+    Ok(inflated)
+}
+
+/// Decompress the body if it's gzipped, and convert it to a string.
+pub fn decode_body(body: Vec<u8>, limit: u64) -> std::io::Result<String> {
+    if u64::try_from(body.len()).map_or(true, |len| len > limit) {
+        return Err(LimitExceeded { limit }.into());
+    }
+
+    let body = if body.starts_with(&GZIP_MAGIC) {
+        let mut decoded = vec![];
+        LimitedReader::new(flate2::read::GzDecoder::new(body.as_slice()), limit)
+            .read_to_end(&mut decoded)?;
+        decoded
+    } else {
+        body
+    };
+
+    String::from_utf8(body).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+/// The error a [`LimitedReader`] fails with.
+#[derive(Debug)]
+struct LimitExceeded {
+    limit: u64,
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "more than {} bytes were read", self.limit)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+impl From<LimitExceeded> for std::io::Error {
+    fn from(error: LimitExceeded) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Turn an error from reading a body into [`HttpClientError::BodyTooLarge`] if a
+/// [`LimitedReader`] ran out.
+fn into_body_error(error: std::io::Error) -> HttpClientError {
+    match error
+        .get_ref()
+        .and_then(|error| error.downcast_ref::<LimitExceeded>())
+    {
+        Some(LimitExceeded { limit }) => HttpClientError::BodyTooLarge(*limit),
+        None => HttpClientError::UreqStdError(error),
+    }
+}
+
+/// A reader that fails with [`LimitExceeded`] once more than `limit` bytes are read from it.
+///
+/// Unlike [`Read::take`], it doesn't pass a truncated body off as the whole thing.
+struct LimitedReader<R> {
+    inner: R,
+    limit: u64,
+    remaining: u64,
+}
+
+impl<R: Read> LimitedReader<R> {
+    fn new(inner: R, limit: u64) -> Self {
+        Self {
+            inner,
+            limit,
+            remaining: limit,
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Reading one byte past the limit tells a body of exactly `limit` bytes from a larger one.
+        let allowed = usize::try_from(self.remaining.saturating_add(1)).unwrap_or(usize::MAX);
+        let len = buf.len().min(allowed);
+        let buf = buf.get_mut(..len).unwrap_or_default();
+        let read = self.inner.read(buf)?;
+        match self
+            .remaining
+            .checked_sub(u64::try_from(read).unwrap_or(u64::MAX))
+        {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(read)
+            }
+            None => Err(LimitExceeded { limit: self.limit }.into()),
+        }
+    }
+}
+
+/// Returns `true` if the `Content-Type` denotes JSON or one of its flavours, like JRD.
+///
+/// Media type parameters, like NodeInfo's `profile`, are ignored.
+pub fn is_json_content_type(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type == "application/json" || media_type.ends_with("+json")
+}
+
+/// Advertise `contact_url` instead of the crawler's homepage in the User-Agent of all requests
+/// made for the rest of the process's life, so that admins know whom to contact about this crawl.
+/// The robots.txt rules are still matched against [`USER_AGENT_TOKEN`].
+pub fn set_contact_url(contact_url: &str) {
+    // Only the first call has any effect; the checker makes just one.
+    let _ = USER_AGENT.set(user_agent_with_contact(contact_url));
+}
+
+fn user_agent_with_contact(contact_url: &str) -> String {
+    format!("{} (+{})", USER_AGENT_NAME, contact_url)
+}
+
+fn user_agent() -> &'static str {
+    USER_AGENT.get().map_or(USER_AGENT_FULL, String::as_str)
+}
+
 /// Returns `true` if the URLs have the same schema, domain, and port.
 fn is_same_origin(lhs: &Url, rhs: &Url) -> bool {
     lhs.origin() == rhs.origin()
 }
 
+/// Returns `true` if `to` is the HTTPS version of the HTTP URL `from`, which is where an onion
+/// service that also serves HTTPS would redirect.
+fn is_https_upgrade(from: &Url, to: &Url) -> bool {
+    from.scheme() == "http"
+        && to.scheme() == "https"
+        && from.host() == to.host()
+        && from.port().is_none()
+        && to.port().is_none()
+}
+
 #[cfg(test)]
 #[allow(clippy::unwrap_used)]
 mod test {
     use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn contact_url_replaces_the_homepage_in_user_agent() {
+        assert_eq!(
+            user_agent_with_contact("https://nodes.fediverse.party"),
+            USER_AGENT_FULL
+        );
+        assert_eq!(
+            user_agent_with_contact("mailto:crawler@example.com"),
+            "Minoru's Fediverse Crawler (+mailto:crawler@example.com)"
+        );
+    }
+
+    #[test]
+    fn alpn_rejection_means_http2_only() {
+        let tls_error = |alert| {
+            let error = std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                rustls::Error::AlertReceived(alert),
+            );
+            ureq::Error::from(error)
+        };
+
+        assert!(is_http2_only(&tls_error(
+            rustls::AlertDescription::NoApplicationProtocol
+        )));
+        assert!(!is_http2_only(&tls_error(
+            rustls::AlertDescription::HandshakeFailure
+        )));
+        let reset = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(!is_http2_only(&ureq::Error::from(reset)));
+    }
+
+    #[test]
+    fn certificate_problems_are_tls_errors() {
+        let expired = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            rustls::Error::InvalidCertificate(rustls::CertificateError::Expired),
+        );
+        assert!(matches!(
+            tls_error(&ureq::Error::from(expired)),
+            Some(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::Expired
+            ))
+        ));
+
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert!(tls_error(&ureq::Error::from(refused)).is_none());
+    }
+
+    #[test]
+    fn retries_once_after_transient_errors() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let io_error = |kind| {
+            Err(HttpClientError::UreqError(Box::new(ureq::Error::from(
+                std::io::Error::from(kind),
+            ))))
+        };
+
+        let mut requests = 0;
+        let response = with_transient_error_retry(&logger, Duration::ZERO, || {
+            requests += 1;
+            match requests {
+                1 => io_error(std::io::ErrorKind::ConnectionReset),
+                _ => Ok("HTTP/1.1 200 OK\r\n\r\n".parse().unwrap()),
+            }
+        })
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(requests, 2);
+
+        let mut requests = 0;
+        let result = with_transient_error_retry(&logger, Duration::ZERO, || {
+            requests += 1;
+            io_error(std::io::ErrorKind::ConnectionAborted)
+        });
+        assert!(result.is_err());
+        assert_eq!(requests, 2);
+    }
+
+    #[test]
+    fn does_not_retry_other_errors() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let errors: [fn() -> HttpClientError; 4] = [
+            || {
+                let response: ureq::Response =
+                    "HTTP/1.1 503 Service Unavailable\r\n\r\n".parse().unwrap();
+                HttpClientError::UreqError(Box::new(ureq::Error::Status(503, response)))
+            },
+            || {
+                let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+                HttpClientError::UreqError(Box::new(ureq::Error::from(refused)))
+            },
+            || {
+                let timeout = std::io::Error::from(std::io::ErrorKind::TimedOut);
+                HttpClientError::UreqError(Box::new(ureq::Error::from(timeout)))
+            },
+            || {
+                let url = Url::parse("https://example.com/api/v1/instance/peers").unwrap();
+                HttpClientError::ForbiddenByRobotsTxt(url)
+            },
+        ];
+        for error in errors {
+            let mut requests = 0;
+            let result = with_transient_error_retry(&logger, Duration::ZERO, || {
+                requests += 1;
+                Err(error())
+            });
+            assert!(result.is_err());
+            assert_eq!(requests, 1);
+        }
+    }
+
+    #[test]
+    fn retries_without_accept_after_406() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let not_acceptable = || {
+            let response: ureq::Response = "HTTP/1.1 406 Not Acceptable\r\n\r\n".parse().unwrap();
+            Err(HttpClientError::UreqError(Box::new(ureq::Error::Status(
+                406, response,
+            ))))
+        };
+
+        let mut requests = vec![];
+        let response = with_accept_fallback(&logger, ACCEPT_JSON, |accept| {
+            requests.push(accept.map(str::to_owned));
+            match accept {
+                Some(_) => not_acceptable(),
+                None => Ok(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n[]"
+                        .parse()
+                        .unwrap(),
+                ),
+            }
+        })
+        .unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(requests, vec![Some(ACCEPT_JSON.to_string()), None]);
+
+        // The fallback is attempted only once, and only for JSON.
+        let mut requests = 0;
+        let result = with_accept_fallback(&logger, ACCEPT_JSON, |accept| {
+            requests += 1;
+            match accept {
+                Some(_) => not_acceptable(),
+                None => Ok("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<html>"
+                    .parse()
+                    .unwrap()),
+            }
+        });
+        assert!(result.is_err());
+        assert_eq!(requests, 2);
+    }
+
+    #[test]
+    fn crawl_delay_for_us_is_preferred() {
+        assert_eq!(crawl_delay(""), None);
+        assert_eq!(crawl_delay("User-agent: *\nDisallow: /admin\n"), None);
+        assert_eq!(
+            crawl_delay("User-agent: *\nCrawl-delay: 2.5\n"),
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(
+            crawl_delay(
+                "User-agent: *\nCrawl-delay: 10\n\n\
+                User-agent: Googlebot\nUser-agent: MinoruFediverseCrawler\ncrawl-delay: 1\n"
+            ),
+            Some(Duration::from_secs(1))
+        );
+        // Other crawlers' delays don't apply to us.
+        assert_eq!(
+            crawl_delay("User-agent: Googlebot\nCrawl-delay: 5\n\nUser-agent: *\nDisallow:\n"),
+            None
+        );
+        assert_eq!(
+            crawl_delay("User-agent: *\nCrawl-delay: 86400\n"),
+            Some(MAX_CRAWL_DELAY)
+        );
+        assert_eq!(crawl_delay("User-agent: *\nCrawl-delay: soon\n"), None);
+        assert_eq!(crawl_delay("User-agent: *\nCrawl-delay: -1\n"), None);
+    }
+
+    #[test]
+    fn requests_are_spaced_out_by_crawl_delay() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let host = Host::Domain("example.invalid".to_string());
+        let robots_txt = "User-agent: *\nCrawl-delay: 0.2\nDisallow: /\n".to_string();
+        let client = HttpClient::new(
+            logger,
+            host,
+            &[],
+            None,
+            Some(robots_txt),
+            &[],
+            Timeouts::default(),
+        )
+        .unwrap();
+        assert_eq!(client.crawl_delay, Some(Duration::from_millis(200)));
+
+        // Requests forbidden by robots.txt aren't made, so they don't wait.
+        let url = Url::parse("https://example.invalid/").unwrap();
+        let start = Instant::now();
+        assert!(client.get(&url, 1024).is_err());
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        *client.last_request.lock().unwrap() = Some(Instant::now());
+        let robots_txt = "User-agent: *\nCrawl-delay: 0.2\n".to_string();
+        let client = HttpClient {
+            robots_txt,
+            ..client
+        };
+        let start = Instant::now();
+        // The host doesn't exist, but the request is only attempted after the delay.
+        assert!(client.get(&url, 1024).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn onion_services_are_recognized() {
+        assert!(is_onion(&Host::parse("example.onion").unwrap()));
+        assert!(is_onion(&Host::parse("Social.Example.Onion").unwrap()));
+        assert!(!is_onion(&Host::parse("onion.example.com").unwrap()));
+        assert!(!is_onion(&Host::parse("onion").unwrap()));
+        assert!(!is_onion(&Host::parse("127.0.0.1").unwrap()));
+
+        assert_eq!(scheme(&Host::parse("example.onion").unwrap()), "http");
+        assert_eq!(scheme(&Host::parse("example.com").unwrap()), "https");
+    }
+
+    #[test]
+    fn only_onion_services_go_through_the_tor_proxy() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let proxy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_address = proxy.local_addr().unwrap().to_string();
+        let request = |hostname: &str| {
+            let host = Host::parse(hostname).unwrap();
+            let client = HttpClient::new(
+                logger.clone(),
+                host,
+                &[],
+                Some(&proxy_address),
+                Some(String::new()),
+                &[],
+                Timeouts {
+                    agent: Duration::from_millis(500),
+                    request: Duration::from_millis(500),
+                },
+            )
+            .unwrap();
+            let url = Url::parse(&format!("https://{}/", hostname)).unwrap();
+            // The proxy refuses to serve anyone, so the request fails either way.
+            assert!(client.get(&url, 1024).is_err());
+        };
+
+        request("example.invalid");
+        proxy.set_nonblocking(true).unwrap();
+        assert!(proxy.accept().is_err());
+        proxy.set_nonblocking(false).unwrap();
+
+        let greeting = std::thread::spawn(move || {
+            let (mut connection, _) = proxy.accept().unwrap();
+            let mut version = [0];
+            connection.read_exact(&mut version).unwrap();
+            // SOCKS5 for "none of your authentication methods are acceptable".
+            connection.write_all(&[5, 0xff]).unwrap();
+            version
+        });
+        request("example.onion");
+        assert_eq!(greeting.join().unwrap(), [5]);
+    }
+
+    #[test]
+    fn html_robots_txt_is_ignored() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+
+        let response: ureq::Response = "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\r\n<html><body>User-agent: *<br>Disallow: /</body></html>"
+            .parse()
+            .unwrap();
+        assert_eq!(robots_txt_from_response(&logger, response).unwrap(), "");
+
+        let response: ureq::Response =
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nUser-agent: *\nDisallow: /"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            robots_txt_from_response(&logger, response).unwrap(),
+            "User-agent: *\nDisallow: /"
+        );
+
+        let response: ureq::Response = "HTTP/1.1 200 OK\r\n\r\nUser-agent: *\nDisallow: /"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            robots_txt_from_response(&logger, response).unwrap(),
+            "User-agent: *\nDisallow: /"
+        );
+    }
+
+    #[test]
+    fn large_robots_txt_is_truncated() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let line = "Disallow: /private\n";
+        // A bit over 512 KiB.
+        let robots_txt = format!("User-agent: *\n{}", line.repeat(30_000));
+
+        let response: ureq::Response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n{}",
+            robots_txt
+        )
+        .parse()
+        .unwrap();
+        let parsed = robots_txt_from_response(&logger, response).unwrap();
+        assert!(parsed.len() <= usize::try_from(ROBOTS_TXT_SIZE_LIMIT).unwrap());
+        assert!(parsed.starts_with("User-agent: *\nDisallow: /private\n"));
+        // No rule was cut in half.
+        assert!(parsed.ends_with(line));
+
+        // A compressed body is cut after decompression.
+        let parsed = read_truncated(gzip(robots_txt.as_bytes()).as_slice(), false, 1024).unwrap();
+        assert_eq!(parsed.len(), 1024 - (1024 - 14) % line.len());
+        assert!(parsed.ends_with(line));
+        // So is a compressed body that is itself too large.
+        let parsed = read_truncated(gzip(robots_txt.as_bytes()).as_slice(), false, 64).unwrap();
+        assert!(robots_txt.starts_with(&parsed));
+        assert!(parsed.is_empty() || parsed.ends_with('\n'));
+
+        let small = "User-agent: *\nDisallow: /";
+        assert_eq!(
+            read_truncated(small.as_bytes(), false, 1024).unwrap(),
+            small
+        );
+    }
 
     #[test]
     fn test_origin() {
@@ -276,5 +1350,209 @@ mod test {
 
         assert!(is_same_origin(&https_example_com, &https_example_com));
         assert!(is_same_origin(&https_example_com, &https_example_com_443));
+
+        assert!(is_https_upgrade(&http_example_com, &https_example_com));
+        assert!(!is_https_upgrade(&https_example_com, &http_example_com));
+        assert!(!is_https_upgrade(&http_example_com, &https_example_org));
+        assert!(!is_https_upgrade(&http_example_com, &https_example_com_444));
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let mut e = GzEncoder::new(Vec::new(), Compression::best());
+        e.write_all(data).unwrap();
+        e.finish().unwrap()
+    }
+
+    #[test]
+    fn decodes_gzipped_bodies() {
+        let body = r#"{"software":{"name":"mastodon"}}"#;
+        assert_eq!(decode_body(body.as_bytes().to_vec(), 1024).unwrap(), body);
+        assert_eq!(decode_body(gzip(body.as_bytes()), 1024).unwrap(), body);
+    }
+
+    #[test]
+    fn limits_decompressed_size() {
+        let body = "x".repeat(4096);
+        let compressed = gzip(body.as_bytes());
+        assert!(compressed.len() < 1024);
+
+        assert!(decode_body(compressed.clone(), 1024).is_err());
+        assert!(decode_body(compressed, 4096).is_ok());
+        assert!(decode_body(body.into_bytes(), 1024).is_err());
+    }
+
+    #[test]
+    fn decodes_deflated_responses() {
+        use flate2::{write::ZlibEncoder, Compression};
+
+        let body = r#"["example.org","example.net"]"#;
+        let mut e = ZlibEncoder::new(Vec::new(), Compression::best());
+        e.write_all(body.as_bytes()).unwrap();
+        let deflated = e.finish().unwrap();
+
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", server.local_addr().unwrap())).unwrap();
+        let request = std::thread::spawn(move || {
+            let (mut connection, _) = server.accept().unwrap();
+            let mut request = vec![];
+            let mut byte = [0];
+            while !request.ends_with(b"\r\n\r\n") {
+                connection.read_exact(&mut byte).unwrap();
+                request.extend_from_slice(&byte);
+            }
+            write!(
+                connection,
+                "HTTP/1.1 200 OK\r\nContent-Encoding: deflate\r\nContent-Length: {}\r\n\r\n",
+                deflated.len()
+            )
+            .unwrap();
+            connection.write_all(&deflated).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let agent = ureq::AgentBuilder::new().redirects(0).build();
+        let response =
+            get_with_type_ignoring_404(&logger, &agent, Duration::from_secs(5), &url, None, 1024)
+                .unwrap();
+        assert_eq!(read_body(response, 1024).unwrap(), body);
+        let request = request.join().unwrap().to_ascii_lowercase();
+        assert!(request.contains("\r\naccept-encoding: gzip, deflate, br\r\n"));
+
+        assert!(inflate(&gzip(body.as_bytes()), 1024).is_err());
+        let bomb = {
+            let mut e = ZlibEncoder::new(Vec::new(), Compression::best());
+            e.write_all(&[b' '; 4096]).unwrap();
+            e.finish().unwrap()
+        };
+        assert!(inflate(&bomb, 1024).is_err());
+        assert!(inflate(&bomb, 4096).is_ok());
+    }
+
+    #[test]
+    fn redirect_loops_are_detected_right_away() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", server.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        {
+            let requests = Arc::clone(&requests);
+            let base = base.clone();
+            // The thread is left blocked on `accept` once the test is over.
+            std::thread::spawn(move || {
+                for connection in server.incoming() {
+                    let mut connection = connection.unwrap();
+                    let mut request = vec![];
+                    let mut byte = [0];
+                    while !request.ends_with(b"\r\n\r\n") {
+                        connection.read_exact(&mut byte).unwrap();
+                        request.extend_from_slice(&byte);
+                    }
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    // /a and /b redirect to each other.
+                    let to = if request.starts_with(b"GET /a ") {
+                        "/b"
+                    } else {
+                        "/a"
+                    };
+                    write!(
+                        connection,
+                        "HTTP/1.1 302 Found\r\nLocation: {}{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        base,
+                        to
+                    )
+                    .unwrap();
+                }
+            });
+        }
+
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let agent = ureq::AgentBuilder::new().redirects(0).build();
+        let url = Url::parse(&format!("{}/a", base)).unwrap();
+        let result =
+            get_with_type_ignoring_404(&logger, &agent, Duration::from_secs(5), &url, None, 1024);
+        assert!(matches!(result, Err(HttpClientError::RedirectLoop(to)) if to == url));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused() {
+        let response = |body: &str| -> ureq::Response {
+            format!("HTTP/1.1 200 OK\r\n\r\n{}", body).parse().unwrap()
+        };
+
+        let body = "x".repeat(1024);
+        assert_eq!(read_body(response(&body), 1024).unwrap(), body);
+        let body = "x".repeat(1025);
+        assert!(matches!(
+            read_body(response(&body), 1024),
+            Err(HttpClientError::BodyTooLarge(1024))
+        ));
+
+        let mut e = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        e.write_all(&[b' '; 4096]).unwrap();
+        let bomb = e.finish().unwrap();
+        assert!(matches!(
+            decode_body(bomb, 1024).map_err(into_body_error),
+            Err(HttpClientError::BodyTooLarge(1024))
+        ));
+    }
+
+    #[test]
+    fn declared_length_is_checked_before_downloading() {
+        let response = |head: &str| -> ureq::Response { head.parse().unwrap() };
+
+        let large = response("HTTP/1.1 200 OK\r\nContent-Length: 1025\r\n\r\n");
+        assert!(declares_body_larger_than(&large, 1024));
+        let small = response("HTTP/1.1 200 OK\r\nContent-Length: 1024\r\n\r\n");
+        assert!(!declares_body_larger_than(&small, 1024));
+        let unknown = response("HTTP/1.1 200 OK\r\n\r\n");
+        assert!(!declares_body_larger_than(&unknown, 1024));
+        let not_found = response("HTTP/1.1 404 Not Found\r\nContent-Length: 1025\r\n\r\n");
+        assert!(!declares_body_larger_than(&not_found, 1024));
+    }
+
+    #[test]
+    fn accepts_json_flavours() {
+        assert!(is_json_content_type("application/json"));
+        assert!(is_json_content_type("application/json; charset=utf-8"));
+        assert!(is_json_content_type("application/jrd+json"));
+        assert!(is_json_content_type("Application/JRD+JSON; charset=utf-8"));
+        assert!(is_json_content_type(
+            r#"application/json; profile="http://nodeinfo.diaspora.software/ns/schema/2.0#""#
+        ));
+        assert!(is_json_content_type("application/activity+json"));
+
+        assert!(!is_json_content_type("text/html"));
+        assert!(!is_json_content_type("text/html; charset=utf-8"));
+        assert!(!is_json_content_type("application/xrd+xml"));
+        assert!(!is_json_content_type(""));
+    }
+
+    #[test]
+    fn pre_resolved_host_is_not_looked_up() {
+        use std::net::Ipv4Addr;
+        use ureq::Resolver;
+
+        let resolver = PreResolved {
+            host: "example.com".to_string(),
+            addresses: vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))],
+        };
+        assert_eq!(
+            resolver.resolve("example.com:443").unwrap(),
+            vec![SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                443
+            )]
+        );
+        assert_eq!(
+            resolver.resolve("127.0.0.1:80").unwrap(),
+            vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 80)]
+        );
     }
 }
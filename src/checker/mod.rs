@@ -1,14 +1,292 @@
+mod deadline;
 mod http_client;
+pub mod rate_limiter;
+mod reporter;
+mod tls;
 
 use crate::{
-    checker::http_client::{HttpClient, HttpClientError},
-    ipc, with_loc,
+    checker::http_client::{
+        is_json_content_type, read_body, scheme, HttpClient, HttpClientError, Timeouts,
+        ACCEPT_NODEINFO, ACCEPT_XRD,
+    },
+    checker::reporter::Reporter,
+    domain::Domain,
+    ipc, software, with_loc,
 };
 use anyhow::{anyhow, bail, Context};
+use quick_xml::events::Event;
+use rustls::pki_types::CertificateDer;
 use serde::Deserialize;
-use slog::{error, info, o, Logger};
+use slog::{debug, error, info, o, warn, Logger};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use url::{Host, Url};
 
+/// Settings of a single check.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Addresses of the host, resolved beforehand. If empty, the host is resolved as usual.
+    pub addresses: Vec<IpAddr>,
+
+    /// Only find out if the instance is alive and whether it wants to be hidden; don't fetch peers.
+    pub privacy_only: bool,
+
+    /// Overrides all HTTP timeouts. Meant for manually checking slow instances.
+    pub timeout: Option<Duration>,
+
+    /// Print a human-readable summary of the peers to stderr. Meant for manual checks.
+    pub summary: bool,
+
+    /// Print how long each phase of the check took to stderr. Meant for finding out why an
+    /// instance is slow to check.
+    pub timings: bool,
+
+    /// Software names, and the paths at which their Mastodon-ish peers lists are found. These
+    /// take precedence over the built-in [`DEFAULT_PEERS_PATH`].
+    pub peers_paths: Vec<(String, String)>,
+
+    /// Only consider the instance alive if its app responds, not just its NodeInfo, which might
+    /// be a static file served while the app is down. See [`is_app_down`].
+    pub strict_liveness: bool,
+
+    /// The "host:port" of a SOCKS5 proxy through which Tor onion services are reached. Other
+    /// hosts are connected to directly.
+    pub tor_proxy: Option<String>,
+
+    /// PEM files with certificates to trust in addition to the usual CAs, e.g. the CA of one's own
+    /// instances. They're trusted for all hosts.
+    pub extra_ca_certs: Vec<PathBuf>,
+
+    /// Make at most one request per this long, to all hosts taken together. The Orchestrator sets
+    /// this so that no single check exceeds `--max-rps`.
+    pub request_interval: Option<Duration>,
+
+    /// A URL at which the operator of the crawl can be reached, advertised in the User-Agent
+    /// instead of the crawler's homepage.
+    pub contact_url: Option<String>,
+
+    /// How the results are printed to stdout.
+    pub format: Format,
+}
+
+/// How the checker prints the results of the check.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// A stream of [`ipc::CheckerResponse`] messages, one per line, for the Orchestrator.
+    #[default]
+    Ipc,
+
+    /// A single JSON object once the check is over, for scripts.
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "ipc" => Ok(Self::Ipc),
+            "json" => Ok(Self::Json),
+            _ => bail!("Unknown format {}, expected \"ipc\" or \"json\"", s),
+        }
+    }
+}
+
+impl Options {
+    /// Command-line arguments that make a checker process use these options.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if !self.addresses.is_empty() {
+            let addresses = self
+                .addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            args.push("--resolved-addresses".to_string());
+            args.push(addresses);
+        }
+        if self.privacy_only {
+            args.push("--privacy-only".to_string());
+        }
+        if let Some(timeout) = self.timeout {
+            args.push("--timeout-secs".to_string());
+            args.push(timeout.as_secs().to_string());
+        }
+        if self.summary {
+            args.push("--summary".to_string());
+        }
+        if self.timings {
+            args.push("--timings".to_string());
+        }
+        for (software, path) in &self.peers_paths {
+            args.push("--peers-path".to_string());
+            args.push(format!("{}={}", software, path));
+        }
+        if self.strict_liveness {
+            args.push("--strict-liveness".to_string());
+        }
+        if let Some(tor_proxy) = &self.tor_proxy {
+            args.push("--tor-proxy".to_string());
+            args.push(tor_proxy.clone());
+        }
+        for path in &self.extra_ca_certs {
+            args.push("--extra-ca-cert".to_string());
+            args.push(path.to_string_lossy().into_owned());
+        }
+        if let Some(interval) = self.request_interval {
+            args.push("--request-interval-ms".to_string());
+            args.push(interval.as_millis().to_string());
+        }
+        if let Some(contact_url) = &self.contact_url {
+            args.push("--contact-url".to_string());
+            args.push(contact_url.clone());
+        }
+        if self.format == Format::Json {
+            args.push("--format".to_string());
+            args.push("json".to_string());
+        }
+        args
+    }
+
+    fn timeouts(&self) -> Timeouts {
+        match self.timeout {
+            Some(timeout) => Timeouts {
+                agent: timeout,
+                request: timeout,
+            },
+            None => Timeouts::default(),
+        }
+    }
+
+    /// How long the whole check may take. `--timeout-secs` is meant for manually checking slow
+    /// instances, so it lifts the limit.
+    fn deadline(&self) -> Option<Duration> {
+        self.timeout.is_none().then_some(CHECK_DEADLINE)
+    }
+}
+
+/// The longest a check may take, all requests included. The time spent waiting between requests,
+/// as asked by robots.txt or `--max-rps`, comes on top of this; see [`deadline`].
+pub const CHECK_DEADLINE: Duration = Duration::from_secs(90);
+
+/// How many peers are shown in the summary.
+const SUMMARY_SAMPLE_SIZE: usize = 5;
+
+/// A human-readable overview of the instance's peers, printed when `--summary` is given.
+#[derive(Debug, PartialEq, Eq)]
+struct PeersSummary {
+    total: usize,
+    valid_domains: usize,
+    sample: Vec<String>,
+}
+
+impl PeersSummary {
+    fn new(peers: &[Host]) -> Self {
+        Self {
+            total: peers.len(),
+            valid_domains: peers
+                .iter()
+                .filter(|peer| Domain::from_host(peer).is_ok())
+                .count(),
+            sample: peers
+                .iter()
+                .take(SUMMARY_SAMPLE_SIZE)
+                .map(|peer| peer.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for PeersSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} peers, {} of them valid domains",
+            self.total, self.valid_domains
+        )?;
+        if !self.sample.is_empty() {
+            write!(f, ", e.g. {}", self.sample.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A phase of the check, as shown by `--timings`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    RobotsTxt,
+    WellKnown,
+    NodeInfo,
+    Peers,
+}
+
+impl Phase {
+    const ALL: [Phase; 4] = [
+        Self::RobotsTxt,
+        Self::WellKnown,
+        Self::NodeInfo,
+        Self::Peers,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::RobotsTxt => "robots.txt",
+            Self::WellKnown => "well-known NodeInfo",
+            Self::NodeInfo => "NodeInfo document",
+            Self::Peers => "peers",
+        }
+    }
+}
+
+/// Wall-clock time spent on each phase of the check. The peers are fetched on another thread,
+/// hence the mutex.
+#[derive(Debug, Default)]
+struct Timings(Mutex<Vec<(Phase, Duration)>>);
+
+impl Timings {
+    /// Run `f`, noting down how long it took.
+    fn measure<T>(&self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        if let Ok(mut timings) = self.0.lock() {
+            timings.push((phase, start.elapsed()));
+        }
+        result
+    }
+}
+
+impl std::fmt::Display for Timings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let timings = self.0.lock().map_err(|_| std::fmt::Error)?;
+        write!(f, "Timings:")?;
+        for phase in Phase::ALL {
+            match timings.iter().find(|(p, _)| *p == phase) {
+                Some((_, duration)) => {
+                    write!(f, "\n  {}: {:.3}s", phase.name(), duration.as_secs_f64())?
+                }
+                None => write!(f, "\n  {}: not reached", phase.name())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// NodeInfo documents are small; anything larger than this is not a NodeInfo document.
+const NODEINFO_SIZE_LIMIT: u64 = 1024 * 1024;
+
+/// Mastodon's instance descriptions are a few kilobytes, even with a long list of rules.
+const INSTANCE_V2_SIZE_LIMIT: u64 = 1024 * 1024;
+
+/// The peers lists of the biggest instances are a few megabytes.
+const PEERS_SIZE_LIMIT: u64 = 8 * 1024 * 1024;
+
+/// StatusNet's config.json and siteinfo.json are a few kilobytes.
+const SITE_CONFIG_SIZE_LIMIT: u64 = 1024 * 1024;
+
 #[derive(Debug)]
 struct UreqHttpStatusError {
     status: u16,
@@ -26,6 +304,43 @@ impl std::error::Error for UreqHttpStatusError {
     }
 }
 
+/// The server answered with an HTML page where a NodeInfo document was expected.
+#[derive(Debug)]
+struct NotNodeInfo {
+    url: String,
+    content_type: String,
+}
+
+impl std::fmt::Display for NotNodeInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(
+            f,
+            "{} is not NodeInfo: it was served as {}, which is likely a parked domain or a landing page",
+            self.url, self.content_type
+        )
+    }
+}
+
+impl std::error::Error for NotNodeInfo {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+/// The error to report if the response, which doesn't parse as JSON, was served as an HTML page.
+///
+/// Parked domains and landing pages answer any URL with an HTML page and a 200 status. Parsing
+/// that as JSON fails with a confusing error that looks just like a real instance's broken
+/// NodeInfo. The type alone proves nothing, though: some instances serve valid JSON as
+/// `text/html`, see [`log_unexpected_content_type()`].
+fn html_error(response: &ureq::Response) -> Option<NotNodeInfo> {
+    let media_type = response.content_type().trim().to_ascii_lowercase();
+    (media_type == "text/html" || media_type == "application/xhtml+xml").then(|| NotNodeInfo {
+        url: response.get_url().to_owned(),
+        content_type: response.content_type().to_owned(),
+    })
+}
+
 /// Turns a reference to a response into an error if the server returned an HTTP error.
 ///
 /// This mimics `reqwest::Response::error_for_status_ref()`.
@@ -42,41 +357,196 @@ fn error_for_status_ref(response: &ureq::Response) -> Result<&ureq::Response, Ur
     }
 }
 
-pub fn main(logger: Logger, host: Host) -> anyhow::Result<()> {
+/// The HTTP status code with which the server responded, if that's what caused the error.
+fn error_status(error: &anyhow::Error) -> Option<u16> {
+    if let Some(HttpClientError::UreqError(err)) = error.downcast_ref::<HttpClientError>() {
+        if let ureq::Error::Status(status, _) = err.as_ref() {
+            return Some(*status);
+        }
+    }
+    error
+        .downcast_ref::<UreqHttpStatusError>()
+        .map(|err| err.status)
+}
+
+/// Some HTTP error codes mean that the instance is up, but refuses to serve us.
+fn blocked_reason(status: u16) -> Option<ipc::BlockedReason> {
+    const UNAUTHORIZED: u16 = 401;
+    const UNAVAILABLE_FOR_LEGAL_REASONS: u16 = 451;
+
+    match status {
+        UNAUTHORIZED => Some(ipc::BlockedReason::AuthenticationRequired),
+        UNAVAILABLE_FOR_LEGAL_REASONS => Some(ipc::BlockedReason::UnavailableForLegalReasons),
+        _ => None,
+    }
+}
+
+/// The instance serves NodeInfo, but its app responds with server errors; see
+/// [`Options::strict_liveness`].
+#[derive(Debug)]
+struct AppIsDown;
+
+impl std::fmt::Display for AppIsDown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "NodeInfo is served, but the app responds with server errors"
+        )
+    }
+}
+
+impl std::error::Error for AppIsDown {}
+
+/// Attached to errors that happened after the instance's state was reported to the Orchestrator,
+/// so that we don't report another one.
+#[derive(Debug)]
+struct StateAlreadyReported;
+
+impl std::fmt::Display for StateAlreadyReported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The instance's state is already reported")
+    }
+}
+
+pub fn main(logger: Logger, host: Host, options: Options) -> anyhow::Result<()> {
     let logger = logger.new(o!("host" => host.to_string()));
     info!(logger, "Started the checker");
 
-    // Here we handle results of redirects. If we don't call `println!` here, the Orchestrator will
-    // mark the host as dead.
-    if let Err(e) = try_check(&logger, host) {
-        if let Some(error) = e.downcast_ref::<HttpClientError>() {
+    let reporter = Arc::new(Reporter::new(&host, options.format));
+    if let Some(deadline) = options.deadline() {
+        let reporter = reporter.clone();
+        let timeout_logger = logger.clone();
+        deadline::give_up_after(logger.clone(), deadline, move || {
+            // `--format json` still prints what's known so far.
+            if let Err(e) = reporter.finish() {
+                error!(timeout_logger, "Failed to print the summary: {:?}", e);
+            }
+        });
+    }
+    if let Some(contact_url) = &options.contact_url {
+        http_client::set_contact_url(contact_url);
+    }
+    if let Some(interval) = options.request_interval {
+        rate_limiter::limit_rate(interval);
+    }
+
+    let result = run(&logger, host, &options, &reporter);
+    reporter.finish()?;
+    result
+}
+
+/// Check the `host` without spawning a checker process, and return the responses that the
+/// checker would've sent to the Orchestrator.
+///
+/// Like [`main`], this gives up after [`CHECK_DEADLINE`], but instead of exiting it returns the
+/// responses sent by then. [`Options::contact_url`] and [`Options::request_interval`] are
+/// process-wide, so only the first call sets them. If the check fails, the error is only logged,
+/// as the responses already say what happened.
+pub fn check_in_process(
+    logger: &Logger,
+    host: Host,
+    options: &Options,
+) -> anyhow::Result<Vec<ipc::CheckerResponse>> {
+    let logger = logger.new(o!("host" => host.to_string()));
+    info!(logger, "Checking in-process");
+    if let Some(contact_url) = &options.contact_url {
+        http_client::set_contact_url(contact_url);
+    }
+    if let Some(interval) = options.request_interval {
+        rate_limiter::limit_rate(interval);
+    }
+
+    let reporter = Arc::new(Reporter::capturing(&host));
+    let (sender, receiver) = mpsc::channel();
+    {
+        let logger = logger.clone();
+        let reporter = reporter.clone();
+        let options = options.clone();
+        std::thread::spawn(move || {
+            // The receiver is gone if we gave up on the check already.
+            let _ = sender.send(run(&logger, host, &options, &reporter));
+        });
+    }
+    let result = match options.deadline() {
+        Some(limit) => deadline::wait_for(&logger, limit, &receiver),
+        None => receiver.recv().ok(),
+    };
+    if let Some(Err(e)) = result {
+        info!(logger, "The check failed: {:#}", e);
+    }
+
+    // A check that ran out of time is still going; this waits for it to finish sending the peers.
+    let _stdout = std::io::stdout().lock();
+    reporter.take_captured()
+}
+
+/// Check the `host`, sending the results to the `reporter`.
+fn run(logger: &Logger, host: Host, options: &Options, reporter: &Reporter) -> anyhow::Result<()> {
+    let timings = Timings::default();
+    reporter.send(ipc::CheckerResponse::Hello {
+        protocol_version: ipc::PROTOCOL_VERSION,
+    })?;
+    let result = try_check(logger, host, options, &timings, reporter);
+    if options.timings {
+        // stdout is reserved for the messages to the Orchestrator.
+        eprintln!("{}", timings);
+    }
+
+    // Here we report the redirects and the reasons why the instance is dead. If nothing is
+    // reported, the Orchestrator will mark the host as dead for an unknown reason.
+    if let Err(e) = result {
+        if e.downcast_ref::<StateAlreadyReported>().is_some() {
+            error!(logger, "Check failed after reporting the state: {:?}", e);
+        } else if e.downcast_ref::<AppIsDown>().is_some() {
+            info!(logger, "The instance is dead: {}", e);
+            report_dead(reporter, &e)?;
+        } else if let Some(reason) = error_status(&e).and_then(blocked_reason) {
+            info!(logger, "Instance is blocked: {}", reason);
+            reporter.send(ipc::CheckerResponse::State {
+                state: ipc::InstanceState::Blocked { reason },
+            })?;
+        } else if let Some(error) = e.downcast_ref::<HttpClientError>() {
             match error {
                 HttpClientError::Moving(redir) => {
                     if let Some(to) = redir.to.host().map(|h| h.to_owned()) {
                         info!(logger, "Instance is moving to {}", to);
-                        let moving = serde_json::to_string(&ipc::CheckerResponse::State {
+                        reporter.send(ipc::CheckerResponse::State {
                             state: ipc::InstanceState::Moving { to },
-                        })
-                        .context(with_loc!("Serializing Moving message"))?;
-                        println!("{}", moving);
+                        })?;
                     }
                 }
 
                 HttpClientError::Moved(redir) => {
                     if let Some(to) = redir.to.host().map(|h| h.to_owned()) {
                         info!(logger, "Instance has moved to {}", to);
-                        let moved = serde_json::to_string(&ipc::CheckerResponse::State {
+                        reporter.send(ipc::CheckerResponse::State {
                             state: ipc::InstanceState::Moved { to },
-                        })
-                        .context(with_loc!("Serializing Moved message"))?;
-                        println!("{}", moved);
+                        })?;
                     }
                 }
 
-                // Propagate all other errors upwards. A lack of response from the checker will
-                // make the orchestrator to mark this host as dead.
+                HttpClientError::Http2Only(url) => {
+                    error!(
+                        logger,
+                        "The instance looks dead, but it might just be serving {} over HTTP/2 only",
+                        url
+                    );
+                    report_dead(reporter, &e)?;
+                }
+
+                HttpClientError::TlsError(err) => {
+                    error!(
+                        logger,
+                        "The instance looks dead, but it might just have a broken TLS certificate: {}",
+                        err
+                    );
+                    report_dead(reporter, &e)?;
+                }
+
+                // Propagate all other errors upwards.
                 _ => {
                     error!(logger, "The instance is dead: {:?}", error);
+                    report_dead(reporter, &e)?;
                 }
             }
         } else {
@@ -84,26 +554,179 @@ pub fn main(logger: Logger, host: Host) -> anyhow::Result<()> {
                 logger,
                 "Couldn't downcast the error to HttpClientError: {:?}", e
             );
+            report_dead(reporter, &e)?;
         }
 
         return Err(e);
     }
 
     info!(logger, "Check finished");
-
     Ok(())
 }
 
-fn try_check(logger: &Logger, host: Host) -> anyhow::Result<()> {
-    let client = HttpClient::new(logger.clone(), host.clone())
+/// Tell the Orchestrator that the instance is dead because of the `error`.
+fn report_dead(reporter: &Reporter, error: &anyhow::Error) -> anyhow::Result<()> {
+    reporter.send(ipc::CheckerResponse::Dead {
+        // The whole chain of contexts, on a single line.
+        reason: format!("{:#}", error),
+        cause: death_cause(error),
+    })
+}
+
+/// Find out what kind of failure the `error` is, so that the Orchestrator can tell the dead
+/// instances apart without parsing the error messages.
+fn death_cause(error: &anyhow::Error) -> ipc::DeathCause {
+    for cause in error.chain() {
+        if cause.downcast_ref::<NotNodeInfo>().is_some() {
+            return ipc::DeathCause::NotNodeInfo;
+        }
+        if cause.downcast_ref::<AppIsDown>().is_some() {
+            return ipc::DeathCause::AppIsDown;
+        }
+        match cause.downcast_ref::<HttpClientError>() {
+            Some(HttpClientError::TlsError(_)) => return ipc::DeathCause::TlsError,
+            Some(HttpClientError::Http2Only(_)) => return ipc::DeathCause::Http2Only,
+            Some(HttpClientError::RedirectLoop(_)) => return ipc::DeathCause::RedirectLoop,
+            Some(HttpClientError::BodyTooLarge(_)) => return ipc::DeathCause::BodyTooLarge,
+            Some(HttpClientError::ForbiddenByRobotsTxt(_)) => {
+                return ipc::DeathCause::ForbiddenByRobotsTxt
+            }
+            _ => {}
+        }
+    }
+    ipc::DeathCause::Other
+}
+
+/// Read the certificates from all the `paths`; see [`Options::extra_ca_certs`].
+pub fn load_extra_ca_certs(paths: &[PathBuf]) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let mut certificates = vec![];
+    for path in paths {
+        certificates.extend(tls::load_certificates(Path::new(path))?);
+    }
+    Ok(certificates)
+}
+
+fn try_check(
+    logger: &Logger,
+    host: Host,
+    options: &Options,
+    timings: &Timings,
+    reporter: &Reporter,
+) -> anyhow::Result<()> {
+    let extra_roots = load_extra_ca_certs(&options.extra_ca_certs)?;
+    let client = timings
+        .measure(Phase::RobotsTxt, || {
+            HttpClient::new(
+                logger.clone(),
+                host.clone(),
+                &options.addresses,
+                options.tor_proxy.as_deref(),
+                None,
+                &extra_roots,
+                options.timeouts(),
+            )
+        })
         .context(with_loc!("Initializing HTTP client"))?;
 
-    let software = get_software(logger, &client, &host)
+    let (software, mut metadata) = get_software(logger, &client, &host, timings)
         .context(with_loc!("Determining instance's software"))?;
+    metadata.software = Some(software.clone());
+    metadata.certificate_expires_at = client
+        .certificate_expiry()
+        .and_then(|expiry| expiry.duration_since(UNIX_EPOCH).ok())
+        .map(|expiry| expiry.as_secs());
     info!(logger, "{} runs {}", host, software);
 
+    if options.privacy_only {
+        return report_alive(logger, &client, &host, &software, reporter);
+    }
+
+    let report = || -> anyhow::Result<()> {
+        report_alive(logger, &client, &host, &software, reporter)?;
+        report_metadata(logger, &client, &host, &software, reporter, metadata)
+    };
+    let fetch_peers = || {
+        timings.measure(Phase::Peers, || {
+            get_peers(logger, &client, &host, &software, &options.peers_paths)
+        })
+    };
+    let (reported, peers) = if options.strict_liveness {
+        // We can't report the instance alive until we know that its app responds.
+        let peers = fetch_peers();
+        let instance_api = || {
+            (software::family(&software) == "mastodon")
+                .then(|| get_instance_v2(logger, &client, &host))
+        };
+        if is_app_down(&peers, instance_api) {
+            return Err(anyhow!(AppIsDown));
+        }
+        (report(), peers)
+    } else {
+        // Big instances take a while to serve their peers lists, so we fetch it while we're busy
+        // with everything else. That's at most two concurrent requests to the instance. The
+        // messages are still sent in the same order.
+        overlapped(report, fetch_peers)?
+    };
+    reported?;
+    let peers = peers
+        .context(with_loc!("Fetching instance's peers list"))
+        .context(StateAlreadyReported)?;
+    info!(logger, "{} has {} peers", host, peers.len());
+    reporter.send_peers(&peers)?;
+
+    if options.summary {
+        // stdout is reserved for the messages to the Orchestrator.
+        eprintln!("{}", PeersSummary::new(&peers));
+    }
+
+    Ok(())
+}
+
+/// Returns `true` if the instance's dynamic endpoints all failed with server errors. Those are the
+/// `peers` list and, if the software has one, the `instance_api`; the latter is only fetched if
+/// the former failed.
+///
+/// Software that has neither can't be told apart from a static NodeInfo, so it's never "down".
+fn is_app_down<P, I>(
+    peers: &anyhow::Result<P>,
+    instance_api: impl FnOnce() -> Option<anyhow::Result<I>>,
+) -> bool {
+    let is_server_error = |result: Result<(), &anyhow::Error>| {
+        result
+            .err()
+            .and_then(error_status)
+            .is_some_and(|status| (500..600).contains(&status))
+    };
+    is_server_error(peers.as_ref().map(|_| ()))
+        && instance_api().is_none_or(|result| is_server_error(result.as_ref().map(|_| ())))
+}
+
+/// Run `background` on a separate thread while running `foreground` on this one, and return both
+/// results.
+fn overlapped<F, B: Send>(
+    foreground: impl FnOnce() -> F,
+    background: impl FnOnce() -> B + Send,
+) -> anyhow::Result<(F, B)> {
+    std::thread::scope(|scope| {
+        let background = scope.spawn(background);
+        let foreground = foreground();
+        let background = background
+            .join()
+            .map_err(|_| anyhow!("The background fetch panicked"))?;
+        Ok((foreground, background))
+    })
+}
+
+/// Tell the Orchestrator that the instance is alive.
+fn report_alive(
+    logger: &Logger,
+    client: &HttpClient,
+    host: &Host,
+    software: &str,
+    reporter: &Reporter,
+) -> anyhow::Result<()> {
     let hide_from_list = {
-        match is_instance_private(&client, &host, &software) {
+        match is_instance_private(client, host, software) {
             Ok(result) => result,
             Err(e) => {
                 info!(logger, "Couldn't check if instance is private: {}", e);
@@ -111,35 +734,112 @@ fn try_check(logger: &Logger, host: Host) -> anyhow::Result<()> {
             }
         }
     };
-    let alive = serde_json::to_string(&ipc::CheckerResponse::State {
-        state: ipc::InstanceState::Alive { hide_from_list },
-    })
-    .context(with_loc!("Serializing Alive message"))?;
     info!(logger, "The instance is alive");
-    println!("{}", alive);
+    reporter.send(ipc::CheckerResponse::State {
+        state: ipc::InstanceState::Alive { hide_from_list },
+    })?;
 
-    let peers = get_peers(logger, &client, &host, &software)
-        .context(with_loc!("Fetching instance's peers list"))?;
-    info!(logger, "{} has {} peers", host, peers.len());
-    for instance in peers {
-        let peer = serde_json::to_string(&ipc::CheckerResponse::Peer { peer: instance })
-            .context(with_loc!("Serializing Peer message"))?;
-        println!("{}", peer);
+    Ok(())
+}
+
+/// Complete the `metadata` with the instance description, if the software has one, and send it
+/// to the Orchestrator.
+///
+/// Mastodon's instance title takes precedence over NodeInfo's `metadata.nodeName`.
+fn report_metadata(
+    logger: &Logger,
+    client: &HttpClient,
+    host: &Host,
+    software: &str,
+    reporter: &Reporter,
+    mut metadata: ipc::Metadata,
+) -> anyhow::Result<()> {
+    if software::family(software) == "mastodon" {
+        let instance = get_instance_v2(logger, client, host)
+            .map(|instance| verified_instance_v2(logger, host, instance));
+        match instance {
+            Ok(Err(domain)) => metadata.misconfigured_domain = Some(domain),
+            Ok(Ok(instance)) => {
+                metadata.rules = instance.rules();
+                metadata.contact = instance.contact(host);
+                if let Some(thumbnail) = instance.thumbnail() {
+                    metadata.thumbnail = Some(thumbnail);
+                }
+                if let Some(title) = instance.title {
+                    metadata.title = Some(title);
+                }
+            }
+            Err(e) => info!(logger, "Couldn't fetch instance description: {:?}", e),
+        }
     }
 
+    reporter.send(ipc::CheckerResponse::Metadata {
+        metadata: metadata.sanitized(),
+    })?;
+
     Ok(())
 }
 
-fn get_software(logger: &Logger, client: &HttpClient, host: &Host) -> anyhow::Result<String> {
-    let nodeinfo = fetch_nodeinfo(logger, client, host).context(with_loc!("Fetching NodeInfo"))?;
-    serde_json::from_str(&nodeinfo)
+/// Find out the software that the instance runs. Along the way, we also collect its metadata.
+fn get_software(
+    logger: &Logger,
+    client: &HttpClient,
+    host: &Host,
+    timings: &Timings,
+) -> anyhow::Result<(String, ipc::Metadata)> {
+    let (nodeinfo, mut metadata) =
+        fetch_nodeinfo(logger, client, host, timings).context(with_loc!("Fetching NodeInfo"))?;
+    let software = software_from_nodeinfo(logger, &nodeinfo)?;
+    metadata.title = title_from_nodeinfo(&nodeinfo);
+    metadata.thumbnail = thumbnail_from_nodeinfo(&nodeinfo);
+    Ok((software, metadata))
+}
+
+/// The instance's name as advertised in NodeInfo's free-form `metadata` object.
+fn title_from_nodeinfo(nodeinfo: &str) -> Option<String> {
+    let nodeinfo: serde_json::Value = serde_json::from_str(nodeinfo).ok()?;
+    nodeinfo
+        .pointer("/metadata/nodeName")?
+        .as_str()
+        .map(str::to_owned)
+}
+
+/// Fields of NodeInfo's `metadata` object which some software uses for the instance's logo, in
+/// order of preference.
+const NODEINFO_LOGO_FIELDS: &[&str] = &["logoImageUrl", "iconUrl"];
+
+/// The instance's logo as advertised in NodeInfo. The URL is validated later, by
+/// [`ipc::Metadata::sanitized`].
+fn thumbnail_from_nodeinfo(nodeinfo: &str) -> Option<String> {
+    let nodeinfo: serde_json::Value = serde_json::from_str(nodeinfo).ok()?;
+    let metadata = nodeinfo.get("metadata")?;
+    NODEINFO_LOGO_FIELDS
+        .iter()
+        .find_map(|field| metadata.get(field)?.as_str())
+        .map(str::to_owned)
+}
+
+/// Places in the NodeInfo document where the software name can be found, in order of preference.
+///
+/// The first one is what the NodeInfo schema prescribes; the rest are used by older or custom
+/// implementations.
+const SOFTWARE_NAME_PATHS: &[&[&str]] = &[&["software", "name"], &["softwareName"]];
+
+fn software_from_nodeinfo(logger: &Logger, nodeinfo: &str) -> anyhow::Result<String> {
+    serde_json::from_str(nodeinfo)
         .map_err(|err| err.into())
         .and_then(|obj: serde_json::Value| {
-            #[allow(clippy::indexing_slicing)] // Indexing into Value returns Value::Null
-            match &obj["software"]["name"] {
-                serde_json::Value::Null => bail!("No software name in NodeInfo"),
-                name => Ok(name.to_string()),
+            for path in SOFTWARE_NAME_PATHS {
+                let name = path
+                    .iter()
+                    .try_fold(&obj, |value, key| value.get(key))
+                    .and_then(|name| name.as_str());
+                if let Some(name) = name {
+                    debug!(logger, "Found software name at {}", path.join("."));
+                    return Ok(name.to_owned());
+                }
             }
+            bail!("No software name in NodeInfo")
         })
         .map_err(|err| {
             let msg = format!(
@@ -180,13 +880,40 @@ struct NodeInfoPointerLink {
     href: String,
 }
 
-fn fetch_nodeinfo(logger: &Logger, client: &HttpClient, host: &Host) -> anyhow::Result<String> {
-    let pointer = fetch_nodeinfo_pointer(logger, client, host)
+fn fetch_nodeinfo(
+    logger: &Logger,
+    client: &HttpClient,
+    host: &Host,
+    timings: &Timings,
+) -> anyhow::Result<(String, ipc::Metadata)> {
+    let pointer = timings
+        .measure(Phase::WellKnown, || {
+            match fetch_nodeinfo_pointer(logger, client, host) {
+                Err(e) if error_status(&e) == Some(404) => {
+                    info!(logger, "No well-known NodeInfo document, trying host-meta");
+                    fetch_nodeinfo_pointer_from_host_meta(logger, client, host).map_err(
+                        |host_meta_err| {
+                            info!(
+                                logger,
+                                "Couldn't find NodeInfo in host-meta: {:?}", host_meta_err
+                            );
+                            // The instance is judged by its lack of NodeInfo, not by host-meta.
+                            e
+                        },
+                    )
+                }
+                result => result,
+            }
+        })
         .context(with_loc!("Fetching NodeInfo well-known document"))?;
     let url = pick_highest_supported_nodeinfo_version(&pointer).context(with_loc!(
         "Picking the highest supported NodeInfo version out of JRD document"
     ))?;
-    fetch_nodeinfo_document(logger, client, &url).context(with_loc!("Fetching NodeInfo document"))
+    timings
+        .measure(Phase::NodeInfo, || {
+            fetch_nodeinfo_document(logger, client, &url)
+        })
+        .context(with_loc!("Fetching NodeInfo document"))
 }
 
 fn fetch_nodeinfo_pointer(
@@ -194,12 +921,12 @@ fn fetch_nodeinfo_pointer(
     client: &HttpClient,
     host: &Host,
 ) -> anyhow::Result<NodeInfoPointer> {
-    let url = format!("https://{}/.well-known/nodeinfo", host);
+    let url = format!("{}://{}/.well-known/nodeinfo", scheme(host), host);
     let url = Url::parse(&url).context(with_loc!(
         "Formatting URL of the well-known NodeInfo document"
     ))?;
     let response = client
-        .get(&url)
+        .get_with_accept(&url, ACCEPT_NODEINFO, NODEINFO_SIZE_LIMIT)
         .context(with_loc!("Fetching the well-known NodeInfo document"))?;
     error_for_status_ref(&response).map_err(|err| {
         error!(
@@ -207,10 +934,96 @@ fn fetch_nodeinfo_pointer(
             "http_error" => err.to_string(), "url" => url.to_string());
         err
     })?;
+    parse_nodeinfo_pointer(logger, response)
+}
+
+fn parse_nodeinfo_pointer(
+    logger: &Logger,
+    response: ureq::Response,
+) -> anyhow::Result<NodeInfoPointer> {
+    log_unexpected_content_type(logger, &response);
+    let html_error = html_error(&response);
+
+    let pointer = read_body(response, NODEINFO_SIZE_LIMIT)
+        .context(with_loc!("Getting NodeInfo pointer's body"))?;
+    match (
+        serde_json::from_str::<NodeInfoPointer>(&pointer),
+        html_error,
+    ) {
+        (Ok(pointer), _) => Ok(pointer),
+        (Err(_), Some(html_error)) => {
+            error!(logger, "{}", html_error);
+            Err(html_error.into())
+        }
+        (Err(e), None) => Err(e).context(with_loc!("Decoding NodeInfo pointer as JSON")),
+    }
+}
+
+/// Some older instances don't serve `/.well-known/nodeinfo`, but link to NodeInfo from the XRD
+/// flavour of `/.well-known/host-meta`.
+fn fetch_nodeinfo_pointer_from_host_meta(
+    logger: &Logger,
+    client: &HttpClient,
+    host: &Host,
+) -> anyhow::Result<NodeInfoPointer> {
+    let url = format!("{}://{}/.well-known/host-meta", scheme(host), host);
+    let url = Url::parse(&url).context(with_loc!("Formatting URL of host-meta"))?;
+    let response = client
+        .get_with_accept(&url, ACCEPT_XRD, NODEINFO_SIZE_LIMIT)
+        .context(with_loc!("Fetching host-meta"))?;
+    error_for_status_ref(&response).map_err(|err| {
+        error!(
+            logger, "Failed to fetch host-meta: {}", err;
+            "http_error" => err.to_string(), "url" => url.to_string());
+        err
+    })?;
+
+    let host_meta =
+        read_body(response, NODEINFO_SIZE_LIMIT).context(with_loc!("Getting host-meta's body"))?;
+    let pointer = nodeinfo_pointer_from_xrd(&host_meta)?;
+    if pointer.links.is_empty() {
+        bail!("host-meta doesn't link to NodeInfo");
+    }
+    Ok(pointer)
+}
 
-    response
-        .into_json::<NodeInfoPointer>()
-        .context(with_loc!("Decoding NodeInfo pointer as JSON"))
+/// The NodeInfo links of an XRD document: its `<Link>` elements with a NodeInfo `rel`.
+///
+/// Elements are matched by their local name, so `<xrd:Link>` counts too.
+fn nodeinfo_pointer_from_xrd(xrd: &str) -> anyhow::Result<NodeInfoPointer> {
+    let mut reader = quick_xml::Reader::from_str(xrd);
+    let mut links = vec![];
+    loop {
+        match reader
+            .read_event()
+            .context(with_loc!("Parsing XRD as XML"))?
+        {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == b"Link" =>
+            {
+                let attribute = |name: &str| -> anyhow::Result<Option<String>> {
+                    let Some(attribute) = element
+                        .try_get_attribute(name)
+                        .context(with_loc!("Parsing attributes of <Link>"))?
+                    else {
+                        return Ok(None);
+                    };
+                    let value = attribute
+                        .unescape_value()
+                        .context(with_loc!("Unescaping an attribute of <Link>"))?;
+                    Ok(Some(value.into_owned()))
+                };
+                if let (Some(rel), Some(href)) = (attribute("rel")?, attribute("href")?) {
+                    if rel.starts_with("http://nodeinfo.diaspora.software/ns/schema/") {
+                        links.push(NodeInfoPointerLink { rel, href });
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(NodeInfoPointer { links })
 }
 
 fn pick_highest_supported_nodeinfo_version(pointer: &NodeInfoPointer) -> anyhow::Result<Url> {
@@ -242,13 +1055,14 @@ fn pick_highest_supported_nodeinfo_version(pointer: &NodeInfoPointer) -> anyhow:
         .context(with_loc!("Picking highest supported NodeInfo version"))
 }
 
+/// Fetch the NodeInfo document, and collect the metadata from the response's headers.
 fn fetch_nodeinfo_document(
     logger: &Logger,
     client: &HttpClient,
     url: &Url,
-) -> anyhow::Result<String> {
+) -> anyhow::Result<(String, ipc::Metadata)> {
     let response = client
-        .get(url)
+        .get_with_accept(url, ACCEPT_NODEINFO, NODEINFO_SIZE_LIMIT)
         .context(with_loc!("Fetching NodeInfo document"))?;
     error_for_status_ref(&response).map_err(|err| {
         error!(
@@ -256,52 +1070,560 @@ fn fetch_nodeinfo_document(
             "http_error" => err.to_string(), "url" => url.to_string());
         err
     })?;
+    log_unexpected_content_type(logger, &response);
 
-    response
-        .into_string()
-        .context(with_loc!("Getting NodeInfo document's body"))
+    let metadata = metadata_from_headers(&response);
+    let nodeinfo = read_body(response, NODEINFO_SIZE_LIMIT)
+        .context(with_loc!("Getting NodeInfo document's body"))?;
+    Ok((nodeinfo, metadata))
 }
 
-fn get_peers(
-    logger: &Logger,
-    client: &HttpClient,
-    host: &Host,
+fn metadata_from_headers(response: &ureq::Response) -> ipc::Metadata {
+    ipc::Metadata {
+        server: response.header("server").map(str::to_owned),
+        powered_by: response.header("x-powered-by").map(str::to_owned),
+        ..Default::default()
+    }
+    .sanitized()
+}
+
+/// The parts of Mastodon's `/api/v2/instance` that we're interested in. All of them are optional,
+/// since forks and older versions might not have them.
+#[derive(Debug, Deserialize)]
+struct InstanceV2 {
+    #[serde(default)]
+    domain: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    rules: Option<Vec<InstanceV2Rule>>,
+    #[serde(default)]
+    contact: Option<InstanceV2Contact>,
+    #[serde(default)]
+    thumbnail: Option<InstanceV2Thumbnail>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InstanceV2Thumbnail {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct InstanceV2Rule {
+    text: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct InstanceV2Contact {
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    account: Option<InstanceV2Account>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InstanceV2Account {
+    acct: String,
+}
+
+impl InstanceV2 {
+    /// Whether the instance describes itself as `host`. Instances that don't report their domain
+    /// are given the benefit of the doubt.
+    ///
+    /// The `domain` is Mastodon's `LOCAL_DOMAIN`, the one in the users' handles. The instance can
+    /// be served from a different `WEB_DOMAIN`, which is usually a subdomain of it, so that is
+    /// accepted too.
+    fn is_about(&self, host: &Host) -> bool {
+        let Some(domain) = &self.domain else {
+            return true;
+        };
+        let Ok(domain) = idna::domain_to_ascii(domain.trim().trim_end_matches('.')) else {
+            return false;
+        };
+        let host = host.to_string();
+        host == domain
+            || host
+                .strip_suffix(&domain)
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    }
+
+    fn rules(&self) -> Option<Vec<String>> {
+        self.rules
+            .as_ref()
+            .map(|rules| rules.iter().map(|rule| rule.text.clone()).collect())
+    }
+
+    fn thumbnail(&self) -> Option<String> {
+        self.thumbnail
+            .as_ref()
+            .map(|thumbnail| thumbnail.url.clone())
+    }
+
+    /// The admin's email, or their account if the email isn't public.
+    fn contact(&self, host: &Host) -> Option<String> {
+        let contact = self.contact.as_ref()?;
+        let email = contact
+            .email
+            .as_ref()
+            .filter(|email| !email.trim().is_empty());
+        match (email, &contact.account) {
+            (Some(email), _) => Some(email.clone()),
+            // Local accounts' `acct` doesn't include the host.
+            (None, Some(account)) if account.acct.contains('@') => {
+                Some(format!("@{}", account.acct))
+            }
+            (None, Some(account)) => Some(format!("@{}@{}", account.acct, host)),
+            (None, None) => None,
+        }
+    }
+}
+
+fn get_instance_v2(
+    logger: &Logger,
+    client: &HttpClient,
+    host: &Host,
+) -> anyhow::Result<InstanceV2> {
+    let url = format!("{}://{}/api/v2/instance", scheme(host), host);
+    let url = Url::parse(&url).context(with_loc!("Formatting URL of the instance description"))?;
+    let response = client
+        .get(&url, INSTANCE_V2_SIZE_LIMIT)
+        .context(with_loc!("Fetching the instance description"))?;
+    error_for_status_ref(&response).map_err(|err| {
+        error!(
+            logger, "Failed to fetch the instance description: {}", err;
+            "http_error" => err.to_string(), "url" => url.to_string());
+        err
+    })?;
+
+    let instance = read_body(response, INSTANCE_V2_SIZE_LIMIT)
+        .context(with_loc!("Getting the instance description's body"))?;
+    serde_json::from_str(&instance).context(with_loc!("Parsing the instance description as JSON"))
+}
+
+/// The instance description, if it describes `host` and not some other site.
+///
+/// Some shared hosts ignore SNI and the `Host` header, and serve their default site instead of the
+/// instance. Such an instance is misconfigured, and the domain that the description is about is
+/// returned instead: the description belongs to someone else.
+fn verified_instance_v2(
+    logger: &Logger,
+    host: &Host,
+    instance: InstanceV2,
+) -> Result<InstanceV2, String> {
+    if instance.is_about(host) {
+        return Ok(instance);
+    }
+    let domain = instance.domain.unwrap_or_default();
+    warn!(logger, "Instance is misconfigured: its description is about a different domain";
+        "reported_domain" => &domain);
+    Err(domain)
+}
+
+/// Servers are sloppy with `Content-Type`, so we try to parse whatever they send. But if it's not
+/// JSON, that's worth a note in case parsing fails.
+fn log_unexpected_content_type(logger: &Logger, response: &ureq::Response) {
+    let content_type = response.content_type();
+    if !is_json_content_type(content_type) {
+        info!(
+            logger,
+            "{} was served as {} rather than JSON",
+            response.get_url(),
+            content_type
+        );
+    }
+}
+
+/// Where Mastodon and software that mimics its API serve the peers list.
+pub const DEFAULT_PEERS_PATH: &str = "/api/v1/instance/peers";
+
+/// The path of the Mastodon-ish peers list of the software, if it has one.
+fn peers_path<'a>(software: &str, peers_paths: &'a [(String, String)]) -> Option<&'a str> {
+    if let Some((_, path)) = peers_paths.iter().find(|(name, _)| name == software) {
+        return Some(path);
+    }
+    match software {
+        "mastodon" | "pleroma" | "misskey" | "bookwyrm" | "smithereen" => Some(DEFAULT_PEERS_PATH),
+        _ => None,
+    }
+}
+
+fn get_peers(
+    logger: &Logger,
+    client: &HttpClient,
+    host: &Host,
     software: &str,
+    peers_paths: &[(String, String)],
 ) -> anyhow::Result<Vec<Host>> {
-    match software {
-        "mastodon" | "pleroma" | "misskey" | "bookwyrm" | "smithereen" => {
-            get_peers_mastodonish(logger, client, host)
-                .context(with_loc!("Fetching peers list via Mastodon-ish API"))
-        }
-        _ => Ok(vec![]),
+    let peers = match (peers_path(software, peers_paths), software) {
+        (Some(path), _) => get_peers_mastodonish(logger, client, host, path)
+            .context(with_loc!("Fetching peers list via Mastodon-ish API")),
+        (None, "lemmy") => get_peers_lemmy(logger, client, host)
+            .context(with_loc!("Fetching peers list via Lemmy API")),
+        (None, "peertube") => get_peers_peertube(logger, client, host)
+            .context(with_loc!("Fetching peers list via PeerTube API")),
+        (None, "friendica") => get_peers_friendica(logger, client, host)
+            .context(with_loc!("Fetching peers list via Friendica API")),
+        (None, _) => Ok(vec![]),
+    }?;
+    Ok(valid_peers(logger, peers))
+}
+
+/// The peers that are valid domains. The Orchestrator would reject the rest anyway, so there's no
+/// point in sending them.
+fn valid_peers(logger: &Logger, peers: Vec<Host>) -> Vec<Host> {
+    let total = peers.len();
+    let valid: Vec<Host> = peers
+        .into_iter()
+        .filter(|peer| Domain::from_host(peer).is_ok())
+        .collect();
+    let dropped = total.saturating_sub(valid.len());
+    if dropped > 0 {
+        debug!(
+            logger,
+            "Dropped {} peers that aren't valid domains", dropped
+        );
     }
+    valid
 }
 
+/// The peers in the order they were first seen, without duplicates and without the instance
+/// itself.
+fn unique_peers(host: &Host, peers: impl IntoIterator<Item = String>) -> Vec<Host> {
+    let own = host.to_string();
+    let mut seen = HashSet::new();
+    peers
+        .into_iter()
+        .filter(|peer| *peer != own && seen.insert(peer.clone()))
+        .map(Host::Domain)
+        .collect()
+}
+
+/// Most instances return all their peers at once, but some paginate them. This limits the number
+/// of pages we're willing to fetch.
+const MAX_PEERS_PAGES: usize = 50;
+
 fn get_peers_mastodonish(
     logger: &Logger,
     client: &HttpClient,
     host: &Host,
+    path: &str,
 ) -> anyhow::Result<Vec<Host>> {
-    let url = format!("https://{}/api/v1/instance/peers", host);
+    let url = format!("{}://{}{}", scheme(host), host, path);
     let url = Url::parse(&url).context(with_loc!(
         "Formatting URL of the Mastodon-ish 'peers' endpoint"
     ))?;
+
+    let peers = collect_pages(logger, url, |url| {
+        let response = client
+            .get(url, PEERS_SIZE_LIMIT)
+            .context(with_loc!("Fetching Mastodon-ish peers list"))?;
+        error_for_status_ref(&response).map_err(|err| {
+            error!(
+                logger, "Failed to fetch Mastodon-ish peers: {}", err;
+                "http_error" => err.to_string(), "url" => url.to_string());
+            err
+        })?;
+
+        let next = response
+            .header("link")
+            .and_then(|link| next_page(link, url));
+        let peers = read_body(response, PEERS_SIZE_LIMIT)
+            .context(with_loc!("Getting Mastodon-ish peers list's body"))?;
+        let peers = serde_json::from_str::<Vec<String>>(&peers)
+            .context(with_loc!("Parsing Mastodon-ish peers list as JSON"))?;
+        Ok((peers, next))
+    })?;
+
+    // Big instances sometimes list the same peer more than once.
+    Ok(unique_peers(host, peers))
+}
+
+/// Lemmy's `/api/v3/federated_instances` response.
+#[derive(Debug, Deserialize)]
+struct LemmyFederatedInstancesResponse {
+    /// Missing if federation is disabled.
+    #[serde(default)]
+    federated_instances: Option<LemmyFederatedInstances>,
+}
+
+/// The arrays are `null` in some versions if they're empty.
+#[derive(Debug, Deserialize)]
+struct LemmyFederatedInstances {
+    #[serde(default)]
+    linked: Option<Vec<LemmyInstance>>,
+    #[serde(default)]
+    allowed: Option<Vec<LemmyInstance>>,
+    #[serde(default)]
+    blocked: Option<Vec<LemmyInstance>>,
+}
+
+/// Lemmy 0.18 and later describe the instances; older versions only list their domains.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LemmyInstance {
+    Domain(String),
+    Described { domain: String },
+}
+
+impl LemmyFederatedInstancesResponse {
+    /// All the listed domains, including duplicates.
+    fn domains(self) -> Vec<String> {
+        let Some(instances) = self.federated_instances else {
+            return vec![];
+        };
+        [instances.linked, instances.allowed, instances.blocked]
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|instance| match instance {
+                LemmyInstance::Domain(domain) | LemmyInstance::Described { domain } => domain,
+            })
+            .collect()
+    }
+}
+
+fn get_peers_lemmy(logger: &Logger, client: &HttpClient, host: &Host) -> anyhow::Result<Vec<Host>> {
+    let url = format!("{}://{}/api/v3/federated_instances", scheme(host), host);
+    let url =
+        Url::parse(&url).context(with_loc!("Formatting URL of Lemmy's federated instances"))?;
     let response = client
-        .get(&url)
-        .context(with_loc!("Fetching Mastodon-ish peers list"))?;
+        .get(&url, PEERS_SIZE_LIMIT)
+        .context(with_loc!("Fetching Lemmy's federated instances"))?;
     error_for_status_ref(&response).map_err(|err| {
         error!(
-            logger, "Failed to fetch Mastodon-ish peers: {}", err;
+            logger, "Failed to fetch Lemmy's federated instances: {}", err;
             "http_error" => err.to_string(), "url" => url.to_string());
         err
     })?;
 
-    Ok(response
-        .into_json::<Vec<String>>()
-        .context(with_loc!("Parsing Mastodon-ish peers list as JSON"))?
-        .into_iter()
-        .map(Host::Domain)
-        .collect())
+    let instances = read_body(response, PEERS_SIZE_LIMIT)
+        .context(with_loc!("Getting Lemmy's federated instances' body"))?;
+    let instances = serde_json::from_str::<LemmyFederatedInstancesResponse>(&instances)
+        .context(with_loc!("Parsing Lemmy's federated instances as JSON"))?;
+    Ok(unique_peers(host, instances.domains()))
+}
+
+/// Friendica's Portable Contacts server directory, `/poco/@server`.
+#[derive(Debug, Deserialize)]
+struct FriendicaPocoServers {
+    /// Missing if the directory is empty.
+    #[serde(default)]
+    entry: Vec<FriendicaPocoServer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FriendicaPocoServer {
+    url: String,
+}
+
+impl FriendicaPocoServers {
+    /// The hosts of the listed servers, including duplicates. Entries with unparseable URLs are
+    /// skipped.
+    fn hosts(self) -> Vec<String> {
+        self.entry
+            .into_iter()
+            .filter_map(|server| {
+                Url::parse(server.url.trim())
+                    .ok()?
+                    .host_str()
+                    .map(str::to_owned)
+            })
+            .collect()
+    }
+}
+
+/// Friendica lists the servers it knows in its Portable Contacts directory. Newer versions dropped
+/// that in favour of the Mastodon-compatible API, so that's what we try if the directory is missing.
+fn get_peers_friendica(
+    logger: &Logger,
+    client: &HttpClient,
+    host: &Host,
+) -> anyhow::Result<Vec<Host>> {
+    let url = format!("{}://{}/poco/@server", scheme(host), host);
+    let url =
+        Url::parse(&url).context(with_loc!("Formatting URL of Friendica's server directory"))?;
+    let servers = client
+        .get(&url, PEERS_SIZE_LIMIT)
+        .context(with_loc!("Fetching Friendica's server directory"))
+        .and_then(|response| {
+            error_for_status_ref(&response)?;
+            Ok(response)
+        });
+    let response = match servers {
+        Err(e) if error_status(&e) == Some(404) => {
+            info!(
+                logger,
+                "No Portable Contacts server directory, trying Mastodon-ish API"
+            );
+            return get_peers_mastodonish(logger, client, host, DEFAULT_PEERS_PATH);
+        }
+        Err(e) => {
+            error!(
+                logger, "Failed to fetch Friendica's server directory: {}", e;
+                "http_error" => e.to_string(), "url" => url.to_string());
+            return Err(e);
+        }
+        Ok(response) => response,
+    };
+
+    let servers = read_body(response, PEERS_SIZE_LIMIT)
+        .context(with_loc!("Getting Friendica's server directory's body"))?;
+    let servers = serde_json::from_str::<FriendicaPocoServers>(&servers)
+        .context(with_loc!("Parsing Friendica's server directory as JSON"))?;
+    Ok(unique_peers(host, servers.hosts()))
+}
+
+/// PeerTube serves at most this many follows per page.
+const PEERTUBE_PAGE_SIZE: u64 = 100;
+
+/// A page of PeerTube's `/api/v1/server/followers` or `/api/v1/server/following`.
+#[derive(Debug, Deserialize)]
+struct PeerTubeFollows {
+    total: u64,
+    data: Vec<PeerTubeFollow>,
+}
+
+/// One instance following another. One side is the instance we're checking, the other is a peer.
+#[derive(Debug, Deserialize)]
+struct PeerTubeFollow {
+    follower: PeerTubeActor,
+    following: PeerTubeActor,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerTubeActor {
+    host: String,
+}
+
+impl PeerTubeFollows {
+    /// The hosts on both sides of the follows, including the instance itself.
+    fn hosts(&self) -> Vec<String> {
+        self.data
+            .iter()
+            .flat_map(|follow| [follow.follower.host.clone(), follow.following.host.clone()])
+            .collect()
+    }
+
+    /// The URL of the page after `current`, if there is one.
+    fn next_page(&self, current: &Url) -> Option<Url> {
+        let start: u64 = current
+            .query_pairs()
+            .find(|(name, _)| name == "start")
+            .and_then(|(_, value)| value.parse().ok())
+            .unwrap_or(0);
+        let next_start = start.checked_add(u64::try_from(self.data.len()).ok()?)?;
+        if self.data.is_empty() || next_start >= self.total {
+            return None;
+        }
+        let mut next = current.clone();
+        next.query_pairs_mut()
+            .clear()
+            .append_pair("start", &next_start.to_string())
+            .append_pair("count", &PEERTUBE_PAGE_SIZE.to_string());
+        Some(next)
+    }
+}
+
+fn get_peers_peertube(
+    logger: &Logger,
+    client: &HttpClient,
+    host: &Host,
+) -> anyhow::Result<Vec<Host>> {
+    let mut hosts = vec![];
+    for path in ["/api/v1/server/followers", "/api/v1/server/following"] {
+        let url = format!(
+            "{}://{}{}?start=0&count={}",
+            scheme(host),
+            host,
+            path,
+            PEERTUBE_PAGE_SIZE
+        );
+        let url = Url::parse(&url).context(with_loc!("Formatting URL of PeerTube's follows"))?;
+        let page_hosts = collect_pages(logger, url, |url| {
+            let response = client
+                .get(url, PEERS_SIZE_LIMIT)
+                .context(with_loc!("Fetching PeerTube's follows"))?;
+            error_for_status_ref(&response).map_err(|err| {
+                error!(
+                    logger, "Failed to fetch PeerTube's follows: {}", err;
+                    "http_error" => err.to_string(), "url" => url.to_string());
+                err
+            })?;
+
+            let page = read_body(response, PEERS_SIZE_LIMIT)
+                .context(with_loc!("Getting PeerTube's follows' body"))?;
+            let page = serde_json::from_str::<PeerTubeFollows>(&page)
+                .context(with_loc!("Parsing PeerTube's follows as JSON"))?;
+            Ok((page.hosts(), page.next_page(url)))
+        })?;
+        hosts.extend(page_hosts);
+    }
+    Ok(unique_peers(host, hosts))
+}
+
+/// Fetch the first page, then follow the links to the next ones, collecting all the items.
+///
+/// `fetch` returns the items on the page, and the URL of the next page if there is one.
+fn collect_pages(
+    logger: &Logger,
+    first: Url,
+    mut fetch: impl FnMut(&Url) -> anyhow::Result<(Vec<String>, Option<Url>)>,
+) -> anyhow::Result<Vec<String>> {
+    let mut items = vec![];
+    let mut visited = vec![];
+    let mut next = Some(first);
+    while let Some(url) = next.take() {
+        if visited.len() >= MAX_PEERS_PAGES {
+            info!(
+                logger,
+                "Stopped after {} pages, not fetching {}", MAX_PEERS_PAGES, url
+            );
+            break;
+        }
+        let (page, next_url) = fetch(&url)?;
+        items.extend(page);
+        visited.push(url);
+        next = next_url.filter(|next_url| !visited.contains(next_url));
+    }
+    Ok(items)
+}
+
+/// The URL of the next page, from an RFC 8288 `Link` header of a response to `current`.
+///
+/// Links to other origins are ignored: the peers of this instance can only be found on this
+/// instance.
+fn next_page(link_header: &str, current: &Url) -> Option<Url> {
+    let mut rest = link_header;
+    while let Some(start) = rest.find('<') {
+        let after_start = rest.get(start.saturating_add(1)..)?;
+        let end = after_start.find('>')?;
+        let target = after_start.get(..end)?;
+        let after_target = after_start.get(end.saturating_add(1)..)?;
+        let params = after_target
+            .find('<')
+            .and_then(|next| after_target.get(..next))
+            .unwrap_or(after_target);
+        rest = after_target;
+
+        let is_next = params.split(';').any(|param| {
+            let Some((name, value)) = param.split_once('=') else {
+                return false;
+            };
+            name.trim().eq_ignore_ascii_case("rel")
+                && value
+                    .trim()
+                    .trim_matches(|c| c == '"' || c == ',')
+                    .split_whitespace()
+                    .any(|rel| rel.eq_ignore_ascii_case("next"))
+        });
+        if !is_next {
+            continue;
+        }
+
+        let url = current.join(target.trim()).ok()?;
+        return (url.origin() == current.origin()).then_some(url);
+    }
+    None
 }
 
 fn is_instance_private(client: &HttpClient, host: &Host, software: &str) -> anyhow::Result<bool> {
@@ -340,23 +1662,23 @@ fn is_instance_private(client: &HttpClient, host: &Host, software: &str) -> anyh
 }
 
 fn get_statusnet_config(client: &HttpClient, host: &Host) -> anyhow::Result<String> {
-    let url = format!("https://{}/api/statusnet/config.json", host);
+    let url = format!("{}://{}/api/statusnet/config.json", scheme(host), host);
     let url = Url::parse(&url).context(with_loc!("Formatting URL StatusNet config"))?;
     let response = client
-        .get(&url)
-        .context(with_loc!("Requesting StatusNet config.json"))?
-        .into_string()
+        .get(&url, SITE_CONFIG_SIZE_LIMIT)
+        .context(with_loc!("Requesting StatusNet config.json"))?;
+    let response = read_body(response, SITE_CONFIG_SIZE_LIMIT)
         .context(with_loc!("Getting a body of config.json response"))?;
     Ok(response)
 }
 
 fn get_siteinfo(client: &HttpClient, host: &Host) -> anyhow::Result<String> {
-    let url = format!("https://{}/siteinfo.json", host);
+    let url = format!("{}://{}/siteinfo.json", scheme(host), host);
     let url = Url::parse(&url).context(with_loc!("Formatting URL of siteinfo document"))?;
     let response = client
-        .get(&url)
-        .context(with_loc!("Requesting siteinfo.json"))?
-        .into_string()
+        .get(&url, SITE_CONFIG_SIZE_LIMIT)
+        .context(with_loc!("Requesting siteinfo.json"))?;
+    let response = read_body(response, SITE_CONFIG_SIZE_LIMIT)
         .context(with_loc!("Getting a body of siteinfo.json response"))?;
     Ok(response)
 }
@@ -426,6 +1748,281 @@ mod test {
         );
     }
 
+    #[test]
+    fn html_is_not_a_nodeinfo_pointer() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let response = |content_type: &str, body: &str| -> ureq::Response {
+            format!("HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\n\r\n{body}")
+                .parse()
+                .unwrap()
+        };
+
+        let error = parse_nodeinfo_pointer(
+            &logger,
+            response(
+                "text/html; charset=utf-8",
+                "<!DOCTYPE html><html><body>This domain is for sale!</body></html>",
+            ),
+        )
+        .unwrap_err();
+        assert!(error.downcast_ref::<NotNodeInfo>().is_some());
+        assert!(error.to_string().contains("text/html"));
+
+        let pointer = r#"{"links": [{"rel": "http://nodeinfo.diaspora.software/ns/schema/2.0", "href": "https://example.com/nodeinfo/2.0"}]}"#;
+        // The type is wrong, but the document is fine.
+        for content_type in ["application/json", "text/plain", "text/html"] {
+            let parsed = parse_nodeinfo_pointer(&logger, response(content_type, pointer)).unwrap();
+            assert_eq!(parsed.links.len(), 1);
+        }
+
+        // JSON that is broken is still reported as such.
+        let error = parse_nodeinfo_pointer(&logger, response("application/json", "{")).unwrap_err();
+        assert!(error.downcast_ref::<NotNodeInfo>().is_none());
+    }
+
+    #[test]
+    fn death_causes_are_told_apart() {
+        let url = Url::parse("https://example.com/nodeinfo/2.0").unwrap();
+        let cause =
+            |error: anyhow::Error| death_cause(&error.context("Determining instance's software"));
+        assert_eq!(
+            cause(HttpClientError::RedirectLoop(url.clone()).into()),
+            ipc::DeathCause::RedirectLoop
+        );
+        assert_eq!(
+            cause(HttpClientError::BodyTooLarge(1024).into()),
+            ipc::DeathCause::BodyTooLarge
+        );
+        assert_eq!(
+            cause(HttpClientError::ForbiddenByRobotsTxt(url.clone()).into()),
+            ipc::DeathCause::ForbiddenByRobotsTxt
+        );
+        assert_eq!(
+            cause(
+                NotNodeInfo {
+                    url: url.to_string(),
+                    content_type: "text/html".to_string(),
+                }
+                .into()
+            ),
+            ipc::DeathCause::NotNodeInfo
+        );
+        assert_eq!(cause(AppIsDown.into()), ipc::DeathCause::AppIsDown);
+        assert_eq!(
+            cause(anyhow::anyhow!("Connection refused")),
+            ipc::DeathCause::Other
+        );
+    }
+
+    #[test]
+    fn http_451_means_blocked() {
+        let response = ureq::Response::new(451, "Unavailable For Legal Reasons", "").unwrap();
+        let error = anyhow::Error::new(HttpClientError::UreqError(Box::new(ureq::Error::Status(
+            451, response,
+        ))))
+        .context("Fetching NodeInfo");
+        assert_eq!(error_status(&error), Some(451));
+        assert_eq!(
+            error_status(&error).and_then(blocked_reason),
+            Some(ipc::BlockedReason::UnavailableForLegalReasons)
+        );
+
+        let error = anyhow::Error::new(UreqHttpStatusError { status: 451 });
+        assert_eq!(
+            error_status(&error).and_then(blocked_reason),
+            Some(ipc::BlockedReason::UnavailableForLegalReasons)
+        );
+
+        let error = anyhow::Error::new(UreqHttpStatusError { status: 500 });
+        assert_eq!(error_status(&error).and_then(blocked_reason), None);
+        assert_eq!(error_status(&anyhow!("Something else")), None);
+    }
+
+    #[test]
+    fn http_401_means_authentication_required() {
+        let response = ureq::Response::new(401, "Unauthorized", "").unwrap();
+        let error = anyhow::Error::new(HttpClientError::UreqError(Box::new(ureq::Error::Status(
+            401, response,
+        ))))
+        .context("Fetching NodeInfo");
+        assert_eq!(
+            error_status(&error).and_then(blocked_reason),
+            Some(ipc::BlockedReason::AuthenticationRequired)
+        );
+
+        // Peers lists are often behind authentication, but by then the instance is known to be
+        // alive, and we shouldn't report it as blocked.
+        let error = error.context(StateAlreadyReported);
+        assert!(error.downcast_ref::<StateAlreadyReported>().is_some());
+    }
+
+    #[test]
+    fn timeout_overrides_all_timeouts() {
+        let options = Options {
+            timeout: Some(Duration::from_secs(120)),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.timeouts(),
+            Timeouts {
+                agent: Duration::from_secs(120),
+                request: Duration::from_secs(120),
+            }
+        );
+        assert_eq!(Options::default().timeouts(), Timeouts::default());
+    }
+
+    #[test]
+    fn timeout_lifts_the_deadline() {
+        assert_eq!(Options::default().deadline(), Some(CHECK_DEADLINE));
+        let options = Options {
+            timeout: Some(Duration::from_secs(120)),
+            ..Default::default()
+        };
+        assert_eq!(options.deadline(), None);
+    }
+
+    #[test]
+    fn nodeinfo_without_a_working_app_is_down() {
+        let status = |status: u16| -> anyhow::Result<()> {
+            Err(anyhow!(UreqHttpStatusError { status })).context("Fetching peers list")
+        };
+        let no_instance_api = || None::<anyhow::Result<()>>;
+
+        // NodeInfo was fine, but the app behind it is down.
+        assert!(is_app_down(&status(503), no_instance_api));
+        assert!(is_app_down(&status(502), || Some(status(500))));
+
+        // Any working dynamic endpoint is enough.
+        assert!(!is_app_down(&Ok(()), || Some(status(503))));
+        assert!(!is_app_down(&status(503), || Some(Ok(()))));
+
+        // Client errors mean that the app is up, it just doesn't want to serve the peers.
+        assert!(!is_app_down(&status(404), no_instance_api));
+        assert!(!is_app_down(&status(503), || Some(status(404))));
+        assert!(!is_app_down(
+            &Err::<(), _>(anyhow!("Timed out")),
+            no_instance_api
+        ));
+    }
+
+    #[test]
+    fn collects_metadata_from_headers() {
+        let response: ureq::Response =
+            "HTTP/1.1 200 OK\r\nServer: nginx/1.25.3\r\nX-Powered-By: Phusion Passenger\r\n\r\n{}"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            metadata_from_headers(&response),
+            ipc::Metadata {
+                server: Some("nginx/1.25.3".to_string()),
+                powered_by: Some("Phusion Passenger".to_string()),
+                ..Default::default()
+            }
+        );
+
+        let response: ureq::Response = "HTTP/1.1 200 OK\r\n\r\n{}".parse().unwrap();
+        assert_eq!(metadata_from_headers(&response), ipc::Metadata::default());
+    }
+
+    #[test]
+    fn finds_software_name() {
+        let logger = Logger::root(slog::Discard, o!());
+
+        let standard = r#"{"version":"2.0","software":{"name":"pleroma","version":"2.5.0"}}"#;
+        assert_eq!(
+            software_from_nodeinfo(&logger, standard).unwrap(),
+            "pleroma"
+        );
+
+        let alias = r#"{"version":"1.0","softwareName":"gnusocial"}"#;
+        assert_eq!(software_from_nodeinfo(&logger, alias).unwrap(), "gnusocial");
+
+        let both = r#"{"software":{"name":"friendica"},"softwareName":"other"}"#;
+        assert_eq!(software_from_nodeinfo(&logger, both).unwrap(), "friendica");
+
+        let neither = r#"{"software":{"version":"1.0"}}"#;
+        assert!(software_from_nodeinfo(&logger, neither).is_err());
+    }
+
+    #[test]
+    fn extracts_software_from_gzipped_nodeinfo() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let logger = Logger::root(slog::Discard, o!());
+        let nodeinfo = r#"{"version":"2.0","software":{"name":"mastodon","version":"4.2.0"}}"#;
+        let mut e = GzEncoder::new(Vec::new(), Compression::best());
+        e.write_all(nodeinfo.as_bytes()).unwrap();
+        let gzipped = e.finish().unwrap();
+
+        let decoded = http_client::decode_body(gzipped, NODEINFO_SIZE_LIMIT).unwrap();
+
+        assert_eq!(
+            software_from_nodeinfo(&logger, &decoded).unwrap(),
+            "mastodon"
+        );
+    }
+
+    #[test]
+    fn finds_nodeinfo_in_host_meta() {
+        let input = r#"<?xml version="1.0" encoding="UTF-8"?>
+<XRD xmlns="http://docs.oasis-open.org/ns/xri/xrd-1.0">
+  <Link rel="lrdd" type="application/xrd+xml" template="https://example.com/.well-known/webfinger?resource={uri}"/>
+  <Link rel="http://nodeinfo.diaspora.software/ns/schema/1.0"
+        href="https://example.com/nodeinfo/1.0"/>
+  <Link href='https://example.com/nodeinfo/2.0?format=json&amp;v=2' rel='http://nodeinfo.diaspora.software/ns/schema/2.0' />
+</XRD>"#;
+        let pointer = nodeinfo_pointer_from_xrd(input).unwrap();
+        assert_eq!(
+            pointer,
+            NodeInfoPointer {
+                links: vec![
+                    NodeInfoPointerLink {
+                        rel: "http://nodeinfo.diaspora.software/ns/schema/1.0".to_string(),
+                        href: "https://example.com/nodeinfo/1.0".to_string(),
+                    },
+                    NodeInfoPointerLink {
+                        rel: "http://nodeinfo.diaspora.software/ns/schema/2.0".to_string(),
+                        href: "https://example.com/nodeinfo/2.0?format=json&v=2".to_string(),
+                    },
+                ]
+            }
+        );
+        assert_eq!(
+            pick_highest_supported_nodeinfo_version(&pointer).unwrap(),
+            Url::parse("https://example.com/nodeinfo/2.0?format=json&v=2").unwrap()
+        );
+    }
+
+    #[test]
+    fn host_meta_without_nodeinfo() {
+        let input = r#"<XRD><Link rel="lrdd" template="https://example.com/{uri}"/><Linkage rel="http://nodeinfo.diaspora.software/ns/schema/2.0" href="https://example.com/"/></XRD>"#;
+        assert!(nodeinfo_pointer_from_xrd(input).unwrap().links.is_empty());
+    }
+
+    #[test]
+    fn finds_nodeinfo_in_prefixed_host_meta() {
+        let input = r#"<xrd:XRD xmlns:xrd="http://docs.oasis-open.org/ns/xri/xrd-1.0">
+  <xrd:Link title="a > b" rel="http://nodeinfo.diaspora.software/ns/schema/2.0" href="https://example.com/nodeinfo/2.0"></xrd:Link>
+</xrd:XRD>"#;
+        assert_eq!(
+            nodeinfo_pointer_from_xrd(input).unwrap(),
+            NodeInfoPointer {
+                links: vec![NodeInfoPointerLink {
+                    rel: "http://nodeinfo.diaspora.software/ns/schema/2.0".to_string(),
+                    href: "https://example.com/nodeinfo/2.0".to_string(),
+                }]
+            }
+        );
+    }
+
+    #[test]
+    fn malformed_host_meta_is_an_error() {
+        let input = r#"<XRD><Link rel="http://nodeinfo.diaspora.software/ns/schema/2.0" href="https://example.com/></XRD>"#;
+        assert!(nodeinfo_pointer_from_xrd(input).is_err());
+    }
+
     #[test]
     fn broken_lemmy_nodeinfo_pointer() {
         let input = r#"{"links":{"rel":"http://nodeinfo.diaspora.software/ns/schema/2.0","href":"https://lemmy.ml/nodeinfo/2.0.json"}}"#;
@@ -438,4 +2035,455 @@ mod test {
         };
         assert_eq!(expected, parsed);
     }
+
+    #[test]
+    fn extracts_rules_and_contact_from_instance_v2() {
+        let host = Host::Domain("example.com".to_string());
+
+        let instance: InstanceV2 = serde_json::from_str(
+            r#"{
+                "domain": "example.com",
+                "contact": {
+                    "email": "",
+                    "account": { "id": "1", "acct": "admin" }
+                },
+                "rules": [
+                    { "id": "1", "text": "No spam", "hint": "" },
+                    { "id": "2", "text": "Be nice" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            instance.rules(),
+            Some(vec!["No spam".to_string(), "Be nice".to_string()])
+        );
+        assert_eq!(
+            instance.contact(&host),
+            Some("@admin@example.com".to_string())
+        );
+
+        let instance: InstanceV2 = serde_json::from_str(
+            r#"{ "contact": { "email": "admin@example.com", "account": null } }"#,
+        )
+        .unwrap();
+        assert_eq!(instance.rules(), None);
+        assert_eq!(
+            instance.contact(&host),
+            Some("admin@example.com".to_string())
+        );
+
+        let instance: InstanceV2 = serde_json::from_str(r#"{ "domain": "example.com" }"#).unwrap();
+        assert_eq!(instance.rules(), None);
+        assert_eq!(instance.contact(&host), None);
+    }
+
+    #[test]
+    fn instance_description_about_another_domain_is_rejected() {
+        let logger = Logger::root(slog::Discard, o!());
+        let host = |domain: &str| Host::Domain(domain.to_string());
+        let describe = |domain: &str| -> InstanceV2 {
+            serde_json::from_str(&format!(r#"{{ "domain": "{}" }}"#, domain)).unwrap()
+        };
+        let verified =
+            |hostname: &str, instance| verified_instance_v2(&logger, &host(hostname), instance);
+
+        assert_eq!(
+            verified("example.com", describe("default-site.example.net")).err(),
+            Some("default-site.example.net".to_string())
+        );
+        assert!(verified("example.com", describe("Example.com.")).is_ok());
+        assert!(verified("xn--e1afmkfd.com", describe("пример.com")).is_ok());
+        assert!(verified("example.com", serde_json::from_str("{}").unwrap()).is_ok());
+
+        // The instance is served from a `WEB_DOMAIN` other than its `LOCAL_DOMAIN`.
+        assert!(verified("social.example.com", describe("example.com")).is_ok());
+        assert!(verified("notexample.com", describe("example.com")).is_err());
+        assert!(verified("example.com", describe("social.example.com")).is_err());
+    }
+
+    #[test]
+    fn overlapped_fetches_match_sequential_ones() {
+        let foreground = || vec!["alive", "metadata"];
+        let background = || vec![Host::Domain("example.org".to_string())];
+        let sequential = (foreground(), background());
+        assert_eq!(overlapped(foreground, background).unwrap(), sequential);
+
+        // Neither closure can finish until both are running, so this only returns if they really
+        // run at the same time.
+        let barrier = std::sync::Barrier::new(2);
+        let (foreground, background) = overlapped(
+            || {
+                barrier.wait();
+                "foreground"
+            },
+            || {
+                barrier.wait();
+                "background"
+            },
+        )
+        .unwrap();
+        assert_eq!((foreground, background), ("foreground", "background"));
+    }
+
+    #[test]
+    fn timings_include_all_phases() {
+        let timings = Timings::default();
+        timings.measure(Phase::RobotsTxt, || ());
+        timings.measure(Phase::NodeInfo, || ());
+
+        let output = timings.to_string();
+        for phase in Phase::ALL {
+            assert!(output.contains(phase.name()), "{} is missing", phase.name());
+        }
+        assert!(output.contains("robots.txt: 0.0"));
+        assert!(output.contains("peers: not reached"));
+    }
+
+    #[test]
+    fn summary_reflects_the_peers() {
+        let peers: Vec<Host> = [
+            "a.example.com",
+            "b.example.com",
+            "not a domain",
+            "c.example.com",
+            "d.example.com",
+            "e.example.com",
+        ]
+        .into_iter()
+        .map(|peer| Host::Domain(peer.to_string()))
+        .collect();
+
+        let summary = PeersSummary::new(&peers);
+        assert_eq!(summary.total, 6);
+        assert_eq!(summary.valid_domains, 5);
+        assert_eq!(summary.sample.len(), SUMMARY_SAMPLE_SIZE);
+        assert!(summary.to_string().starts_with(
+            "6 peers, 5 of them valid domains, e.g. a.example.com, b.example.com, not a domain"
+        ));
+
+        assert_eq!(
+            PeersSummary::new(&[]).to_string(),
+            "0 peers, 0 of them valid domains"
+        );
+    }
+
+    #[test]
+    fn finds_next_page_in_link_header() {
+        let current = Url::parse("https://example.com/api/v1/instance/peers").unwrap();
+
+        assert_eq!(
+            next_page(
+                r#"<https://example.com/api/v1/instance/peers?page=2>; rel="next", <https://example.com/api/v1/instance/peers?page=1>; rel="prev""#,
+                &current
+            ),
+            Some(Url::parse("https://example.com/api/v1/instance/peers?page=2").unwrap())
+        );
+        assert_eq!(
+            next_page(r#"</api/v1/instance/peers?max_id=10>; rel=next"#, &current),
+            Some(Url::parse("https://example.com/api/v1/instance/peers?max_id=10").unwrap())
+        );
+        assert_eq!(
+            next_page(
+                r#"<https://example.com/api/v1/instance/peers?page=1>; rel="prev""#,
+                &current
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn next_page_must_be_on_the_same_origin() {
+        let current = Url::parse("https://example.com/api/v1/instance/peers").unwrap();
+        for link in [
+            r#"<https://example.org/api/v1/instance/peers?page=2>; rel="next""#,
+            r#"<http://example.com/api/v1/instance/peers?page=2>; rel="next""#,
+            r#"<https://example.com:8443/api/v1/instance/peers?page=2>; rel="next""#,
+        ] {
+            assert_eq!(next_page(link, &current), None, "{}", link);
+        }
+    }
+
+    #[test]
+    fn collects_peers_from_a_single_page() {
+        let logger = Logger::root(slog::Discard, o!());
+        let first = Url::parse("https://example.com/api/v1/instance/peers").unwrap();
+        let mut fetches = 0;
+
+        let peers = collect_pages(&logger, first, |_| {
+            fetches += 1;
+            Ok((vec!["a.example.org".to_string()], None))
+        })
+        .unwrap();
+
+        assert_eq!(peers, vec!["a.example.org".to_string()]);
+        assert_eq!(fetches, 1);
+    }
+
+    #[test]
+    fn follows_links_to_next_pages() {
+        let logger = Logger::root(slog::Discard, o!());
+        let first = Url::parse("https://example.com/api/v1/instance/peers").unwrap();
+
+        let peers = collect_pages(&logger, first.clone(), |url| {
+            let link = match url.query() {
+                None => r#"</api/v1/instance/peers?page=2>; rel="next""#,
+                Some("page=2") => r#"</api/v1/instance/peers?page=3>; rel="next""#,
+                // The last page links back to the first one, which must not cause a loop.
+                _ => r#"</api/v1/instance/peers>; rel="next""#,
+            };
+            let peer = format!("{}.example.org", url.query().unwrap_or("page=1"));
+            Ok((vec![peer], next_page(link, url)))
+        })
+        .unwrap();
+        assert_eq!(
+            peers,
+            vec![
+                "page=1.example.org".to_string(),
+                "page=2.example.org".to_string(),
+                "page=3.example.org".to_string(),
+            ]
+        );
+
+        // A server that never stops paginating is cut off.
+        let mut page: usize = 0;
+        let peers = collect_pages(&logger, first, |url| {
+            page += 1;
+            let next = url.join(&format!("?page={}", page)).unwrap();
+            Ok((vec![page.to_string()], Some(next)))
+        })
+        .unwrap();
+        assert_eq!(peers.len(), MAX_PEERS_PAGES);
+    }
+
+    #[test]
+    fn extracts_title_from_nodeinfo() {
+        let nodeinfo = r#"{ "software": { "name": "pleroma" }, "metadata": { "nodeName": "  Example\nSocial " } }"#;
+        let metadata = ipc::Metadata {
+            title: title_from_nodeinfo(nodeinfo),
+            ..Default::default()
+        }
+        .sanitized();
+        assert_eq!(metadata.title.as_deref(), Some("ExampleSocial"));
+
+        let nodeinfo = r#"{ "software": { "name": "pleroma" }, "metadata": { "nodeName": 42 } }"#;
+        assert_eq!(title_from_nodeinfo(nodeinfo), None);
+    }
+
+    #[test]
+    fn title_is_absent_without_nodeinfo_metadata() {
+        let nodeinfo = r#"{ "software": { "name": "gotosocial" } }"#;
+        assert_eq!(title_from_nodeinfo(nodeinfo), None);
+
+        let nodeinfo = r#"{ "software": { "name": "gotosocial" }, "metadata": {} }"#;
+        assert_eq!(title_from_nodeinfo(nodeinfo), None);
+    }
+
+    #[test]
+    fn extracts_thumbnails() {
+        let instance: InstanceV2 = serde_json::from_str(
+            r#"{ "thumbnail": { "url": "https://files.example.com/site_uploads/thumbnail.png" } }"#,
+        )
+        .unwrap();
+        let metadata = ipc::Metadata {
+            thumbnail: instance.thumbnail(),
+            ..Default::default()
+        }
+        .sanitized();
+        assert_eq!(
+            metadata.thumbnail.as_deref(),
+            Some("https://files.example.com/site_uploads/thumbnail.png")
+        );
+
+        let nodeinfo =
+            r#"{ "software": { "name": "misskey" }, "metadata": { "iconUrl": "/favicon.ico" } }"#;
+        let metadata = ipc::Metadata {
+            thumbnail: thumbnail_from_nodeinfo(nodeinfo),
+            ..Default::default()
+        };
+        assert_eq!(metadata.thumbnail.as_deref(), Some("/favicon.ico"));
+        assert_eq!(metadata.sanitized().thumbnail, None);
+
+        let nodeinfo = r#"{ "metadata": { "logoImageUrl": "https://example.com/logo.png", "iconUrl": "https://example.com/icon.png" } }"#;
+        assert_eq!(
+            thumbnail_from_nodeinfo(nodeinfo).as_deref(),
+            Some("https://example.com/logo.png")
+        );
+    }
+
+    #[test]
+    fn configured_peers_path_overrides_the_default() {
+        let peers_paths = vec![
+            ("misskey".to_string(), "/api/federation/peers".to_string()),
+            (
+                "gotosocial".to_string(),
+                "/api/v1/instance/peers".to_string(),
+            ),
+        ];
+
+        assert_eq!(
+            peers_path("misskey", &peers_paths),
+            Some("/api/federation/peers")
+        );
+        assert_eq!(
+            peers_path("gotosocial", &peers_paths),
+            Some(DEFAULT_PEERS_PATH)
+        );
+        assert_eq!(
+            peers_path("mastodon", &peers_paths),
+            Some(DEFAULT_PEERS_PATH)
+        );
+        assert_eq!(peers_path("misskey", &[]), Some(DEFAULT_PEERS_PATH));
+        assert_eq!(peers_path("gotosocial", &[]), None);
+    }
+
+    fn peer_names(peers: Vec<Host>) -> Vec<String> {
+        peers.iter().map(|peer| peer.to_string()).collect()
+    }
+
+    #[test]
+    fn invalid_peers_are_dropped() {
+        let logger = Logger::root(slog::Discard, o!());
+        let peers = [
+            "example.org",
+            "http://example.org/hello",
+            "example.com",
+            "127.0.0.1",
+            "example.i2p",
+        ]
+        .into_iter()
+        .map(|peer| Host::Domain(peer.to_string()))
+        .chain([Host::parse("[2001:db8::1]").unwrap()])
+        .collect();
+        assert_eq!(
+            peer_names(valid_peers(&logger, peers)),
+            vec!["example.org", "example.com"]
+        );
+    }
+
+    #[test]
+    fn duplicate_peers_collapse() {
+        let input =
+            r#"["a.example.org","b.example.org","a.example.org","example.com","b.example.org"]"#;
+        let peers: Vec<String> = serde_json::from_str(input).unwrap();
+        let host = Host::Domain("example.com".to_string());
+        assert_eq!(
+            peer_names(unique_peers(&host, peers)),
+            vec!["a.example.org", "b.example.org"]
+        );
+    }
+
+    #[test]
+    fn parses_lemmy_federated_instances() {
+        let input = r#"{"federated_instances":{
+            "linked":[
+                {"id":1,"domain":"lemmy.ml","published":"2023-06-01T00:00:00Z","software":"lemmy","version":"0.19.3"},
+                {"id":2,"domain":"mastodon.social","published":"2023-06-01T00:00:00Z","software":"mastodon"}
+            ],
+            "allowed":[],
+            "blocked":[{"id":3,"domain":"spam.example","published":"2023-06-01T00:00:00Z"}]
+        }}"#;
+        let instances: LemmyFederatedInstancesResponse = serde_json::from_str(input).unwrap();
+        assert_eq!(
+            instances.domains(),
+            vec!["lemmy.ml", "mastodon.social", "spam.example"]
+        );
+    }
+
+    #[test]
+    fn parses_old_lemmy_federated_instances() {
+        let input = r#"{"federated_instances":{
+            "linked":["lemmy.ml","beehaw.org","lemmy.ml"],
+            "allowed":null,
+            "blocked":["beehaw.org"]
+        }}"#;
+        let instances: LemmyFederatedInstancesResponse = serde_json::from_str(input).unwrap();
+        let host = Host::Domain("lemmy.example".to_string());
+        assert_eq!(
+            peer_names(unique_peers(&host, instances.domains())),
+            vec!["lemmy.ml", "beehaw.org"]
+        );
+
+        // Federation is disabled.
+        let instances: LemmyFederatedInstancesResponse =
+            serde_json::from_str(r#"{"federated_instances":null}"#).unwrap();
+        assert!(instances.domains().is_empty());
+    }
+
+    #[test]
+    fn parses_friendica_server_directory() {
+        let input = r#"{"startIndex":0,"itemsPerPage":4,"totalResults":4,"entry":[
+            {"url":"https://friendica.example.org","site_name":"Friendica Example","info":"",
+             "version":"2022.10","platform":"friendica","updated":"2023-01-02 03:04:05"},
+            {"url":"https://social.example.net/","site_name":"Social","info":"A Hubzilla hub",
+             "version":"8.2","platform":"hubzilla","updated":"2023-01-02 03:04:05"},
+            {"url":"https://friendica.example.com","site_name":"Ourselves","info":"",
+             "version":"2022.10","platform":"friendica","updated":"2023-01-02 03:04:05"},
+            {"url":"not a url","site_name":"Broken","info":"",
+             "version":"","platform":"","updated":"2023-01-02 03:04:05"},
+            {"url":"https://friendica.example.org/","site_name":"Friendica Example","info":"",
+             "version":"2022.10","platform":"friendica","updated":"2023-01-02 03:04:05"}
+        ]}"#;
+        let servers: FriendicaPocoServers = serde_json::from_str(input).unwrap();
+        let host = Host::Domain("friendica.example.com".to_string());
+        assert_eq!(
+            peer_names(unique_peers(&host, servers.hosts())),
+            vec!["friendica.example.org", "social.example.net"]
+        );
+
+        let servers: FriendicaPocoServers =
+            serde_json::from_str(r#"{"startIndex":0,"itemsPerPage":0,"totalResults":0}"#).unwrap();
+        assert!(servers.hosts().is_empty());
+    }
+
+    #[test]
+    fn parses_peertube_follows() {
+        let followers = r#"{"total":2,"data":[
+            {"id":1,"follower":{"name":"peertube","host":"videos.example.org"},
+             "following":{"name":"peertube","host":"tube.example.com"},"state":"accepted"},
+            {"id":2,"follower":{"name":"peertube","host":"peertube.example.net"},
+             "following":{"name":"peertube","host":"tube.example.com"},"state":"pending"}
+        ]}"#;
+        let following = r#"{"total":1,"data":[
+            {"id":3,"follower":{"name":"peertube","host":"tube.example.com"},
+             "following":{"name":"peertube","host":"videos.example.org"},"state":"accepted"}
+        ]}"#;
+        let host = Host::Domain("tube.example.com".to_string());
+        let hosts = [followers, following].into_iter().flat_map(|page| {
+            serde_json::from_str::<PeerTubeFollows>(page)
+                .unwrap()
+                .hosts()
+        });
+        assert_eq!(
+            peer_names(unique_peers(&host, hosts)),
+            vec!["videos.example.org", "peertube.example.net"]
+        );
+    }
+
+    #[test]
+    fn pages_through_peertube_follows() {
+        let first =
+            Url::parse("https://tube.example.com/api/v1/server/followers?start=0&count=100")
+                .unwrap();
+        let follow =
+            r#"{"follower":{"host":"a.example.org"},"following":{"host":"tube.example.com"}}"#;
+        let page = |total: u64, count: usize| {
+            let data = vec![follow; count].join(",");
+            serde_json::from_str::<PeerTubeFollows>(&format!(
+                r#"{{"total":{},"data":[{}]}}"#,
+                total, data
+            ))
+            .unwrap()
+        };
+
+        let second = page(150, 100).next_page(&first).unwrap();
+        assert_eq!(
+            second.as_str(),
+            "https://tube.example.com/api/v1/server/followers?start=100&count=100"
+        );
+        assert_eq!(page(150, 50).next_page(&second), None);
+        assert_eq!(page(100, 100).next_page(&first), None);
+        // A server that stops returning follows before the total is reached.
+        assert_eq!(page(150, 0).next_page(&second), None);
+    }
 }
@@ -0,0 +1,119 @@
+//! A limit on the rate of all requests that a checker makes, regardless of the host.
+//!
+//! This is about the crawler's total footprint, not politeness towards a single host; the latter
+//! is handled by Crawl-delay. Each checker is a process of its own, so the Orchestrator shares its
+//! `--max-rps` budget between them by limiting how often checks start, using a limiter of its own.
+//! It also passes the whole budget to each checker as
+//! [`crate::checker::Options::request_interval`], so that a check with many pages of peers can't
+//! exceed the budget by itself.
+//!
+//! The limiter is a token bucket, implemented as the "generic cell rate algorithm": rather than
+//! counting tokens, it keeps the time at which the next request is due at the steady rate, and
+//! lets requests run ahead of that by at most the size of the bucket.
+use crate::checker::deadline;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Set by [`limit_rate()`].
+static RATE_LIMITER: OnceLock<RateLimiter> = OnceLock::new();
+
+/// Make at most one request per `interval` for the rest of the process's life.
+pub fn limit_rate(interval: Duration) {
+    // Only the first call has any effect; the checker makes just one.
+    let _ = RATE_LIMITER.set(RateLimiter::new(interval, 1));
+}
+
+/// Wait until the next request can be made, if [`limit_rate()`] was called.
+pub fn acquire() {
+    if let Some(limiter) = RATE_LIMITER.get() {
+        let started = Instant::now();
+        limiter.acquire();
+        deadline::extend(started.elapsed());
+    }
+}
+
+pub struct RateLimiter {
+    /// The steady rate: one request per this long.
+    interval: Duration,
+
+    /// How far ahead of the steady rate the requests may get, i.e. the size of the bucket minus
+    /// one request.
+    burst: Duration,
+
+    /// When the next request is due at the steady rate. Requests can be made from multiple
+    /// threads, and they wait for each other while holding this lock.
+    next: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    /// A limiter that lets through one request per `interval`, and bursts of up to `capacity`
+    /// requests after a lull.
+    pub fn new(interval: Duration, capacity: u32) -> Self {
+        let burst = interval.saturating_mul(capacity.saturating_sub(1));
+        Self {
+            interval,
+            burst,
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Block until a request can be made.
+    pub fn acquire(&self) {
+        let Ok(mut next) = self.next.lock() else {
+            return;
+        };
+        let now = Instant::now();
+        let due = next.map_or(now, |next| next.max(now));
+        if let Some(wait) = due
+            .checked_sub(self.burst)
+            .and_then(|earliest| earliest.checked_duration_since(now))
+        {
+            std::thread::sleep(wait);
+        }
+        *next = due.checked_add(self.interval);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bursts_are_throttled_to_the_steady_rate() {
+        let interval = Duration::from_millis(50);
+        let limiter = RateLimiter::new(interval, 3);
+
+        // A full bucket lets a burst through right away...
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire();
+        }
+        assert!(start.elapsed() < interval);
+
+        // ...but after that, requests go at the steady rate.
+        for _ in 0..5 {
+            limiter.acquire();
+        }
+        assert!(start.elapsed() >= interval * 5);
+    }
+
+    #[test]
+    fn concurrent_requests_share_the_limit() {
+        let interval = Duration::from_millis(20);
+        let limiter = RateLimiter::new(interval, 1);
+
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..3 {
+                        limiter.acquire();
+                    }
+                });
+            }
+        });
+        // 12 requests, the first of which goes right away.
+        assert!(start.elapsed() >= interval * 11);
+    }
+}
@@ -0,0 +1,268 @@
+//! Sending the results of a check to whoever asked for it.
+use crate::{checker::Format, ipc, with_loc};
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
+use url::Host;
+
+/// The outcome of a check, printed by `--check --format json`. The fields and their names are
+/// stable.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+struct CheckSummary {
+    host: String,
+
+    /// "alive", "dead", "moving", "moved" or "blocked".
+    state: &'static str,
+
+    /// The software name from NodeInfo, sanitized the way all metadata is: without non-printable
+    /// characters, and cut short if it's too long. Absent with `--privacy-only`, which doesn't
+    /// report the metadata.
+    software: Option<String>,
+
+    /// Where the instance redirects to, if it's moving or has moved.
+    redirect_to: Option<String>,
+
+    /// Why the instance refuses to serve us, if it's blocked.
+    blocked_reason: Option<String>,
+
+    /// The number of peers, if they were fetched.
+    peers: Option<usize>,
+
+    /// The number of peers received so far; they become `peers` once the list is complete.
+    #[serde(skip)]
+    peers_so_far: usize,
+}
+
+impl CheckSummary {
+    fn new(host: &Host) -> Self {
+        Self {
+            host: host.to_string(),
+            // Unless the checker says otherwise, the instance is dead.
+            state: "dead",
+            software: None,
+            redirect_to: None,
+            blocked_reason: None,
+            peers: None,
+            peers_so_far: 0,
+        }
+    }
+
+    fn record(&mut self, response: ipc::CheckerResponse) {
+        match response {
+            ipc::CheckerResponse::Hello { .. } | ipc::CheckerResponse::Dead { .. } => {}
+            ipc::CheckerResponse::State { state } => match state {
+                ipc::InstanceState::Alive { .. } => self.state = "alive",
+                ipc::InstanceState::Moving { to } => {
+                    self.state = "moving";
+                    self.redirect_to = Some(to.to_string());
+                }
+                ipc::InstanceState::Moved { to } => {
+                    self.state = "moved";
+                    self.redirect_to = Some(to.to_string());
+                }
+                ipc::InstanceState::Blocked { reason } => {
+                    self.state = "blocked";
+                    self.blocked_reason = Some(reason.to_string());
+                }
+            },
+            ipc::CheckerResponse::Peer { peer: _ } => {
+                self.peers_so_far = self.peers_so_far.saturating_add(1);
+            }
+            ipc::CheckerResponse::EndOfPeers => self.peers = Some(self.peers_so_far),
+            ipc::CheckerResponse::Metadata { metadata } => self.software = metadata.software,
+        }
+    }
+}
+
+/// Sends the checker's responses in the chosen [`Format`].
+pub struct Reporter {
+    format: Format,
+    summary: Mutex<CheckSummary>,
+    /// If set, the responses are kept here rather than printed.
+    captured: Option<Mutex<Vec<ipc::CheckerResponse>>>,
+    /// Set once the summary is printed, so that it's printed only once.
+    finished: AtomicBool,
+}
+
+impl Reporter {
+    pub fn new(host: &Host, format: Format) -> Self {
+        Self {
+            format,
+            summary: Mutex::new(CheckSummary::new(host)),
+            captured: None,
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    /// A reporter that keeps the responses for [`Reporter::into_captured`], for checks that run
+    /// inside the Orchestrator's process.
+    pub fn capturing(host: &Host) -> Self {
+        Self {
+            captured: Some(Mutex::new(vec![])),
+            ..Self::new(host, Format::Ipc)
+        }
+    }
+
+    /// Print the `response` right away, or add it to the summary.
+    pub fn send(&self, response: ipc::CheckerResponse) -> anyhow::Result<()> {
+        match self.format {
+            Format::Ipc => {
+                if let Some(captured) = &self.captured {
+                    captured
+                        .lock()
+                        .map_err(|_| anyhow!("The captured responses mutex is poisoned"))?
+                        .push(response);
+                    return Ok(());
+                }
+                let line = serde_json::to_string(&response)
+                    .context(with_loc!("Serializing checker's response"))?;
+                println!("{}", line);
+            }
+            Format::Json => self
+                .summary
+                .lock()
+                .map_err(|_| anyhow!("The check summary mutex is poisoned"))?
+                .record(response),
+        }
+        Ok(())
+    }
+
+    /// Send all the `peers`, followed by [`ipc::CheckerResponse::EndOfPeers`].
+    ///
+    /// The list is printed in one go, so that a checker that runs out of time doesn't exit in the
+    /// middle of it.
+    pub fn send_peers(&self, peers: &[Host]) -> anyhow::Result<()> {
+        // The lock is reentrant, so `send` can still print.
+        let _stdout = std::io::stdout().lock();
+        for peer in peers {
+            self.send(ipc::CheckerResponse::Peer { peer: peer.clone() })?;
+        }
+        self.send(ipc::CheckerResponse::EndOfPeers)
+    }
+
+    /// Print the summary, if that's the format. Only the first call does anything, so this can
+    /// also be called when the check is cut short.
+    pub fn finish(&self) -> anyhow::Result<()> {
+        if self.format == Format::Json && !self.finished.swap(true, Ordering::Relaxed) {
+            let summary = self
+                .summary
+                .lock()
+                .map_err(|_| anyhow!("The check summary mutex is poisoned"))?;
+            let summary =
+                serde_json::to_string(&*summary).context(with_loc!("Serializing check summary"))?;
+            println!("{}", summary);
+        }
+        Ok(())
+    }
+
+    /// The responses sent to a [`Reporter::capturing`] reporter so far, in order. They're taken
+    /// out, so the next call only returns the ones sent after this one.
+    pub fn take_captured(&self) -> anyhow::Result<Vec<ipc::CheckerResponse>> {
+        match &self.captured {
+            Some(captured) => {
+                Ok(std::mem::take(&mut *captured.lock().map_err(|_| {
+                    anyhow!("The captured responses mutex is poisoned")
+                })?))
+            }
+            None => Ok(vec![]),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    fn host(name: &str) -> Host {
+        Host::Domain(name.to_string())
+    }
+
+    #[test]
+    fn summary_of_an_alive_instance() {
+        let mut summary = CheckSummary::new(&host("example.com"));
+        summary.record(ipc::CheckerResponse::State {
+            state: ipc::InstanceState::Alive {
+                hide_from_list: false,
+            },
+        });
+        summary.record(ipc::CheckerResponse::Metadata {
+            metadata: ipc::Metadata {
+                software: Some("Mastodon".to_string()),
+                ..Default::default()
+            },
+        });
+        summary.record(ipc::CheckerResponse::Peer {
+            peer: host("example.org"),
+        });
+        summary.record(ipc::CheckerResponse::Peer {
+            peer: host("example.net"),
+        });
+        summary.record(ipc::CheckerResponse::EndOfPeers);
+        assert_eq!(
+            serde_json::to_value(&summary).unwrap(),
+            serde_json::json!({
+                "host": "example.com",
+                "state": "alive",
+                "software": "Mastodon",
+                "redirect_to": null,
+                "blocked_reason": null,
+                "peers": 2,
+            })
+        );
+    }
+
+    #[test]
+    fn summary_of_a_moved_instance() {
+        let mut summary = CheckSummary::new(&host("example.com"));
+        assert_eq!(summary.state, "dead");
+
+        summary.record(ipc::CheckerResponse::State {
+            state: ipc::InstanceState::Moved {
+                to: host("example.org"),
+            },
+        });
+        assert_eq!(summary.state, "moved");
+        assert_eq!(summary.redirect_to.as_deref(), Some("example.org"));
+        assert_eq!(summary.peers, None);
+    }
+
+    #[test]
+    fn capturing_reporter_keeps_the_responses() {
+        let reporter = Reporter::capturing(&host("example.com"));
+        reporter
+            .send(ipc::CheckerResponse::Hello {
+                protocol_version: ipc::PROTOCOL_VERSION,
+            })
+            .unwrap();
+        reporter.send_peers(&[host("example.org")]).unwrap();
+        assert_eq!(
+            reporter.take_captured().unwrap(),
+            vec![
+                ipc::CheckerResponse::Hello {
+                    protocol_version: ipc::PROTOCOL_VERSION,
+                },
+                ipc::CheckerResponse::Peer {
+                    peer: host("example.org"),
+                },
+                ipc::CheckerResponse::EndOfPeers,
+            ]
+        );
+    }
+
+    #[test]
+    fn only_complete_peers_lists_are_counted() {
+        let reporter = Reporter::new(&host("example.com"), Format::Json);
+        reporter.send_peers(&[]).unwrap();
+        assert_eq!(reporter.summary.lock().unwrap().peers, Some(0));
+
+        let mut summary = CheckSummary::new(&host("example.com"));
+        summary.record(ipc::CheckerResponse::Peer {
+            peer: host("example.org"),
+        });
+        assert_eq!(summary.peers, None);
+    }
+}
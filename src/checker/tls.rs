@@ -0,0 +1,340 @@
+//! TLS settings that take note of when the instance's certificate expires.
+//!
+//! Expired certificates are a common cause of instances suddenly "dying", so we record the expiry
+//! date in order to warn about it beforehand.
+use anyhow::{bail, Context};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The `notAfter` date of the instance's certificate. It's filled in during the TLS handshake.
+#[derive(Debug, Clone, Default)]
+pub struct CertificateExpiry(Arc<Mutex<Option<SystemTime>>>);
+
+impl CertificateExpiry {
+    pub fn get(&self) -> Option<SystemTime> {
+        self.0.lock().ok().and_then(|expiry| *expiry)
+    }
+
+    fn set(&self, not_after: SystemTime) {
+        if let Ok(mut expiry) = self.0.lock() {
+            *expiry = Some(not_after);
+        }
+    }
+}
+
+/// Verifies certificates with the `inner` verifier, and records the expiry date of the `host`'s
+/// certificate.
+#[derive(Debug)]
+struct RecordingVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    host: String,
+    expiry: CertificateExpiry,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // An onion address is derived from the service's key, so Tor already made sure that we're
+        // talking to the right server. Their certificates are mostly self-signed, and their expiry
+        // means little.
+        if server_name.to_str().ends_with(".onion") {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        // Redirects can lead to other hosts, whose certificates are none of our business.
+        if server_name.to_str() == self.host {
+            if let Some(not_after) = not_after(end_entity) {
+                self.expiry.set(not_after);
+            }
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Read the certificates from the PEM file at `path`.
+pub fn load_certificates(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", path.display()))?;
+    if certificates.is_empty() {
+        bail!("{} contains no certificates", path.display());
+    }
+    Ok(certificates)
+}
+
+/// The CAs that ureq trusts by default, plus the `extra_roots`.
+fn root_store(extra_roots: &[CertificateDer<'static>]) -> Result<RootCertStore, rustls::Error> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    for root in extra_roots {
+        roots.add(root.clone())?;
+    }
+    Ok(roots)
+}
+
+/// The same TLS settings that ureq uses by default, plus recording of the expiry date of the
+/// `host`'s certificate. The `extra_roots` are trusted in addition to the usual CAs, for all hosts.
+/// Certificates of onion services aren't checked.
+///
+/// We also tell the server via ALPN that we only speak HTTP/1.1. Servers that only speak HTTP/2
+/// then reject the handshake with an alert we can recognize, rather than with garbage in place of
+/// an HTTP/1.1 response.
+pub fn client_config(
+    host: &str,
+    extra_roots: &[CertificateDer<'static>],
+) -> Result<(Arc<ClientConfig>, CertificateExpiry), rustls::Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = Arc::new(root_store(extra_roots)?);
+    let inner = WebPkiServerVerifier::builder_with_provider(roots, provider.clone())
+        .build()
+        .map_err(|e| rustls::Error::General(e.to_string()))?;
+    let expiry = CertificateExpiry::default();
+    let verifier = RecordingVerifier {
+        inner,
+        host: host.to_owned(),
+        expiry: expiry.clone(),
+    };
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS12, &rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok((Arc::new(config), expiry))
+}
+
+/// The `notAfter` date of a DER-encoded X.509 certificate.
+///
+/// webpki doesn't expose the validity period, but it reports `notAfter` when a certificate turns
+/// out to be expired, and that is the first thing it checks. So we ask it to verify the
+/// certificate at the end of time.
+fn not_after(certificate: &CertificateDer<'_>) -> Option<SystemTime> {
+    let certificate = webpki::EndEntityCert::try_from(certificate).ok()?;
+    let end_of_time = UnixTime::since_unix_epoch(Duration::from_secs(u64::MAX));
+    match certificate.verify_for_usage(
+        &[],
+        &[],
+        &[],
+        end_of_time,
+        webpki::KeyUsage::server_auth(),
+        None,
+        None,
+    ) {
+        Err(webpki::Error::CertExpired { not_after, .. }) => {
+            UNIX_EPOCH.checked_add(Duration::from_secs(not_after.as_secs()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    /// A self-signed certificate for example.com, valid until 2030-06-15 12:34:56 UTC.
+    const CERTIFICATE: &[u8] = &[
+        0x30, 0x82, 0x01, 0x80, 0x30, 0x82, 0x01, 0x27, 0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x14,
+        0x6c, 0x64, 0x7b, 0xf7, 0x2e, 0xb2, 0x58, 0xfa, 0xe8, 0x23, 0x9f, 0x0b, 0x00, 0x77, 0x9b,
+        0x5f, 0xe8, 0xa2, 0xf4, 0x22, 0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04,
+        0x03, 0x02, 0x30, 0x16, 0x31, 0x14, 0x30, 0x12, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x0b,
+        0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x30, 0x1e, 0x17, 0x0d,
+        0x32, 0x34, 0x30, 0x31, 0x30, 0x31, 0x30, 0x30, 0x30, 0x30, 0x30, 0x30, 0x5a, 0x17, 0x0d,
+        0x33, 0x30, 0x30, 0x36, 0x31, 0x35, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x5a, 0x30, 0x16,
+        0x31, 0x14, 0x30, 0x12, 0x06, 0x03, 0x55, 0x04, 0x03, 0x0c, 0x0b, 0x65, 0x78, 0x61, 0x6d,
+        0x70, 0x6c, 0x65, 0x2e, 0x63, 0x6f, 0x6d, 0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86,
+        0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07,
+        0x03, 0x42, 0x00, 0x04, 0xed, 0xf0, 0xfd, 0x4e, 0x0b, 0xf4, 0xa4, 0x3d, 0x8e, 0xc5, 0x59,
+        0xb4, 0xd5, 0x97, 0xbb, 0x8d, 0xed, 0x1c, 0xda, 0x53, 0x9d, 0x9c, 0x0e, 0xa4, 0xa8, 0xf7,
+        0xec, 0x87, 0x4c, 0x62, 0x38, 0x2d, 0x89, 0x0a, 0x4e, 0xde, 0x6a, 0x1d, 0x31, 0xdb, 0x4d,
+        0x61, 0xd8, 0xc2, 0xdd, 0x7f, 0xff, 0x88, 0xfd, 0x6d, 0x21, 0xa3, 0x25, 0xef, 0xa1, 0x4f,
+        0xe7, 0x37, 0xc8, 0xab, 0x46, 0xc9, 0x38, 0x0e, 0xa3, 0x53, 0x30, 0x51, 0x30, 0x1d, 0x06,
+        0x03, 0x55, 0x1d, 0x0e, 0x04, 0x16, 0x04, 0x14, 0xfe, 0x0f, 0x0c, 0xea, 0xc0, 0x6e, 0x83,
+        0xd8, 0x3c, 0x27, 0xb0, 0xc2, 0xe0, 0x6d, 0x12, 0xa2, 0x3a, 0xe4, 0xb5, 0xc0, 0x30, 0x1f,
+        0x06, 0x03, 0x55, 0x1d, 0x23, 0x04, 0x18, 0x30, 0x16, 0x80, 0x14, 0xfe, 0x0f, 0x0c, 0xea,
+        0xc0, 0x6e, 0x83, 0xd8, 0x3c, 0x27, 0xb0, 0xc2, 0xe0, 0x6d, 0x12, 0xa2, 0x3a, 0xe4, 0xb5,
+        0xc0, 0x30, 0x0f, 0x06, 0x03, 0x55, 0x1d, 0x13, 0x01, 0x01, 0xff, 0x04, 0x05, 0x30, 0x03,
+        0x01, 0x01, 0xff, 0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02,
+        0x03, 0x47, 0x00, 0x30, 0x44, 0x02, 0x20, 0x21, 0xda, 0x9f, 0x04, 0xd7, 0x2f, 0xe6, 0x15,
+        0x0a, 0x4f, 0xf6, 0xe7, 0xf2, 0xc1, 0xd4, 0x3e, 0x24, 0x57, 0x4b, 0x7a, 0x89, 0x34, 0xeb,
+        0xdc, 0xd6, 0x27, 0xe9, 0xf6, 0x15, 0xf1, 0x13, 0xa9, 0x02, 0x20, 0x79, 0x2f, 0xf2, 0xcc,
+        0x6c, 0xb5, 0x1a, 0xf6, 0xd9, 0x3e, 0x5c, 0x92, 0x33, 0xa0, 0x7f, 0xac, 0x70, 0x43, 0x2d,
+        0xbc, 0xf3, 0x6b, 0x0d, 0xfd, 0x73, 0xd7, 0xa2, 0xfe, 0xfd, 0x6e, 0xf9, 0x8f,
+    ];
+
+    const CERTIFICATE_NOT_AFTER: u64 = 1_907_757_296;
+
+    /// [`CERTIFICATE`] in PEM.
+    const CERTIFICATE_PEM: &str = "-----BEGIN CERTIFICATE-----
+MIIBgDCCASegAwIBAgIUbGR79y6yWProI58LAHebX+ii9CIwCgYIKoZIzj0EAwIw
+FjEUMBIGA1UEAwwLZXhhbXBsZS5jb20wHhcNMjQwMTAxMDAwMDAwWhcNMzAwNjE1
+MTIzNDU2WjAWMRQwEgYDVQQDDAtleGFtcGxlLmNvbTBZMBMGByqGSM49AgEGCCqG
+SM49AwEHA0IABO3w/U4L9KQ9jsVZtNWXu43tHNpTnZwOpKj37IdMYjgtiQpO3mod
+MdtNYdjC3X//iP1tIaMl76FP5zfIq0bJOA6jUzBRMB0GA1UdDgQWBBT+DwzqwG6D
+2DwnsMLgbRKiOuS1wDAfBgNVHSMEGDAWgBT+DwzqwG6D2DwnsMLgbRKiOuS1wDAP
+BgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0cAMEQCICHanwTXL+YVCk/25/LB
+1D4kV0t6iTTr3NYn6fYV8ROpAiB5L/LMbLUa9tk+XJIzoH+scEMtvPNrDf1z16L+
+/W75jw==
+-----END CERTIFICATE-----
+";
+
+    /// Accepts any certificate.
+    #[derive(Debug)]
+    struct AcceptAll;
+
+    impl ServerCertVerifier for AcceptAll {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &CertificateDer<'_>,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            vec![]
+        }
+    }
+
+    #[test]
+    fn extra_roots_are_trusted_in_addition_to_the_usual_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ca.pem");
+        std::fs::write(&path, CERTIFICATE_PEM).unwrap();
+        let extra_roots = load_certificates(&path).unwrap();
+        assert_eq!(extra_roots, vec![CertificateDer::from(CERTIFICATE)]);
+
+        let roots = root_store(&extra_roots).unwrap();
+        assert_eq!(roots.len(), webpki_roots::TLS_SERVER_ROOTS.len() + 1);
+        assert!(client_config("example.com", &extra_roots).is_ok());
+
+        assert!(root_store(&[CertificateDer::from(&b"garbage"[..])]).is_err());
+
+        std::fs::write(&path, "").unwrap();
+        assert!(load_certificates(&path).is_err());
+        assert!(load_certificates(&dir.path().join("missing.pem")).is_err());
+    }
+
+    #[test]
+    fn reads_not_after_from_certificate() {
+        assert_eq!(
+            not_after(&CertificateDer::from(CERTIFICATE)),
+            Some(UNIX_EPOCH + Duration::from_secs(CERTIFICATE_NOT_AFTER))
+        );
+        assert_eq!(not_after(&CertificateDer::from(&[][..])), None);
+        assert_eq!(
+            not_after(&CertificateDer::from(CERTIFICATE.get(..100).unwrap())),
+            None
+        );
+    }
+
+    #[test]
+    fn records_expiry_of_the_hosts_certificate() {
+        let certificate = CertificateDer::from(CERTIFICATE);
+        let verify = |host: &str| {
+            let expiry = CertificateExpiry::default();
+            let verifier = RecordingVerifier {
+                inner: Arc::new(AcceptAll),
+                host: "example.com".to_string(),
+                expiry: expiry.clone(),
+            };
+            let server_name = ServerName::try_from(host.to_string()).unwrap();
+            verifier
+                .verify_server_cert(&certificate, &[], &server_name, &[], UnixTime::now())
+                .unwrap();
+            expiry.get()
+        };
+
+        assert_eq!(
+            verify("example.com"),
+            Some(UNIX_EPOCH + Duration::from_secs(CERTIFICATE_NOT_AFTER))
+        );
+        assert_eq!(verify("redirect.example.net"), None);
+    }
+
+    #[test]
+    fn onion_services_certificates_are_not_checked() {
+        let certificate = CertificateDer::from(CERTIFICATE);
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = RecordingVerifier {
+            inner: WebPkiServerVerifier::builder_with_provider(
+                Arc::new(root_store(&[]).unwrap()),
+                provider,
+            )
+            .build()
+            .unwrap(),
+            host: "example.onion".to_string(),
+            expiry: CertificateExpiry::default(),
+        };
+        let verify = |host: &str| {
+            let server_name = ServerName::try_from(host.to_string()).unwrap();
+            verifier.verify_server_cert(&certificate, &[], &server_name, &[], UnixTime::now())
+        };
+
+        // The certificate is self-signed.
+        assert!(verify("example.com").is_err());
+        assert!(verify("example.onion").is_ok());
+        assert_eq!(verifier.expiry.get(), None);
+    }
+}
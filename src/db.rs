@@ -1,17 +1,32 @@
 //! Functions to query and update the database, plus some helpers.
 
-use crate::{domain::Domain, time, with_loc};
-use anyhow::{anyhow, Context};
+use crate::{
+    domain::{Domain, UnknownSuffixPolicy},
+    ipc::{BlockedReason, DeathCause, Metadata},
+    software, time, with_loc,
+};
+use anyhow::{anyhow, bail, Context};
 use rusqlite::{
     params,
     types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
-    Connection, ToSql, Transaction,
+    Connection, OpenFlags, OptionalExtension, ToSql, Transaction, TransactionBehavior,
 };
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const ONE_WEEK_IN_SECONDS: u64 = 60 * 60 * 24 * 7;
 
-fn is_sqlite_busy_error(error: &anyhow::Error) -> bool {
+/// The number of most recent checks which are considered when looking for flapping instances.
+const FLAPPING_WINDOW: u32 = 32;
+
+/// An instance that failed this many of the last [`FLAPPING_WINDOW`] checks is considered dead,
+/// even if it occasionally responds.
+const FLAPPING_FAILURES: u32 = 24;
+
+/// The rate at which [`reschedule_missed_checks()`] schedules the overdue checks. One check per
+/// second fits over a hundred thousand checks into the usual 29 hours.
+const RESCHEDULE_CHECKS_PER_SECOND: u64 = 1;
+
+pub fn is_sqlite_busy_error(error: &anyhow::Error) -> bool {
     if let Some(error) = error.downcast_ref::<rusqlite::Error>() {
         if let Some(code) = error.sqlite_error_code() {
             return code == rusqlite::ErrorCode::DatabaseBusy;
@@ -21,18 +36,46 @@ fn is_sqlite_busy_error(error: &anyhow::Error) -> bool {
     false
 }
 
-/// A helper that, upon encountering `SQLITE_BUSY`, just waits a bit and retries.
-pub fn on_sqlite_busy_retry_indefinitely<T, F>(f: &mut F) -> anyhow::Result<T>
+/// How long the first retry after `SQLITE_BUSY` waits, at most. Each next retry can wait twice as
+/// long, up to [`MAX_BUSY_BACKOFF`].
+const MIN_BUSY_BACKOFF: Duration = Duration::from_millis(2);
+
+/// The longest a single retry after `SQLITE_BUSY` waits.
+const MAX_BUSY_BACKOFF: Duration = Duration::from_millis(256);
+
+/// The longest the retry after `attempt` failed attempts can wait.
+fn busy_backoff_cap(attempt: u32) -> Duration {
+    2u32.checked_pow(attempt)
+        .and_then(|factor| MIN_BUSY_BACKOFF.checked_mul(factor))
+        .map_or(MAX_BUSY_BACKOFF, |cap| cap.min(MAX_BUSY_BACKOFF))
+}
+
+/// How long to wait before the retry after `attempt` failed attempts: a random duration up to
+/// [`busy_backoff_cap`]. The randomness spreads out the processes that collided, and the growing
+/// cap makes them spread further the more they collide.
+fn busy_backoff(attempt: u32) -> Duration {
+    let cap = u64::try_from(busy_backoff_cap(attempt).as_millis()).unwrap_or(u64::MAX);
+    Duration::from_millis(fastrand::u64(1..=cap))
+}
+
+/// Runs `f`, retrying it after a [`busy_backoff`] for as long as it fails with `SQLITE_BUSY`,
+/// but no more than `max_retries` times if that is given.
+fn retry_on_busy<T, F>(
+    f: &mut F,
+    max_retries: Option<u32>,
+    sleep: &mut dyn FnMut(Duration),
+) -> anyhow::Result<T>
 where
     F: FnMut() -> anyhow::Result<T>,
 {
+    let mut attempt: u32 = 0;
     loop {
         match f() {
             result @ Ok(_) => return result,
             Err(e) => {
-                if is_sqlite_busy_error(&e) {
-                    let duration = fastrand::u64(1..50);
-                    std::thread::sleep(std::time::Duration::from_millis(duration));
+                if is_sqlite_busy_error(&e) && max_retries.is_none_or(|max| attempt < max) {
+                    sleep(busy_backoff(attempt));
+                    attempt = attempt.saturating_add(1);
                 } else {
                     return Err(e);
                 }
@@ -41,26 +84,20 @@ where
     }
 }
 
-/// A helper that, upon encountering `SQLITE_BUSY`, just waits a bit and retries, up to 100 times.
-pub fn on_sqlite_busy_retry<T, F>(f: &mut F) -> anyhow::Result<T>
+/// A helper that, upon encountering `SQLITE_BUSY`, waits a bit and retries.
+pub fn on_sqlite_busy_retry_indefinitely<T, F>(f: &mut F) -> anyhow::Result<T>
 where
     F: FnMut() -> anyhow::Result<T>,
 {
-    for _ in 0..100 {
-        match f() {
-            result @ Ok(_) => return result,
-            Err(e) => {
-                if is_sqlite_busy_error(&e) {
-                    let duration = fastrand::u64(1..50);
-                    std::thread::sleep(std::time::Duration::from_millis(duration));
-                } else {
-                    return Err(e);
-                }
-            }
-        }
-    }
+    retry_on_busy(f, None, &mut std::thread::sleep)
+}
 
-    f()
+/// A helper that, upon encountering `SQLITE_BUSY`, waits a bit and retries, up to 100 times.
+pub fn on_sqlite_busy_retry<T, F>(f: &mut F) -> anyhow::Result<T>
+where
+    F: FnMut() -> anyhow::Result<T>,
+{
+    retry_on_busy(f, Some(100), &mut std::thread::sleep)
 }
 
 /// Wrapper over `std::time::SystemTime`. In SQL, it's stored as an integer number of seconds since
@@ -93,6 +130,61 @@ impl FromSql for UnixTimestamp {
     }
 }
 
+/// What the database knows about an instance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceRecord {
+    pub id: i64,
+    pub state: InstanceState,
+    pub next_check: SystemTime,
+    /// The family of the software that the instance ran the last time it was alive, if known.
+    pub software: Option<String>,
+    /// The software name exactly as the instance reported it.
+    pub software_raw: Option<String>,
+    /// The instance asked not to be included in the list.
+    pub hide_from_list: bool,
+    /// How many checks in a row found the instance alive.
+    pub consecutive_alive_checks: u64,
+    /// Why the latest check failed, unless the instance was found alive since.
+    pub death_reason: Option<DeathReason>,
+    /// The domain whose description the instance served on its latest check, if not its own.
+    pub misconfigured_domain: Option<String>,
+}
+
+impl std::fmt::Display for InstanceRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}, {}", self.id, self.state.name())?;
+        match (&self.software, &self.software_raw) {
+            (Some(family), Some(raw)) if family != raw => write!(f, ", runs {} ({})", raw, family)?,
+            (Some(family), _) => write!(f, ", runs {}", family)?,
+            _ => {}
+        }
+        if self.hide_from_list {
+            write!(f, ", hidden from the list")?;
+        }
+        if self.consecutive_alive_checks > 0 {
+            write!(
+                f,
+                ", alive for the last {} checks",
+                self.consecutive_alive_checks
+            )?;
+        }
+        if let Some(reason) = self.death_reason {
+            write!(f, ", last check failed: {}", reason)?;
+        }
+        if let Some(domain) = &self.misconfigured_domain {
+            write!(f, ", misconfigured: serves the description of {}", domain)?;
+        }
+        match self.next_check.duration_since(SystemTime::now()) {
+            Ok(wait) => write!(
+                f,
+                ", next check in {:.1} hours",
+                wait.as_secs_f64() / 3600.0
+            ),
+            Err(_) => write!(f, ", next check is due"),
+        }
+    }
+}
+
 /// Possible states of a Fediverse instance, mapped to integers used in the database.
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum InstanceState {
@@ -102,6 +194,46 @@ pub enum InstanceState {
     Dead = 3,
     Moving = 4,
     Moved = 5,
+    Blocked = 6,
+}
+
+impl InstanceState {
+    pub const ALL: [InstanceState; 7] = [
+        Self::Discovered,
+        Self::Alive,
+        Self::Dying,
+        Self::Dead,
+        Self::Moving,
+        Self::Moved,
+        Self::Blocked,
+    ];
+
+    /// The name of the state, as stored in the `states` table.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Discovered => "discovered",
+            Self::Alive => "alive",
+            Self::Dying => "dying",
+            Self::Dead => "dead",
+            Self::Moving => "moving",
+            Self::Moved => "moved",
+            Self::Blocked => "blocked",
+        }
+    }
+}
+
+impl std::str::FromStr for InstanceState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|state| state.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Self::ALL.iter().map(|state| state.name()).collect();
+                anyhow!("Unknown state {}, expected one of {}", s, names.join(", "))
+            })
+    }
 }
 
 impl ToSql for InstanceState {
@@ -120,29 +252,220 @@ impl FromSql for InstanceState {
             3 => Ok(Self::Dead),
             4 => Ok(Self::Moving),
             5 => Ok(Self::Moved),
+            6 => Ok(Self::Blocked),
             _ => Err(rusqlite::types::FromSqlError::OutOfRange(v)),
         }
     }
 }
 
+/// Why the Orchestrator marked an instance dead.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum DeathReason {
+    /// The checker exited without reporting anything, i.e. it couldn't reach the instance.
+    NoResponse,
+    /// The instance redirected us temporarily, to itself, or to something that's not a domain.
+    Redirect,
+    /// The checker's messages didn't come in the expected order.
+    BadIpc,
+    /// The checker crashed or was killed before reporting anything.
+    CheckerError,
+    /// The checker reported that the instance is dead for a reason that has no variant of its
+    /// own, e.g. the connection was refused or NodeInfo is malformed.
+    CheckFailed,
+    /// The TLS handshake failed.
+    TlsError,
+    /// The instance only seems to speak HTTP/2.
+    Http2Only,
+    /// The instance redirected the checker in circles.
+    RedirectLoop,
+    /// The instance responded with a body that was too large.
+    BodyTooLarge,
+    /// The instance's robots.txt doesn't let us fetch NodeInfo.
+    ForbiddenByRobotsTxt,
+    /// The instance served an HTML page instead of NodeInfo.
+    NotNodeInfo,
+    /// The instance serves NodeInfo, but its app responds with server errors.
+    AppIsDown,
+}
+
+impl From<DeathCause> for DeathReason {
+    fn from(cause: DeathCause) -> Self {
+        match cause {
+            DeathCause::TlsError => Self::TlsError,
+            DeathCause::Http2Only => Self::Http2Only,
+            DeathCause::RedirectLoop => Self::RedirectLoop,
+            DeathCause::BodyTooLarge => Self::BodyTooLarge,
+            DeathCause::ForbiddenByRobotsTxt => Self::ForbiddenByRobotsTxt,
+            DeathCause::NotNodeInfo => Self::NotNodeInfo,
+            DeathCause::AppIsDown => Self::AppIsDown,
+            DeathCause::Other => Self::CheckFailed,
+        }
+    }
+}
+
+impl DeathReason {
+    /// The reason as stored in the database.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::NoResponse => "no-response",
+            Self::Redirect => "redirect",
+            Self::BadIpc => "bad-ipc",
+            Self::CheckerError => "checker-error",
+            Self::CheckFailed => "check-failed",
+            Self::TlsError => "tls-error",
+            Self::Http2Only => "http2-only",
+            Self::RedirectLoop => "redirect-loop",
+            Self::BodyTooLarge => "body-too-large",
+            Self::ForbiddenByRobotsTxt => "forbidden-by-robots-txt",
+            Self::NotNodeInfo => "not-nodeinfo",
+            Self::AppIsDown => "app-is-down",
+        }
+    }
+}
+
+impl std::fmt::Display for DeathReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl ToSql for DeathReason {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.name()))
+    }
+}
+
+impl FromSql for DeathReason {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "no-response" => Ok(Self::NoResponse),
+            "redirect" => Ok(Self::Redirect),
+            "bad-ipc" => Ok(Self::BadIpc),
+            "checker-error" => Ok(Self::CheckerError),
+            "check-failed" => Ok(Self::CheckFailed),
+            "tls-error" => Ok(Self::TlsError),
+            "http2-only" => Ok(Self::Http2Only),
+            "redirect-loop" => Ok(Self::RedirectLoop),
+            "body-too-large" => Ok(Self::BodyTooLarge),
+            "forbidden-by-robots-txt" => Ok(Self::ForbiddenByRobotsTxt),
+            "not-nodeinfo" => Ok(Self::NotNodeInfo),
+            "app-is-down" => Ok(Self::AppIsDown),
+            other => Err(FromSqlError::Other(
+                format!("Unknown death reason: {}", other).into(),
+            )),
+        }
+    }
+}
+
+impl ToSql for BlockedReason {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let reason = match self {
+            BlockedReason::UnavailableForLegalReasons => "unavailable-for-legal-reasons",
+            BlockedReason::AuthenticationRequired => "authentication-required",
+        };
+        Ok(ToSqlOutput::from(reason))
+    }
+}
+
+impl FromSql for BlockedReason {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "unavailable-for-legal-reasons" => Ok(Self::UnavailableForLegalReasons),
+            "authentication-required" => Ok(Self::AuthenticationRequired),
+            other => Err(FromSqlError::Other(
+                format!("Unknown blocked reason: {}", other).into(),
+            )),
+        }
+    }
+}
+
+const DATABASE_PATH: &str = "minoru-fediverse-crawler.db";
+
 /// Connect to the database.
 pub fn open() -> anyhow::Result<Connection> {
-    let conn = Connection::open("minoru-fediverse-crawler.db")
-        .context(with_loc!("Failed to initialize the database"))?;
+    let conn =
+        Connection::open(DATABASE_PATH).context(with_loc!("Failed to initialize the database"))?;
     conn.pragma_update(None, "journal_mode", "WAL")
         .context(with_loc!("Switching to WAL mode"))?;
     Ok(conn)
 }
 
-/// Initialize the database.
+/// Connect to an existing database without the ability to change it.
+pub fn open_read_only() -> anyhow::Result<Connection> {
+    Connection::open_with_flags(
+        DATABASE_PATH,
+        OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .context(with_loc!("Failed to open the database read-only"))
+}
+
+/// The size of the database file in bytes, not counting the write-ahead log.
+pub fn file_size() -> anyhow::Result<u64> {
+    let metadata =
+        std::fs::metadata(DATABASE_PATH).context(with_loc!("Getting the database file size"))?;
+    Ok(metadata.len())
+}
+
+/// A step in the evolution of the database schema.
+type Migration = fn(&Transaction) -> anyhow::Result<()>;
+
+/// All the migrations, in the order in which they're applied. The database's `user_version` is
+/// the number of migrations that were applied to it.
+///
+/// Released migrations are never changed; changes to the schema go into new migrations at the end
+/// of the list.
+const MIGRATIONS: &[Migration] = &[
+    create_initial_schema,
+    add_last_alive_datetime,
+    punycode_hostnames,
+    add_settings,
+    add_misconfigured_domain,
+];
+
+/// Initialize the database, or bring an existing one up to date.
 ///
 /// This is safe to run concurrently with other processes; it will do nothing if the database is
 /// already initialized.
 pub fn init(conn: &mut Connection) -> anyhow::Result<()> {
+    migrate(conn, MIGRATIONS)
+}
+
+/// Apply the `migrations` that weren't applied to the database yet.
+fn migrate(conn: &mut Connection, migrations: &[Migration]) -> anyhow::Result<()> {
+    // The write lock is taken right away, so that concurrent processes apply the migrations one
+    // after another, and each sees the version left by the previous one.
     let tx = conn
-        .transaction()
+        .transaction_with_behavior(TransactionBehavior::Immediate)
         .context(with_loc!("Beginning a transaction"))?;
 
+    let version: usize = tx
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .context(with_loc!("Getting the schema version"))?;
+    if version > migrations.len() {
+        bail!(
+            "The database schema is at version {}, but this version of the crawler only knows up to {}; please upgrade the crawler",
+            version,
+            migrations.len()
+        );
+    }
+    for (applied, migration) in migrations.iter().enumerate().skip(version) {
+        let version = applied.saturating_add(1);
+        migration(&tx)
+            .with_context(|| format!("Migrating the database schema to version {}", version))?;
+        tx.pragma_update(None, "user_version", version)
+            .context(with_loc!("Updating the schema version"))?;
+    }
+
+    tx.commit().context(with_loc!("Committing the transaction"))
+}
+
+/// The schema as it was before migrations were introduced.
+///
+/// Databases created back then have no version, so this migration is applied to them too. That's
+/// why it only creates what's missing, and adds the columns that were added over time.
+fn create_initial_schema(tx: &Transaction) -> anyhow::Result<()> {
     tx.execute(
         "CREATE TABLE IF NOT EXISTS states(
             id INTEGER PRIMARY KEY NOT NULL,
@@ -160,7 +483,8 @@ pub fn init(conn: &mut Connection) -> anyhow::Result<()> {
             (2, "dying"),
             (3, "dead"),
             (4, "moving"),
-            (5, "moved")"#,
+            (5, "moved"),
+            (6, "blocked")"#,
         [],
     )
     .context(with_loc!("Filling table 'states'"))?;
@@ -169,11 +493,48 @@ pub fn init(conn: &mut Connection) -> anyhow::Result<()> {
             id INTEGER PRIMARY KEY NOT NULL,
             hostname TEXT UNIQUE NOT NULL,
             state REFERENCES states(id) NOT NULL DEFAULT 0,
-            next_check_datetime INTEGER DEFAULT (strftime('%s', CURRENT_TIMESTAMP))
+            next_check_datetime INTEGER DEFAULT (strftime('%s', CURRENT_TIMESTAMP)),
+            discovery_depth INTEGER NOT NULL DEFAULT 0,
+            software TEXT,
+            software_raw TEXT,
+            first_alive_datetime INTEGER,
+            consecutive_alive_checks INTEGER NOT NULL DEFAULT 0,
+            tag TEXT,
+            peers_count INTEGER,
+            death_reason TEXT
         )",
         [],
     )
     .context(with_loc!("Creating table 'instances'"))?;
+    add_column_if_missing(
+        tx,
+        "instances",
+        "discovery_depth",
+        "INTEGER NOT NULL DEFAULT 0",
+    )
+    .context(with_loc!("Adding column 'discovery_depth' to 'instances'"))?;
+    add_column_if_missing(tx, "instances", "software", "TEXT")
+        .context(with_loc!("Adding column 'software' to 'instances'"))?;
+    add_column_if_missing(tx, "instances", "software_raw", "TEXT")
+        .context(with_loc!("Adding column 'software_raw' to 'instances'"))?;
+    add_column_if_missing(tx, "instances", "first_alive_datetime", "INTEGER").context(
+        with_loc!("Adding column 'first_alive_datetime' to 'instances'"),
+    )?;
+    add_column_if_missing(
+        tx,
+        "instances",
+        "consecutive_alive_checks",
+        "INTEGER NOT NULL DEFAULT 0",
+    )
+    .context(with_loc!(
+        "Adding column 'consecutive_alive_checks' to 'instances'"
+    ))?;
+    add_column_if_missing(tx, "instances", "tag", "TEXT")
+        .context(with_loc!("Adding column 'tag' to 'instances'"))?;
+    add_column_if_missing(tx, "instances", "peers_count", "INTEGER")
+        .context(with_loc!("Adding column 'peers_count' to 'instances'"))?;
+    add_column_if_missing(tx, "instances", "death_reason", "TEXT")
+        .context(with_loc!("Adding column 'death_reason' to 'instances'"))?;
     tx.execute(
         r#"INSERT OR IGNORE
         INTO instances(hostname)
@@ -216,6 +577,19 @@ pub fn init(conn: &mut Connection) -> anyhow::Result<()> {
         "Creating index 'dying_state_data_previous_state_instance'"
     ))?;
 
+    // `outcomes` is a bit field of the most recent checks, the latest in the lowest bit; 1 means
+    // the check failed.
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS check_history(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL UNIQUE,
+            outcomes INTEGER NOT NULL DEFAULT 0,
+            checks_count INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'check_history'"))?;
+
     tx.execute(
         "CREATE TABLE IF NOT EXISTS moving_state_data(
             id INTEGER PRIMARY KEY NOT NULL,
@@ -247,6 +621,17 @@ pub fn init(conn: &mut Connection) -> anyhow::Result<()> {
     )
     .context(with_loc!("Creating table 'moved_state_data'"))?;
 
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS blocked_state_data(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL UNIQUE,
+            blocked_since INTEGER NOT NULL,
+            reason TEXT NOT NULL
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'blocked_state_data'"))?;
+
     tx.execute(
         "CREATE TABLE IF NOT EXISTS hidden_instances(
             id INTEGER PRIMARY KEY NOT NULL,
@@ -265,34 +650,378 @@ pub fn init(conn: &mut Connection) -> anyhow::Result<()> {
         "Creating index 'hidden_instances_hide_from_list_instance'"
     ))?;
 
-    tx.commit().context(with_loc!("Committing the transaction"))
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS unknown_suffix_instances(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL UNIQUE
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table unknown_suffix_instances"))?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS peerings(
+            id INTEGER PRIMARY KEY NOT NULL,
+            from_instance REFERENCES instances(id) NOT NULL,
+            to_instance REFERENCES instances(id) NOT NULL,
+            UNIQUE(from_instance, to_instance)
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'peerings'"))?;
+
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS instance_metadata(
+            id INTEGER PRIMARY KEY NOT NULL,
+            instance REFERENCES instances(id) NOT NULL UNIQUE,
+            server TEXT,
+            powered_by TEXT,
+            title TEXT,
+            rules TEXT,
+            contact TEXT,
+            thumbnail TEXT,
+            certificate_expires_at INTEGER
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table instance_metadata"))?;
+    add_column_if_missing(tx, "instance_metadata", "title", "TEXT")
+        .context(with_loc!("Adding column 'title' to 'instance_metadata'"))?;
+    add_column_if_missing(tx, "instance_metadata", "rules", "TEXT")
+        .context(with_loc!("Adding column 'rules' to 'instance_metadata'"))?;
+    add_column_if_missing(tx, "instance_metadata", "contact", "TEXT")
+        .context(with_loc!("Adding column 'contact' to 'instance_metadata'"))?;
+    add_column_if_missing(tx, "instance_metadata", "thumbnail", "TEXT").context(with_loc!(
+        "Adding column 'thumbnail' to 'instance_metadata'"
+    ))?;
+    add_column_if_missing(tx, "instance_metadata", "certificate_expires_at", "INTEGER").context(
+        with_loc!("Adding column 'certificate_expires_at' to 'instance_metadata'"),
+    )?;
+
+    Ok(())
+}
+
+/// Remember when each instance was last seen alive. Instances that are alive now get it on their
+/// next check.
+fn add_last_alive_datetime(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute(
+        "ALTER TABLE instances ADD COLUMN last_alive_datetime INTEGER",
+        [],
+    )
+    .context(with_loc!(
+        "Adding column 'last_alive_datetime' to 'instances'"
+    ))?;
+    Ok(())
+}
+
+/// Add a table for the crawl's settings that other commands need too; see [`save_periods`].
+fn add_settings(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute(
+        "CREATE TABLE settings(
+            name TEXT PRIMARY KEY,
+            value INTEGER NOT NULL
+        )",
+        [],
+    )
+    .context(with_loc!("Creating table 'settings'"))?;
+    Ok(())
+}
+
+/// Remember which domain a misconfigured instance serves the description of; see
+/// [`Metadata::misconfigured_domain`].
+fn add_misconfigured_domain(tx: &Transaction) -> anyhow::Result<()> {
+    tx.execute(
+        "ALTER TABLE instance_metadata ADD COLUMN misconfigured_domain TEXT",
+        [],
+    )
+    .context(with_loc!(
+        "Adding column 'misconfigured_domain' to 'instance_metadata'"
+    ))?;
+    Ok(())
+}
+
+/// Store internationalized hostnames in their ASCII (Punycode) form, which is what [`Domain`]
+/// produces nowadays; older versions stored them in Unicode, and lookups by hostname missed them.
+///
+/// If the instance was already re-added under its ASCII name, the two rows are merged into the
+/// ASCII one. Its own data wins where both rows have some.
+fn punycode_hostnames(tx: &Transaction) -> anyhow::Result<()> {
+    let unicode = {
+        let mut statement = tx
+            .prepare("SELECT id, hostname FROM instances")
+            .context(with_loc!("Preparing a SELECT"))?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })
+            .context(with_loc!("Executing the SELECT"))?;
+        rows.collect::<Result<Vec<_>, _>>()
+            .context(with_loc!("Getting the hostnames"))?
+            .into_iter()
+            .filter(|(_, hostname)| !hostname.is_ascii())
+            .collect::<Vec<_>>()
+    };
+
+    for (id, hostname) in unicode {
+        let ascii = match idna::domain_to_ascii(&hostname) {
+            Ok(ascii) => ascii,
+            // Nothing can look this one up anyway; it'll be left as is.
+            Err(_) => continue,
+        };
+        let existing: Option<i64> = tx
+            .query_row(
+                "SELECT id FROM instances WHERE hostname = ?1",
+                params![ascii],
+                |row| row.get(0),
+            )
+            .optional()
+            .context(with_loc!("Looking up the ASCII hostname"))?;
+        match existing {
+            None => {
+                tx.execute(
+                    "UPDATE instances SET hostname = ?1 WHERE id = ?2",
+                    params![ascii, id],
+                )
+                .context(with_loc!("Re-encoding the hostname"))?;
+            }
+            Some(kept) => merge_instances(tx, id, kept)
+                .with_context(|| format!("Merging {} into {}", hostname, ascii))?,
+        }
+    }
+    Ok(())
+}
+
+/// Move everything that refers to instance `from` over to instance `into`, then delete `from`.
+fn merge_instances(tx: &Transaction, from: i64, into: i64) -> anyhow::Result<()> {
+    // Tables that hold at most one row per instance; `into`'s own row wins.
+    for table in [
+        "dying_state_data",
+        "check_history",
+        "moving_state_data",
+        "moved_state_data",
+        "blocked_state_data",
+        "hidden_instances",
+        "unknown_suffix_instances",
+        "instance_metadata",
+    ] {
+        tx.execute(
+            &format!(
+                "UPDATE OR IGNORE {} SET instance = ?1 WHERE instance = ?2",
+                table
+            ),
+            params![into, from],
+        )
+        .with_context(|| format!("Moving rows of '{}'", table))?;
+        tx.execute(
+            &format!("DELETE FROM {} WHERE instance = ?1", table),
+            params![from],
+        )
+        .with_context(|| format!("Deleting leftover rows of '{}'", table))?;
+    }
+
+    tx.execute(
+        "UPDATE moving_state_data SET moving_to = ?1 WHERE moving_to = ?2",
+        params![into, from],
+    )
+    .context(with_loc!("Redirecting moves"))?;
+    tx.execute(
+        "UPDATE moved_state_data SET moved_to = ?1 WHERE moved_to = ?2",
+        params![into, from],
+    )
+    .context(with_loc!("Redirecting finished moves"))?;
+
+    for column in ["from_instance", "to_instance"] {
+        tx.execute(
+            &format!(
+                "UPDATE OR IGNORE peerings SET {0} = ?1 WHERE {0} = ?2",
+                column
+            ),
+            params![into, from],
+        )
+        .with_context(|| format!("Moving peerings by '{}'", column))?;
+        tx.execute(
+            &format!("DELETE FROM peerings WHERE {} = ?1", column),
+            params![from],
+        )
+        .with_context(|| format!("Deleting duplicate peerings by '{}'", column))?;
+    }
+
+    // An instance listing its own other spelling doesn't peer with itself.
+    tx.execute(
+        "DELETE FROM peerings WHERE from_instance = ?1 AND to_instance = ?1",
+        params![into],
+    )
+    .context(with_loc!("Deleting self-peerings"))?;
+
+    tx.execute("DELETE FROM instances WHERE id = ?1", params![from])
+        .context(with_loc!("Deleting the merged instance"))?;
+    Ok(())
+}
+
+/// Add a column to a table created by an older version of the crawler.
+///
+/// `CREATE TABLE IF NOT EXISTS` leaves existing tables alone, so new columns have to be added to
+/// them separately.
+fn add_column_if_missing(
+    tx: &Transaction,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> anyhow::Result<()> {
+    let exists: bool = tx
+        .query_row(
+            "SELECT count(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+            params![table, column],
+            |row| row.get(0),
+        )
+        .context(with_loc!("Looking up the column"))?;
+    if !exists {
+        tx.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )
+        .context(with_loc!("Adding the column"))?;
+    }
+    Ok(())
+}
+
+/// For any check whose time has already passed, move that check up to a "daily" period (29 hours,
+/// by default) from now.
+///
+/// The same is done to checks that are further away than [`time::max_check_delay()`]; those were
+/// scheduled before the system clock jumped backwards.
+///
+/// The checks are spread out so that, together with the checks that are already scheduled, they
+/// don't exceed [`RESCHEDULE_CHECKS_PER_SECOND`], which matters if the crawler was down for days.
+///
+/// Returns the number of rescheduled checks.
+pub fn reschedule_missed_checks(conn: &mut Connection) -> anyhow::Result<usize> {
+    let tx = conn
+        .transaction()
+        .context(with_loc!("Beginning a transaction"))?;
+
+    let now = SystemTime::now();
+    let latest = now
+        .checked_add(time::max_check_delay()?)
+        .ok_or_else(|| anyhow!("Failed to compute the latest possible check's datetime"))?;
+    let ids = {
+        let mut statement = tx
+            .prepare(
+                "SELECT id
+                FROM instances
+                WHERE next_check_datetime < ?1
+                    OR next_check_datetime > ?2",
+            )
+            .context(with_loc!("Preparing a SELECT"))?;
+        let ids = statement
+            .query_map(params![UnixTimestamp(now), UnixTimestamp(latest)], |row| {
+                row.get::<_, i64>(0)
+            })
+            .context(with_loc!("Executing the SELECT"))?;
+        ids.collect::<Result<Vec<_>, _>>()
+            .context(with_loc!("Getting `instance_id`"))?
+    };
+
+    let window = reschedule_window(&tx, now, u64::try_from(ids.len()).unwrap_or(u64::MAX))?;
+    for &instance_id in &ids {
+        let next_check =
+            time::sometime_within(window).context(with_loc!("Picking next check's datetime"))?;
+        reschedule_instance_to(&tx, instance_id, next_check)
+            .context(with_loc!("Rescheduling instance"))?;
+    }
+
+    tx.commit()
+        .context(with_loc!("Committing the transaction"))?;
+    Ok(ids.len())
+}
+
+/// The window over which [`reschedule_missed_checks()`] spreads the `missed` checks.
+///
+/// The checks that are already scheduled into the window share the rate with the missed ones.
+/// A wider window might take in more of them, so it's widened until it fits them all; it can't
+/// grow past [`time::max_check_delay()`], so this ends.
+fn reschedule_window(conn: &Connection, now: SystemTime, missed: u64) -> anyhow::Result<Duration> {
+    let mut window = time::spread_window(missed, RESCHEDULE_CHECKS_PER_SECOND)?;
+    loop {
+        let end = now
+            .checked_add(window)
+            .ok_or_else(|| anyhow!("Failed to compute the end of the window"))?;
+        let scheduled = count_checks_between(conn, now, end)?;
+        let wider = time::spread_window(
+            missed.saturating_add(scheduled),
+            RESCHEDULE_CHECKS_PER_SECOND,
+        )?;
+        if wider <= window {
+            return Ok(window);
+        }
+        window = wider;
+    }
+}
+
+/// The number of checks scheduled between `start` and `end`, inclusive.
+fn count_checks_between(
+    conn: &Connection,
+    start: SystemTime,
+    end: SystemTime,
+) -> anyhow::Result<u64> {
+    conn.query_row(
+        "SELECT count(*)
+        FROM instances
+        WHERE next_check_datetime BETWEEN ?1 AND ?2",
+        params![UnixTimestamp(start), UnixTimestamp(end)],
+        |row| row.get(0),
+    )
+    .context(with_loc!("Counting the scheduled checks"))
+}
+
+/// Rebuild the database to reclaim the space left by deleted rows, and update the statistics
+/// that the query planner relies on.
+///
+/// The rebuild goes through the write-ahead log, so the log is checkpointed afterwards to move
+/// the result into the database file.
+pub fn vacuum(conn: &Connection) -> anyhow::Result<()> {
+    conn.execute_batch("VACUUM")
+        .context(with_loc!("Vacuuming"))?;
+    conn.execute_batch("PRAGMA optimize")
+        .context(with_loc!("Optimizing"))?;
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+        .context(with_loc!("Checkpointing the write-ahead log"))
 }
 
-/// For any check whose time has already passed, move that check up to 24 hours from now.
-pub fn reschedule_missed_checks(conn: &mut Connection) -> anyhow::Result<()> {
+/// Move the checks of all instances in the given `states` to sometime in the next 29 hours,
+/// without changing their states.
+///
+/// Returns the number of rescheduled checks.
+pub fn recheck_soon(conn: &mut Connection, states: &[InstanceState]) -> anyhow::Result<usize> {
     let tx = conn
         .transaction()
         .context(with_loc!("Beginning a transaction"))?;
 
+    let mut rescheduled: usize = 0;
     {
         let mut statement = tx
             .prepare(
                 "SELECT id
                 FROM instances
-                WHERE next_check_datetime < strftime('%s', CURRENT_TIMESTAMP)",
+                WHERE state = ?1",
             )
             .context(with_loc!("Preparing a SELECT"))?;
-        let mut ids = statement.query([])?;
-        while let Some(row) = ids.next()? {
-            let instance_id: i64 = row.get(0).context(with_loc!("Getting `instance_id`"))?;
-            let next_check =
-                time::sometime_today().context(with_loc!("Picking next check's datetime"))?;
-            reschedule_instance_to(&tx, instance_id, next_check)
-                .context(with_loc!("Rescheduling instance"))?;
+        for state in states {
+            let mut ids = statement.query(params![state])?;
+            while let Some(row) = ids.next()? {
+                let instance_id: i64 = row.get(0).context(with_loc!("Getting `instance_id`"))?;
+                let next_check =
+                    time::sometime_today().context(with_loc!("Picking next check's datetime"))?;
+                reschedule_instance_to(&tx, instance_id, next_check)
+                    .context(with_loc!("Rescheduling instance"))?;
+                rescheduled = rescheduled.saturating_add(1);
+            }
         }
     }
 
-    tx.commit().context(with_loc!("Committing the transaction"))
+    tx.commit()
+        .context(with_loc!("Committing the transaction"))?;
+    Ok(rescheduled)
 }
 
 /// Note down that the instance is alive.
@@ -307,9 +1036,21 @@ pub fn mark_alive(
 
     let (instance_id, state) =
         get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
+    let now = UnixTimestamp(SystemTime::now());
 
     set_hide_instance_from_list(&tx, instance_id, hide_from_list)
         .context(with_loc!("Updating the flag in `hidden_instances`"))?;
+    record_check_outcome(&tx, instance_id, false)
+        .context(with_loc!("Recording the check in 'check_history'"))?;
+    tx.execute(
+        "UPDATE instances
+        SET consecutive_alive_checks = consecutive_alive_checks + 1,
+            death_reason = NULL,
+            last_alive_datetime = ?1
+        WHERE id = ?2",
+        params![now, instance_id],
+    )
+    .context(with_loc!("Counting the alive check"))?;
 
     if state == InstanceState::Alive {
         return tx
@@ -319,20 +1060,22 @@ pub fn mark_alive(
 
     assert_ne!(state, InstanceState::Alive);
 
-    // Delete any previous state data related to this instance
-    match state {
-        InstanceState::Dying => delete_dying_state_data(&tx, instance_id)
-            .context(with_loc!("Deleting from table `dying_state_data'"))?,
-        InstanceState::Moving => delete_moving_state_data(&tx, instance_id)
-            .context(with_loc!("Deleting from table 'moving_state_data'"))?,
-        InstanceState::Moved => delete_moved_state_data(&tx, instance_id)
-            .context(with_loc!("Deleting from table 'moved_state_data'"))?,
-        _ => {}
-    }
-
-    set_instance_state(&tx, instance_id, InstanceState::Alive)
-        .context(with_loc!("Marking instance as alive"))?;
-
+    // Instances that were already alive when this column was added keep it NULL, which the list
+    // generator treats as "alive since long ago".
+    tx.execute(
+        "UPDATE instances
+        SET first_alive_datetime = ?1
+        WHERE id = ?2
+            AND first_alive_datetime IS NULL",
+        params![now, instance_id],
+    )
+    .context(with_loc!(
+        "Noting down when the instance first became alive"
+    ))?;
+
+    set_state(&tx, instance_id, state, InstanceState::Alive)
+        .context(with_loc!("Marking instance as alive"))?;
+
     if state == InstanceState::Dead || state == InstanceState::Moved {
         let next_check =
             time::about_a_day_from_now().context(with_loc!("Picking next check's datetime"))?;
@@ -347,7 +1090,16 @@ pub fn mark_alive(
 ///
 /// This will first move the instance into a "dying" state, and after a week of calling this
 /// function, it will finally move the instance into the "dead" state.
-pub fn mark_dead(conn: &mut Connection, instance: &Domain) -> anyhow::Result<()> {
+///
+/// An instance that keeps failing most of its checks is moved into the "dead" state even if it
+/// occasionally comes back, because otherwise each comeback would restart the week-long clock.
+///
+/// The `reason` is noted down until the instance is found alive again.
+pub fn mark_dead(
+    conn: &mut Connection,
+    instance: &Domain,
+    reason: DeathReason,
+) -> anyhow::Result<()> {
     let tx = conn
         .transaction()
         .context(with_loc!("Beginning a transaction"))?;
@@ -355,19 +1107,27 @@ pub fn mark_dead(conn: &mut Connection, instance: &Domain) -> anyhow::Result<()>
     let now = SystemTime::now();
     let (instance_id, state) =
         get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
+    tx.execute(
+        "UPDATE instances
+        SET death_reason = ?1
+        WHERE id = ?2",
+        params![reason, instance_id],
+    )
+    .context(with_loc!("Noting down the death reason"))?;
     if state == InstanceState::Dead {
-        return Ok(());
+        return tx.commit().context(with_loc!("Committing the transaction"));
     }
 
     assert_ne!(state, InstanceState::Dead);
 
-    // Delete any unrelated state data for this instance
-    match state {
-        InstanceState::Moving => delete_moving_state_data(&tx, instance_id)
-            .context(with_loc!("Deleting from table 'moving_state_data'"))?,
-        InstanceState::Moved => delete_moved_state_data(&tx, instance_id)
-            .context(with_loc!("Deleting from table 'moved_state_data'"))?,
-        _ => {}
+    reset_consecutive_alive_checks(&tx, instance_id)
+        .context(with_loc!("Resetting the count of alive checks"))?;
+    let (failures, checks) = record_check_outcome(&tx, instance_id, true)
+        .context(with_loc!("Recording the check in 'check_history'"))?;
+    if checks >= FLAPPING_WINDOW && failures >= FLAPPING_FAILURES {
+        make_dead(&tx, instance_id, state)
+            .context(with_loc!("Marking a flapping instance as dead"))?;
+        return tx.commit().context(with_loc!("Committing the transaction"));
     }
 
     match state {
@@ -376,7 +1136,11 @@ pub fn mark_dead(conn: &mut Connection, instance: &Domain) -> anyhow::Result<()>
         InstanceState::Discovered
         | InstanceState::Alive
         | InstanceState::Moving
-        | InstanceState::Moved => {
+        | InstanceState::Moved
+        | InstanceState::Blocked => {
+            set_state(&tx, instance_id, state, InstanceState::Dying)
+                .context(with_loc!("Marking instance as dying"))?;
+
             tx.execute(
                 "INSERT
                 INTO dying_state_data(instance, previous_state, dying_since)
@@ -384,9 +1148,6 @@ pub fn mark_dead(conn: &mut Connection, instance: &Domain) -> anyhow::Result<()>
                 params![instance_id, state, UnixTimestamp(now)],
             )
             .context(with_loc!("Inserting into table 'dying_state_data'"))?;
-
-            set_instance_state(&tx, instance_id, InstanceState::Dying)
-                .context(with_loc!("Marking instance as dying"))?;
         }
 
         InstanceState::Dying => {
@@ -419,15 +1180,7 @@ pub fn mark_dead(conn: &mut Connection, instance: &Domain) -> anyhow::Result<()>
             // "daily" checks per peal week. So 6 failed checks means "we've been failing for about
             // a week".
             if checks_count > 6 && since < week_ago {
-                delete_from_hidden_instances(&tx, instance_id)
-                    .context(with_loc!("Deleting from 'hidden_instances'"))?;
-                delete_dying_state_data(&tx, instance_id)
-                    .context(with_loc!("Deleting from table 'dying_state_data'"))?;
-                let next_check = time::about_a_week_from_now()
-                    .context(with_loc!("Picking next check's datetime"))?;
-                reschedule_instance_to(&tx, instance_id, next_check)
-                    .context(with_loc!("Rescheduling instance"))?;
-                set_instance_state(&tx, instance_id, InstanceState::Dead)
+                make_dead(&tx, instance_id, state)
                     .context(with_loc!("Marking instance as dead"))?;
             }
         }
@@ -436,6 +1189,67 @@ pub fn mark_dead(conn: &mut Connection, instance: &Domain) -> anyhow::Result<()>
     tx.commit().context(with_loc!("Committing the transaction"))
 }
 
+/// Move the instance from the `state` into the "dead" state.
+fn make_dead(tx: &Transaction, instance_id: i64, state: InstanceState) -> anyhow::Result<()> {
+    delete_from_hidden_instances(tx, instance_id)
+        .context(with_loc!("Deleting from 'hidden_instances'"))?;
+    // If the instance ever comes back, it deserves a clean slate.
+    tx.execute(
+        "DELETE FROM check_history
+        WHERE instance = ?1",
+        params![instance_id],
+    )
+    .context(with_loc!("Deleting from table 'check_history'"))?;
+    let next_check =
+        time::about_a_week_from_now().context(with_loc!("Picking next check's datetime"))?;
+    reschedule_instance_to(tx, instance_id, next_check)
+        .context(with_loc!("Rescheduling instance"))?;
+    set_state(tx, instance_id, state, InstanceState::Dead)
+        .context(with_loc!("Marking instance as dead"))
+}
+
+fn reset_consecutive_alive_checks(tx: &Transaction, instance_id: i64) -> anyhow::Result<()> {
+    tx.execute(
+        "UPDATE instances
+        SET consecutive_alive_checks = 0
+        WHERE id = ?1",
+        params![instance_id],
+    )
+    .map(|_| ())
+    .context(with_loc!("Updating table 'instances'"))
+}
+
+/// Add the outcome of a check to the instance's history.
+///
+/// Returns the number of failed checks and the number of all checks in the history, which covers
+/// at most [`FLAPPING_WINDOW`] latest checks.
+fn record_check_outcome(
+    tx: &Transaction,
+    instance_id: i64,
+    failed: bool,
+) -> anyhow::Result<(u32, u32)> {
+    let window_mask: i64 = (1i64 << FLAPPING_WINDOW) - 1;
+    tx.execute(
+        "INSERT INTO check_history(instance, outcomes, checks_count)
+        VALUES (?1, ?2, 1)
+        ON CONFLICT(instance) DO UPDATE
+        SET outcomes = ((outcomes << 1) | excluded.outcomes) & ?3,
+            checks_count = min(checks_count + 1, ?4)",
+        params![instance_id, failed, window_mask, FLAPPING_WINDOW],
+    )
+    .context(with_loc!("Updating table 'check_history'"))?;
+    let (outcomes, checks): (i64, u32) = tx
+        .query_row(
+            "SELECT outcomes, checks_count
+            FROM check_history
+            WHERE instance = ?1",
+            params![instance_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .context(with_loc!("Selecting data from 'check_history'"))?;
+    Ok((outcomes.count_ones(), checks))
+}
+
 fn is_moving_to_that_host_already(tx: &Transaction, from: i64, to: i64) -> anyhow::Result<bool> {
     Ok(tx.query_row(
         "SELECT count(id)
@@ -477,6 +1291,8 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
     let now = SystemTime::now();
     let (instance_id, state) =
         get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
+    reset_consecutive_alive_checks(&tx, instance_id)
+        .context(with_loc!("Resetting the count of alive checks"))?;
     if state == InstanceState::Moved {
         let (to_instance_id, _) =
             get_instance(&tx, to).context(with_loc!("Getting instance id"))?;
@@ -485,8 +1301,8 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
         if !already_moved_there {
             // Redirect's target changed; change the state back to "moving"
 
-            delete_moved_state_data(&tx, instance_id)
-                .context(with_loc!("Deleting from table 'moved_state_data'"))?;
+            set_state(&tx, instance_id, state, InstanceState::Moving)
+                .context(with_loc!("Marking instance as moving"))?;
 
             tx.execute(
                 "INSERT INTO moving_state_data(instance, previous_state, moving_since, moving_to)
@@ -494,9 +1310,6 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
                 params![instance_id, state, UnixTimestamp(now), to_instance_id],
             )
             .context(with_loc!("Inserting into 'moving_state_data'"))?;
-
-            set_instance_state(&tx, instance_id, InstanceState::Moving)
-                .context(with_loc!("Marking instance as moving"))?;
         }
 
         return tx.commit().context(with_loc!("Committing the transaction"));
@@ -504,18 +1317,14 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
 
     assert_ne!(state, InstanceState::Moved);
 
-    if state == InstanceState::Dying {
-        delete_dying_state_data(&tx, instance_id)
-            .context(with_loc!("Deleting from table 'dying_state_data'"))?;
-    }
-
     match state {
         InstanceState::Moved => {}
 
         InstanceState::Discovered
         | InstanceState::Alive
         | InstanceState::Dying
-        | InstanceState::Dead => {
+        | InstanceState::Dead
+        | InstanceState::Blocked => {
             let next_check =
                 time::sometime_today().context(with_loc!("Picking next check's datatime"))?;
             tx.execute(
@@ -528,15 +1337,15 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
             let (to_instance_id, _) = get_instance(&tx, to)
                 .context(with_loc!("Getting id of the newly inserted instance"))?;
 
+            set_state(&tx, instance_id, state, InstanceState::Moving)
+                .context(with_loc!("Marking instance as moving"))?;
+
             tx.execute(
                 "INSERT INTO moving_state_data(instance, previous_state, moving_since, moving_to)
                 VALUES (?1, ?2, ?3, ?4)",
                 params![instance_id, state, UnixTimestamp(now), to_instance_id],
             )
             .context(with_loc!("Inserting into 'moving_state_data'"))?;
-
-            set_instance_state(&tx, instance_id, InstanceState::Moving)
-                .context(with_loc!("Marking instance as moving"))?;
         }
 
         InstanceState::Moving => {
@@ -579,8 +1388,8 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
                 if redirects_count > 6 && since < week_ago {
                     delete_from_hidden_instances(&tx, instance_id)
                         .context(with_loc!("Deleting from 'hidden_instances'"))?;
-                    delete_moving_state_data(&tx, instance_id)
-                        .context(with_loc!("Deleting from 'moving_state_data'"))?;
+                    set_state(&tx, instance_id, state, InstanceState::Moved)
+                        .context(with_loc!("Marking instance as moved"))?;
                     tx.execute(
                         "INSERT INTO moved_state_data(instance, moved_to)
                         VALUES (?1, ?2)",
@@ -591,8 +1400,6 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
                         .context(with_loc!("Picking next check's datetime"))?;
                     reschedule_instance_to(&tx, instance_id, next_check)
                         .context(with_loc!("Rescheduling instance"))?;
-                    set_instance_state(&tx, instance_id, InstanceState::Moved)
-                        .context(with_loc!("Marking instance as moved"))?;
                 }
             } else {
                 // Previous checks got redirected to another host; restart the counts
@@ -612,53 +1419,294 @@ pub fn mark_moved(conn: &mut Connection, instance: &Domain, to: &Domain) -> anyh
     tx.commit().context(with_loc!("Committing the transaction"))
 }
 
+/// Note down that the instance is up, but refuses to serve us.
+///
+/// Unlike dying instances, blocked ones don't progress towards "dead"; they simply stay blocked
+/// until they start serving us again or go down.
+pub fn mark_blocked(
+    conn: &mut Connection,
+    instance: &Domain,
+    reason: BlockedReason,
+) -> anyhow::Result<()> {
+    let tx = conn
+        .transaction()
+        .context(with_loc!("Beginning a transaction"))?;
+
+    let now = SystemTime::now();
+    let (instance_id, state) =
+        get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
+
+    if state == InstanceState::Blocked {
+        tx.execute(
+            "UPDATE blocked_state_data
+            SET reason = ?1
+            WHERE instance = ?2",
+            params![reason, instance_id],
+        )
+        .context(with_loc!("Updating table 'blocked_state_data'"))?;
+        return tx.commit().context(with_loc!("Committing the transaction"));
+    }
+
+    assert_ne!(state, InstanceState::Blocked);
+
+    set_state(&tx, instance_id, state, InstanceState::Blocked)
+        .context(with_loc!("Marking instance as blocked"))?;
+
+    tx.execute(
+        "INSERT INTO blocked_state_data(instance, blocked_since, reason)
+        VALUES (?1, ?2, ?3)",
+        params![instance_id, UnixTimestamp(now), reason],
+    )
+    .context(with_loc!("Inserting into table 'blocked_state_data'"))?;
+
+    if state == InstanceState::Dead || state == InstanceState::Moved {
+        let next_check =
+            time::about_a_day_from_now().context(with_loc!("Picking next check's datetime"))?;
+        reschedule_instance_to(&tx, instance_id, next_check)
+            .context(with_loc!("Rescheduling instance"))?;
+    }
+
+    tx.commit().context(with_loc!("Committing the transaction"))
+}
+
 /// Attempt to add an instance to the database. Does nothing if the instance is already known.
 pub fn add_instance(conn: &Connection, instance: &Domain) -> anyhow::Result<()> {
+    add_instance_at_depth(conn, instance, 0)
+}
+
+/// Add an instance that was found `depth` peers lists away from the seeds (which have depth 0).
+///
+/// If the instance is already known, its depth is left unchanged.
+pub fn add_instance_at_depth(
+    conn: &Connection,
+    instance: &Domain,
+    depth: u32,
+) -> anyhow::Result<()> {
     let mut statement = conn
         .prepare_cached(
             "INSERT OR IGNORE
-            INTO instances(hostname, next_check_datetime)
-            VALUES (?1, ?2)",
+            INTO instances(hostname, next_check_datetime, discovery_depth)
+            VALUES (?1, ?2, ?3)",
         )
         .context(with_loc!("Preparing cached INSERT OR IGNORE statement"))?;
     let next_check = time::sometime_today().context(with_loc!("Picking next check's datetime"))?;
     statement
-        .execute(params![instance.to_string(), UnixTimestamp(next_check)])
+        .execute(params![
+            instance.to_string(),
+            UnixTimestamp(next_check),
+            depth
+        ])
         .context(with_loc!("Executing the statement"))?;
 
+    if !instance.has_known_suffix() {
+        conn.execute(
+            "INSERT OR IGNORE
+            INTO unknown_suffix_instances(instance)
+            SELECT id FROM instances WHERE hostname = ?1",
+            params![instance.to_string()],
+        )
+        .context(with_loc!("Flagging the instance's suffix as unknown"))?;
+    }
+
     Ok(())
 }
 
-/// Reschedule the instance according to its state.
-pub fn reschedule(conn: &mut Connection, instance: &Domain) -> anyhow::Result<()> {
+/// Note down that `peers` are all the peers that `instance` lists, forgetting the ones it listed
+/// before. Peers that aren't in the database are skipped.
+pub fn replace_peerings(
+    conn: &mut Connection,
+    instance: &Domain,
+    peers: &[Domain],
+) -> anyhow::Result<()> {
     let tx = conn
         .transaction()
         .context(with_loc!("Beginning a transaction"))?;
 
-    let (instance_id, state) =
-        get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
-
-    let next_check_datetime = match state {
-        InstanceState::Discovered => time::about_a_day_from_now(),
-        InstanceState::Alive => time::about_a_day_from_now(),
-        InstanceState::Dying => time::about_a_day_from_now(),
-        InstanceState::Dead => time::about_a_week_from_now(),
-        InstanceState::Moving => time::about_a_day_from_now(),
-        InstanceState::Moved => time::about_a_week_from_now(),
-    }
-    .context(with_loc!("Picking next check's datetiem"))?;
-
     tx.execute(
-        "UPDATE instances
-        SET next_check_datetime = ?1
-        WHERE id = ?2",
-        params![UnixTimestamp(next_check_datetime), instance_id],
+        "DELETE FROM peerings
+        WHERE from_instance = (SELECT id FROM instances WHERE hostname = ?1)",
+        params![instance.to_string()],
     )
-    .context(with_loc!("Updating table 'instances'"))?;
+    .context(with_loc!("Deleting the old peerings"))?;
+    {
+        let mut statement = tx
+            .prepare(
+                "INSERT OR IGNORE
+                INTO peerings(from_instance, to_instance)
+                SELECT from_instances.id, to_instances.id
+                FROM instances AS from_instances, instances AS to_instances
+                WHERE from_instances.hostname = ?1 AND to_instances.hostname = ?2",
+            )
+            .context(with_loc!("Preparing INSERT OR IGNORE statement"))?;
+        for peer in peers {
+            statement
+                .execute(params![instance.to_string(), peer.to_string()])
+                .context(with_loc!("Executing the statement"))?;
+        }
+    }
+
+    tx.commit().context(with_loc!("Committing the transaction"))
+}
+
+/// Hostnames were validated before they got into the database, but the suffix might've been
+/// accepted as plausible rather than known, so we don't validate them as strictly again.
+fn parse_stored_hostname(hostname: &str) -> anyhow::Result<Domain> {
+    Domain::from_str_with_policy(hostname, UnknownSuffixPolicy::AcceptPlausible)
+}
+
+/// Pick the instance whose check is due the earliest, and reschedule it according to its state
+/// and software (see [`check_period_for`]).
+///
+/// This is done in a single write transaction, so if multiple Orchestrators share the database,
+/// each due check is claimed by only one of them. Returns `None` if no check is due at `now`.
+pub fn claim_next_instance(
+    conn: &mut Connection,
+    now: SystemTime,
+    software_periods: &[(String, time::Period)],
+) -> anyhow::Result<Option<Domain>> {
+    // An immediate transaction takes the write lock right away, so no other connection can claim
+    // the same instance between our SELECT and UPDATE.
+    let tx = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .context(with_loc!("Beginning a transaction"))?;
+
+    let due: Option<(i64, String, InstanceState, Option<String>)> = tx
+        .query_row(
+            &format!(
+                "SELECT id, hostname, state, software
+                FROM instances
+                WHERE next_check_datetime <= ?1
+                ORDER BY {}
+                LIMIT 1",
+                schedule_order()
+            ),
+            params![UnixTimestamp(now)],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()
+        .context(with_loc!("Picking a due instance"))?;
+    let Some((instance_id, hostname, state, software)) = due else {
+        return Ok(None);
+    };
+    let instance = parse_stored_hostname(&hostname)?;
+
+    let next_check = check_period_for(state, software.as_deref(), software_periods)
+        .next_check()
+        .context(with_loc!("Picking next check's datetime"))?;
+    reschedule_instance_to(&tx, instance_id, next_check)
+        .context(with_loc!("Rescheduling instance"))?;
+
+    tx.commit()
+        .context(with_loc!("Committing the transaction"))?;
+    Ok(Some(instance))
+}
+
+/// Schedule the next check of the `instance` according to its current state and software, like
+/// [`claim_next_instance`] does. Meant for checks that were done outside of the schedule.
+pub fn reschedule_by_state(
+    conn: &mut Connection,
+    instance: &Domain,
+    software_periods: &[(String, time::Period)],
+) -> anyhow::Result<()> {
+    let tx = conn
+        .transaction()
+        .context(with_loc!("Beginning a transaction"))?;
+
+    let (instance_id, state, software): (i64, InstanceState, Option<String>) = tx
+        .query_row(
+            "SELECT id, state, software FROM instances WHERE hostname = ?1",
+            params![instance.to_string()],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .context(with_loc!("Looking up the instance"))?;
+    let next_check = check_period_for(state, software.as_deref(), software_periods)
+        .next_check()
+        .context(with_loc!("Picking next check's datetime"))?;
+    reschedule_instance_to(&tx, instance_id, next_check)
+        .context(with_loc!("Rescheduling instance"))?;
 
     tx.commit().context(with_loc!("Committing the transaction"))
 }
 
+/// The ORDER BY clause that puts the instances in the order in which they're checked.
+///
+/// Instances scheduled for the same second are checked in no particular order, unless the times
+/// are deterministic (see [`time::make_deterministic`]); then they're ordered by hostname, so that
+/// runs are reproducible. That's not the default because the index doesn't cover the hostname.
+fn schedule_order() -> &'static str {
+    if time::is_deterministic() {
+        "next_check_datetime ASC, hostname ASC"
+    } else {
+        "next_check_datetime ASC"
+    }
+}
+
+/// How often an instance in the given state is checked.
+pub fn check_period(state: InstanceState) -> time::Period {
+    match state {
+        InstanceState::Discovered => time::Period::Daily,
+        InstanceState::Alive => time::Period::Daily,
+        InstanceState::Dying => time::Period::Daily,
+        InstanceState::Dead => time::Period::Weekly,
+        InstanceState::Moving => time::Period::Daily,
+        InstanceState::Moved => time::Period::Weekly,
+        InstanceState::Blocked => time::Period::Daily,
+    }
+}
+
+/// How often an instance in the given state and running the given `software` family is checked.
+///
+/// `software_periods` override the period of alive instances running the listed software
+/// families; other states are about finding out what happened to the instance, which shouldn't
+/// be delayed.
+pub fn check_period_for(
+    state: InstanceState,
+    software: Option<&str>,
+    software_periods: &[(String, time::Period)],
+) -> time::Period {
+    let configured = software_periods
+        .iter()
+        .find(|(family, _)| Some(family.as_str()) == software)
+        .map(|(_, period)| *period);
+    match (state, configured) {
+        (InstanceState::Alive, Some(period)) => period,
+        _ => check_period(state),
+    }
+}
+
+/// Look up an instance, returning `None` if it's not in the database.
+pub fn get_instance_by_host(
+    conn: &Connection,
+    instance: &Domain,
+) -> anyhow::Result<Option<InstanceRecord>> {
+    conn.query_row(
+        "SELECT instances.id, state, next_check_datetime, software, software_raw, hide_from_list,
+            consecutive_alive_checks, death_reason, misconfigured_domain
+        FROM instances
+            LEFT JOIN hidden_instances ON instances.id = hidden_instances.instance
+            LEFT JOIN instance_metadata ON instances.id = instance_metadata.instance
+        WHERE hostname = ?1",
+        params![instance.to_string()],
+        |row| {
+            let next_check: UnixTimestamp = row.get(2)?;
+            let hide_from_list: Option<bool> = row.get(5)?;
+            Ok(InstanceRecord {
+                id: row.get(0)?,
+                state: row.get(1)?,
+                next_check: next_check.0,
+                software: row.get(3)?,
+                software_raw: row.get(4)?,
+                hide_from_list: hide_from_list.unwrap_or(false),
+                consecutive_alive_checks: row.get(6)?,
+                death_reason: row.get(7)?,
+                misconfigured_domain: row.get(8)?,
+            })
+        },
+    )
+    .optional()
+    .context(with_loc!("Looking up the instance"))
+}
+
 fn get_instance(tx: &Transaction, instance: &Domain) -> anyhow::Result<(i64, InstanceState)> {
     tx.query_row(
         "SELECT id, state
@@ -704,6 +1752,16 @@ fn delete_moved_state_data(tx: &Transaction, id: i64) -> anyhow::Result<()> {
     .context(with_loc!("Deleting from table 'moved_state_data'"))
 }
 
+fn delete_blocked_state_data(tx: &Transaction, id: i64) -> anyhow::Result<()> {
+    tx.execute(
+        "DELETE FROM blocked_state_data
+        WHERE instance = ?1",
+        params![id],
+    )
+    .map(|_| ())
+    .context(with_loc!("Deleting from table 'blocked_state_data'"))
+}
+
 fn reschedule_instance_to(
     tx: &Transaction,
     id: i64,
@@ -719,25 +1777,76 @@ fn reschedule_instance_to(
     .context(with_loc!("Updating table 'instances'"))
 }
 
-fn set_instance_state(tx: &Transaction, id: i64, state: InstanceState) -> anyhow::Result<()> {
-    tx.execute(
-        "UPDATE instances
-        SET state = ?1
-        WHERE id = ?2",
-        params![state, id],
-    )
-    .map(|_| ())
-    .context(with_loc!("Updating table 'instances'"))
+/// Returns `true` if an instance in state `from` can be moved into state `to`.
+///
+/// Nothing ever goes back to "discovered", and an instance only becomes "moved" after a while in
+/// "moving". Staying in the same state is not a transition.
+fn is_legal_transition(from: InstanceState, to: InstanceState) -> bool {
+    use InstanceState::*;
+
+    match to {
+        _ if from == to => false,
+        Discovered => false,
+        Alive | Dead | Moving | Blocked => true,
+        Dying => from != Dead,
+        Moved => from == Moving,
+    }
+}
+
+/// Move the instance from state `from` to state `to`, deleting the data of the state it leaves.
+/// The caller inserts the data of the new state, if it has any.
+///
+/// Fails if the transition is not legal (see [`is_legal_transition`]), or if the instance is not
+/// in state `from`.
+fn set_state(
+    tx: &Transaction,
+    id: i64,
+    from: InstanceState,
+    to: InstanceState,
+) -> anyhow::Result<()> {
+    if !is_legal_transition(from, to) {
+        bail!(
+            "Instance #{} can't go from {} to {}",
+            id,
+            from.name(),
+            to.name()
+        );
+    }
+
+    match from {
+        InstanceState::Dying => delete_dying_state_data(tx, id)?,
+        InstanceState::Moving => delete_moving_state_data(tx, id)?,
+        InstanceState::Moved => delete_moved_state_data(tx, id)?,
+        InstanceState::Blocked => delete_blocked_state_data(tx, id)?,
+        InstanceState::Discovered | InstanceState::Alive | InstanceState::Dead => {}
+    }
+
+    let updated = tx
+        .execute(
+            "UPDATE instances
+            SET state = ?1
+            WHERE id = ?2
+                AND state = ?3",
+            params![to, id, from],
+        )
+        .context(with_loc!("Updating table 'instances'"))?;
+    if updated != 1 {
+        bail!("Instance #{} is not {}", id, from.name());
+    }
+    Ok(())
 }
 
 /// Pick the next instance to check, i.e. the one with the smallest `next_check_datetime` value.
 pub fn pick_next_instance(conn: &Connection) -> anyhow::Result<(Domain, SystemTime)> {
     let (hostname, next_check_datetime): (String, SystemTime) = conn
         .query_row(
-            "SELECT hostname, next_check_datetime
-            FROM instances
-            ORDER BY next_check_datetime ASC
-            LIMIT 1",
+            &format!(
+                "SELECT hostname, next_check_datetime
+                FROM instances
+                ORDER BY {}
+                LIMIT 1",
+                schedule_order()
+            ),
             [],
             |row| {
                 let hostname = row.get(0)?;
@@ -746,29 +1855,1535 @@ pub fn pick_next_instance(conn: &Connection) -> anyhow::Result<(Domain, SystemTi
             },
         )
         .context(with_loc!("Picking next instance"))?;
-    let domain = Domain::from_str(&hostname)?;
+    let domain = parse_stored_hostname(&hostname)?;
     Ok((domain, next_check_datetime))
 }
 
-fn set_hide_instance_from_list(
-    tx: &Transaction,
-    instance: i64,
-    hide_from_list: bool,
-) -> anyhow::Result<()> {
-    tx.execute(
-        "INSERT OR REPLACE
-        INTO hidden_instances(instance, hide_from_list)
-        VALUES (?1, ?2)",
-        params![instance, hide_from_list],
-    )?;
-    Ok(())
+/// Pick the instance whose check comes right after `after` in the schedule, or the first one if
+/// `after` is `None`. Ties are broken by hostname, so walking the schedule with this visits every
+/// instance exactly once.
+pub fn pick_next_instance_after(
+    conn: &Connection,
+    after: Option<&(SystemTime, Domain)>,
+) -> anyhow::Result<Option<(Domain, SystemTime)>> {
+    let (after_datetime, after_hostname) = match after {
+        Some((datetime, instance)) => (UnixTimestamp(*datetime), instance.to_string()),
+        None => (UnixTimestamp(UNIX_EPOCH), String::new()),
+    };
+    let next: Option<(String, UnixTimestamp)> = conn
+        .query_row(
+            "SELECT hostname, next_check_datetime
+            FROM instances
+            WHERE (next_check_datetime, hostname) > (?1, ?2)
+            ORDER BY next_check_datetime ASC, hostname ASC
+            LIMIT 1",
+            params![after_datetime, after_hostname],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .context(with_loc!("Picking next instance"))?;
+    next.map(|(hostname, next_check_datetime)| {
+        Ok((parse_stored_hostname(&hostname)?, next_check_datetime.0))
+    })
+    .transpose()
 }
 
-fn delete_from_hidden_instances(tx: &Transaction, instance: i64) -> anyhow::Result<()> {
-    tx.execute(
-        "DELETE FROM hidden_instances
-        WHERE instance = ?1",
-        params![instance],
-    )?;
+/// Get the next check's datetime and the check period of every instance, taking the
+/// `software_periods` into account like [`claim_next_instance`] does.
+pub fn get_schedule(
+    conn: &Connection,
+    software_periods: &[(String, time::Period)],
+) -> anyhow::Result<Vec<(SystemTime, time::Period)>> {
+    let mut schedule = vec![];
+    let mut statement = conn
+        .prepare(
+            "SELECT next_check_datetime, state, software
+            FROM instances",
+        )
+        .context(with_loc!("Preparing a SELECT"))?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let next_check_datetime: UnixTimestamp = row
+            .get(0)
+            .context(with_loc!("Getting `next_check_datetime`"))?;
+        let state: InstanceState = row.get(1).context(with_loc!("Getting `state`"))?;
+        let software: Option<String> = row.get(2).context(with_loc!("Getting `software`"))?;
+        let period = check_period_for(state, software.as_deref(), software_periods);
+        schedule.push((next_check_datetime.0, period));
+    }
+    Ok(schedule)
+}
+
+/// The earliest and the latest scheduled checks, or `None` if there are no instances.
+pub fn get_schedule_extremes(
+    conn: &Connection,
+) -> anyhow::Result<Option<(SystemTime, SystemTime)>> {
+    conn.query_row(
+        "SELECT min(next_check_datetime), max(next_check_datetime)
+        FROM instances",
+        [],
+        |row| {
+            let earliest: Option<UnixTimestamp> = row.get(0)?;
+            let latest: Option<UnixTimestamp> = row.get(1)?;
+            Ok(earliest
+                .zip(latest)
+                .map(|(earliest, latest)| (earliest.0, latest.0)))
+        },
+    )
+    .context(with_loc!("Getting the earliest and the latest checks"))
+}
+
+/// How many peers lists away from the seeds the instance was found.
+pub fn get_discovery_depth(conn: &Connection, instance: &Domain) -> anyhow::Result<u32> {
+    conn.query_row(
+        "SELECT discovery_depth
+        FROM instances
+        WHERE hostname = ?1",
+        params![instance.to_string()],
+        |row| row.get(0),
+    )
+    .context(with_loc!("Getting instance's discovery depth"))
+}
+
+/// The names under which [`save_periods`] stores each period, in seconds.
+const PERIOD_SETTINGS: [&str; 3] = [
+    "daily_period_secs",
+    "weekly_period_secs",
+    "list_generation_period_secs",
+];
+
+/// Note down the periods the crawl uses, so that other commands that schedule checks use them too.
+pub fn save_periods(conn: &mut Connection, periods: &time::Periods) -> anyhow::Result<()> {
+    let tx = conn
+        .transaction()
+        .context(with_loc!("Beginning a transaction"))?;
+    {
+        let mut statement = tx
+            .prepare("INSERT OR REPLACE INTO settings(name, value) VALUES (?1, ?2)")
+            .context(with_loc!("Preparing INSERT OR REPLACE statement"))?;
+        let values = [periods.daily, periods.weekly, periods.list_generation];
+        for (name, period) in PERIOD_SETTINGS.iter().zip(values) {
+            statement
+                .execute(params![name, period.as_secs()])
+                .context(with_loc!("Saving a period"))?;
+        }
+    }
+    tx.commit().context(with_loc!("Committing the transaction"))
+}
+
+/// The periods saved by the last crawl, or the default ones if there was none.
+pub fn get_periods(conn: &Connection) -> anyhow::Result<time::Periods> {
+    let mut statement = conn
+        .prepare_cached("SELECT value FROM settings WHERE name = ?1")
+        .context(with_loc!("Preparing cached SELECT statement"))?;
+    let mut get = |name: &str, default: Duration| -> anyhow::Result<Duration> {
+        let secs: Option<u64> = statement
+            .query_row(params![name], |row| row.get(0))
+            .optional()
+            .context(with_loc!("Getting a period"))?;
+        Ok(secs.map_or(default, Duration::from_secs))
+    };
+    let [daily, weekly, list_generation] = PERIOD_SETTINGS;
+    let defaults = time::Periods::default();
+    let periods = time::Periods {
+        daily: get(daily, defaults.daily)?,
+        weekly: get(weekly, defaults.weekly)?,
+        list_generation: get(list_generation, defaults.list_generation)?,
+    };
+    periods
+        .validate()
+        .context(with_loc!("The saved periods are invalid"))?;
+    Ok(periods)
+}
+
+/// Use the periods saved by the last crawl for the rest of the process's life; see
+/// [`time::set_periods`].
+pub fn use_saved_periods(conn: &Connection) -> anyhow::Result<()> {
+    let periods = on_sqlite_busy_retry_indefinitely(&mut || get_periods(conn))?;
+    time::set_periods(periods);
     Ok(())
 }
+
+/// The number of peers the instance reported the last time it was checked, if it was.
+pub fn get_peers_count(conn: &Connection, instance: &Domain) -> anyhow::Result<Option<u64>> {
+    conn.query_row(
+        "SELECT peers_count
+        FROM instances
+        WHERE hostname = ?1",
+        params![instance.to_string()],
+        |row| row.get(0),
+    )
+    .context(with_loc!("Getting instance's peers count"))
+}
+
+/// The number of the instance's peers that are in the database.
+pub fn get_peerings_count(conn: &Connection, instance: &Domain) -> anyhow::Result<u64> {
+    conn.query_row(
+        "SELECT count(*)
+        FROM peerings
+            JOIN instances ON instances.id = peerings.from_instance
+        WHERE hostname = ?1",
+        params![instance.to_string()],
+        |row| row.get(0),
+    )
+    .context(with_loc!("Getting instance's peerings count"))
+}
+
+/// Note down how many peers the instance reported.
+pub fn set_peers_count(conn: &Connection, instance: &Domain, count: u64) -> anyhow::Result<()> {
+    conn.execute(
+        "UPDATE instances
+        SET peers_count = ?1
+        WHERE hostname = ?2",
+        params![count, instance.to_string()],
+    )
+    .map(|_| ())
+    .context(with_loc!("Updating instance's peers count"))
+}
+
+/// Store the instance's metadata, replacing what was stored before.
+///
+/// The software name is stored along with its family (see [`software::family`]). If the name is
+/// missing, the previously stored one is kept. The rules are stored as a JSON array.
+pub fn set_metadata(
+    conn: &Connection,
+    instance: &Domain,
+    metadata: &Metadata,
+) -> anyhow::Result<()> {
+    if let Some(raw) = &metadata.software {
+        conn.execute(
+            "UPDATE instances
+            SET software = ?2, software_raw = ?3
+            WHERE hostname = ?1",
+            params![instance.to_string(), software::family(raw), raw],
+        )
+        .context(with_loc!("Updating the software in 'instances'"))?;
+    }
+    let rules = metadata
+        .rules
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .context(with_loc!("Serializing the rules"))?;
+    conn.execute(
+        "INSERT INTO instance_metadata(
+            instance, server, powered_by, title, rules, contact, thumbnail,
+            certificate_expires_at, misconfigured_domain)
+        SELECT id, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9 FROM instances WHERE hostname = ?1
+        ON CONFLICT(instance) DO UPDATE
+        SET server = excluded.server,
+            powered_by = excluded.powered_by,
+            title = excluded.title,
+            rules = excluded.rules,
+            contact = excluded.contact,
+            thumbnail = excluded.thumbnail,
+            certificate_expires_at = excluded.certificate_expires_at,
+            misconfigured_domain = excluded.misconfigured_domain",
+        params![
+            instance.to_string(),
+            metadata.server,
+            metadata.powered_by,
+            metadata.title,
+            rules,
+            metadata.contact,
+            metadata.thumbnail,
+            metadata.certificate_expires_at,
+            metadata.misconfigured_domain
+        ],
+    )
+    .context(with_loc!("Updating table 'instance_metadata'"))?;
+    Ok(())
+}
+
+/// The number of instances in each state.
+pub fn count_instances_by_state(conn: &Connection) -> anyhow::Result<Vec<(InstanceState, u64)>> {
+    let mut statement = conn
+        .prepare(
+            "SELECT state, count(*)
+            FROM instances
+            GROUP BY state
+            ORDER BY state",
+        )
+        .context(with_loc!("Preparing a SELECT statement"))?;
+    let counts = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+        .context(with_loc!("Executing the statement"))?
+        .collect::<Result<_, _>>()
+        .context(with_loc!("Getting the counts"))?;
+    Ok(counts)
+}
+
+/// The instances that were found alive by the most checks in a row, most stable first.
+pub fn get_most_stable_instances(
+    conn: &Connection,
+    limit: u32,
+) -> anyhow::Result<Vec<(Domain, u64)>> {
+    let mut instances = vec![];
+    let mut statement = conn
+        .prepare(
+            "SELECT hostname, consecutive_alive_checks
+            FROM instances
+            WHERE consecutive_alive_checks > 0
+            ORDER BY consecutive_alive_checks DESC, hostname ASC
+            LIMIT ?1",
+        )
+        .context(with_loc!("Preparing a SELECT statement"))?;
+    let mut rows = statement
+        .query(params![limit])
+        .context(with_loc!("Executing the statement"))?;
+    while let Some(row) = rows.next()? {
+        let hostname: String = row.get(0).context(with_loc!("Getting `hostname`"))?;
+        let checks = row
+            .get(1)
+            .context(with_loc!("Getting `consecutive_alive_checks`"))?;
+        instances.push((parse_stored_hostname(&hostname)?, checks));
+    }
+    Ok(instances)
+}
+
+/// Alive instances whose TLS certificates expire before `before`, soonest first.
+pub fn get_expiring_certificates(
+    conn: &Connection,
+    before: SystemTime,
+) -> anyhow::Result<Vec<(Domain, SystemTime)>> {
+    let mut instances = vec![];
+    let mut statement = conn
+        .prepare(
+            "SELECT hostname, certificate_expires_at
+            FROM instances
+                JOIN instance_metadata ON instances.id = instance_metadata.instance
+            WHERE state = ?1 AND certificate_expires_at <= ?2
+            ORDER BY certificate_expires_at ASC, hostname ASC",
+        )
+        .context(with_loc!("Preparing a SELECT statement"))?;
+    let mut rows = statement
+        .query(params![InstanceState::Alive, UnixTimestamp(before)])
+        .context(with_loc!("Executing the statement"))?;
+    while let Some(row) = rows.next()? {
+        let hostname: String = row.get(0).context(with_loc!("Getting `hostname`"))?;
+        let expires_at: UnixTimestamp = row
+            .get(1)
+            .context(with_loc!("Getting `certificate_expires_at`"))?;
+        instances.push((parse_stored_hostname(&hostname)?, expires_at.0));
+    }
+    Ok(instances)
+}
+
+/// How far a dying or moving instance has got.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Transition {
+    /// When the instance started dying or moving.
+    pub since: SystemTime,
+
+    /// The number of failed checks of a dying instance, or of redirects of a moving one.
+    pub checks: u64,
+
+    /// Where a moving instance redirects to.
+    pub moving_to: Option<Domain>,
+}
+
+/// Get the instances in the `state`, sorted by hostname. Dying and moving instances come with
+/// their [`Transition`].
+pub fn get_instances_in_state(
+    conn: &Connection,
+    state: InstanceState,
+) -> anyhow::Result<Vec<(Domain, Option<Transition>)>> {
+    let mut instances = vec![];
+    let mut statement = conn
+        .prepare(
+            "SELECT instances.hostname,
+                coalesce(dying_since, moving_since),
+                coalesce(failed_checks_count, redirects_count),
+                moving_to_instances.hostname
+            FROM instances
+                LEFT JOIN dying_state_data ON instances.id = dying_state_data.instance
+                LEFT JOIN moving_state_data ON instances.id = moving_state_data.instance
+                LEFT JOIN instances AS moving_to_instances
+                    ON moving_to_instances.id = moving_state_data.moving_to
+            WHERE instances.state = ?1
+            ORDER BY instances.hostname",
+        )
+        .context(with_loc!("Preparing a SELECT statement"))?;
+    let mut rows = statement
+        .query(params![state])
+        .context(with_loc!("Executing the statement"))?;
+    while let Some(row) = rows.next()? {
+        let hostname: String = row.get(0).context(with_loc!("Getting `hostname`"))?;
+        let since: Option<UnixTimestamp> = row.get(1).context(with_loc!("Getting the start"))?;
+        let checks: Option<u64> = row
+            .get(2)
+            .context(with_loc!("Getting the number of checks"))?;
+        let moving_to: Option<String> = row.get(3).context(with_loc!("Getting `moving_to`"))?;
+        let transition = match (since, checks) {
+            (Some(since), Some(checks)) => Some(Transition {
+                since: since.0,
+                checks,
+                moving_to: moving_to
+                    .as_deref()
+                    .map(parse_stored_hostname)
+                    .transpose()?,
+            }),
+            _ => None,
+        };
+        instances.push((parse_stored_hostname(&hostname)?, transition));
+    }
+    Ok(instances)
+}
+
+/// Get the hostnames of all alive instances.
+pub fn get_alive_instances(conn: &Connection) -> anyhow::Result<Vec<Domain>> {
+    let mut instances = vec![];
+    let mut statement = conn
+        .prepare(
+            "SELECT hostname
+            FROM instances
+            WHERE state = ?1",
+        )
+        .context(with_loc!("Preparing a SELECT statement"))?;
+    let mut rows = statement
+        .query(params![InstanceState::Alive])
+        .context(with_loc!("Executing the statement"))?;
+    while let Some(row) = rows.next()? {
+        let hostname: String = row.get(0).context(with_loc!("Getting `hostname`"))?;
+        instances.push(parse_stored_hostname(&hostname)?);
+    }
+    Ok(instances)
+}
+
+/// Get the hostnames of the instances in the peering graph, sorted. Unless `include_all` is set,
+/// only alive instances are included.
+pub fn get_graph_instances(conn: &Connection, include_all: bool) -> anyhow::Result<Vec<Domain>> {
+    let mut instances = vec![];
+    let mut statement = conn
+        .prepare(
+            "SELECT hostname
+            FROM instances
+            WHERE ?1 OR state = ?2
+            ORDER BY hostname",
+        )
+        .context(with_loc!("Preparing a SELECT statement"))?;
+    let mut rows = statement
+        .query(params![include_all, InstanceState::Alive])
+        .context(with_loc!("Executing the statement"))?;
+    while let Some(row) = rows.next()? {
+        let hostname: String = row.get(0).context(with_loc!("Getting `hostname`"))?;
+        instances.push(parse_stored_hostname(&hostname)?);
+    }
+    Ok(instances)
+}
+
+/// Call `f` with each peering, i.e. an instance and one of its peers, sorted by hostnames. Unless
+/// `include_all` is set, only peerings between alive instances are included.
+///
+/// The graph can be large, so the peerings are passed on one by one instead of being collected.
+pub fn for_each_peering(
+    conn: &Connection,
+    include_all: bool,
+    mut f: impl FnMut(Domain, Domain) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut statement = conn
+        .prepare(
+            "SELECT from_instances.hostname, to_instances.hostname
+            FROM peerings
+                JOIN instances AS from_instances ON from_instances.id = peerings.from_instance
+                JOIN instances AS to_instances ON to_instances.id = peerings.to_instance
+            WHERE ?1 OR (from_instances.state = ?2 AND to_instances.state = ?2)
+            ORDER BY from_instances.hostname, to_instances.hostname",
+        )
+        .context(with_loc!("Preparing a SELECT statement"))?;
+    let mut rows = statement
+        .query(params![include_all, InstanceState::Alive])
+        .context(with_loc!("Executing the statement"))?;
+    while let Some(row) = rows.next()? {
+        let from: String = row.get(0).context(with_loc!("Getting `from_instance`"))?;
+        let to: String = row.get(1).context(with_loc!("Getting `to_instance`"))?;
+        f(parse_stored_hostname(&from)?, parse_stored_hostname(&to)?)?;
+    }
+    Ok(())
+}
+
+/// Set the `tag` on the instances whose hostnames match the predicate, and remove it from all the
+/// others. Other tags are left alone. Returns the number of tagged instances.
+pub fn retag_instances(
+    conn: &mut Connection,
+    tag: &str,
+    matches: impl Fn(&str) -> bool,
+) -> anyhow::Result<usize> {
+    let tx = conn
+        .transaction()
+        .context(with_loc!("Beginning a transaction"))?;
+
+    let mut matching = vec![];
+    {
+        let mut statement = tx
+            .prepare("SELECT id, hostname FROM instances")
+            .context(with_loc!("Preparing a SELECT statement"))?;
+        let mut rows = statement
+            .query([])
+            .context(with_loc!("Executing the statement"))?;
+        while let Some(row) = rows.next()? {
+            let hostname: String = row.get(1).context(with_loc!("Getting `hostname`"))?;
+            if matches(&hostname) {
+                matching.push(row.get::<_, i64>(0).context(with_loc!("Getting `id`"))?);
+            }
+        }
+    }
+
+    tx.execute(
+        "UPDATE instances
+        SET tag = NULL
+        WHERE tag = ?1",
+        params![tag],
+    )
+    .context(with_loc!("Removing the tag"))?;
+    for id in &matching {
+        tx.execute(
+            "UPDATE instances
+            SET tag = ?1
+            WHERE id = ?2",
+            params![tag, id],
+        )
+        .context(with_loc!("Setting the tag"))?;
+    }
+
+    tx.commit()
+        .context(with_loc!("Committing the transaction"))?;
+    Ok(matching.len())
+}
+
+/// Update the "hide from list" flag of the instance, but only if the instance is still alive.
+///
+/// Unlike [`mark_alive`], this doesn't change the instance's state.
+pub fn set_hidden(
+    conn: &mut Connection,
+    instance: &Domain,
+    hide_from_list: bool,
+) -> anyhow::Result<()> {
+    let tx = conn
+        .transaction()
+        .context(with_loc!("Beginning a transaction"))?;
+
+    let (instance_id, state) =
+        get_instance(&tx, instance).context(with_loc!("Getting instance id and state"))?;
+    if state == InstanceState::Alive {
+        set_hide_instance_from_list(&tx, instance_id, hide_from_list)
+            .context(with_loc!("Updating the flag in `hidden_instances`"))?;
+    }
+
+    tx.commit().context(with_loc!("Committing the transaction"))
+}
+
+fn set_hide_instance_from_list(
+    tx: &Transaction,
+    instance: i64,
+    hide_from_list: bool,
+) -> anyhow::Result<()> {
+    tx.execute(
+        "INSERT OR REPLACE
+        INTO hidden_instances(instance, hide_from_list)
+        VALUES (?1, ?2)",
+        params![instance, hide_from_list],
+    )?;
+    Ok(())
+}
+
+fn delete_from_hidden_instances(tx: &Transaction, instance: i64) -> anyhow::Result<()> {
+    tx.execute(
+        "DELETE FROM hidden_instances
+        WHERE instance = ?1",
+        params![instance],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    fn open_in_memory() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        init(&mut conn).unwrap();
+        conn
+    }
+
+    fn get_state(conn: &mut Connection, instance: &Domain) -> InstanceState {
+        let tx = conn.transaction().unwrap();
+        get_instance(&tx, instance).unwrap().1
+    }
+
+    fn busy_error() -> anyhow::Error {
+        rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY), None)
+            .into()
+    }
+
+    #[test]
+    fn busy_backoff_grows_and_is_bounded() {
+        let caps: Vec<Duration> = (0..20).map(busy_backoff_cap).collect();
+        assert_eq!(caps.first(), Some(&MIN_BUSY_BACKOFF));
+        assert!(caps.windows(2).all(|pair| pair.first() <= pair.get(1)));
+        assert!(caps.get(3) > caps.get(2));
+        assert_eq!(caps.last(), Some(&MAX_BUSY_BACKOFF));
+        assert_eq!(busy_backoff_cap(u32::MAX), MAX_BUSY_BACKOFF);
+
+        let mut failures = 10;
+        let mut sleeps = vec![];
+        let result = retry_on_busy(
+            &mut || {
+                if failures == 0 {
+                    Ok(())
+                } else {
+                    failures -= 1;
+                    Err(busy_error())
+                }
+            },
+            None,
+            &mut |duration| sleeps.push(duration),
+        );
+        assert!(result.is_ok());
+        assert_eq!(sleeps.len(), 10);
+        for (attempt, sleep) in (0..).zip(&sleeps) {
+            assert!(*sleep <= busy_backoff_cap(attempt));
+            assert!(*sleep > Duration::ZERO);
+        }
+
+        let mut calls = 0;
+        let mut sleeps = 0;
+        let result: anyhow::Result<()> = retry_on_busy(
+            &mut || {
+                calls += 1;
+                Err(busy_error())
+            },
+            Some(100),
+            &mut |_| sleeps += 1,
+        );
+        assert!(is_sqlite_busy_error(&result.unwrap_err()));
+        assert_eq!(calls, 101);
+        assert_eq!(sleeps, 100);
+    }
+
+    #[test]
+    fn counts_consecutive_alive_checks() {
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+        let count = |conn: &Connection| {
+            get_instance_by_host(conn, &instance)
+                .unwrap()
+                .unwrap()
+                .consecutive_alive_checks
+        };
+
+        for expected in 1..=3 {
+            mark_alive(&mut conn, &instance, false).unwrap();
+            assert_eq!(count(&conn), expected);
+        }
+        assert_eq!(
+            get_most_stable_instances(&conn, 10).unwrap(),
+            vec![(instance.clone(), 3)]
+        );
+
+        mark_dead(&mut conn, &instance, DeathReason::NoResponse).unwrap();
+        assert_eq!(count(&conn), 0);
+        assert!(get_most_stable_instances(&conn, 10).unwrap().is_empty());
+
+        mark_alive(&mut conn, &instance, false).unwrap();
+        assert_eq!(count(&conn), 1);
+        let target = Domain::from_str("example.org").unwrap();
+        add_instance(&conn, &target).unwrap();
+        mark_moved(&mut conn, &instance, &target).unwrap();
+        assert_eq!(count(&conn), 0);
+    }
+
+    #[test]
+    fn remembers_when_the_instance_was_last_alive() {
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+        let last_alive = |conn: &Connection| -> Option<i64> {
+            conn.query_row(
+                "SELECT last_alive_datetime FROM instances WHERE hostname = 'example.com'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(last_alive(&conn), None);
+
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        mark_alive(&mut conn, &instance, false).unwrap();
+        let marked = last_alive(&conn).unwrap();
+        assert!(u64::try_from(marked).unwrap() >= before);
+
+        // A failed check doesn't change it.
+        mark_dead(&mut conn, &instance, DeathReason::NoResponse).unwrap();
+        assert_eq!(last_alive(&conn), Some(marked));
+    }
+
+    #[test]
+    fn concurrent_orchestrators_never_claim_the_same_instance() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("crawler.db");
+        let open = || {
+            let conn = Connection::open(&path).unwrap();
+            conn.pragma_update(None, "journal_mode", "WAL").unwrap();
+            conn.busy_timeout(std::time::Duration::from_secs(60))
+                .unwrap();
+            conn
+        };
+
+        let mut conn = open();
+        init(&mut conn).unwrap();
+        for i in 0..100 {
+            let instance = Domain::from_str(&format!("i{}.example.com", i)).unwrap();
+            add_instance(&conn, &instance).unwrap();
+        }
+        conn.execute("UPDATE instances SET next_check_datetime = 0", [])
+            .unwrap();
+        let total: usize = conn
+            .query_row("SELECT count(*) FROM instances", [], |row| row.get(0))
+            .unwrap();
+
+        let claimers: Vec<_> = (0..2)
+            .map(|_| {
+                let mut conn = open();
+                std::thread::spawn(move || {
+                    let mut claimed = vec![];
+                    while let Some(instance) =
+                        claim_next_instance(&mut conn, SystemTime::now(), &[]).unwrap()
+                    {
+                        claimed.push(instance.to_string());
+                    }
+                    claimed
+                })
+            })
+            .collect();
+        let mut claimed: Vec<String> = claimers
+            .into_iter()
+            .flat_map(|claimer| claimer.join().unwrap())
+            .collect();
+
+        assert_eq!(claimed.len(), total);
+        claimed.sort();
+        claimed.dedup();
+        assert_eq!(claimed.len(), total);
+    }
+
+    #[test]
+    fn walking_the_schedule_visits_every_instance_once() {
+        let conn = open_in_memory();
+        for hostname in ["b.example.com", "a.example.com", "c.example.com"] {
+            add_instance(&conn, &Domain::from_str(hostname).unwrap()).unwrap();
+        }
+        // Ties have to be broken by hostname.
+        conn.execute(
+            "UPDATE instances SET next_check_datetime = 1000 WHERE hostname != 'c.example.com'",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "UPDATE instances SET next_check_datetime = 500 WHERE hostname = 'c.example.com'",
+            [],
+        )
+        .unwrap();
+        let total: usize = conn
+            .query_row("SELECT count(*) FROM instances", [], |row| row.get(0))
+            .unwrap();
+
+        let mut visited = vec![];
+        let mut cursor = None;
+        while let Some((instance, next_check)) =
+            pick_next_instance_after(&conn, cursor.as_ref()).unwrap()
+        {
+            visited.push(instance.to_string());
+            cursor = Some((next_check, instance));
+        }
+
+        assert_eq!(visited.len(), total);
+        assert_eq!(visited.first().unwrap(), "c.example.com");
+        let position = |hostname: &str| visited.iter().position(|v| v == hostname).unwrap();
+        assert!(position("a.example.com") < position("b.example.com"));
+    }
+
+    #[test]
+    fn checks_outside_the_schedule_window_are_rescheduled() {
+        let mut conn = open_in_memory();
+        // Bring the seed instance into the window, so that it doesn't affect the counts below.
+        reschedule_missed_checks(&mut conn).unwrap();
+
+        let now = SystemTime::now();
+        let month = Duration::from_secs(30 * 24 * 3600);
+        let hour = Duration::from_secs(3600);
+        let cases = [
+            // The clock jumped forward, so the check is long overdue.
+            ("overdue.example.com", now - month, true),
+            // The clock jumped backward, so the check is too far away.
+            ("too-far.example.com", now + month, true),
+            ("on-time.example.com", now + hour, false),
+        ];
+        for (hostname, next_check, _) in cases {
+            let instance = Domain::from_str(hostname).unwrap();
+            add_instance(&conn, &instance).unwrap();
+            conn.execute(
+                "UPDATE instances SET next_check_datetime = ?1 WHERE hostname = ?2",
+                params![UnixTimestamp(next_check), hostname],
+            )
+            .unwrap();
+        }
+
+        assert_eq!(reschedule_missed_checks(&mut conn).unwrap(), 2);
+
+        let latest = now + Duration::from_secs(29 * 3600 + 60);
+        for (hostname, next_check, rescheduled) in cases {
+            let instance = Domain::from_str(hostname).unwrap();
+            let record = get_instance_by_host(&conn, &instance).unwrap().unwrap();
+            if rescheduled {
+                assert!(record.next_check >= now - Duration::from_secs(1));
+                assert!(record.next_check <= latest);
+            } else {
+                let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap().as_secs();
+                assert_eq!(secs(record.next_check), secs(next_check));
+            }
+        }
+    }
+
+    #[test]
+    fn already_scheduled_checks_widen_the_window() {
+        let mut conn = open_in_memory();
+        reschedule_missed_checks(&mut conn).unwrap();
+
+        // The next 29 hours are fully booked at one check per second, and the seed instance is
+        // scheduled in there too.
+        let now = SystemTime::now();
+        let day = 29 * 3600;
+        conn.execute(
+            "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < ?1)
+            INSERT INTO instances(hostname, next_check_datetime)
+            SELECT 'scheduled' || i || '.example.com', ?2 + i FROM n",
+            params![day, UnixTimestamp(now)],
+        )
+        .unwrap();
+        let window = |missed| reschedule_window(&conn, now, missed).unwrap().as_secs();
+        // Each of the scheduled checks pushes the window further.
+        assert_eq!(window(0), day + 1);
+        assert_eq!(window(1000), day + 1000 + 1);
+        // But not past the furthest a check can be scheduled.
+        assert_eq!(window(u64::MAX), time::max_check_delay().unwrap().as_secs());
+    }
+
+    #[test]
+    fn looks_up_instances_by_host() {
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        assert_eq!(get_instance_by_host(&conn, &instance).unwrap(), None);
+
+        add_instance(&conn, &instance).unwrap();
+        let record = get_instance_by_host(&conn, &instance).unwrap().unwrap();
+        assert_eq!(record.state, InstanceState::Discovered);
+        assert_eq!(record.software, None);
+        assert!(!record.hide_from_list);
+
+        mark_alive(&mut conn, &instance, true).unwrap();
+        let alive = get_instance_by_host(&conn, &instance).unwrap().unwrap();
+        assert_eq!(alive.id, record.id);
+        assert_eq!(alive.state, InstanceState::Alive);
+        assert_eq!(alive.next_check, record.next_check);
+        assert!(alive.hide_from_list);
+    }
+
+    #[test]
+    fn death_reason_is_kept_until_the_instance_is_alive() {
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+        let death_reason = |conn: &Connection| {
+            get_instance_by_host(conn, &instance)
+                .unwrap()
+                .unwrap()
+                .death_reason
+        };
+
+        mark_dead(&mut conn, &instance, DeathReason::Redirect).unwrap();
+        assert_eq!(death_reason(&conn), Some(DeathReason::Redirect));
+        mark_dead(&mut conn, &instance, DeathReason::BadIpc).unwrap();
+        assert_eq!(death_reason(&conn), Some(DeathReason::BadIpc));
+        let record = get_instance_by_host(&conn, &instance).unwrap().unwrap();
+        assert!(record.to_string().contains("last check failed: bad-ipc"));
+
+        mark_alive(&mut conn, &instance, false).unwrap();
+        assert_eq!(death_reason(&conn), None);
+    }
+
+    #[test]
+    fn dead_instances_are_rechecked_soon() {
+        let mut conn = open_in_memory();
+        let dead = Domain::from_str("example.com").unwrap();
+        let alive = Domain::from_str("example.org").unwrap();
+        for instance in [&dead, &alive] {
+            add_instance(&conn, instance).unwrap();
+        }
+        mark_alive(&mut conn, &alive, false).unwrap();
+        let tx = conn.transaction().unwrap();
+        let (id, _) = get_instance(&tx, &dead).unwrap();
+        make_dead(&tx, id, InstanceState::Discovered).unwrap();
+        tx.commit().unwrap();
+        let next_check = |conn: &Connection, instance| {
+            get_instance_by_host(conn, instance)
+                .unwrap()
+                .unwrap()
+                .next_check
+        };
+        let alive_check = next_check(&conn, &alive);
+        let soon = SystemTime::now() + time::Period::Daily.duration();
+        assert!(next_check(&conn, &dead) > soon);
+
+        assert_eq!(recheck_soon(&mut conn, &[InstanceState::Dead]).unwrap(), 1);
+        assert!(next_check(&conn, &dead) <= soon);
+        assert_eq!(get_state(&mut conn, &dead), InstanceState::Dead);
+        assert_eq!(next_check(&conn, &alive), alive_check);
+    }
+
+    #[test]
+    fn chronically_flapping_instance_dies() {
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+
+        // The instance is down most of the time, but comes back often enough to never stay in
+        // the "dying" state for a week.
+        let died = (0..2 * FLAPPING_WINDOW).any(|check| {
+            if check % 4 == 3 {
+                mark_alive(&mut conn, &instance, false).unwrap();
+            } else {
+                mark_dead(&mut conn, &instance, DeathReason::NoResponse).unwrap();
+            }
+            get_state(&mut conn, &instance) == InstanceState::Dead
+        });
+        assert!(died, "Flapping instance never died");
+    }
+
+    #[test]
+    fn occasionally_failing_instance_stays_alive() {
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+
+        for _ in 0..(4 * FLAPPING_WINDOW) {
+            mark_alive(&mut conn, &instance, false).unwrap();
+            mark_alive(&mut conn, &instance, false).unwrap();
+            mark_alive(&mut conn, &instance, false).unwrap();
+            mark_dead(&mut conn, &instance, DeathReason::NoResponse).unwrap();
+            assert_ne!(get_state(&mut conn, &instance), InstanceState::Dead);
+        }
+    }
+
+    #[test]
+    fn fqdn_and_bare_name_are_the_same_instance() {
+        let conn = open_in_memory();
+        add_instance(&conn, &Domain::from_str("example.com.").unwrap()).unwrap();
+        add_instance(&conn, &Domain::from_str("example.com").unwrap()).unwrap();
+
+        let hostnames: Vec<String> = conn
+            .prepare("SELECT hostname FROM instances WHERE hostname LIKE 'example.com%'")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(hostnames, vec!["example.com"]);
+    }
+
+    #[test]
+    fn names_differing_in_case_are_the_same_instance() {
+        let mut conn = open_in_memory();
+        // The seed instance is already in the database, in lowercase.
+        let peer = Domain::from_str("Mastodon.Social").unwrap();
+        add_instance(&conn, &peer).unwrap();
+        mark_alive(&mut conn, &peer, false).unwrap();
+
+        // LIKE is case-insensitive, so it would find every spelling.
+        let hostnames: Vec<String> = conn
+            .prepare("SELECT hostname FROM instances WHERE hostname LIKE 'mastodon.social'")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(hostnames, vec!["mastodon.social"]);
+        assert_eq!(get_state(&mut conn, &peer), InstanceState::Alive);
+    }
+
+    #[test]
+    fn discovery_depth_is_set_once() {
+        let conn = open_in_memory();
+        let seed = Domain::from_str("example.com").unwrap();
+        let peer = Domain::from_str("example.org").unwrap();
+
+        add_instance(&conn, &seed).unwrap();
+        add_instance_at_depth(&conn, &peer, 1).unwrap();
+        assert_eq!(get_discovery_depth(&conn, &seed).unwrap(), 0);
+        assert_eq!(get_discovery_depth(&conn, &peer).unwrap(), 1);
+
+        add_instance_at_depth(&conn, &seed, 3).unwrap();
+        add_instance_at_depth(&conn, &peer, 0).unwrap();
+        assert_eq!(get_discovery_depth(&conn, &seed).unwrap(), 0);
+        assert_eq!(get_discovery_depth(&conn, &peer).unwrap(), 1);
+    }
+
+    #[test]
+    fn lists_instances_in_a_state() {
+        let mut conn = open_in_memory();
+        let alive = Domain::from_str("example.com").unwrap();
+        let dying = Domain::from_str("example.org").unwrap();
+        for instance in [&alive, &dying] {
+            add_instance(&conn, instance).unwrap();
+            mark_alive(&mut conn, instance, false).unwrap();
+        }
+        mark_dead(&mut conn, &dying, DeathReason::NoResponse).unwrap();
+
+        assert_eq!(
+            get_instances_in_state(&conn, InstanceState::Alive).unwrap(),
+            vec![(alive, None)]
+        );
+        let mut dying_instances = get_instances_in_state(&conn, InstanceState::Dying).unwrap();
+        let (instance, transition) = dying_instances.pop().unwrap();
+        assert!(dying_instances.is_empty());
+        assert_eq!(instance, dying);
+        let transition = transition.unwrap();
+        assert_eq!(transition.checks, 1);
+        assert_eq!(transition.moving_to, None);
+        assert!(get_instances_in_state(&conn, InstanceState::Dead)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn states_are_parsed_by_name() {
+        for state in InstanceState::ALL {
+            assert_eq!(state.name().parse::<InstanceState>().unwrap(), state);
+        }
+        assert!("Alive".parse::<InstanceState>().is_err());
+        assert!("gone".parse::<InstanceState>().is_err());
+    }
+
+    #[test]
+    fn peerings_are_replaced() {
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        let peer = Domain::from_str("example.org").unwrap();
+        let other = Domain::from_str("example.net").unwrap();
+        let unknown = Domain::from_str("example.info").unwrap();
+        add_instance(&conn, &instance).unwrap();
+        add_instance(&conn, &peer).unwrap();
+        add_instance(&conn, &other).unwrap();
+
+        let count_peerings = |conn: &Connection| -> u64 {
+            conn.query_row("SELECT count(*) FROM peerings", [], |row| row.get(0))
+                .unwrap()
+        };
+        replace_peerings(&mut conn, &instance, &[peer.clone(), peer.clone()]).unwrap();
+        assert_eq!(count_peerings(&conn), 1);
+
+        replace_peerings(&mut conn, &peer, std::slice::from_ref(&instance)).unwrap();
+        assert_eq!(count_peerings(&conn), 2);
+
+        // The instance no longer lists `peer`, and `unknown` isn't in the database.
+        replace_peerings(&mut conn, &instance, &[other.clone(), unknown]).unwrap();
+        assert_eq!(count_peerings(&conn), 2);
+        let from_instance: u64 = conn
+            .query_row(
+                "SELECT count(*)
+                FROM peerings
+                    JOIN instances ON instances.id = peerings.to_instance
+                WHERE hostname = 'example.net'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(from_instance, 1);
+    }
+
+    #[test]
+    fn periods_are_saved() {
+        let mut conn = open_in_memory();
+        assert_eq!(get_periods(&conn).unwrap(), time::Periods::default());
+
+        let periods = time::Periods {
+            daily: Duration::from_secs(5 * 3600),
+            weekly: Duration::from_secs(23 * 3600),
+            list_generation: Duration::from_secs(59 * 60),
+        };
+        save_periods(&mut conn, &periods).unwrap();
+        assert_eq!(get_periods(&conn).unwrap(), periods);
+
+        save_periods(&mut conn, &time::Periods::default()).unwrap();
+        assert_eq!(get_periods(&conn).unwrap(), time::Periods::default());
+    }
+
+    fn schema_version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn vacuum_keeps_the_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut conn = Connection::open(dir.path().join("crawler.db")).unwrap();
+        conn.pragma_update(None, "journal_mode", "WAL").unwrap();
+        init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+        let before = count_instances_by_state(&conn).unwrap();
+
+        vacuum(&conn).unwrap();
+        assert_eq!(count_instances_by_state(&conn).unwrap(), before);
+    }
+
+    #[test]
+    fn new_database_is_at_the_latest_version() {
+        let mut conn = open_in_memory();
+        assert_eq!(schema_version(&conn), MIGRATIONS.len());
+        init(&mut conn).unwrap();
+        assert_eq!(schema_version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn only_pending_migrations_are_applied() {
+        // Neither of these can be applied twice.
+        fn first(tx: &Transaction) -> anyhow::Result<()> {
+            tx.execute("CREATE TABLE first(id INTEGER)", [])?;
+            Ok(())
+        }
+        fn second(tx: &Transaction) -> anyhow::Result<()> {
+            tx.execute("ALTER TABLE first ADD COLUMN second INTEGER", [])?;
+            Ok(())
+        }
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, &[first]).unwrap();
+        assert_eq!(schema_version(&conn), 1);
+        migrate(&mut conn, &[first, second]).unwrap();
+        assert_eq!(schema_version(&conn), 2);
+        migrate(&mut conn, &[first, second]).unwrap();
+        assert_eq!(schema_version(&conn), 2);
+
+        // The database is newer than this list of migrations.
+        assert!(migrate(&mut conn, &[first]).is_err());
+    }
+
+    #[test]
+    fn adds_columns_to_old_tables() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute(
+            "CREATE TABLE instances(
+                id INTEGER PRIMARY KEY NOT NULL,
+                hostname TEXT UNIQUE NOT NULL,
+                state INTEGER NOT NULL DEFAULT 0,
+                next_check_datetime INTEGER DEFAULT (strftime('%s', CURRENT_TIMESTAMP))
+            )",
+            [],
+        )
+        .unwrap();
+        conn.execute("INSERT INTO instances(hostname) VALUES ('example.com')", [])
+            .unwrap();
+
+        init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+        assert_eq!(get_discovery_depth(&conn, &instance).unwrap(), 0);
+    }
+
+    #[test]
+    fn unicode_hostnames_are_re_encoded() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, &[create_initial_schema, add_last_alive_datetime]).unwrap();
+        conn.execute(
+            "INSERT INTO instances(hostname) VALUES ('bücher.com'), ('пример.com')",
+            [],
+        )
+        .unwrap();
+        // This one was re-added under its ASCII name by a newer crawler.
+        conn.execute(
+            "INSERT INTO instances(hostname) VALUES ('xn--e1afmkfd.com')",
+            [],
+        )
+        .unwrap();
+        let id = |hostname: &str| -> i64 {
+            conn.query_row(
+                "SELECT id FROM instances WHERE hostname = ?1",
+                params![hostname],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        let (books, unicode, ascii) = (id("bücher.com"), id("пример.com"), id("xn--e1afmkfd.com"));
+        conn.execute(
+            "INSERT INTO peerings(from_instance, to_instance) VALUES (?1, ?2), (?3, ?2), (?2, ?1)",
+            params![books, unicode, ascii],
+        )
+        .unwrap();
+
+        init(&mut conn).unwrap();
+
+        let instance = Domain::from_str("bücher.com").unwrap();
+        let record = get_instance_by_host(&conn, &instance).unwrap().unwrap();
+        assert_eq!(record.id, books);
+        let instance = Domain::from_str("пример.com").unwrap();
+        let record = get_instance_by_host(&conn, &instance).unwrap().unwrap();
+        assert_eq!(record.id, ascii);
+
+        let count = |sql: &str| -> i64 { conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+        // The Unicode row was merged into the ASCII one; the other is the initial instance.
+        assert_eq!(count("SELECT count(*) FROM instances"), 3);
+        assert_eq!(
+            count("SELECT count(*) FROM peerings WHERE from_instance = to_instance"),
+            0
+        );
+        assert_eq!(count("SELECT count(*) FROM peerings"), 2);
+    }
+
+    #[test]
+    fn configured_software_is_checked_less_often() {
+        let mut conn = open_in_memory();
+        let software_periods = [("writefreely".to_string(), time::Period::Weekly)];
+        for (hostname, software) in [
+            ("blog.example.com", "WriteFreely"),
+            ("social.example.com", "mastodon"),
+        ] {
+            let instance = Domain::from_str(hostname).unwrap();
+            add_instance(&conn, &instance).unwrap();
+            mark_alive(&mut conn, &instance, false).unwrap();
+            let metadata = Metadata {
+                software: Some(software.to_string()),
+                ..Default::default()
+            };
+            set_metadata(&conn, &instance, &metadata).unwrap();
+        }
+        conn.execute("UPDATE instances SET next_check_datetime = 0", [])
+            .unwrap();
+
+        let now = SystemTime::now();
+        while claim_next_instance(&mut conn, now, &software_periods)
+            .unwrap()
+            .is_some()
+        {}
+
+        let next_check = |hostname: &str| {
+            let instance = Domain::from_str(hostname).unwrap();
+            get_instance_by_host(&conn, &instance)
+                .unwrap()
+                .unwrap()
+                .next_check
+        };
+        // The furthest a "daily" check can be scheduled is 29 hours plus 2 hours of randomness.
+        let latest_daily = now + Duration::from_secs(31 * 3600 + 60);
+        assert!(next_check("blog.example.com") > latest_daily);
+        assert!(next_check("social.example.com") <= latest_daily);
+
+        // The schedule simulator sees the same periods.
+        let periods: Vec<time::Period> = get_schedule(&conn, &software_periods)
+            .unwrap()
+            .into_iter()
+            .map(|(_, period)| period)
+            .collect();
+        assert_eq!(
+            periods
+                .iter()
+                .filter(|period| **period == time::Period::Weekly)
+                .count(),
+            1
+        );
+
+        assert_eq!(
+            check_period_for(InstanceState::Dead, Some("writefreely"), &software_periods),
+            time::Period::Weekly
+        );
+        assert_eq!(
+            check_period_for(InstanceState::Dying, Some("writefreely"), &software_periods),
+            time::Period::Daily
+        );
+    }
+
+    #[test]
+    fn stores_software_family_and_raw_name() {
+        let conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+
+        let metadata = Metadata {
+            software: Some("Firefish".to_string()),
+            ..Default::default()
+        };
+        set_metadata(&conn, &instance, &metadata).unwrap();
+        let record = get_instance_by_host(&conn, &instance).unwrap().unwrap();
+        assert_eq!(record.software.as_deref(), Some("misskey"));
+        assert_eq!(record.software_raw.as_deref(), Some("Firefish"));
+
+        // Metadata without a software name doesn't erase the known one.
+        set_metadata(&conn, &instance, &Metadata::default()).unwrap();
+        let record = get_instance_by_host(&conn, &instance).unwrap().unwrap();
+        assert_eq!(record.software.as_deref(), Some("misskey"));
+    }
+
+    #[test]
+    fn misconfiguration_is_shown_until_fixed() {
+        let conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+        let record = get_instance_by_host(&conn, &instance).unwrap().unwrap();
+        assert_eq!(record.misconfigured_domain, None);
+
+        let metadata = Metadata {
+            misconfigured_domain: Some("default-site.example.net".to_string()),
+            ..Default::default()
+        };
+        set_metadata(&conn, &instance, &metadata).unwrap();
+        let record = get_instance_by_host(&conn, &instance).unwrap().unwrap();
+        assert!(record
+            .to_string()
+            .contains(", misconfigured: serves the description of default-site.example.net"));
+
+        set_metadata(&conn, &instance, &Metadata::default()).unwrap();
+        let record = get_instance_by_host(&conn, &instance).unwrap().unwrap();
+        assert_eq!(record.misconfigured_domain, None);
+    }
+
+    #[test]
+    fn absent_metadata_is_stored_as_null() {
+        let conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+        let get_metadata = || -> (Option<String>, Option<String>) {
+            conn.query_row(
+                "SELECT server, powered_by
+                FROM instance_metadata
+                    JOIN instances ON instances.id = instance_metadata.instance
+                WHERE hostname = ?1",
+                params![instance.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap()
+        };
+
+        let metadata = Metadata {
+            server: Some("nginx".to_string()),
+            powered_by: Some("Express".to_string()),
+            ..Default::default()
+        };
+        set_metadata(&conn, &instance, &metadata).unwrap();
+        assert_eq!(
+            get_metadata(),
+            (Some("nginx".to_string()), Some("Express".to_string()))
+        );
+
+        set_metadata(&conn, &instance, &Metadata::default()).unwrap();
+        assert_eq!(get_metadata(), (None, None));
+    }
+
+    #[test]
+    fn rules_are_stored_as_json() {
+        let conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+        let get_metadata = || -> (Option<String>, Option<String>) {
+            conn.query_row(
+                "SELECT rules, contact
+                FROM instance_metadata
+                    JOIN instances ON instances.id = instance_metadata.instance
+                WHERE hostname = ?1",
+                params![instance.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap()
+        };
+
+        let metadata = Metadata {
+            rules: Some(vec!["No spam".to_string(), "Be \"nice\"".to_string()]),
+            contact: Some("admin@example.com".to_string()),
+            ..Default::default()
+        };
+        set_metadata(&conn, &instance, &metadata).unwrap();
+        assert_eq!(
+            get_metadata(),
+            (
+                Some(r#"["No spam","Be \"nice\""]"#.to_string()),
+                Some("admin@example.com".to_string())
+            )
+        );
+
+        set_metadata(&conn, &instance, &Metadata::default()).unwrap();
+        assert_eq!(get_metadata(), (None, None));
+    }
+
+    #[test]
+    fn certificate_expiry_is_recorded() {
+        let mut conn = open_in_memory();
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let cases = [
+            ("soon.example.com", Some(now + day)),
+            ("later.example.com", Some(now + 60 * day)),
+            ("unknown.example.com", None),
+        ];
+        for (hostname, expires_at) in cases {
+            let instance = Domain::from_str(hostname).unwrap();
+            add_instance(&conn, &instance).unwrap();
+            mark_alive(&mut conn, &instance, false).unwrap();
+            let metadata = Metadata {
+                certificate_expires_at: expires_at.map(secs),
+                ..Default::default()
+            };
+            set_metadata(&conn, &instance, &metadata).unwrap();
+        }
+
+        let expiring = get_expiring_certificates(&conn, now + 14 * day).unwrap();
+        let expiring: Vec<(String, u64)> = expiring
+            .into_iter()
+            .map(|(instance, expires_at)| (instance.to_string(), secs(expires_at)))
+            .collect();
+        assert_eq!(
+            expiring,
+            vec![("soon.example.com".to_string(), secs(now + day))]
+        );
+    }
+
+    #[test]
+    fn instances_with_unknown_suffixes_are_flagged() {
+        let mut conn = open_in_memory();
+        let known = Domain::from_str("example.com").unwrap();
+        let unknown =
+            Domain::from_str_with_policy("example.bbs", UnknownSuffixPolicy::AcceptPlausible)
+                .unwrap();
+        add_instance(&conn, &known).unwrap();
+        add_instance(&conn, &unknown).unwrap();
+
+        let flagged: Vec<String> = conn
+            .prepare(
+                "SELECT hostname
+                FROM unknown_suffix_instances
+                    JOIN instances ON instances.id = unknown_suffix_instances.instance",
+            )
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(flagged, vec!["example.bbs".to_string()]);
+
+        // Reading the hostname back doesn't trip over the unknown suffix.
+        mark_alive(&mut conn, &unknown, false).unwrap();
+        assert_eq!(get_alive_instances(&conn).unwrap(), vec![unknown]);
+    }
+
+    #[test]
+    fn blocked_instance_records_the_reason() {
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+
+        mark_blocked(
+            &mut conn,
+            &instance,
+            BlockedReason::UnavailableForLegalReasons,
+        )
+        .unwrap();
+        assert_eq!(get_state(&mut conn, &instance), InstanceState::Blocked);
+        let reason: BlockedReason = conn
+            .query_row(
+                "SELECT reason
+                FROM blocked_state_data
+                    JOIN instances ON instances.id = blocked_state_data.instance
+                WHERE hostname = ?1",
+                params![instance.to_string()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(reason, BlockedReason::UnavailableForLegalReasons);
+
+        mark_alive(&mut conn, &instance, false).unwrap();
+        assert_eq!(get_state(&mut conn, &instance), InstanceState::Alive);
+        let count: u64 = conn
+            .query_row("SELECT count(id) FROM blocked_state_data", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn blocked_instance_is_not_dead() {
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+
+        for _ in 0..10 {
+            mark_blocked(
+                &mut conn,
+                &instance,
+                BlockedReason::UnavailableForLegalReasons,
+            )
+            .unwrap();
+        }
+        assert_eq!(get_state(&mut conn, &instance), InstanceState::Blocked);
+
+        mark_dead(&mut conn, &instance, DeathReason::NoResponse).unwrap();
+        assert_eq!(get_state(&mut conn, &instance), InstanceState::Dying);
+    }
+
+    #[test]
+    fn legal_state_transitions() {
+        use InstanceState::*;
+
+        for from in [Discovered, Alive, Dying, Dead, Moving, Moved, Blocked] {
+            assert!(!is_legal_transition(from, from));
+            assert!(!is_legal_transition(from, Discovered));
+        }
+        assert!(is_legal_transition(Discovered, Alive));
+        assert!(is_legal_transition(Moved, Dying));
+        assert!(is_legal_transition(Moving, Moved));
+        assert!(is_legal_transition(Dead, Moving));
+        assert!(!is_legal_transition(Alive, Moved));
+        assert!(!is_legal_transition(Dead, Dying));
+    }
+
+    #[test]
+    fn set_state_clears_the_data_of_the_previous_state() {
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+        mark_dead(&mut conn, &instance, DeathReason::NoResponse).unwrap();
+        assert_eq!(get_state(&mut conn, &instance), InstanceState::Dying);
+
+        let tx = conn.transaction().unwrap();
+        let (id, _) = get_instance(&tx, &instance).unwrap();
+        set_state(&tx, id, InstanceState::Dying, InstanceState::Blocked).unwrap();
+        let count: u64 = tx
+            .query_row("SELECT count(id) FROM dying_state_data", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(count, 0);
+        tx.commit().unwrap();
+        assert_eq!(get_state(&mut conn, &instance), InstanceState::Blocked);
+    }
+
+    #[test]
+    fn set_state_rejects_illegal_transitions() {
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        add_instance(&conn, &instance).unwrap();
+        mark_alive(&mut conn, &instance, false).unwrap();
+
+        let tx = conn.transaction().unwrap();
+        let (id, _) = get_instance(&tx, &instance).unwrap();
+        assert!(set_state(&tx, id, InstanceState::Alive, InstanceState::Moved).is_err());
+        assert!(set_state(&tx, id, InstanceState::Alive, InstanceState::Discovered).is_err());
+        // The instance is not dying, so it can't stop dying.
+        assert!(set_state(&tx, id, InstanceState::Dying, InstanceState::Alive).is_err());
+        drop(tx);
+        assert_eq!(get_state(&mut conn, &instance), InstanceState::Alive);
+    }
+}
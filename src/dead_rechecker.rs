@@ -0,0 +1,34 @@
+//! Schedule dead instances to be checked again soon.
+//!
+//! This is the way to recover after a problem on our side, like a network outage, made a lot of
+//! instances look dead. Their states are left alone; the checks will sort them out.
+use crate::{
+    db::{self, InstanceState},
+    time,
+};
+use anyhow::Context;
+use slog::{info, Logger};
+
+pub fn main(logger: Logger, include_dying: bool) -> anyhow::Result<()> {
+    let mut conn = db::open()?;
+    db::init(&mut conn)?;
+    db::use_saved_periods(&conn)?;
+
+    let states: &[InstanceState] = if include_dying {
+        &[InstanceState::Dead, InstanceState::Dying]
+    } else {
+        &[InstanceState::Dead]
+    };
+    let rescheduled =
+        db::on_sqlite_busy_retry_indefinitely(&mut || db::recheck_soon(&mut conn, states))
+            .context("Rescheduling the checks")?;
+
+    let msg = format!(
+        "Scheduled {} instances to be checked within the next {:.1} hours",
+        rescheduled,
+        time::Period::Daily.duration().as_secs_f64() / 3600.0
+    );
+    info!(logger, "{}", msg);
+    println!("{}", msg);
+    Ok(())
+}
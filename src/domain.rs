@@ -1,31 +1,83 @@
 //! A domain name with a suffix known to the Public Suffix List.
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use url::Host;
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// What to do with domain names whose suffix is not in the Public Suffix List.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UnknownSuffixPolicy {
+    /// Reject such names.
+    #[default]
+    Reject,
+
+    /// Accept the name if its suffix looks like a top-level domain. This lets us keep instances
+    /// on TLDs that were created after our copy of the Public Suffix List.
+    AcceptPlausible,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 /// A domain name with a suffix known to the Public Suffix List.
+///
+/// The suffix can be unknown only if the domain was constructed with
+/// [`UnknownSuffixPolicy::AcceptPlausible`].
+///
+/// Internationalized names are stored in Punycode, so the same instance is the same `Domain` no
+/// matter how it was spelled.
 pub struct Domain {
     domain: String,
+    known_suffix: bool,
 }
 
 impl Domain {
     /// Construct from an arbitrary string.
     pub fn from_str(domain: &str) -> anyhow::Result<Self> {
-        // `addr` only works with lowercase.
-        let domain = domain.to_lowercase();
+        Self::from_str_with_policy(domain, UnknownSuffixPolicy::Reject)
+    }
+
+    /// Construct from an arbitrary string, treating unknown suffixes according to `policy`.
+    pub fn from_str_with_policy(domain: &str, policy: UnknownSuffixPolicy) -> anyhow::Result<Self> {
+        // A fully-qualified name may end with a dot, but it's the same domain as without it.
+        let domain = domain.strip_suffix('.').unwrap_or(domain);
+        if domain.ends_with('.') {
+            bail!("The domain name {} ends with an empty label", domain);
+        }
+        // Names are case-insensitive, so they're lowercased to get a single spelling per
+        // instance; `addr` only works with lowercase ASCII anyway. This also maps equivalent
+        // Unicode spellings to the same name.
+        let domain = idna::domain_to_ascii(domain)
+            .map_err(|e| anyhow!("Converting domain name {} to ASCII failed: {}", domain, e))?;
 
         let name = match addr::parse_domain_name(&domain) {
             Err(e) => bail!("Parsing domain name {} failed: {}", domain, e),
             Ok(name) => name,
         };
-        if !name.has_known_suffix() {
-            bail!(
-                "The domain name {} has valid syntax, but its suffix is not in the Public Suffix List",
-                domain
-            )
+        let known_suffix = name.has_known_suffix();
+        if !known_suffix {
+            let accept = match policy {
+                UnknownSuffixPolicy::Reject => false,
+                UnknownSuffixPolicy::AcceptPlausible => has_plausible_suffix(name.as_str()),
+            };
+            if !accept {
+                bail!(
+                    "The domain name {} has valid syntax, but its suffix is not in the Public Suffix List",
+                    domain
+                )
+            }
         }
         let domain = name.as_str().to_owned();
-        Ok(Self { domain })
+        Ok(Self {
+            domain,
+            known_suffix,
+        })
+    }
+
+    /// Returns `false` if the domain's suffix is not in the Public Suffix List.
+    pub fn has_known_suffix(&self) -> bool {
+        self.known_suffix
+    }
+
+    /// Returns `true` if this is a Tor onion service, which isn't in the DNS.
+    pub fn is_onion(&self) -> bool {
+        self.domain.ends_with(".onion")
     }
 
     /// Construct from [`url::Host::Domain`].
@@ -38,6 +90,19 @@ impl Domain {
     }
 }
 
+/// Returns `true` if the last label of a syntactically valid domain name looks like a TLD: it's
+/// either all letters, or an internationalized name in Punycode.
+fn has_plausible_suffix(domain: &str) -> bool {
+    let Some((_, tld)) = domain.rsplit_once('.') else {
+        return false;
+    };
+    let is_letters = tld.len() >= 2 && tld.chars().all(|c| c.is_ascii_lowercase());
+    let is_punycode = tld.starts_with("xn--")
+        && tld.len() > 4
+        && tld.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    is_letters || is_punycode
+}
+
 impl std::fmt::Display for Domain {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.domain)
@@ -121,6 +186,10 @@ mod test {
 
         // Onion hidden services
         assert!(Domain::from_str("yzw45do3yrjfnbpr.onion").is_ok());
+        assert!(Domain::from_str("yzw45do3yrjfnbpr.onion")
+            .unwrap()
+            .is_onion());
+        assert!(!Domain::from_str("onion.example.com").unwrap().is_onion());
         assert!(
             Domain::from_str("zlzvfg5zcehs2t4qcm7woogyywfzwvrduqujsnehrjeg3tndn6a55nqd.onion")
                 .is_ok()
@@ -135,6 +204,83 @@ mod test {
         assert!(Domain::from_str("this.one.is.free").is_ok());
     }
 
+    #[test]
+    fn plausible_unknown_suffixes_are_accepted_only_on_request() {
+        use UnknownSuffixPolicy::*;
+
+        assert!(Domain::from_str_with_policy("outdated.bbs", Reject).is_err());
+        let domain = Domain::from_str_with_policy("outdated.bbs", AcceptPlausible).unwrap();
+        assert!(!domain.has_known_suffix());
+        assert_eq!(domain.to_string(), "outdated.bbs");
+
+        assert!(Domain::from_str_with_policy("example.xn--brnd-moa", Reject).is_err());
+        assert!(Domain::from_str_with_policy("example.xn--brnd-moa", AcceptPlausible).is_ok());
+        // Not valid Punycode, so not a name at all.
+        assert!(Domain::from_str_with_policy("example.xn--brand", AcceptPlausible).is_err());
+
+        // Not TLD-like
+        assert!(Domain::from_str_with_policy("example.i2p", AcceptPlausible).is_err());
+        assert!(Domain::from_str_with_policy("localhost", AcceptPlausible).is_err());
+        assert!(Domain::from_str_with_policy("127.0.0.1", AcceptPlausible).is_err());
+
+        // Known suffixes are accepted either way.
+        let domain = Domain::from_str_with_policy("example.com", AcceptPlausible).unwrap();
+        assert!(domain.has_known_suffix());
+        assert!(Domain::from_str_with_policy("example.com", Reject).is_ok());
+    }
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let lowercase = Domain::from_str("example.com").unwrap();
+        assert_eq!(Domain::from_str("EXAMPLE.COM").unwrap(), lowercase);
+        assert_eq!(Domain::from_str("Example.Com").unwrap(), lowercase);
+        assert_eq!(
+            Domain::from_str("EXAMPLE.COM").unwrap().to_string(),
+            "example.com"
+        );
+        assert_eq!(
+            Domain::from_host(&Host::Domain("Mastodon.Social".to_string()))
+                .unwrap()
+                .to_string(),
+            "mastodon.social"
+        );
+    }
+
+    #[test]
+    fn trailing_dot_is_ignored() {
+        let fqdn = Domain::from_str("example.com.").unwrap();
+        assert_eq!(fqdn, Domain::from_str("example.com").unwrap());
+        assert_eq!(fqdn.to_string(), "example.com");
+        assert_eq!(
+            Domain::from_host(&Host::Domain("Example.COM.".to_string())).unwrap(),
+            fqdn
+        );
+
+        // Only a single dot is a valid FQDN.
+        assert!(Domain::from_str("example.com..").is_err());
+        assert!(Domain::from_str(".").is_err());
+    }
+
+    #[test]
+    fn internationalized_names_are_stored_in_punycode() {
+        let policy = UnknownSuffixPolicy::AcceptPlausible;
+        let unicode = Domain::from_str_with_policy("münchen.example", policy).unwrap();
+        let punycode = Domain::from_str_with_policy("xn--mnchen-3ya.example", policy).unwrap();
+        assert_eq!(unicode, punycode);
+        assert_eq!(unicode.to_string(), "xn--mnchen-3ya.example");
+        assert_eq!(
+            Domain::from_str_with_policy("MÜNCHEN.example", policy).unwrap(),
+            punycode
+        );
+
+        let unicode = Domain::from_str("café.example.org").unwrap();
+        assert_eq!(unicode.to_string(), "xn--caf-dma.example.org");
+        assert_eq!(
+            Domain::from_host(&Host::Domain("xn--caf-dma.example.org".to_string())).unwrap(),
+            unicode
+        );
+    }
+
     #[test]
     fn what_addr_accepts_and_rejects() {
         use addr::parse_domain_name;
@@ -0,0 +1,98 @@
+//! Export the peering graph in the Graphviz DOT format.
+//!
+//! Instances are the nodes, and an edge goes from an instance to each of its peers.
+use crate::{db, with_loc};
+use anyhow::Context;
+use rusqlite::Connection;
+use slog::{info, Logger};
+use std::io::Write;
+
+/// Write the peering graph to stdout. Unless `include_all` is set, only alive instances and the
+/// peerings between them are exported.
+pub fn main(logger: Logger, include_all: bool) -> anyhow::Result<()> {
+    info!(logger, "Exporting the peering graph");
+
+    let mut conn = db::open()?;
+    db::init(&mut conn)?;
+
+    let stdout = std::io::stdout();
+    let mut out = std::io::BufWriter::new(stdout.lock());
+    write_dot(&conn, include_all, &mut out)?;
+    out.flush().context(with_loc!("Flushing stdout"))
+}
+
+fn write_dot(conn: &Connection, include_all: bool, out: &mut impl Write) -> anyhow::Result<()> {
+    writeln!(out, "digraph fediverse {{").context(with_loc!("Writing the graph header"))?;
+    // Nodes are listed explicitly so that instances without peerings show up too.
+    let instances =
+        db::get_graph_instances(conn, include_all).context(with_loc!("Getting the instances"))?;
+    for instance in instances {
+        writeln!(out, "    \"{}\";", instance).context(with_loc!("Writing a node"))?;
+    }
+    db::for_each_peering(conn, include_all, |from, to| {
+        writeln!(out, "    \"{}\" -> \"{}\";", from, to).context(with_loc!("Writing an edge"))
+    })
+    .context(with_loc!("Getting the peerings"))?;
+    writeln!(out, "}}").context(with_loc!("Writing the graph footer"))
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::domain::Domain;
+
+    fn add_peerings(conn: &mut Connection, from: &str, to: &[&str]) {
+        let from = Domain::from_str(from).unwrap();
+        let to: Vec<Domain> = to.iter().map(|to| Domain::from_str(to).unwrap()).collect();
+        db::replace_peerings(conn, &from, &to).unwrap();
+    }
+
+    fn export(include_all: bool) -> String {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        for host in ["example.com", "example.org", "example.net"] {
+            db::add_instance(&conn, &Domain::from_str(host).unwrap()).unwrap();
+        }
+        for host in ["example.com", "example.org"] {
+            db::mark_alive(&mut conn, &Domain::from_str(host).unwrap(), false).unwrap();
+        }
+        add_peerings(&mut conn, "example.com", &["example.org", "example.net"]);
+        add_peerings(&mut conn, "example.org", &["example.com"]);
+
+        let mut out = vec![];
+        write_dot(&conn, include_all, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn only_alive_instances_by_default() {
+        assert_eq!(
+            export(false),
+            r#"digraph fediverse {
+    "example.com";
+    "example.org";
+    "example.com" -> "example.org";
+    "example.org" -> "example.com";
+}
+"#
+        );
+    }
+
+    #[test]
+    fn all_instances_on_request() {
+        assert_eq!(
+            export(true),
+            r#"digraph fediverse {
+    "example.com";
+    "example.net";
+    "example.org";
+    "mastodon.social";
+    "example.com" -> "example.net";
+    "example.com" -> "example.org";
+    "example.org" -> "example.com";
+}
+"#
+        );
+    }
+}
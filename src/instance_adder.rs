@@ -1,10 +1,15 @@
-use crate::{db, domain::Domain};
-use slog::{error, info, Logger};
+use crate::{
+    db,
+    domain::{Domain, UnknownSuffixPolicy},
+};
+use slog::{error, info, warn, Logger};
 use std::io::{self, BufRead};
+use url::Url;
 
-pub fn main(logger: Logger) -> anyhow::Result<()> {
+pub fn main(logger: Logger, unknown_suffix_policy: UnknownSuffixPolicy) -> anyhow::Result<()> {
     let mut conn = db::open()?;
     db::init(&mut conn)?;
+    db::use_saved_periods(&conn)?;
 
     let stdin = io::stdin();
     let stdin = stdin.lock();
@@ -12,7 +17,7 @@ pub fn main(logger: Logger) -> anyhow::Result<()> {
 
     for domain in reader.lines() {
         let domain = domain?;
-        let domain = match Domain::from_str(&domain) {
+        let domain = match Domain::from_str_with_policy(&host_of(&domain), unknown_suffix_policy) {
             Err(e) => {
                 let msg = format!(
                     "Couldn't manually add {}, it's not a valid domain name: {}",
@@ -25,6 +30,24 @@ pub fn main(logger: Logger) -> anyhow::Result<()> {
 
             Ok(domain) => domain,
         };
+        if !domain.has_known_suffix() {
+            let msg = format!(
+                "{}'s suffix is not in the Public Suffix List, but looks plausible; adding anyway",
+                domain
+            );
+            warn!(logger, "{}", msg);
+            println!("{}", msg);
+        }
+        match db::on_sqlite_busy_retry_indefinitely(&mut || {
+            db::get_instance_by_host(&conn, &domain)
+        }) {
+            Ok(Some(record)) => {
+                println!("{} is already in the database: {}", domain, record);
+                continue;
+            }
+            Ok(None) => {}
+            Err(e) => error!(logger, "Failed to look up {}: {:?}", domain, e),
+        }
         match db::on_sqlite_busy_retry_indefinitely(&mut || db::add_instance(&conn, &domain)) {
             Err(e) => {
                 let msg = format!("Failed to add {} to the database: {}", domain, e);
@@ -44,3 +67,38 @@ pub fn main(logger: Logger) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// The host of the URL on the `line`, e.g. of "https://mastodon.social/@someone" that people copy
+/// from their browsers. A line that isn't a URL is returned as is, on the assumption that it's
+/// a bare domain.
+fn host_of(line: &str) -> String {
+    match Url::parse(line) {
+        Ok(url) => match url.host_str() {
+            Some(host) => host.to_string(),
+            // A bare domain with a port, like "example.com:8080", parses as a URL with
+            // "example.com" as the scheme and no host.
+            None => line.to_string(),
+        },
+        Err(_) => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hosts_are_extracted_from_urls() {
+        assert_eq!(
+            host_of("https://mastodon.social/@someone"),
+            "mastodon.social"
+        );
+        assert_eq!(host_of("https://example.com:8443/about"), "example.com");
+        assert_eq!(host_of("mastodon.social"), "mastodon.social");
+        assert_eq!(host_of("example.com:8080"), "example.com:8080");
+
+        let domain = Domain::from_str(&host_of("https://mastodon.social/@someone")).unwrap();
+        assert_eq!(domain.to_string(), "mastodon.social");
+    }
+}
@@ -1,5 +1,6 @@
+use anyhow::bail;
 use serde::{Deserialize, Serialize};
-use url::Host;
+use url::{Host, Url};
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub enum InstanceState {
@@ -14,14 +15,341 @@ pub enum InstanceState {
 
     /// The instance responded with a permanent redirect (HTTP codes 301, 308)
     Moved { to: Host },
+
+    /// The instance is up, but refuses to serve us.
+    Blocked { reason: BlockedReason },
+}
+
+/// Why an instance that is up didn't let us check it.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BlockedReason {
+    /// The instance responded with HTTP code 451.
+    UnavailableForLegalReasons,
+
+    /// The instance responded with HTTP code 401, i.e. it's misconfigured to require
+    /// authentication for NodeInfo.
+    AuthenticationRequired,
+}
+
+impl std::fmt::Display for BlockedReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockedReason::UnavailableForLegalReasons => write!(f, "unavailable for legal reasons"),
+            BlockedReason::AuthenticationRequired => write!(f, "authentication required"),
+        }
+    }
+}
+
+/// What kind of failure made the checker decide that an instance is dead.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum DeathCause {
+    /// The TLS handshake failed, e.g. because the certificate is expired or self-signed.
+    TlsError,
+
+    /// The instance only seems to speak HTTP/2, which we don't.
+    Http2Only,
+
+    /// The instance redirected us in circles.
+    RedirectLoop,
+
+    /// The instance responded with a body larger than we're willing to read.
+    BodyTooLarge,
+
+    /// The instance's robots.txt doesn't let us fetch NodeInfo.
+    ForbiddenByRobotsTxt,
+
+    /// The instance served an HTML page instead of NodeInfo, e.g. it's a parked domain.
+    NotNodeInfo,
+
+    /// The instance serves NodeInfo, but its app responds with server errors.
+    AppIsDown,
+
+    /// Anything else, e.g. the connection was refused or NodeInfo is malformed.
+    Other,
+}
+
+/// Additional details about an instance, which aren't needed for crawling.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct Metadata {
+    /// The `Server` header of the NodeInfo response.
+    #[serde(default)]
+    pub server: Option<String>,
+
+    /// The `X-Powered-By` header of the NodeInfo response.
+    #[serde(default)]
+    pub powered_by: Option<String>,
+
+    /// The software name, exactly as reported in NodeInfo.
+    #[serde(default)]
+    pub software: Option<String>,
+
+    /// The instance's name, from Mastodon's `/api/v2/instance` or NodeInfo's `metadata.nodeName`.
+    #[serde(default)]
+    pub title: Option<String>,
+
+    /// The instance's rules, from Mastodon's `/api/v2/instance`.
+    #[serde(default)]
+    pub rules: Option<Vec<String>>,
+
+    /// The admin's contact (an email or an account), from Mastodon's `/api/v2/instance`.
+    #[serde(default)]
+    pub contact: Option<String>,
+
+    /// The URL of the instance's thumbnail or logo.
+    #[serde(default)]
+    pub thumbnail: Option<String>,
+
+    /// When the instance's TLS certificate expires, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub certificate_expires_at: Option<u64>,
+
+    /// The domain that the instance's description is about, if it's not the instance's own. Shared
+    /// hosts that ignore SNI serve their default site's description instead.
+    #[serde(default)]
+    pub misconfigured_domain: Option<String>,
 }
 
+impl Metadata {
+    /// The longest header value we store; the rest is cut off.
+    const MAX_HEADER_LENGTH: usize = 256;
+
+    /// The most rules we store; the rest are dropped.
+    const MAX_RULES: usize = 100;
+
+    /// The longest rule we store; the rest is cut off.
+    const MAX_RULE_LENGTH: usize = 1024;
+
+    /// The longest URL we store. Longer ones are dropped, since a truncated URL is useless.
+    const MAX_URL_LENGTH: usize = 2048;
+
+    /// Strip everything but printable ASCII from the headers and control characters from the
+    /// texts, and truncate them.
+    ///
+    /// The checker does this before sending the metadata, and the Orchestrator does it again
+    /// before storing it, because the checker could be compromised.
+    pub fn sanitized(self) -> Self {
+        Self {
+            server: self.server.and_then(Self::sanitize_header),
+            powered_by: self.powered_by.and_then(Self::sanitize_header),
+            software: self.software.and_then(Self::sanitize_header),
+            title: self
+                .title
+                .and_then(|title| Self::sanitize_text(title, Self::MAX_HEADER_LENGTH)),
+            rules: self.rules.map(|rules| {
+                rules
+                    .into_iter()
+                    .filter_map(|rule| Self::sanitize_text(rule, Self::MAX_RULE_LENGTH))
+                    .take(Self::MAX_RULES)
+                    .collect()
+            }),
+            contact: self
+                .contact
+                .and_then(|contact| Self::sanitize_text(contact, Self::MAX_HEADER_LENGTH)),
+            thumbnail: self.thumbnail.and_then(Self::sanitize_url),
+            // SQLite only stores signed integers.
+            certificate_expires_at: self
+                .certificate_expires_at
+                .filter(|expiry| i64::try_from(*expiry).is_ok()),
+            misconfigured_domain: self
+                .misconfigured_domain
+                .and_then(|domain| Self::sanitize_text(domain, Self::MAX_HEADER_LENGTH)),
+        }
+    }
+
+    /// Only absolute HTTPS URLs are kept; we only talk to instances over HTTPS, so other schemes
+    /// and relative URLs are either mistakes or tricks.
+    fn sanitize_url(value: String) -> Option<String> {
+        let url = Url::parse(value.trim()).ok()?;
+        let url = url.as_str();
+        (url.starts_with("https://") && url.len() <= Self::MAX_URL_LENGTH).then(|| url.to_owned())
+    }
+
+    fn sanitize_text(value: String, max_length: usize) -> Option<String> {
+        let value: String = value
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(max_length)
+            .collect();
+        let value = value.trim();
+        if value.is_empty() {
+            None
+        } else {
+            Some(value.to_owned())
+        }
+    }
+
+    fn sanitize_header(value: String) -> Option<String> {
+        let value: String = value
+            .chars()
+            .filter(|c| c.is_ascii_graphic() || *c == ' ')
+            .take(Self::MAX_HEADER_LENGTH)
+            .collect();
+        let value = value.trim();
+        if value.is_empty() {
+            None
+        } else {
+            Some(value.to_owned())
+        }
+    }
+}
+
+/// The version of the protocol between the Orchestrator and the checkers. Bump it whenever
+/// [`CheckerResponse`] or any of the types in it change, so that a checker from a different build
+/// (e.g. one still running during an upgrade) is turned away rather than misunderstood.
+pub const PROTOCOL_VERSION: u32 = 5;
+
 /// Messages that the checker can send to the orchestrator.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub enum CheckerResponse {
+    /// The first message, sent before anything else. Its format never changes, so that builds
+    /// with different [`PROTOCOL_VERSION`]s can tell that they don't understand each other.
+    Hello { protocol_version: u32 },
+
     /// The state of the instance.
     State { state: InstanceState },
 
+    /// The instance is dead because of the `cause`, and `reason` explains it in words. Sent
+    /// instead of `State`; nothing follows it. A checker that exits without sending either is
+    /// assumed to have failed to reach the instance.
+    Dead { reason: String, cause: DeathCause },
+
     /// The instance peers with another instance, which is located at `hostname`.
     Peer { peer: Host },
+
+    /// All of the instance's peers were sent. Without this, the `Peer` messages are only a part
+    /// of the list, if any: e.g. the list couldn't be fetched, or the checker ran out of time.
+    EndOfPeers,
+
+    /// Details about the instance. Only sent after the `Alive` state.
+    Metadata { metadata: Metadata },
+}
+
+/// The longest death reason the Orchestrator keeps; the rest is cut off.
+const MAX_DEATH_REASON_LENGTH: usize = 1024;
+
+/// Strip control characters from the checker's death reason, and truncate it.
+///
+/// The checker could be compromised, so the reason is cleaned up before it gets into the logs.
+pub fn sanitize_death_reason(reason: String) -> String {
+    Metadata::sanitize_text(reason, MAX_DEATH_REASON_LENGTH)
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Check that the checker's first message is a [`CheckerResponse::Hello`] with our
+/// [`PROTOCOL_VERSION`].
+pub fn check_hello(response: &CheckerResponse) -> anyhow::Result<()> {
+    match response {
+        CheckerResponse::Hello { protocol_version } if *protocol_version == PROTOCOL_VERSION => {
+            Ok(())
+        }
+        CheckerResponse::Hello { protocol_version } => bail!(
+            "The checker speaks IPC protocol version {}, but we speak version {}; is it from a different build?",
+            protocol_version,
+            PROTOCOL_VERSION
+        ),
+        _ => bail!(
+            "Expected the checker to start with Hello; is it from a build that predates IPC versioning?"
+        ),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sanitizes_metadata() {
+        let metadata = Metadata {
+            server: Some(format!("  nginx/1.2\r\n\u{0}évil{}", "x".repeat(1000))),
+            powered_by: Some(" \t ".to_string()),
+            rules: Some(vec![
+                "No spam\u{0}\n".to_string(),
+                " ".to_string(),
+                "Être gentil".repeat(1000),
+            ]),
+            ..Default::default()
+        }
+        .sanitized();
+
+        let server = metadata.server.unwrap();
+        assert!(server.starts_with("nginx/1.2vil"));
+        assert_eq!(server.len(), Metadata::MAX_HEADER_LENGTH - 2);
+        assert_eq!(metadata.powered_by, None);
+
+        let rules = metadata.rules.unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules.first().unwrap(), "No spam");
+        let long_rule = rules.get(1).unwrap();
+        assert!(long_rule.starts_with("Être gentil"));
+        assert_eq!(long_rule.chars().count(), Metadata::MAX_RULE_LENGTH);
+
+        let expiry = |certificate_expires_at| {
+            Metadata {
+                certificate_expires_at,
+                ..Default::default()
+            }
+            .sanitized()
+            .certificate_expires_at
+        };
+        assert_eq!(expiry(Some(1_907_757_296)), Some(1_907_757_296));
+        assert_eq!(expiry(Some(u64::MAX)), None);
+    }
+
+    #[test]
+    fn sanitizes_death_reasons() {
+        assert_eq!(
+            sanitize_death_reason("Connection refused\n\u{1b}[31m".to_string()),
+            "Connection refused[31m"
+        );
+        assert_eq!(sanitize_death_reason(" \n".to_string()), "unknown");
+        assert_eq!(
+            sanitize_death_reason("x".repeat(5000)).len(),
+            MAX_DEATH_REASON_LENGTH
+        );
+    }
+
+    #[test]
+    fn only_our_protocol_version_is_accepted() {
+        let hello = |protocol_version| CheckerResponse::Hello { protocol_version };
+        assert!(check_hello(&hello(PROTOCOL_VERSION)).is_ok());
+        assert!(check_hello(&hello(PROTOCOL_VERSION.wrapping_add(1))).is_err());
+        assert!(check_hello(&hello(0)).is_err());
+        assert!(check_hello(&CheckerResponse::State {
+            state: InstanceState::Alive {
+                hide_from_list: false
+            }
+        })
+        .is_err());
+
+        // Older and newer builds must be able to read it.
+        assert_eq!(
+            serde_json::to_string(&hello(7)).unwrap(),
+            r#"{"Hello":{"protocol_version":7}}"#
+        );
+    }
+
+    #[test]
+    fn keeps_only_absolute_https_thumbnails() {
+        let thumbnail = |url: &str| {
+            Metadata {
+                thumbnail: Some(url.to_string()),
+                ..Default::default()
+            }
+            .sanitized()
+            .thumbnail
+        };
+
+        assert_eq!(
+            thumbnail("https://files.example.com/site/thumbnail.png"),
+            Some("https://files.example.com/site/thumbnail.png".to_string())
+        );
+        assert_eq!(thumbnail("/packs/media/images/preview.png"), None);
+        assert_eq!(thumbnail("http://example.com/thumbnail.png"), None);
+        assert_eq!(thumbnail("javascript:alert(1)"), None);
+        assert_eq!(
+            thumbnail(&format!("https://example.com/{}", "x".repeat(5000))),
+            None
+        );
+    }
 }
@@ -9,53 +9,587 @@
     clippy::match_on_vec_items
 )]
 
-use anyhow::{anyhow, bail};
+use anyhow::{anyhow, bail, Context};
 use slog::{error, o, Drain, Logger};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
 use url::Host;
 
 mod checker;
 mod db;
+mod dead_rechecker;
 mod domain;
+mod dot_exporter;
 mod instance_adder;
 mod ipc;
 mod logging_helpers;
 mod orchestrator;
+mod schedule_simulator;
+mod software;
+mod stats;
 mod time;
+mod vacuum;
+
+/// The mode the program runs in.
+enum Command {
+    /// Crawl the Fediverse (the default).
+    Orchestrate,
+
+    /// Read hostnames from stdin and add them to the database.
+    AddInstances {
+        unknown_suffix_policy: domain::UnknownSuffixPolicy,
+    },
+
+    /// Check a single host and report the results to stdout.
+    Check { host: String },
+
+    /// Print the projected load for the next `hours` hours.
+    SimulateSchedule { hours: Option<u64> },
+
+    /// Re-check whether alive instances want to be hidden from the list.
+    RecomputeHidden,
+
+    /// Schedule dead (and optionally dying) instances to be checked soon.
+    RecheckDead { include_dying: bool },
+
+    /// Print what the database knows about a single host.
+    Show { host: String },
+
+    /// Check a single host right away, update the database, and print how its state changed.
+    Recheck { host: String },
+
+    /// Print statistics about the instances in the database.
+    Stats { format: stats::Format },
+
+    /// Print the instances that are in the given state.
+    ListState { state: db::InstanceState },
+
+    /// Print the peering graph in Graphviz DOT format, optionally including instances that
+    /// aren't alive.
+    ExportDot { include_all: bool },
+
+    /// Compact the database and print how much space that saved.
+    Vacuum,
+}
+
+impl Command {
+    /// The command-line option that selects this command.
+    fn option(&self) -> &'static str {
+        match self {
+            Command::Orchestrate => "(none)",
+            Command::AddInstances { .. } => "--add-instances",
+            Command::Check { .. } => "--check",
+            Command::SimulateSchedule { .. } => "--simulate-schedule",
+            Command::RecomputeHidden => "--recompute-hidden",
+            Command::RecheckDead { .. } => "--recheck-dead",
+            Command::Show { .. } => "--show",
+            Command::Recheck { .. } => "--recheck",
+            Command::Stats { .. } => "--stats",
+            Command::ListState { .. } => "--list-state",
+            Command::ExportDot { .. } => "--export-dot",
+            Command::Vacuum => "--vacuum",
+        }
+    }
+}
 
 struct Args {
-    add_instances: bool,
-    host_to_check: Option<String>,
+    command: Command,
+    orchestrator_options: orchestrator::Options,
+    checker_options: checker::Options,
 }
 
-fn parse_args() -> anyhow::Result<Args> {
+/// The options that affect how instances are checked. The crawl passes them on to its checkers,
+/// and so do the commands that check the way the crawl does.
+#[derive(Default, PartialEq)]
+struct CheckOptions {
+    peers_paths: Vec<(String, String)>,
+    strict_liveness: bool,
+    tor_proxy: Option<String>,
+    extra_ca_certs: Vec<PathBuf>,
+    contact_url: Option<String>,
+}
+
+impl CheckOptions {
+    fn copy_to_orchestrator(&self, options: &mut orchestrator::Options) {
+        options.peers_paths = self.peers_paths.clone();
+        options.strict_liveness = self.strict_liveness;
+        options.tor_proxy = self.tor_proxy.clone();
+        options.extra_ca_certs = self.extra_ca_certs.clone();
+        options.contact_url = self.contact_url.clone();
+    }
+
+    fn copy_to_checker(&self, options: &mut checker::Options) {
+        options.peers_paths = self.peers_paths.clone();
+        options.strict_liveness = self.strict_liveness;
+        options.tor_proxy = self.tor_proxy.clone();
+        options.extra_ca_certs = self.extra_ca_certs.clone();
+        options.contact_url = self.contact_url.clone();
+    }
+}
+
+/// Where an option that only some commands take can be used.
+struct Scope {
+    /// When crawling.
+    crawl: bool,
+    /// With --check.
+    check: bool,
+    /// With these other commands, by their [`Command::option`].
+    commands: &'static [&'static str],
+}
+
+impl Scope {
+    /// Options of [`CheckOptions`], which every command that checks instances takes.
+    const CHECKING: Scope = Scope {
+        crawl: true,
+        check: true,
+        commands: &["--recheck", "--recompute-hidden"],
+    };
+
+    /// Options of the checker alone.
+    const CHECK_ONLY: Scope = Scope {
+        crawl: false,
+        check: true,
+        commands: &[],
+    };
+
+    /// Options of the crawl that the `commands` take as well.
+    const fn crawl(commands: &'static [&'static str]) -> Scope {
+        Scope {
+            crawl: true,
+            check: false,
+            commands,
+        }
+    }
+}
+
+/// Options that only some commands take. Error messages list them in this order.
+const RESTRICTED_OPTIONS: &[(&str, Scope)] = &[
+    ("--max-depth", Scope::crawl(&["--recheck"])),
+    ("--checker-exe", Scope::crawl(&["--recompute-hidden"])),
+    (
+        "--checker-memory-limit-mb",
+        Scope::crawl(&["--recompute-hidden"]),
+    ),
+    (
+        "--checker-cpu-limit-secs",
+        Scope::crawl(&["--recompute-hidden"]),
+    ),
+    ("--min-workers", Scope::crawl(&[])),
+    ("--max-workers", Scope::crawl(&[])),
+    ("--max-rps", Scope::crawl(&[])),
+    ("--worker-idle-secs", Scope::crawl(&[])),
+    ("--min-alive-days", Scope::crawl(&[])),
+    ("--daily-period-hours", Scope::crawl(&[])),
+    ("--weekly-period-hours", Scope::crawl(&[])),
+    ("--list-period-minutes", Scope::crawl(&[])),
+    ("--pause-list-generation", Scope::crawl(&[])),
+    ("--states-list", Scope::crawl(&[])),
+    ("--rich-list", Scope::crawl(&[])),
+    ("--ndjson-list", Scope::crawl(&[])),
+    (
+        "--software-period",
+        Scope::crawl(&["--recheck", "--simulate-schedule"]),
+    ),
+    ("--test-instance-suffix", Scope::crawl(&[])),
+    ("--deterministic", Scope::crawl(&[])),
+    ("--verify-dns-on-add", Scope::crawl(&["--recheck"])),
+    ("--once", Scope::crawl(&[])),
+    ("--single-iteration", Scope::crawl(&[])),
+    ("--read-only", Scope::crawl(&[])),
+    ("--metrics-addr", Scope::crawl(&[])),
+    ("--peers-path", Scope::CHECKING),
+    ("--strict-liveness", Scope::CHECKING),
+    ("--tor-proxy", Scope::CHECKING),
+    ("--extra-ca-cert", Scope::CHECKING),
+    ("--contact-url", Scope::CHECKING),
+    ("--resolved-addresses", Scope::CHECK_ONLY),
+    ("--privacy-only", Scope::CHECK_ONLY),
+    ("--timeout-secs", Scope::CHECK_ONLY),
+    ("--summary", Scope::CHECK_ONLY),
+    ("--timings", Scope::CHECK_ONLY),
+    ("--request-interval-ms", Scope::CHECK_ONLY),
+];
+
+/// The [`RESTRICTED_OPTIONS`] whose scope matches, as a list for an error message.
+fn restricted_options(matches: impl Fn(&Scope) -> bool) -> String {
+    let options: Vec<&str> = RESTRICTED_OPTIONS
+        .iter()
+        .filter(|(_, scope)| matches(scope))
+        .map(|(option, _)| *option)
+        .collect();
+    match options.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+        None => String::new(),
+    }
+}
+
+fn set_command(current: &mut Option<Command>, new: Command) -> anyhow::Result<()> {
+    if let Some(current) = current {
+        bail!(
+            "{} and {} are mutually exclusive",
+            current.option(),
+            new.option()
+        );
+    }
+    *current = Some(new);
+    Ok(())
+}
+
+/// The value of the current option, as a string.
+fn string_value(parser: &mut lexopt::Parser) -> anyhow::Result<String> {
+    // .into_string() returns Result<String, OsString> , and OsString can't be converted to
+    // anyhow::Error. To fix this, we convert the error into String.
+    parser
+        .value()?
+        .into_string()
+        .map_err(|ostr| anyhow!("{}", ostr.to_string_lossy()))
+}
+
+fn parse_args(args: impl IntoIterator<Item = impl Into<OsString>>) -> anyhow::Result<Args> {
     use lexopt::prelude::*;
 
-    let mut add_instances = false;
-    let mut host_to_check = None;
-    let mut parser = lexopt::Parser::from_env();
+    let mut command = None;
+    let mut orchestrator_options = orchestrator::Options::default();
+    let mut checker_options = checker::Options::default();
+    let mut check_options = CheckOptions::default();
+    let mut format = None;
+    let mut parser = lexopt::Parser::from_args(args);
     while let Some(arg) = parser.next()? {
         match arg {
-            Long("add-instances") => add_instances = true,
+            Long("add-instances") => set_command(
+                &mut command,
+                Command::AddInstances {
+                    unknown_suffix_policy: domain::UnknownSuffixPolicy::default(),
+                },
+            )?,
+            Long("accept-unknown-suffixes") => match &mut command {
+                Some(Command::AddInstances {
+                    unknown_suffix_policy,
+                }) => *unknown_suffix_policy = domain::UnknownSuffixPolicy::AcceptPlausible,
+                _ => bail!("--accept-unknown-suffixes can only be used after --add-instances"),
+            },
             Long("check") => {
-                let value = parser.value()?;
-                // .into_string() returns Result<String, OsString> , and OsString can't be
-                // converted to anyhow::Error. To fix this, we convert the error into String.
-                let value = value
-                    .into_string()
-                    .map_err(|ostr| anyhow!("{}", ostr.to_string_lossy()))?;
-                host_to_check = Some(value);
+                let host = string_value(&mut parser)?;
+                set_command(&mut command, Command::Check { host })?;
+            }
+            Long("show") => {
+                let host = string_value(&mut parser)?;
+                set_command(&mut command, Command::Show { host })?;
+            }
+            Long("recheck") => {
+                let host = string_value(&mut parser)?;
+                set_command(&mut command, Command::Recheck { host })?;
+            }
+            Long("stats") => set_command(
+                &mut command,
+                Command::Stats {
+                    format: stats::Format::default(),
+                },
+            )?,
+            Long("list-state") => {
+                let state = string_value(&mut parser)?.parse()?;
+                set_command(&mut command, Command::ListState { state })?;
+            }
+            Long("format") => {
+                format = Some(string_value(&mut parser)?);
+            }
+            Long("resolved-addresses") => {
+                let value = string_value(&mut parser)?;
+                for address in value.split(',') {
+                    let address = address
+                        .parse()
+                        .with_context(|| format!("Invalid IP address: {}", address))?;
+                    checker_options.addresses.push(address);
+                }
+            }
+            Long("checker-exe") => {
+                let path: PathBuf = parser.value()?.into();
+                orchestrator::ensure_executable(&path)
+                    .context("--checker-exe should be an executable file")?;
+                orchestrator_options.checker_exe = Some(path);
+            }
+            Long("checker-memory-limit-mb") => {
+                let megabytes: u64 = parser.value()?.parse()?;
+                orchestrator_options.checker_limits.address_space = megabytes
+                    .checked_mul(1024 * 1024)
+                    .filter(|bytes| *bytes > 0)
+                    .ok_or_else(|| anyhow!("--checker-memory-limit-mb is out of range"))?;
+            }
+            Long("checker-cpu-limit-secs") => {
+                let secs: u64 = parser.value()?.parse()?;
+                if secs == 0 {
+                    bail!("--checker-cpu-limit-secs should be positive");
+                }
+                orchestrator_options.checker_limits.cpu_time = Duration::from_secs(secs);
+            }
+            Long("min-workers") => {
+                orchestrator_options.workers.min = parser.value()?.parse()?;
+            }
+            Long("max-workers") => {
+                orchestrator_options.workers.max = parser.value()?.parse()?;
+            }
+            Long("max-rps") => {
+                let rps = parser.value()?.parse()?;
+                if rps == 0 {
+                    bail!("--max-rps should be positive");
+                }
+                orchestrator_options.max_rps = Some(rps);
+            }
+            Long("worker-idle-secs") => {
+                let secs = parser.value()?.parse()?;
+                orchestrator_options.workers.idle_time = Duration::from_secs(secs);
+            }
+            Long("metrics-addr") => {
+                let addr = parser.value()?;
+                let addr = addr
+                    .parse()
+                    .with_context(|| format!("Invalid address for metrics: {:?}", addr))?;
+                orchestrator_options.metrics_addr = Some(addr);
+            }
+            Long("min-alive-days") => {
+                let days: u64 = parser.value()?.parse()?;
+                let secs = days
+                    .checked_mul(24 * 60 * 60)
+                    .ok_or_else(|| anyhow!("--min-alive-days is too large"))?;
+                orchestrator_options.min_alive_age = Duration::from_secs(secs);
+            }
+            Long("daily-period-hours") => {
+                let hours: u64 = parser.value()?.parse()?;
+                let secs = hours
+                    .checked_mul(60 * 60)
+                    .ok_or_else(|| anyhow!("--daily-period-hours is too large"))?;
+                orchestrator_options.periods.daily = Duration::from_secs(secs);
+            }
+            Long("weekly-period-hours") => {
+                let hours: u64 = parser.value()?.parse()?;
+                let secs = hours
+                    .checked_mul(60 * 60)
+                    .ok_or_else(|| anyhow!("--weekly-period-hours is too large"))?;
+                orchestrator_options.periods.weekly = Duration::from_secs(secs);
+            }
+            Long("list-period-minutes") => {
+                let minutes: u64 = parser.value()?.parse()?;
+                let secs = minutes
+                    .checked_mul(60)
+                    .ok_or_else(|| anyhow!("--list-period-minutes is too large"))?;
+                orchestrator_options.periods.list_generation = Duration::from_secs(secs);
+            }
+            Long("pause-list-generation") => orchestrator_options.pause_list_generation = true,
+            Long("states-list") => orchestrator_options.states_list = true,
+            Long("rich-list") => orchestrator_options.rich_list = true,
+            Long("ndjson-list") => orchestrator_options.ndjson_list = true,
+            Long("read-only") => orchestrator_options.read_only = true,
+            Long("once") => orchestrator_options.once = true,
+            Long("single-iteration") => orchestrator_options.single_iteration = true,
+            Long("deterministic") => orchestrator_options.deterministic = true,
+            Long("verify-dns-on-add") => orchestrator_options.verify_dns_on_add = true,
+            Long("test-instance-suffix") => {
+                let value = string_value(&mut parser)?;
+                let suffix = value.trim_start_matches('.').to_lowercase();
+                if suffix.is_empty() {
+                    bail!("--test-instance-suffix can't be empty");
+                }
+                orchestrator_options.test_instance_suffixes.push(suffix);
+            }
+            Long("max-depth") => {
+                orchestrator_options.max_depth = Some(parser.value()?.parse()?);
+            }
+            Long("peers-path") => {
+                let value = string_value(&mut parser)?;
+                let (software, path) = value
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Expected SOFTWARE=PATH, got {}", value))?;
+                if !path.starts_with('/') {
+                    bail!("Peers path should start with a slash: {}", path);
+                }
+                check_options
+                    .peers_paths
+                    .push((software.to_lowercase(), path.to_string()));
+            }
+            Long("software-period") => {
+                let value = string_value(&mut parser)?;
+                let (software, period) = value
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Expected SOFTWARE=PERIOD, got {}", value))?;
+                orchestrator_options
+                    .software_periods
+                    .push((software::family(software), period.parse()?));
+            }
+            Long("strict-liveness") => check_options.strict_liveness = true,
+            Long("tor-proxy") => {
+                let value = string_value(&mut parser)?;
+                let port = value
+                    .rsplit_once(':')
+                    .filter(|(host, _)| !host.is_empty())
+                    .map(|(_, port)| port)
+                    .ok_or_else(|| anyhow!("Expected HOST:PORT, got {}", value))?;
+                port.parse::<u16>()
+                    .with_context(|| format!("Invalid port of the Tor proxy: {}", port))?;
+                check_options.tor_proxy = Some(value);
+            }
+            Long("extra-ca-cert") => {
+                let value = string_value(&mut parser)?;
+                check_options.extra_ca_certs.push(PathBuf::from(value));
+            }
+            Long("contact-url") => {
+                let value = string_value(&mut parser)?;
+                let url = url::Url::parse(&value)
+                    .with_context(|| format!("Invalid contact URL: {}", value))?;
+                if !["https", "http", "mailto"].contains(&url.scheme()) {
+                    bail!("The contact URL should be http(s) or mailto, got {}", value);
+                }
+                check_options.contact_url = Some(url.to_string());
+            }
+            Long("privacy-only") => checker_options.privacy_only = true,
+            Long("summary") => checker_options.summary = true,
+            Long("timings") => checker_options.timings = true,
+            Long("request-interval-ms") => {
+                let millis = parser.value()?.parse()?;
+                if millis == 0 {
+                    bail!("--request-interval-ms should be positive");
+                }
+                checker_options.request_interval = Some(Duration::from_millis(millis));
+            }
+            Long("timeout-secs") => {
+                let secs = parser.value()?.parse()?;
+                checker_options.timeout = Some(Duration::from_secs(secs));
+            }
+            Long("recompute-hidden") => set_command(&mut command, Command::RecomputeHidden)?,
+            Long("recheck-dead") => set_command(
+                &mut command,
+                Command::RecheckDead {
+                    include_dying: false,
+                },
+            )?,
+            Long("include-dying") => match &mut command {
+                Some(Command::RecheckDead { include_dying }) => *include_dying = true,
+                _ => bail!("--include-dying can only be used after --recheck-dead"),
+            },
+            Long("export-dot") => {
+                set_command(&mut command, Command::ExportDot { include_all: false })?
+            }
+            Long("include-all") => match &mut command {
+                Some(Command::ExportDot { include_all }) => *include_all = true,
+                _ => bail!("--include-all can only be used after --export-dot"),
+            },
+            Long("vacuum") => set_command(&mut command, Command::Vacuum)?,
+            Long("simulate-schedule") => {
+                set_command(&mut command, Command::SimulateSchedule { hours: None })?
+            }
+            // The number of hours is optional, so it's a positional argument rather than
+            // a value of the option.
+            Value(value) if matches!(command, Some(Command::SimulateSchedule { hours: None })) => {
+                let hours = value.parse()?;
+                command = Some(Command::SimulateSchedule { hours: Some(hours) });
             }
             _ => return Err(arg.unexpected().into()),
         }
     }
 
-    if add_instances && host_to_check.is_some() {
-        bail!("--add-instances and --check are mutually exclusive");
+    let mut command = command.unwrap_or(Command::Orchestrate);
+    // --recheck and --recompute-hidden check the way the crawl does, so they take the same options.
+    match command {
+        Command::Orchestrate | Command::Recheck { .. } | Command::RecomputeHidden => {
+            check_options.copy_to_orchestrator(&mut orchestrator_options)
+        }
+        Command::Check { .. } => check_options.copy_to_checker(&mut checker_options),
+        _ if check_options != CheckOptions::default() => bail!(
+            "{} can only be used when crawling, with --check, --recheck or --recompute-hidden",
+            restricted_options(|scope| scope.crawl && scope.check)
+        ),
+        _ => {}
+    }
+    // Each command has its own formats, so the value can only be parsed once the command is known.
+    match (&mut command, format) {
+        (Command::Stats { format }, Some(value)) => *format = value.parse()?,
+        (Command::Check { .. }, Some(value)) => checker_options.format = value.parse()?,
+        (_, Some(_)) => bail!("--format can only be used with --stats or --check"),
+        (_, None) => {}
+    }
+    if !matches!(command, Command::Check { .. }) && checker_options != checker::Options::default() {
+        bail!(
+            "{} can only be used with --check",
+            restricted_options(|scope| !scope.crawl && scope.check)
+        );
+    }
+
+    if orchestrator_options.once && orchestrator_options.single_iteration {
+        bail!("--once and --single-iteration can't be used together");
+    }
+    if orchestrator_options.read_only && !orchestrator_options.once {
+        // The checks aren't rescheduled, so once the schedule is walked there's nothing left to do.
+        bail!("--read-only can only be used with --once");
+    }
+
+    let workers = orchestrator_options.workers;
+    if workers.max == 0 {
+        bail!("--max-workers should be positive");
+    }
+    if workers.min > workers.max {
+        bail!(
+            "--min-workers ({}) can't be larger than --max-workers ({})",
+            workers.min,
+            workers.max
+        );
+    }
+
+    orchestrator_options
+        .periods
+        .validate()
+        .context("Invalid --daily-period-hours, --weekly-period-hours or --list-period-minutes")?;
+
+    // The options of the crawl that also apply to other commands, which were set above. The crawl
+    // itself takes all of them.
+    let allowed_options = match command {
+        Command::Orchestrate => None,
+        Command::Recheck { .. } => {
+            let mut allowed = orchestrator::Options {
+                max_depth: orchestrator_options.max_depth,
+                software_periods: orchestrator_options.software_periods.clone(),
+                verify_dns_on_add: orchestrator_options.verify_dns_on_add,
+                ..Default::default()
+            };
+            check_options.copy_to_orchestrator(&mut allowed);
+            Some(allowed)
+        }
+        Command::RecomputeHidden => {
+            let mut allowed = orchestrator::Options {
+                checker_exe: orchestrator_options.checker_exe.clone(),
+                checker_limits: orchestrator_options.checker_limits,
+                ..Default::default()
+            };
+            check_options.copy_to_orchestrator(&mut allowed);
+            Some(allowed)
+        }
+        // The simulated schedule should match the crawl's.
+        Command::SimulateSchedule { .. } => Some(orchestrator::Options {
+            software_periods: orchestrator_options.software_periods.clone(),
+            ..Default::default()
+        }),
+        _ => Some(orchestrator::Options::default()),
+    };
+    if allowed_options.is_some_and(|allowed| orchestrator_options != allowed) {
+        let option = command.option();
+        let allowed = restricted_options(|scope| scope.crawl && scope.commands.contains(&option));
+        if allowed.is_empty() {
+            bail!(
+                "{} can only be used when crawling",
+                restricted_options(|scope| scope.crawl && !scope.check)
+            );
+        }
+        bail!(
+            "Only {} of the crawl's options can be used with {}",
+            allowed,
+            option
+        );
     }
 
     Ok(Args {
-        add_instances,
-        host_to_check,
+        command,
+        orchestrator_options,
+        checker_options,
     })
 }
 
@@ -68,16 +602,678 @@ fn main() -> anyhow::Result<()> {
 }
 
 fn logged_main(logger: Logger) -> anyhow::Result<()> {
-    let args = parse_args()?;
-    if args.add_instances {
-        instance_adder::main(logger)
-    } else {
-        match args.host_to_check {
-            None => orchestrator::main(logger),
-            Some(host) => {
-                let host = Host::parse(&host)?;
-                checker::main(logger, host)
+    let args = parse_args(std::env::args_os().skip(1))?;
+    match args.command {
+        Command::Orchestrate => orchestrator::main(logger, args.orchestrator_options),
+        Command::AddInstances {
+            unknown_suffix_policy,
+        } => instance_adder::main(logger, unknown_suffix_policy),
+        Command::Check { host } => {
+            let host = Host::parse(&host)?;
+            checker::main(logger, host, args.checker_options)
+        }
+        Command::SimulateSchedule { hours } => {
+            schedule_simulator::main(logger, hours, &args.orchestrator_options)
+        }
+        Command::RecomputeHidden => {
+            orchestrator::hidden_recomputer::main(logger, &args.orchestrator_options)
+        }
+        Command::RecheckDead { include_dying } => dead_rechecker::main(logger, include_dying),
+        Command::Show { host } => stats::show(logger, &host),
+        Command::Recheck { host } => {
+            orchestrator::rechecker::main(logger, &host, &args.orchestrator_options)
+        }
+        Command::Stats { format } => stats::main(logger, format),
+        Command::ListState { state } => stats::list_state(logger, state),
+        Command::ExportDot { include_all } => dot_exporter::main(logger, include_all),
+        Command::Vacuum => vacuum::main(logger),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timeout_secs_sets_checker_timeout() {
+        let args = parse_args(["--check", "example.com", "--timeout-secs", "120"]).unwrap();
+        assert!(matches!(args.command, Command::Check { .. }));
+        assert_eq!(args.checker_options.timeout, Some(Duration::from_secs(120)));
+
+        let args = parse_args(["--check", "example.com"]).unwrap();
+        assert_eq!(args.checker_options.timeout, None);
+    }
+
+    #[test]
+    fn include_dying_is_an_option_of_recheck_dead() {
+        let args = parse_args(["--recheck-dead"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::RecheckDead {
+                include_dying: false
+            }
+        ));
+        let args = parse_args(["--recheck-dead", "--include-dying"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::RecheckDead {
+                include_dying: true
+            }
+        ));
+        assert!(parse_args(["--include-dying"]).is_err());
+    }
+
+    #[test]
+    fn list_state_takes_a_state_name() {
+        let args = parse_args(["--list-state", "dying"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::ListState {
+                state: db::InstanceState::Dying
+            }
+        ));
+        assert!(parse_args(["--list-state", "gone"]).is_err());
+        assert!(parse_args(["--list-state"]).is_err());
+    }
+
+    #[test]
+    fn include_all_is_an_option_of_export_dot() {
+        let args = parse_args(["--export-dot"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::ExportDot { include_all: false }
+        ));
+        let args = parse_args(["--export-dot", "--include-all"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::ExportDot { include_all: true }
+        ));
+        assert!(parse_args(["--include-all", "--export-dot"]).is_err());
+    }
+
+    #[test]
+    fn show_and_stats_are_commands() {
+        let args = parse_args(["--show", "example.com"]).unwrap();
+        assert!(matches!(args.command, Command::Show { host } if host == "example.com"));
+
+        let args = parse_args(["--recheck", "example.com"]).unwrap();
+        assert!(matches!(args.command, Command::Recheck { host } if host == "example.com"));
+        assert!(parse_args(["--recheck", "example.com", "--show", "example.org"]).is_err());
+
+        let args = parse_args(["--stats"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::Stats {
+                format: stats::Format::Text
+            }
+        ));
+        let args = parse_args(["--stats", "--format", "json"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::Stats {
+                format: stats::Format::Json
+            }
+        ));
+        assert!(parse_args(["--stats", "--format", "yaml"]).is_err());
+        let args = parse_args(["--format", "json", "--stats"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::Stats {
+                format: stats::Format::Json
+            }
+        ));
+        assert!(parse_args(["--format", "json"]).is_err());
+        assert!(parse_args(["--format", "json", "--vacuum"]).is_err());
+
+        assert!(parse_args(["--stats", "--show", "example.com"]).is_err());
+        assert!(parse_args(["--stats", "--privacy-only"]).is_err());
+    }
+
+    #[test]
+    fn recheck_takes_the_options_that_affect_checks() {
+        let args = parse_args([
+            "--recheck",
+            "example.com",
+            "--max-depth",
+            "2",
+            "--software-period",
+            "mastodon=weekly",
+            "--peers-path",
+            "gotosocial=/api/v1/instance/peers",
+            "--strict-liveness",
+            "--tor-proxy",
+            "127.0.0.1:9050",
+            "--extra-ca-cert",
+            "/etc/ssl/own-ca.pem",
+            "--contact-url",
+            "mailto:admin@example.com",
+        ])
+        .unwrap();
+        let options = args.orchestrator_options;
+        assert_eq!(options.max_depth, Some(2));
+        assert_eq!(options.software_periods.len(), 1);
+        assert_eq!(options.peers_paths.len(), 1);
+        assert!(options.strict_liveness);
+        assert_eq!(options.tor_proxy.as_deref(), Some("127.0.0.1:9050"));
+        assert_eq!(options.extra_ca_certs.len(), 1);
+        assert!(options.contact_url.is_some());
+        assert_eq!(args.checker_options, checker::Options::default());
+
+        // Options that only matter to a running crawl are refused rather than ignored.
+        assert!(parse_args(["--recheck", "example.com", "--max-workers", "4"]).is_err());
+        assert!(parse_args(["--recheck", "example.com", "--rich-list"]).is_err());
+        assert!(parse_args(["--recheck", "example.com", "--timeout-secs", "5"]).is_err());
+    }
+
+    #[test]
+    fn vacuum_is_a_command() {
+        let args = parse_args(["--vacuum"]).unwrap();
+        assert!(matches!(args.command, Command::Vacuum));
+        assert!(parse_args(["--vacuum", "--stats"]).is_err());
+        assert!(parse_args(["--vacuum", "--once"]).is_err());
+    }
+
+    #[test]
+    fn unknown_suffixes_are_rejected_by_default() {
+        let args = parse_args(["--add-instances"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::AddInstances {
+                unknown_suffix_policy: domain::UnknownSuffixPolicy::Reject
             }
+        ));
+
+        let args = parse_args(["--add-instances", "--accept-unknown-suffixes"]).unwrap();
+        assert!(matches!(
+            args.command,
+            Command::AddInstances {
+                unknown_suffix_policy: domain::UnknownSuffixPolicy::AcceptPlausible
+            }
+        ));
+
+        assert!(parse_args(["--accept-unknown-suffixes"]).is_err());
+    }
+
+    #[test]
+    fn max_depth_is_only_for_crawling() {
+        let args = parse_args(["--max-depth", "2"]).unwrap();
+        assert_eq!(args.orchestrator_options.max_depth, Some(2));
+
+        assert!(parse_args(["--add-instances", "--max-depth", "2"]).is_err());
+    }
+
+    #[test]
+    fn min_alive_days_sets_min_alive_age() {
+        let args = parse_args(["--min-alive-days", "7"]).unwrap();
+        assert_eq!(
+            args.orchestrator_options.min_alive_age,
+            Duration::from_secs(7 * 24 * 60 * 60)
+        );
+
+        let args = parse_args([] as [&str; 0]).unwrap();
+        assert_eq!(args.orchestrator_options.min_alive_age, Duration::ZERO);
+    }
+
+    #[test]
+    fn deterministic_is_off_by_default_and_only_for_crawling() {
+        assert!(
+            !parse_args([] as [&str; 0])
+                .unwrap()
+                .orchestrator_options
+                .deterministic
+        );
+        assert!(
+            parse_args(["--deterministic"])
+                .unwrap()
+                .orchestrator_options
+                .deterministic
+        );
+        assert!(parse_args(["--stats", "--deterministic"]).is_err());
+    }
+
+    #[test]
+    fn test_instance_suffixes_are_normalized() {
+        let args = parse_args([
+            "--test-instance-suffix",
+            ".Staging.example.com",
+            "--test-instance-suffix",
+            "dev.example.org",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.orchestrator_options.test_instance_suffixes,
+            vec!["staging.example.com", "dev.example.org"]
+        );
+
+        assert!(parse_args(["--test-instance-suffix", "."]).is_err());
+        assert!(parse_args(["--stats", "--test-instance-suffix", "example.com"]).is_err());
+    }
+
+    #[test]
+    fn checker_exe_is_only_for_crawling() {
+        let args = parse_args(["--checker-exe", "/bin/sh"]).unwrap();
+        assert_eq!(
+            args.orchestrator_options.checker_exe,
+            Some("/bin/sh".into())
+        );
+
+        assert!(parse_args(["--check", "example.com", "--checker-exe", "/bin/sh"]).is_err());
+        // Typos are caught right away, rather than once our own executable is upgraded.
+        assert!(parse_args(["--checker-exe", "/usr/local/bin/no-such-crawler"]).is_err());
+        assert!(parse_args(["--checker-exe", "/etc/passwd"]).is_err());
+        assert!(parse_args(["--checker-exe", "/bin"]).is_err());
+    }
+
+    #[test]
+    fn timeout_secs_requires_check() {
+        assert!(parse_args(["--timeout-secs", "120"]).is_err());
+    }
+
+    #[test]
+    fn software_periods_are_keyed_by_family() {
+        let args = parse_args([
+            "--software-period",
+            "WriteFreely=weekly",
+            "--software-period",
+            "akkoma=daily",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.orchestrator_options.software_periods,
+            vec![
+                ("writefreely".to_string(), time::Period::Weekly),
+                ("pleroma".to_string(), time::Period::Daily),
+            ]
+        );
+
+        assert!(parse_args(["--software-period", "writefreely"]).is_err());
+        assert!(parse_args(["--software-period", "writefreely=monthly"]).is_err());
+        assert!(parse_args(["--stats", "--software-period", "writefreely=weekly"]).is_err());
+
+        let args = parse_args([
+            "--simulate-schedule",
+            "24",
+            "--software-period",
+            "writefreely=weekly",
+        ])
+        .unwrap();
+        assert!(matches!(
+            args.command,
+            Command::SimulateSchedule { hours: Some(24) }
+        ));
+        assert_eq!(
+            args.orchestrator_options.software_periods,
+            vec![("writefreely".to_string(), time::Period::Weekly)]
+        );
+        assert!(parse_args(["--simulate-schedule", "--max-workers", "3"]).is_err());
+    }
+
+    #[test]
+    fn checker_limits_are_for_crawling() {
+        let args = parse_args([
+            "--checker-memory-limit-mb",
+            "512",
+            "--checker-cpu-limit-secs",
+            "30",
+        ])
+        .unwrap();
+        let limits = args.orchestrator_options.checker_limits;
+        assert_eq!(limits.address_space, 512 * 1024 * 1024);
+        assert_eq!(limits.cpu_time, Duration::from_secs(30));
+
+        assert!(parse_args(["--checker-memory-limit-mb", "0"]).is_err());
+        assert!(parse_args(["--checker-cpu-limit-secs", "0"]).is_err());
+        assert!(parse_args(["--stats", "--checker-cpu-limit-secs", "30"]).is_err());
+
+        let args = parse_args(["--recompute-hidden", "--checker-cpu-limit-secs", "30"]).unwrap();
+        assert!(matches!(args.command, Command::RecomputeHidden));
+        assert_eq!(
+            args.orchestrator_options.checker_limits.cpu_time,
+            Duration::from_secs(30)
+        );
+        assert!(parse_args(["--recompute-hidden", "--max-workers", "3"]).is_err());
+    }
+
+    #[test]
+    fn recompute_hidden_runs_checkers_like_the_crawl() {
+        let args = parse_args([
+            "--recompute-hidden",
+            "--checker-exe",
+            "/bin/sh",
+            "--tor-proxy",
+            "127.0.0.1:9050",
+            "--extra-ca-cert",
+            "/etc/ssl/own-ca.pem",
+            "--contact-url",
+            "mailto:admin@example.com",
+        ])
+        .unwrap();
+        let options = args.orchestrator_options;
+        assert_eq!(options.checker_exe, Some("/bin/sh".into()));
+        assert_eq!(options.tor_proxy.as_deref(), Some("127.0.0.1:9050"));
+        assert_eq!(options.extra_ca_certs.len(), 1);
+        assert_eq!(
+            options.contact_url.as_deref(),
+            Some("mailto:admin@example.com")
+        );
+
+        assert!(parse_args(["--recompute-hidden", "--max-depth", "2"]).is_err());
+    }
+
+    #[test]
+    fn misplaced_options_are_listed_in_errors() {
+        let error = |args: &[&str]| parse_args(args).err().unwrap().to_string();
+
+        let crawl_only = error(&["--stats", "--once"]);
+        assert!(crawl_only.starts_with("--max-depth, --checker-exe, --checker-memory-limit-mb, "));
+        assert!(
+            crawl_only.ends_with(", --read-only and --metrics-addr can only be used when crawling")
+        );
+        assert_eq!(
+            error(&["--simulate-schedule", "--once"]),
+            "Only --software-period of the crawl's options can be used with --simulate-schedule"
+        );
+        assert_eq!(
+            error(&["--stats", "--tor-proxy", "127.0.0.1:9050"]),
+            "--peers-path, --strict-liveness, --tor-proxy, --extra-ca-cert and --contact-url can only be used when crawling, with --check, --recheck or --recompute-hidden"
+        );
+        assert_eq!(
+            error(&["--timings"]),
+            "--resolved-addresses, --privacy-only, --timeout-secs, --summary, --timings and --request-interval-ms can only be used with --check"
+        );
+    }
+
+    #[test]
+    fn strict_liveness_goes_to_the_checkers() {
+        let args = parse_args(["--strict-liveness"]).unwrap();
+        assert!(args.orchestrator_options.strict_liveness);
+
+        let args = parse_args(["--check", "example.com", "--strict-liveness"]).unwrap();
+        assert!(args.checker_options.strict_liveness);
+        assert_eq!(args.checker_options.to_args(), vec!["--strict-liveness"]);
+
+        assert!(parse_args(["--stats", "--strict-liveness"]).is_err());
+    }
+
+    #[test]
+    fn tor_proxy_goes_to_the_checkers() {
+        let args = parse_args(["--tor-proxy", "127.0.0.1:9050"]).unwrap();
+        assert_eq!(
+            args.orchestrator_options.tor_proxy.as_deref(),
+            Some("127.0.0.1:9050")
+        );
+
+        let args =
+            parse_args(["--check", "example.onion", "--tor-proxy", "localhost:9050"]).unwrap();
+        assert_eq!(
+            args.checker_options.to_args(),
+            vec!["--tor-proxy", "localhost:9050"]
+        );
+
+        assert!(parse_args(["--tor-proxy", "localhost"]).is_err());
+        assert!(parse_args(["--tor-proxy", ":9050"]).is_err());
+        assert!(parse_args(["--tor-proxy", "localhost:tor"]).is_err());
+        assert!(parse_args(["--stats", "--tor-proxy", "localhost:9050"]).is_err());
+    }
+
+    #[test]
+    fn extra_ca_certs_go_to_the_checkers() {
+        let args = parse_args([
+            "--extra-ca-cert",
+            "/etc/ssl/ours.pem",
+            "--extra-ca-cert",
+            "theirs.pem",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.orchestrator_options.extra_ca_certs,
+            vec![
+                PathBuf::from("/etc/ssl/ours.pem"),
+                PathBuf::from("theirs.pem")
+            ]
+        );
+
+        let args = parse_args(["--check", "example.com", "--extra-ca-cert", "ours.pem"]).unwrap();
+        assert_eq!(
+            args.checker_options.to_args(),
+            vec!["--extra-ca-cert", "ours.pem"]
+        );
+
+        assert!(parse_args(["--stats", "--extra-ca-cert", "ours.pem"]).is_err());
+        assert!(parse_args(["--extra-ca-cert"]).is_err());
+    }
+
+    #[test]
+    fn contact_url_goes_to_the_checkers() {
+        let args = parse_args(["--contact-url", "https://crawler.example.com/about"]).unwrap();
+        assert_eq!(
+            args.orchestrator_options.contact_url.as_deref(),
+            Some("https://crawler.example.com/about")
+        );
+
+        let args = parse_args([
+            "--check",
+            "example.com",
+            "--contact-url",
+            "mailto:crawler@example.com",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.checker_options.to_args(),
+            vec!["--contact-url", "mailto:crawler@example.com"]
+        );
+
+        assert!(parse_args(["--contact-url", "crawler@example.com"]).is_err());
+        assert!(parse_args(["--contact-url", "ftp://example.com"]).is_err());
+        assert!(parse_args(["--stats", "--contact-url", "https://example.com"]).is_err());
+    }
+
+    #[test]
+    fn peers_paths_go_to_the_checkers() {
+        let args = parse_args(["--peers-path", "Misskey=/api/federation/peers"]).unwrap();
+        assert_eq!(
+            args.orchestrator_options.peers_paths,
+            vec![("misskey".to_string(), "/api/federation/peers".to_string())]
+        );
+
+        let args = parse_args([
+            "--check",
+            "example.com",
+            "--peers-path",
+            "misskey=/api/federation/peers",
+        ])
+        .unwrap();
+        assert_eq!(args.checker_options.peers_paths.len(), 1);
+        assert_eq!(
+            args.checker_options.to_args(),
+            vec!["--peers-path", "misskey=/api/federation/peers"]
+        );
+
+        assert!(parse_args(["--peers-path", "misskey"]).is_err());
+        assert!(parse_args(["--peers-path", "misskey=api/peers"]).is_err());
+        assert!(parse_args(["--add-instances", "--peers-path", "misskey=/peers"]).is_err());
+    }
+
+    #[test]
+    fn worker_pool_is_configurable() {
+        let args = parse_args(["--once"]).unwrap();
+        assert_eq!(
+            args.orchestrator_options.workers,
+            orchestrator::Workers::default()
+        );
+
+        let args = parse_args([
+            "--min-workers",
+            "2",
+            "--max-workers",
+            "16",
+            "--worker-idle-secs",
+            "30",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.orchestrator_options.workers,
+            orchestrator::Workers {
+                min: 2,
+                max: 16,
+                idle_time: Duration::from_secs(30),
+            }
+        );
+
+        let args = parse_args(["--max-workers", "1"]).unwrap();
+        assert_eq!(args.orchestrator_options.workers.max, 1);
+
+        assert!(parse_args(["--min-workers", "200"]).is_err());
+        assert!(parse_args(["--min-workers", "8", "--max-workers", "4"]).is_err());
+        assert!(parse_args(["--max-workers", "0"]).is_err());
+        assert!(parse_args(["--max-workers", "-1"]).is_err());
+        assert!(parse_args(["--stats", "--max-workers", "4"]).is_err());
+    }
+
+    #[test]
+    fn request_rate_is_shared_by_the_checks() {
+        let args = parse_args(["--once"]).unwrap();
+        assert_eq!(args.orchestrator_options.check_interval(), None);
+        assert_eq!(args.orchestrator_options.checker_request_interval(), None);
+
+        // The number of workers doesn't matter.
+        for workers in ["1", "128"] {
+            let args = parse_args(["--max-workers", workers, "--max-rps", "8"]).unwrap();
+            assert_eq!(args.orchestrator_options.max_rps, Some(8));
+            assert_eq!(
+                args.orchestrator_options.check_interval(),
+                Some(Duration::from_secs(1))
+            );
+            assert_eq!(
+                args.orchestrator_options.checker_request_interval(),
+                Some(Duration::from_millis(125))
+            );
         }
+
+        // Rounded up, so that the checkers never go faster than asked.
+        let args = parse_args(["--max-rps", "3"]).unwrap();
+        assert_eq!(
+            args.orchestrator_options.check_interval(),
+            Some(Duration::from_millis(2667))
+        );
+        assert_eq!(
+            args.orchestrator_options.checker_request_interval(),
+            Some(Duration::from_millis(334))
+        );
+
+        let args = parse_args(["--check", "example.com", "--request-interval-ms", "250"]).unwrap();
+        assert_eq!(
+            args.checker_options.to_args(),
+            vec!["--request-interval-ms", "250"]
+        );
+
+        assert!(parse_args(["--max-rps", "0"]).is_err());
+        assert!(parse_args(["--stats", "--max-rps", "8"]).is_err());
+        assert!(parse_args(["--request-interval-ms", "250"]).is_err());
+        assert!(parse_args(["--check", "example.com", "--request-interval-ms", "0"]).is_err());
+    }
+
+    #[test]
+    fn periods_are_configurable() {
+        let args = parse_args(["--once"]).unwrap();
+        assert_eq!(args.orchestrator_options.periods, time::Periods::default());
+
+        let args = parse_args([
+            "--daily-period-hours",
+            "5",
+            "--weekly-period-hours",
+            "23",
+            "--list-period-minutes",
+            "59",
+        ])
+        .unwrap();
+        assert_eq!(
+            args.orchestrator_options.periods,
+            time::Periods {
+                daily: Duration::from_secs(5 * 60 * 60),
+                weekly: Duration::from_secs(23 * 60 * 60),
+                list_generation: Duration::from_secs(59 * 60),
+            }
+        );
+
+        assert!(parse_args(["--daily-period-hours", "0"]).is_err());
+        assert!(parse_args(["--list-period-minutes", "0"]).is_err());
+        assert!(parse_args(["--daily-period-hours", "200"]).is_err());
+        assert!(parse_args(["--stats", "--list-period-minutes", "59"]).is_err());
+    }
+
+    #[test]
+    fn single_iteration_is_not_once() {
+        let args = parse_args(["--single-iteration"]).unwrap();
+        assert!(args.orchestrator_options.single_iteration);
+        assert!(!args.orchestrator_options.once);
+
+        assert!(parse_args(["--once", "--single-iteration"]).is_err());
+        assert!(parse_args(["--stats", "--single-iteration"]).is_err());
+    }
+
+    #[test]
+    fn metrics_are_off_by_default() {
+        let args = parse_args(["--once"]).unwrap();
+        assert_eq!(args.orchestrator_options.metrics_addr, None);
+
+        let args = parse_args(["--metrics-addr", "127.0.0.1:9100"]).unwrap();
+        assert_eq!(
+            args.orchestrator_options.metrics_addr,
+            Some("127.0.0.1:9100".parse().unwrap())
+        );
+
+        assert!(parse_args(["--metrics-addr", "localhost"]).is_err());
+        assert!(parse_args(["--stats", "--metrics-addr", "127.0.0.1:9100"]).is_err());
+    }
+
+    #[test]
+    fn read_only_is_for_crawling() {
+        let args = parse_args(["--once", "--read-only"]).unwrap();
+        assert!(args.orchestrator_options.read_only);
+
+        assert!(parse_args(["--read-only"]).is_err());
+        assert!(parse_args(["--single-iteration", "--read-only"]).is_err());
+        assert!(parse_args(["--stats", "--read-only"]).is_err());
+    }
+
+    #[test]
+    fn verify_dns_on_add_is_for_crawling() {
+        let args = parse_args(["--verify-dns-on-add"]).unwrap();
+        assert!(args.orchestrator_options.verify_dns_on_add);
+
+        assert!(parse_args(["--add-instances", "--verify-dns-on-add"]).is_err());
+    }
+
+    #[test]
+    fn summary_requires_check() {
+        let args = parse_args(["--check", "example.com", "--summary"]).unwrap();
+        assert!(args.checker_options.summary);
+
+        assert!(parse_args(["--summary"]).is_err());
+    }
+
+    #[test]
+    fn check_can_print_a_json_summary() {
+        let args = parse_args(["--check", "example.com"]).unwrap();
+        assert_eq!(args.checker_options.format, checker::Format::Ipc);
+
+        let args = parse_args(["--check", "example.com", "--format", "json"]).unwrap();
+        assert_eq!(args.checker_options.format, checker::Format::Json);
+        assert_eq!(args.checker_options.to_args(), vec!["--format", "json"]);
+
+        assert!(parse_args(["--check", "example.com", "--format", "text"]).is_err());
+        let args = parse_args(["--format", "json", "--check", "example.com"]).unwrap();
+        assert_eq!(args.checker_options.format, checker::Format::Json);
+        // "ipc" is only known to --check.
+        assert!(parse_args(["--format", "ipc", "--stats"]).is_err());
+    }
+
+    #[test]
+    fn timings_require_check() {
+        let args = parse_args(["--check", "example.com", "--timings"]).unwrap();
+        assert!(args.checker_options.timings);
+        assert!(parse_args(["--timings"]).is_err());
     }
 }
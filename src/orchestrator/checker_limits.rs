@@ -0,0 +1,108 @@
+//! Limits on the resources of checker processes.
+//!
+//! A checker that goes haywire, e.g. because of a parser bug that makes it allocate without end,
+//! should die alone rather than take the whole host down with it. The limits are set between
+//! `fork` and `exec`, so they apply to the checker and nothing else.
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckerLimits {
+    /// The most virtual memory a checker can map, in bytes. This counts reservations too, like
+    /// thread stacks and malloc arenas, so it's well above what a checker actually uses.
+    pub address_space: u64,
+
+    /// The most CPU time a checker can use. Checkers mostly wait for the network, so this is far
+    /// above what a check normally takes.
+    pub cpu_time: Duration,
+}
+
+impl Default for CheckerLimits {
+    fn default() -> Self {
+        Self {
+            address_space: 1024 * 1024 * 1024,
+            cpu_time: Duration::from_secs(60),
+        }
+    }
+}
+
+impl CheckerLimits {
+    /// Make the process spawned by `command` run under these limits.
+    pub fn apply(self, command: &mut Command) {
+        // SAFETY: the closure runs in the child between `fork` and `exec`, where only
+        // async-signal-safe functions may be called. It only calls `setrlimit`, which is one, and
+        // doesn't allocate.
+        unsafe {
+            command.pre_exec(move || self.set());
+        }
+    }
+
+    fn set(self) -> std::io::Result<()> {
+        let address_space = rlimit(self.address_space);
+        // SAFETY: the pointer is to a valid `rlimit` that outlives the call.
+        if unsafe { libc::setrlimit(libc::RLIMIT_AS, &address_space) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let cpu_time = rlimit(self.cpu_time.as_secs());
+        // SAFETY: the pointer is to a valid `rlimit` that outlives the call.
+        if unsafe { libc::setrlimit(libc::RLIMIT_CPU, &cpu_time) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Both the soft and the hard limit set to `limit`, or to infinity if `limit` doesn't fit.
+fn rlimit(limit: u64) -> libc::rlimit {
+    let limit = libc::rlim_t::try_from(limit).unwrap_or(libc::RLIM_INFINITY);
+    libc::rlimit {
+        rlim_cur: limit,
+        rlim_max: limit,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::process::Stdio;
+
+    /// Run a shell that needs about 64 MiB of memory, and return what it printed.
+    fn run_memory_hog(limits: CheckerLimits) -> (String, bool) {
+        let mut command = Command::new("sh");
+        command
+            .args([
+                "-c",
+                r#"hog=$(head -c 67108864 /dev/zero | tr '\0' x); echo "survived ${#hog}""#,
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        limits.apply(&mut command);
+        let mut child = command.spawn().unwrap();
+        let mut stdout = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut stdout)
+            .unwrap();
+        let status = child.wait().unwrap();
+        (stdout, status.success())
+    }
+
+    #[test]
+    fn checker_exceeding_the_memory_limit_dies_alone() {
+        let (stdout, success) = run_memory_hog(CheckerLimits::default());
+        assert!(success);
+        assert_eq!(stdout.trim(), "survived 67108864");
+
+        let (stdout, success) = run_memory_hog(CheckerLimits {
+            address_space: 32 * 1024 * 1024,
+            ..Default::default()
+        });
+        assert!(!success);
+        assert!(stdout.is_empty());
+    }
+}
@@ -0,0 +1,215 @@
+//! A cache of DNS resolution results.
+//!
+//! Checkers are short-lived processes, so they can't cache anything themselves. Instead, the
+//! Orchestrator resolves the host before spawning a checker, and passes the addresses to it.
+//!
+//! The system resolver doesn't tell us the TTLs of the records, so we use a fixed one.
+//!
+//! Failures are only remembered by [`DnsCache::resolves`], and only for [`NEGATIVE_TTL`]: long
+//! enough to not look up the same dead domain over and over while adding peers, but short enough
+//! that a check doesn't trip over a failure that has since been fixed.
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::panic::RefUnwindSafe;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a resolution result is considered fresh.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// How long a failure to resolve is remembered.
+const NEGATIVE_TTL: Duration = Duration::from_secs(60);
+
+/// When the cache grows beyond this many entries, expired entries are purged.
+const MAX_ENTRIES: usize = 10_000;
+
+type ResolveFn = dyn Fn(&str) -> std::io::Result<Vec<IpAddr>> + Send + Sync + RefUnwindSafe;
+
+struct CacheEntry {
+    addresses: Vec<IpAddr>,
+    expires_at: Instant,
+}
+
+pub struct DnsCache {
+    ttl: Duration,
+    resolve: Box<ResolveFn>,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    /// Hosts that failed to resolve, and when that failure expires.
+    failures: Mutex<HashMap<String, Instant>>,
+}
+
+impl DnsCache {
+    /// Create a cache that uses the system resolver.
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_resolver(ttl, Box::new(resolve_with_system_resolver))
+    }
+
+    /// Create a cache that uses the given `resolve` function instead of the system resolver.
+    pub fn with_resolver(ttl: Duration, resolve: Box<ResolveFn>) -> Self {
+        Self {
+            ttl,
+            resolve,
+            entries: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns `true` if the `host` resolves to at least one address.
+    pub fn resolves(&self, host: &str) -> anyhow::Result<bool> {
+        let now = Instant::now();
+        {
+            let failures = self
+                .failures
+                .lock()
+                .map_err(|_| anyhow!("DNS cache mutex is poisoned"))?;
+            if failures
+                .get(host)
+                .is_some_and(|expires_at| *expires_at > now)
+            {
+                return Ok(false);
+            }
+        }
+
+        match self.resolve(host) {
+            Ok(addresses) if !addresses.is_empty() => return Ok(true),
+            Ok(_) => {}
+            Err(e) if e.downcast_ref::<std::io::Error>().is_some() => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut failures = self
+            .failures
+            .lock()
+            .map_err(|_| anyhow!("DNS cache mutex is poisoned"))?;
+        if failures.len() >= MAX_ENTRIES {
+            failures.retain(|_, expires_at| *expires_at > now);
+        }
+        if let Some(expires_at) = now.checked_add(NEGATIVE_TTL) {
+            failures.insert(host.to_owned(), expires_at);
+        }
+        Ok(false)
+    }
+
+    /// Get the addresses of the `host`, either from the cache or from the resolver.
+    pub fn resolve(&self, host: &str) -> anyhow::Result<Vec<IpAddr>> {
+        let now = Instant::now();
+        {
+            let entries = self
+                .entries
+                .lock()
+                .map_err(|_| anyhow!("DNS cache mutex is poisoned"))?;
+            if let Some(entry) = entries.get(host) {
+                if entry.expires_at > now {
+                    return Ok(entry.addresses.clone());
+                }
+            }
+        }
+
+        // The lock is not held while resolving, so other threads can use the cache meanwhile.
+        let addresses = (self.resolve)(host)?;
+
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|_| anyhow!("DNS cache mutex is poisoned"))?;
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, entry| entry.expires_at > now);
+        }
+        if let Some(expires_at) = now.checked_add(self.ttl) {
+            entries.insert(
+                host.to_owned(),
+                CacheEntry {
+                    addresses: addresses.clone(),
+                    expires_at,
+                },
+            );
+        }
+
+        Ok(addresses)
+    }
+}
+
+fn resolve_with_system_resolver(host: &str) -> std::io::Result<Vec<IpAddr>> {
+    // The port doesn't matter, it's only needed to satisfy the API.
+    Ok((host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect())
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    fn counting_cache(ttl: Duration) -> (DnsCache, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = {
+            let calls = calls.clone();
+            DnsCache::with_resolver(
+                ttl,
+                Box::new(move |_host| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Ok(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))])
+                }),
+            )
+        };
+        (cache, calls)
+    }
+
+    #[test]
+    fn reuses_entries_within_ttl() {
+        let (cache, calls) = counting_cache(Duration::from_secs(60));
+
+        let first = cache.resolve("example.com").unwrap();
+        let second = cache.resolve("example.com").unwrap();
+
+        assert_eq!(first, vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))]);
+        assert_eq!(first, second);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        cache.resolve("example.org").unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn failures_are_remembered_briefly() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = {
+            let calls = calls.clone();
+            DnsCache::with_resolver(
+                Duration::from_secs(60),
+                Box::new(move |host| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    if host == "example.com" {
+                        Ok(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))])
+                    } else {
+                        Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+                    }
+                }),
+            )
+        };
+
+        assert!(cache.resolves("example.com").unwrap());
+        assert!(!cache.resolves("gone.example.org").unwrap());
+        assert!(!cache.resolves("gone.example.org").unwrap());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Only `resolves` looks at the failures; a check tries again.
+        assert!(cache.resolve("gone.example.org").is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn resolves_again_after_ttl() {
+        let (cache, calls) = counting_cache(Duration::ZERO);
+
+        cache.resolve("example.com").unwrap();
+        cache.resolve("example.com").unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
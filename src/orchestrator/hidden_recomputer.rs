@@ -0,0 +1,167 @@
+//! Re-derive the "hide from list" flag of all alive instances.
+//!
+//! The flag is normally updated only when an instance is checked. If the privacy detection
+//! changes (e.g. it learns about a new software), existing instances keep the stale flag until
+//! their next check; this module lets the operator update them right away.
+use crate::{
+    checker,
+    domain::Domain,
+    ipc,
+    orchestrator::{
+        checker_limits::CheckerLimits,
+        db,
+        instance_checker::{self, CheckerHandle},
+        Options,
+    },
+    with_loc,
+};
+use anyhow::{anyhow, Context};
+use rusqlite::Connection;
+use slog::{error, info, o, Logger};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::Duration;
+
+/// Pause between checks, so that re-checking all instances doesn't create a load spike.
+const PAUSE_BETWEEN_CHECKS: Duration = Duration::from_secs(1);
+
+/// The checkers are run the way the crawl runs them: from the `checker_exe` of the `options`,
+/// within its `checker_limits`, and with the checker options that follow from it.
+pub fn main(logger: Logger, options: &Options) -> anyhow::Result<()> {
+    let mut conn = db::open()?;
+    db::init(&mut conn)?;
+
+    let checker_options = checker::Options {
+        privacy_only: true,
+        ..instance_checker::checker_options(options)
+    };
+    recompute(&logger, &mut conn, PAUSE_BETWEEN_CHECKS, |instance| {
+        check_privacy(
+            &logger,
+            options.checker_exe.as_deref(),
+            options.checker_limits,
+            &checker_options,
+            instance,
+        )
+    })
+}
+
+/// Update the flag of every alive instance, using `check` to find out the new value.
+///
+/// `check` returns `None` if the instance isn't alive anymore; such instances are left alone
+/// until their next scheduled check.
+fn recompute(
+    logger: &Logger,
+    conn: &mut Connection,
+    pause: Duration,
+    mut check: impl FnMut(&Domain) -> anyhow::Result<Option<bool>>,
+) -> anyhow::Result<()> {
+    let instances = db::on_sqlite_busy_retry_indefinitely(&mut || db::get_alive_instances(conn))
+        .context(with_loc!("Getting the list of alive instances"))?;
+    info!(
+        logger,
+        "Re-checking privacy settings of {} instances",
+        instances.len()
+    );
+
+    for instance in instances {
+        match check(&instance) {
+            Ok(Some(hide_from_list)) => {
+                db::on_sqlite_busy_retry_indefinitely(&mut || {
+                    db::set_hidden(conn, &instance, hide_from_list)
+                })
+                .context(with_loc!("Updating the flag"))?;
+                info!(logger, "{}: hide_from_list = {}", instance, hide_from_list);
+            }
+            Ok(None) => info!(
+                logger,
+                "{} didn't respond as an alive instance, skipping", instance
+            ),
+            Err(e) => error!(logger, "Failed to check {}: {:?}", instance, e),
+        }
+
+        std::thread::sleep(pause);
+    }
+
+    Ok(())
+}
+
+/// Ask a checker if the instance wants to be hidden from the list.
+fn check_privacy(
+    logger: &Logger,
+    checker_exe: Option<&Path>,
+    limits: CheckerLimits,
+    options: &checker::Options,
+    instance: &Domain,
+) -> anyhow::Result<Option<bool>> {
+    let logger = logger.new(o!("host" => instance.to_string()));
+    let mut checker = CheckerHandle::new(logger, instance.clone(), checker_exe, limits, options)?;
+    let output = checker
+        .inner
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("Failed to connect to checker's stdout"))?;
+
+    let mut lines = BufReader::new(output).lines();
+    let Some(hello) = lines.next() else {
+        return Ok(None);
+    };
+    let hello = hello.context(with_loc!("Failed to read a line of checker's response"))?;
+    let hello = serde_json::from_str(&hello)
+        .context(with_loc!("Failed to deserialize checker's response"))?;
+    ipc::check_hello(&hello)?;
+
+    let Some(line) = lines.next() else {
+        return Ok(None);
+    };
+    let line = line.context(with_loc!("Failed to read a line of checker's response"))?;
+    let response = serde_json::from_str(&line)
+        .context(with_loc!("Failed to deserialize checker's response"))?;
+    match response {
+        ipc::CheckerResponse::State {
+            state: ipc::InstanceState::Alive { hide_from_list },
+        } => Ok(Some(hide_from_list)),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use rusqlite::params;
+
+    fn is_hidden(conn: &Connection, instance: &Domain) -> bool {
+        conn.query_row(
+            "SELECT hide_from_list
+            FROM hidden_instances
+                JOIN instances ON instances.id = hidden_instances.instance
+            WHERE hostname = ?1",
+            params![instance.to_string()],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn updates_the_flag_of_alive_instances() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+
+        let alive = Domain::from_str("alive.example.com").unwrap();
+        let gone = Domain::from_str("gone.example.com").unwrap();
+        for instance in [&alive, &gone] {
+            db::add_instance(&conn, instance).unwrap();
+            db::mark_alive(&mut conn, instance, false).unwrap();
+        }
+
+        recompute(&logger, &mut conn, Duration::ZERO, |instance| {
+            Ok((instance == &alive).then_some(true))
+        })
+        .unwrap();
+
+        assert!(is_hidden(&conn, &alive));
+        assert!(!is_hidden(&conn, &gone));
+    }
+}
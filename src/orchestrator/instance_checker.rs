@@ -1,52 +1,250 @@
-use crate::{domain::Domain, ipc, orchestrator::db, with_loc};
+use crate::{
+    checker,
+    domain::Domain,
+    ipc,
+    orchestrator::{
+        checker_limits::CheckerLimits, db, dns_cache::DnsCache, metrics::Metrics, Options,
+    },
+    with_loc,
+};
 use anyhow::{anyhow, bail, Context};
 use rusqlite::Connection;
-use slog::{error, info, Logger};
+use slog::{error, info, warn, Logger};
 use std::env;
-use std::io::{BufRead, BufReader};
-use std::process::{Child, Command, Stdio};
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStderr, Command, ExitStatus, Stdio};
+use std::thread::JoinHandle;
 
-pub fn run(logger: Logger, instance: Domain) -> anyhow::Result<()> {
-    let mut conn = db::open()?;
+/// How much of the checker's stderr is kept for the logs. Panic messages come first, so that's
+/// the part we keep; the rest is read and thrown away, so that the checker doesn't block on
+/// a full pipe.
+const MAX_STDERR_LENGTH: u64 = 16 * 1024;
+
+/// A failure on the Orchestrator's side rather than the instance's. The check can be retried
+/// without waiting for its next scheduled time.
+#[derive(Debug)]
+pub struct OrchestratorSideFailure;
+
+impl std::fmt::Display for OrchestratorSideFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The check failed due to a problem on the Orchestrator's side"
+        )
+    }
+}
+
+/// Returns `true` if the check failed because of us rather than the instance.
+pub fn is_orchestrator_side_failure(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<OrchestratorSideFailure>().is_some() || db::is_sqlite_busy_error(error)
+}
+
+pub fn run(
+    logger: Logger,
+    instance: Domain,
+    dns_cache: &DnsCache,
+    metrics: &Metrics,
+    options: &Options,
+) -> anyhow::Result<()> {
+    let mut conn = if options.read_only {
+        db::open_read_only()
+    } else {
+        db::open()
+    }
+    .context(OrchestratorSideFailure)?;
     println!("Checking {}", instance);
 
-    let mut checker = CheckerHandle::new(logger.clone(), instance.clone())?;
-    process_checker_response(&logger, &mut conn, &instance, &mut checker.inner)?;
+    // Onion services aren't in the DNS, and looking them up would tell the resolver which ones we
+    // visit. The Tor proxy resolves them instead.
+    let addresses = if instance.is_onion() {
+        vec![]
+    } else {
+        match dns_cache.resolve(&instance.to_string()) {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                // The checker will try to resolve the host itself, and fail the check if it can't.
+                info!(logger, "Failed to resolve {}: {:?}", instance, e);
+                vec![]
+            }
+        }
+    };
+
+    let checker_options = checker::Options {
+        addresses,
+        ..checker_options(options)
+    };
+    let mut checker = CheckerHandle::new(
+        logger.clone(),
+        instance.clone(),
+        options.checker_exe.as_deref(),
+        options.checker_limits,
+        &checker_options,
+    )
+    .inspect_err(|_| metrics.record_checker_spawn_failure())
+    .context(OrchestratorSideFailure)?;
+    metrics.record_check();
+    process_checker_response(
+        &logger,
+        &mut conn,
+        &instance,
+        &mut checker.inner,
+        dns_cache,
+        options,
+    )?;
 
     Ok(())
 }
 
-struct CheckerHandle {
-    inner: Child,
+/// The checker options that follow from the crawl's `options`.
+pub fn checker_options(options: &Options) -> checker::Options {
+    checker::Options {
+        peers_paths: options.peers_paths.clone(),
+        strict_liveness: options.strict_liveness,
+        tor_proxy: options.tor_proxy.clone(),
+        extra_ca_certs: options.extra_ca_certs.clone(),
+        request_interval: options.checker_request_interval(),
+        contact_url: options.contact_url.clone(),
+        ..Default::default()
+    }
+}
+
+/// The executable to run checkers from: the `given` one if any, or else our own.
+pub fn checker_exe(given: Option<&Path>) -> anyhow::Result<PathBuf> {
+    resolve_checker_exe(env::current_exe(), given)
+}
+
+fn resolve_checker_exe(
+    current_exe: std::io::Result<PathBuf>,
+    given: Option<&Path>,
+) -> anyhow::Result<PathBuf> {
+    if let Some(given) = given {
+        ensure_executable(given)?;
+        return Ok(given.to_path_buf());
+    }
+    match current_exe {
+        Ok(exe_path) if !was_deleted(&exe_path) => Ok(exe_path),
+        Ok(exe_path) => bail!(
+            "Our own executable was deleted, e.g. by an upgrade: {}; pass the path to the new one via --checker-exe",
+            exe_path.display()
+        ),
+        Err(e) => Err(anyhow::Error::new(e).context(with_loc!(
+            "Failed to find our own executable; pass its path via --checker-exe"
+        ))),
+    }
+}
+
+/// Whether our own executable at `exe_path` is gone. On Linux, the path of a deleted executable
+/// gets a " (deleted)" suffix, so it doesn't point at anything, or worse, points at a different
+/// file that happens to be named like that.
+fn was_deleted(exe_path: &Path) -> bool {
+    exe_path.to_string_lossy().ends_with(" (deleted)") || !exe_path.exists()
+}
+
+/// Check that checkers can be run from the `path`; see [`super::Options::checker_exe`].
+pub fn ensure_executable(path: &Path) -> anyhow::Result<()> {
+    let metadata =
+        std::fs::metadata(path).with_context(|| format!("Failed to access {}", path.display()))?;
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        bail!("{} is not an executable file", path.display());
+    }
+    Ok(())
+}
+
+pub struct CheckerHandle {
+    pub inner: Child,
     logger: Logger,
     instance: Domain,
+    /// Collects the checker's stderr, which is logged if the checker fails.
+    stderr: Option<JoinHandle<String>>,
 }
 
 impl CheckerHandle {
-    fn new(logger: Logger, instance: Domain) -> anyhow::Result<Self> {
-        let exe_path = env::current_exe()?;
+    pub fn new(
+        logger: Logger,
+        instance: Domain,
+        given_checker_exe: Option<&Path>,
+        limits: CheckerLimits,
+        options: &checker::Options,
+    ) -> anyhow::Result<Self> {
+        let exe_path = checker_exe(given_checker_exe)?;
 
-        let inner = Command::new(exe_path)
+        let mut command = Command::new(exe_path);
+        command
             .arg("--check")
             .arg(instance.to_string())
+            .args(options.to_args());
+        limits.apply(&mut command);
+        Self::spawn(logger, instance, command)
+    }
+
+    /// Run the `command` as a checker.
+    fn spawn(logger: Logger, instance: Domain, mut command: Command) -> anyhow::Result<Self> {
+        let mut inner = command
             .stdin(Stdio::null())
             .stdout(Stdio::piped())
-            .stderr(Stdio::null())
+            .stderr(Stdio::piped())
             .spawn()
             .context(with_loc!("Failed to spawn a checker"))?;
-
+        let stderr = inner
+            .stderr
+            .take()
+            .map(|stderr| std::thread::spawn(move || read_stderr(stderr)));
         Ok(Self {
             inner,
             logger,
             instance,
+            stderr,
         })
     }
 }
 
+impl CheckerHandle {
+    /// Log the checker's stderr if it didn't exit successfully, e.g. because it panicked.
+    fn log_stderr_on_failure(&mut self, status: Option<ExitStatus>) {
+        let Some(stderr) = self.stderr.take() else {
+            return;
+        };
+        // The checker is gone, so its stderr is closed and the thread is about to finish.
+        let stderr = match stderr.join() {
+            Ok(stderr) => stderr,
+            Err(e) => {
+                error!(
+                    self.logger,
+                    "Failed to read the stderr of the checker for {}: {:?}", self.instance, e
+                );
+                return;
+            }
+        };
+        if status.is_some_and(|status| status.success()) || stderr.trim().is_empty() {
+            return;
+        }
+        let status = status.map_or_else(|| "unknown status".to_string(), |s| s.to_string());
+        error!(
+            self.logger,
+            "The checker for {} failed ({}), its stderr:\n{}", self.instance, status, stderr
+        );
+    }
+}
+
+/// Read the checker's stderr until it's closed, keeping the first [`MAX_STDERR_LENGTH`] bytes.
+fn read_stderr(stderr: ChildStderr) -> String {
+    let mut stderr = BufReader::new(stderr);
+    let mut kept = vec![];
+    // Errors only mean that we won't have the checker's stderr, which is not worth reporting.
+    let _ = (&mut stderr).take(MAX_STDERR_LENGTH).read_to_end(&mut kept);
+    let _ = std::io::copy(&mut stderr, &mut std::io::sink());
+    String::from_utf8_lossy(&kept).into_owned()
+}
+
 impl Drop for CheckerHandle {
     fn drop(&mut self) {
         match self.inner.try_wait() {
-            Ok(Some(_)) => return,
+            Ok(Some(status)) => {
+                self.log_stderr_on_failure(Some(status));
+                return;
+            }
             Ok(None) => {}
             Err(e) => {
                 error!(
@@ -63,11 +261,17 @@ impl Drop for CheckerHandle {
             );
         }
 
-        if let Err(e) = self.inner.wait() {
-            error!(
-                self.logger,
-                "Failed to wait for the checker for {} to exit after kill: {}", self.instance, e
-            );
+        match self.inner.wait() {
+            Ok(status) => self.log_stderr_on_failure(Some(status)),
+            Err(e) => {
+                error!(
+                    self.logger,
+                    "Failed to wait for the checker for {} to exit after kill: {}",
+                    self.instance,
+                    e
+                );
+                self.log_stderr_on_failure(None);
+            }
         }
     }
 }
@@ -77,40 +281,115 @@ fn process_checker_response(
     conn: &mut Connection,
     target: &Domain,
     checker: &mut Child,
+    dns_cache: &DnsCache,
+    options: &Options,
 ) -> anyhow::Result<()> {
     let output = checker
         .stdout
         .take()
         .ok_or_else(|| anyhow!("Failed to connect to checker's stdout"))?;
     let reader = BufReader::new(output);
-    let mut lines = reader.lines();
+    let reported =
+        apply_checker_responses(logger, conn, target, reader.lines(), dns_cache, options)?;
+    if !reported {
+        // The checker closed its stdout, so it's exiting.
+        let reason = silence_reason(checker.wait().ok());
+        mark_unreported_dead(logger, conn, target, options, reason)?;
+    }
+    Ok(())
+}
 
+/// Mark the instance dead because the checker exited without reporting its state.
+pub fn mark_unreported_dead(
+    logger: &Logger,
+    conn: &mut Connection,
+    target: &Domain,
+    options: &Options,
+    reason: db::DeathReason,
+) -> anyhow::Result<()> {
+    info!(
+        logger,
+        "No response from checker, marking the instance as dead ({})", reason
+    );
+    update_db(logger, conn, options, "mark the instance dead", |conn| {
+        db::mark_dead(conn, target, reason)
+    })
+}
+
+/// Apply the checker's responses, serialized one per line, to the database.
+///
+/// Returns `false` if the checker didn't report the instance's state, which is for the caller to
+/// deal with.
+pub fn apply_checker_responses(
+    logger: &Logger,
+    conn: &mut Connection,
+    target: &Domain,
+    mut lines: impl Iterator<Item = std::io::Result<String>>,
+    dns_cache: &DnsCache,
+    options: &Options,
+) -> anyhow::Result<bool> {
     let state = {
-        if let Some(line) = lines.next() {
+        // The checker greets us first, unless it exits right away.
+        let mut line = lines.next();
+        if let Some(hello) = line {
+            let hello = hello.context(with_loc!("Failed to read a line of checker's response"))?;
+            let hello = serde_json::from_str(&hello)
+                .context(with_loc!("Failed to deserialize checker's response"))?;
+            ipc::check_hello(&hello)?;
+            line = lines.next();
+        }
+
+        if let Some(line) = line {
             let line = line.context(with_loc!("Failed to read a line of checker's response"))?;
             serde_json::from_str(&line)
                 .context(with_loc!("Failed to deserialize checker's response"))?
         } else {
-            info!(
-                logger,
-                "No response from checker, marking the instance as dead"
-            );
-
-            return db::on_sqlite_busy_retry(&mut || db::mark_dead(conn, target));
+            return Ok(false);
         }
     };
 
     match state {
+        ipc::CheckerResponse::Hello { .. } => {
+            update_db(logger, conn, options, "mark the instance dead", |conn| {
+                db::mark_dead(conn, target, db::DeathReason::BadIpc)
+            })?;
+            bail!("Expected the checker to respond with State, but it responded with Hello again");
+        }
+        ipc::CheckerResponse::Dead { reason, cause } => {
+            let msg = format!("{} is dead: {}", target, ipc::sanitize_death_reason(reason));
+            info!(logger, "{}", msg);
+            println!("{}", msg);
+
+            update_db(logger, conn, options, "mark the instance dead", |conn| {
+                db::mark_dead(conn, target, db::DeathReason::from(cause))
+            })?;
+        }
         ipc::CheckerResponse::Peer { peer: _ } => {
-            db::on_sqlite_busy_retry(&mut || db::mark_dead(conn, target))?;
+            update_db(logger, conn, options, "mark the instance dead", |conn| {
+                db::mark_dead(conn, target, db::DeathReason::BadIpc)
+            })?;
             bail!("Expected the checker to respond with State, but it responded with Peer");
         }
+        ipc::CheckerResponse::EndOfPeers => {
+            update_db(logger, conn, options, "mark the instance dead", |conn| {
+                db::mark_dead(conn, target, db::DeathReason::BadIpc)
+            })?;
+            bail!("Expected the checker to respond with State, but it responded with EndOfPeers");
+        }
+        ipc::CheckerResponse::Metadata { metadata: _ } => {
+            update_db(logger, conn, options, "mark the instance dead", |conn| {
+                db::mark_dead(conn, target, db::DeathReason::BadIpc)
+            })?;
+            bail!("Expected the checker to respond with State, but it responded with Metadata");
+        }
         ipc::CheckerResponse::State { state } => match state {
             ipc::InstanceState::Alive { hide_from_list } => {
                 info!(logger, "The instance is alive");
 
-                db::on_sqlite_busy_retry(&mut || db::mark_alive(conn, target, hide_from_list))?;
-                process_peers(logger, conn, target, lines)?;
+                update_db(logger, conn, options, "mark the instance alive", |conn| {
+                    db::mark_alive(conn, target, hide_from_list)
+                })?;
+                process_peers(logger, conn, target, lines, dns_cache, options)?;
             }
             ipc::InstanceState::Moving { to } => {
                 let msg = format!(
@@ -120,7 +399,9 @@ fn process_checker_response(
                 info!(logger, "{}", msg);
                 println!("{}", msg);
 
-                db::on_sqlite_busy_retry(&mut || db::mark_dead(conn, target))?;
+                update_db(logger, conn, options, "mark the instance dead", |conn| {
+                    db::mark_dead(conn, target, db::DeathReason::Redirect)
+                })?;
             }
             ipc::InstanceState::Moved { to } => {
                 match Domain::from_host(&to) {
@@ -129,12 +410,16 @@ fn process_checker_response(
                             let msg = format!("{} has moved to *itself*, marking as dead", target);
                             info!(logger, "{}", msg);
                             println!("{}", msg);
-                            db::on_sqlite_busy_retry(&mut || db::mark_dead(conn, target))?;
+                            update_db(logger, conn, options, "mark the instance dead", |conn| {
+                                db::mark_dead(conn, target, db::DeathReason::Redirect)
+                            })?;
                         } else {
                             let msg = format!("{} has moved to {}", target, to);
                             info!(logger, "{}", msg);
                             println!("{}", msg);
-                            db::on_sqlite_busy_retry(&mut || db::mark_moved(conn, target, &to))?;
+                            update_db(logger, conn, options, "mark the instance moved", |conn| {
+                                db::mark_moved(conn, target, &to)
+                            })?;
                         }
                     }
 
@@ -145,23 +430,118 @@ fn process_checker_response(
                         );
                         info!(logger, "{}", msg);
                         println!("{}", msg);
-                        db::on_sqlite_busy_retry(&mut || db::mark_dead(conn, target))?;
+                        update_db(logger, conn, options, "mark the instance dead", |conn| {
+                            db::mark_dead(conn, target, db::DeathReason::Redirect)
+                        })?;
                     }
                 };
             }
+            ipc::InstanceState::Blocked { reason } => {
+                let msg = format!("{} is up, but blocked: {}", target, reason);
+                info!(logger, "{}", msg);
+                println!("{}", msg);
+
+                update_db(logger, conn, options, "mark the instance blocked", |conn| {
+                    db::mark_blocked(conn, target, reason)
+                })?;
+            }
         },
     }
 
-    Ok(())
+    Ok(true)
 }
 
+/// Why a checker that exited with `status` didn't report anything.
+///
+/// A checker that couldn't check the instance exits with 1, like any program whose `main` returns
+/// an error. Anything else means that the checker itself failed: e.g. it panicked (exit code 101)
+/// or was killed by a signal.
+fn silence_reason(status: Option<ExitStatus>) -> db::DeathReason {
+    match status.and_then(|status| status.code()) {
+        Some(0 | 1) => db::DeathReason::NoResponse,
+        _ => db::DeathReason::CheckerError,
+    }
+}
+
+/// Apply a change to the database, or in read-only mode, only log that it would've been applied.
+fn update_db(
+    logger: &Logger,
+    conn: &mut Connection,
+    options: &Options,
+    change: &str,
+    mut update: impl FnMut(&mut Connection) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    if options.read_only {
+        info!(logger, "Would {}, but running in read-only mode", change);
+        return Ok(());
+    }
+    db::on_sqlite_busy_retry(&mut || update(conn))
+}
+
+/// An instance's peers list can grow at most this many times between checks. Anything more is
+/// suspicious: a compromised instance could list a lot of made-up domains to get them crawled.
+const PEERS_GROWTH_LIMIT: u64 = 10;
+
+/// Peers lists shorter than this are never suspicious, so that small instances can grow freely.
+const MIN_PEERS_LIMIT: u64 = 1_000;
+
+/// How many peers of the instance are added to the database, given how many were added the last
+/// time. There's no limit for instances whose peers weren't fetched before.
+fn peers_limit(previously_added: Option<u64>) -> Option<u64> {
+    previously_added.map(|count| {
+        count
+            .saturating_mul(PEERS_GROWTH_LIMIT)
+            .max(MIN_PEERS_LIMIT)
+    })
+}
+
+/// Adds the peers reported by the checker to the database.
+///
+/// If the peers list grew suspiciously since the last check (see [`PEERS_GROWTH_LIMIT`]), only
+/// the first peers up to the limit are added. If the instance keeps reporting that many peers,
+/// each check adds more of them.
+///
+/// With [`Options::verify_dns_on_add`], peers that don't resolve are not added. Onion services
+/// are exempt, since they aren't in the DNS.
+///
+/// The peerings and the peers count are only updated if the checker sent the whole list.
 fn process_peers(
     logger: &Logger,
     conn: &mut Connection,
     target: &Domain,
     lines: impl Iterator<Item = std::io::Result<String>>,
+    dns_cache: &DnsCache,
+    options: &Options,
 ) -> anyhow::Result<()> {
+    let peers_depth =
+        db::on_sqlite_busy_retry(&mut || db::get_discovery_depth(conn, target))?.saturating_add(1);
+    let too_deep = options
+        .max_depth
+        .is_some_and(|max_depth| peers_depth > max_depth);
+    if too_deep {
+        info!(
+            logger,
+            "{}'s peers are beyond the maximum discovery depth, not adding them", target
+        );
+    }
+
+    // The limit grows from the number of peers that were added the last time, rather than the
+    // number that was reported: otherwise an instance could report a huge list once, and have all
+    // of it added on the next check.
+    let previous_count = db::on_sqlite_busy_retry(&mut || db::get_peers_count(conn, target))?;
+    let previously_added = match previous_count {
+        None => None,
+        Some(_) => Some(db::on_sqlite_busy_retry(&mut || {
+            db::get_peerings_count(conn, target)
+        })?),
+    };
+    let limit = peers_limit(previously_added);
+    let mut skipped: u64 = 0;
+    let mut unresolved: u64 = 0;
+
     let mut peers_count: Option<u64> = Some(0);
+    let mut accepted = vec![];
+    let mut complete = false;
     for response in lines {
         let response =
             response.context(with_loc!("Failed to read a line of checker's response"))?;
@@ -170,21 +550,82 @@ fn process_peers(
             .context(with_loc!("Failed to deserialize checker's response"))?;
 
         match response {
+            ipc::CheckerResponse::Hello { .. } => {
+                bail!("Expected the checker to respond with Peer, but it responded with Hello")
+            }
             ipc::CheckerResponse::State { state: _ } => {
                 bail!("Expected the checker to respond with Peer, but it responded with State")
             }
+            ipc::CheckerResponse::Dead { .. } => {
+                bail!("Expected the checker to respond with Peer, but it responded with Dead")
+            }
+            ipc::CheckerResponse::Metadata { metadata } => {
+                let metadata = metadata.sanitized();
+                update_db(logger, conn, options, "store the metadata", |conn| {
+                    db::set_metadata(conn, target, &metadata)
+                })?;
+            }
             ipc::CheckerResponse::Peer { peer } => {
-                if let Err(e) = Domain::from_host(&peer).and_then(|peer| {
-                    db::on_sqlite_busy_retry(&mut || db::add_instance(conn, &peer))
-                }) {
-                    info!(logger, "Failed to add {} to the database: {:?}", peer, e);
-                } else {
-                    peers_count = peers_count.and_then(|x| x.checked_add(1));
+                // Every peer counts, even if it isn't added.
+                let seen = peers_count;
+                peers_count = peers_count.and_then(|x| x.checked_add(1));
+                if too_deep {
+                    continue;
+                }
+                if limit.is_some_and(|limit| seen.is_none_or(|count| count >= limit)) {
+                    skipped = skipped.saturating_add(1);
+                    continue;
+                }
+
+                let added = Domain::from_host(&peer).and_then(|peer| {
+                    if options.verify_dns_on_add
+                        && !peer.is_onion()
+                        && !dns_cache.resolves(&peer.to_string())?
+                    {
+                        return Ok(false);
+                    }
+                    update_db(logger, conn, options, "add a peer", |conn| {
+                        db::add_instance_at_depth(conn, &peer, peers_depth)
+                    })?;
+                    accepted.push(peer);
+                    Ok(true)
+                });
+                match added {
+                    Ok(true) => {}
+                    Ok(false) => unresolved = unresolved.saturating_add(1),
+                    Err(e) => info!(logger, "Failed to add {} to the database: {:?}", peer, e),
                 }
             }
+            ipc::CheckerResponse::EndOfPeers => complete = true,
         }
     }
 
+    if skipped > 0 {
+        warn!(
+            logger,
+            "{}'s peers list grew from {} to over {}; skipped {} peers as possible poisoning",
+            target,
+            previously_added.unwrap_or(0),
+            limit.unwrap_or(0),
+            skipped
+        );
+    }
+    if unresolved > 0 {
+        info!(
+            logger,
+            "Skipped {} of {}'s peers that don't resolve", unresolved, target
+        );
+    }
+
+    // A partial list would make the instance look like it stopped peering with the rest.
+    if !complete {
+        info!(
+            logger,
+            "{} didn't send its full peers list, leaving the old one in place", target
+        );
+        return Ok(());
+    }
+
     let msg = match peers_count {
         None => format!("{} has more than {} peers", target, u64::MAX),
         Some(count) => format!("{} has {} peers", target, count),
@@ -192,5 +633,542 @@ fn process_peers(
     info!(logger, "{}", msg);
     println!("{}", msg);
 
+    update_db(logger, conn, options, "note down the peerings", |conn| {
+        db::replace_peerings(conn, target, &accepted)
+    })?;
+    update_db(logger, conn, options, "note down the peers count", |conn| {
+        db::set_peers_count(conn, target, peers_count.unwrap_or(u64::MAX))
+    })?;
+
     Ok(())
 }
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use slog::o;
+    use std::time::Duration;
+
+    /// A complete list of `peers`, as the checker would send it.
+    fn peers_lines(peers: &[&str]) -> impl Iterator<Item = std::io::Result<String>> {
+        peers
+            .iter()
+            .map(|peer| ipc::CheckerResponse::Peer {
+                peer: url::Host::Domain(peer.to_string()),
+            })
+            .chain(std::iter::once(ipc::CheckerResponse::EndOfPeers))
+            .map(|response| Ok(serde_json::to_string(&response).unwrap()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn is_known(conn: &Connection, instance: &str) -> bool {
+        conn.query_row(
+            "SELECT count(*) > 0 FROM instances WHERE hostname = ?1",
+            [instance],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn peers_get_the_next_depth() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let seed = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &seed).unwrap();
+
+        let options = Options {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        let dns_cache = DnsCache::new(Duration::ZERO);
+        process_peers(
+            &logger,
+            &mut conn,
+            &seed,
+            peers_lines(&["example.org"]),
+            &dns_cache,
+            &options,
+        )
+        .unwrap();
+        let peer = Domain::from_str("example.org").unwrap();
+        assert_eq!(db::get_discovery_depth(&conn, &peer).unwrap(), 1);
+
+        process_peers(
+            &logger,
+            &mut conn,
+            &peer,
+            peers_lines(&["example.net"]),
+            &dns_cache,
+            &options,
+        )
+        .unwrap();
+        let peer = Domain::from_str("example.net").unwrap();
+        assert_eq!(db::get_discovery_depth(&conn, &peer).unwrap(), 2);
+
+        // Peers of "example.net" would be at depth 3, which is too deep.
+        process_peers(
+            &logger,
+            &mut conn,
+            &peer,
+            peers_lines(&["too-deep.example.com"]),
+            &dns_cache,
+            &options,
+        )
+        .unwrap();
+        assert!(!is_known(&conn, "too-deep.example.com"));
+
+        // Without a limit, the crawl is unbounded.
+        process_peers(
+            &logger,
+            &mut conn,
+            &peer,
+            peers_lines(&["too-deep.example.com"]),
+            &dns_cache,
+            &Options::default(),
+        )
+        .unwrap();
+        assert!(is_known(&conn, "too-deep.example.com"));
+    }
+
+    #[test]
+    fn peers_are_recorded_as_peerings() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        let dns_cache = DnsCache::new(Duration::ZERO);
+        let mut check = |lines: Vec<std::io::Result<String>>| {
+            process_peers(
+                &logger,
+                &mut conn,
+                &instance,
+                lines.into_iter(),
+                &dns_cache,
+                &Options::default(),
+            )
+            .unwrap();
+            let mut peers = vec![];
+            db::for_each_peering(&conn, true, |from, to| {
+                peers.push(format!("{} -> {}", from, to));
+                Ok(())
+            })
+            .unwrap();
+            peers
+        };
+
+        assert_eq!(
+            check(peers_lines(&["example.org", "example.net"]).collect()),
+            ["example.com -> example.net", "example.com -> example.org"]
+        );
+        // A list that wasn't sent in full doesn't change the peerings.
+        assert_eq!(
+            check(peers_lines(&["example.info"]).take(1).collect()),
+            ["example.com -> example.net", "example.com -> example.org"]
+        );
+        // A full list replaces them.
+        assert_eq!(
+            check(peers_lines(&["example.org"]).collect()),
+            ["example.com -> example.org"]
+        );
+    }
+
+    #[test]
+    fn peers_limit_allows_tenfold_growth() {
+        assert_eq!(peers_limit(None), None);
+        assert_eq!(peers_limit(Some(0)), Some(MIN_PEERS_LIMIT));
+        assert_eq!(peers_limit(Some(50)), Some(MIN_PEERS_LIMIT));
+        assert_eq!(peers_limit(Some(500)), Some(5_000));
+        assert_eq!(peers_limit(Some(u64::MAX)), Some(u64::MAX));
+    }
+
+    #[test]
+    fn suspiciously_grown_peers_list_is_capped() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        let dns_cache = DnsCache::new(Duration::ZERO);
+        let peers: Vec<String> = (0..=MIN_PEERS_LIMIT)
+            .map(|i| format!("peer{}.example.org", i))
+            .collect();
+        let peers: Vec<&str> = peers.iter().map(String::as_str).collect();
+        let last_peer = format!("peer{}.example.org", MIN_PEERS_LIMIT);
+
+        db::set_peers_count(&conn, &instance, 1).unwrap();
+        process_peers(
+            &logger,
+            &mut conn,
+            &instance,
+            peers_lines(&peers),
+            &dns_cache,
+            &Options::default(),
+        )
+        .unwrap();
+        assert!(is_known(&conn, "peer0.example.org"));
+        assert!(!is_known(&conn, &last_peer));
+        // The count is what the instance reported, but it's the added peers that the next limit
+        // grows from.
+        assert_eq!(
+            db::get_peers_count(&conn, &instance).unwrap(),
+            Some(MIN_PEERS_LIMIT + 1)
+        );
+        assert_eq!(
+            db::get_peerings_count(&conn, &instance).unwrap(),
+            MIN_PEERS_LIMIT
+        );
+
+        // The instance reported as many peers again, so more of them are believed now.
+        process_peers(
+            &logger,
+            &mut conn,
+            &instance,
+            peers_lines(&peers),
+            &dns_cache,
+            &Options::default(),
+        )
+        .unwrap();
+        assert!(is_known(&conn, &last_peer));
+    }
+
+    #[test]
+    fn partial_peers_list_is_not_counted() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        let dns_cache = DnsCache::new(Duration::ZERO);
+
+        // The peers couldn't be fetched at all.
+        process_peers(
+            &logger,
+            &mut conn,
+            &instance,
+            std::iter::empty(),
+            &dns_cache,
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(db::get_peers_count(&conn, &instance).unwrap(), None);
+
+        // The checker stopped in the middle of the list.
+        process_peers(
+            &logger,
+            &mut conn,
+            &instance,
+            peers_lines(&["example.org", "example.net"]).take(1),
+            &dns_cache,
+            &Options::default(),
+        )
+        .unwrap();
+        assert!(is_known(&conn, "example.org"));
+        assert_eq!(db::get_peers_count(&conn, &instance).unwrap(), None);
+
+        process_peers(
+            &logger,
+            &mut conn,
+            &instance,
+            peers_lines(&["example.org", "not a domain"]),
+            &dns_cache,
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(db::get_peers_count(&conn, &instance).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn peers_that_dont_resolve_are_not_added() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        let dns_cache = DnsCache::with_resolver(
+            Duration::from_secs(60),
+            Box::new(|host| match host {
+                "example.org" => Ok(vec!["192.0.2.1".parse().unwrap()]),
+                "resolves-to-nothing.example.org" => Ok(vec![]),
+                _ => Err(std::io::Error::from(std::io::ErrorKind::NotFound)),
+            }),
+        );
+        let peers = [
+            "example.org",
+            "gone.example.org",
+            "resolves-to-nothing.example.org",
+            "yzw45do3yrjfnbpr.onion",
+        ];
+
+        process_peers(
+            &logger,
+            &mut conn,
+            &instance,
+            peers_lines(&peers),
+            &dns_cache,
+            &Options::default(),
+        )
+        .unwrap();
+        assert!(peers.iter().all(|peer| is_known(&conn, peer)));
+
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        let options = Options {
+            verify_dns_on_add: true,
+            ..Default::default()
+        };
+        process_peers(
+            &logger,
+            &mut conn,
+            &instance,
+            peers_lines(&peers),
+            &dns_cache,
+            &options,
+        )
+        .unwrap();
+        assert!(is_known(&conn, "example.org"));
+        assert!(!is_known(&conn, "gone.example.org"));
+        assert!(!is_known(&conn, "resolves-to-nothing.example.org"));
+        // Onion services are never in the DNS.
+        assert!(is_known(&conn, "yzw45do3yrjfnbpr.onion"));
+        // Peers that don't resolve are still peers, as far as the growth limit is concerned.
+        assert_eq!(db::get_peers_count(&conn, &instance).unwrap(), Some(4));
+    }
+
+    #[test]
+    fn read_only_mode_leaves_the_database_alone() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        let dns_cache = DnsCache::new(Duration::ZERO);
+        let options = Options {
+            read_only: true,
+            ..Default::default()
+        };
+        let changes_before = conn.total_changes();
+
+        let metadata = serde_json::to_string(&ipc::CheckerResponse::Metadata {
+            metadata: ipc::Metadata {
+                server: Some("nginx".to_string()),
+                ..Default::default()
+            },
+        })
+        .unwrap();
+        let lines = std::iter::once(Ok(metadata)).chain(peers_lines(&["example.org"]));
+        process_peers(&logger, &mut conn, &instance, lines, &dns_cache, &options).unwrap();
+        update_db(
+            &logger,
+            &mut conn,
+            &options,
+            "mark the instance dead",
+            |conn| db::mark_dead(conn, &instance, db::DeathReason::NoResponse),
+        )
+        .unwrap();
+
+        assert_eq!(conn.total_changes(), changes_before);
+        assert!(!is_known(&conn, "example.org"));
+    }
+
+    /// A drain that keeps the messages, so that tests can look at them.
+    #[derive(Clone, Default)]
+    struct Messages(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl slog::Drain for Messages {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(
+            &self,
+            record: &slog::Record,
+            _values: &slog::OwnedKVList,
+        ) -> Result<Self::Ok, Self::Err> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stderr_of_failed_checker_is_logged() {
+        let messages = Messages::default();
+        let logger = Logger::root(messages.clone(), o!());
+        let instance = Domain::from_str("example.com").unwrap();
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo 'checker panicked at src/main.rs' >&2; exit 101"]);
+        let mut checker = CheckerHandle::spawn(logger.clone(), instance.clone(), command).unwrap();
+        checker.inner.wait().unwrap();
+        drop(checker);
+
+        let logged = messages.0.lock().unwrap().join("\n");
+        assert!(logged.contains("The checker for example.com failed"));
+        assert!(logged.contains("checker panicked at src/main.rs"));
+
+        // Successful checkers' stderr is not logged.
+        messages.0.lock().unwrap().clear();
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo 'just a warning' >&2"]);
+        let mut checker = CheckerHandle::spawn(logger, instance, command).unwrap();
+        checker.inner.wait().unwrap();
+        drop(checker);
+        assert!(messages.0.lock().unwrap().is_empty());
+    }
+
+    /// Run a fake checker for example.com with the shell `script`. Returns the outcome, and the
+    /// reason why the instance was marked dead.
+    fn run_fake_checker(script: &str) -> (anyhow::Result<()>, Option<db::DeathReason>) {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        let dns_cache = DnsCache::new(Duration::ZERO);
+
+        let mut command = Command::new("sh");
+        command.args(["-c", script]);
+        let mut checker = CheckerHandle::spawn(logger.clone(), instance.clone(), command).unwrap();
+        let result = process_checker_response(
+            &logger,
+            &mut conn,
+            &instance,
+            &mut checker.inner,
+            &dns_cache,
+            &Options::default(),
+        );
+
+        let death_reason = db::get_instance_by_host(&conn, &instance)
+            .unwrap()
+            .unwrap()
+            .death_reason;
+        (result, death_reason)
+    }
+
+    /// Like [`run_fake_checker`], but only returns the death reason. Unexpected messages are
+    /// reported as errors after the instance is marked dead, so the outcome doesn't matter.
+    fn death_reason_after(script: &str) -> Option<db::DeathReason> {
+        run_fake_checker(script).1
+    }
+
+    fn echo(response: &ipc::CheckerResponse) -> String {
+        format!("echo '{}'", serde_json::to_string(response).unwrap())
+    }
+
+    fn hello() -> String {
+        echo(&ipc::CheckerResponse::Hello {
+            protocol_version: ipc::PROTOCOL_VERSION,
+        })
+    }
+
+    #[test]
+    fn death_reasons_match_the_checkers_behaviour() {
+        use db::DeathReason::*;
+        use ipc::DeathCause;
+
+        let message = |response: ipc::CheckerResponse| format!("{}; {}", hello(), echo(&response));
+        let moving = message(ipc::CheckerResponse::State {
+            state: ipc::InstanceState::Moving {
+                to: url::Host::Domain("example.org".to_string()),
+            },
+        });
+        let moved_to_itself = message(ipc::CheckerResponse::State {
+            state: ipc::InstanceState::Moved {
+                to: url::Host::Domain("example.com".to_string()),
+            },
+        });
+        let peer_first = message(ipc::CheckerResponse::Peer {
+            peer: url::Host::Domain("example.org".to_string()),
+        });
+
+        assert_eq!(death_reason_after("exit 1"), Some(NoResponse));
+        assert_eq!(death_reason_after("exit 101"), Some(CheckerError));
+        assert_eq!(death_reason_after("kill -9 $$"), Some(CheckerError));
+        assert_eq!(death_reason_after(&moving), Some(Redirect));
+        assert_eq!(death_reason_after(&moved_to_itself), Some(Redirect));
+        assert_eq!(death_reason_after(&peer_first), Some(BadIpc));
+        assert_eq!(death_reason_after(&hello()), Some(NoResponse));
+        let dead = |cause| {
+            echo(&ipc::CheckerResponse::Dead {
+                reason: "Connection refused".to_string(),
+                cause,
+            })
+        };
+        assert_eq!(
+            death_reason_after(&format!("{}; {}; exit 1", hello(), dead(DeathCause::Other))),
+            Some(CheckFailed)
+        );
+        assert_eq!(
+            death_reason_after(&format!("{}; {}", hello(), dead(DeathCause::TlsError))),
+            Some(TlsError)
+        );
+        assert_eq!(
+            death_reason_after(&format!("{}; {}", hello(), dead(DeathCause::NotNodeInfo))),
+            Some(NotNodeInfo)
+        );
+        assert_eq!(
+            death_reason_after(&format!("{}; {}", hello(), hello())),
+            Some(BadIpc)
+        );
+    }
+
+    #[test]
+    fn checkers_from_other_builds_are_rejected() {
+        let alive = echo(&ipc::CheckerResponse::State {
+            state: ipc::InstanceState::Alive {
+                hide_from_list: false,
+            },
+        });
+        let other_version = echo(&ipc::CheckerResponse::Hello {
+            protocol_version: ipc::PROTOCOL_VERSION.wrapping_add(1),
+        });
+
+        // The instance is left alone: it's not its fault.
+        let (result, death_reason) = run_fake_checker(&format!("{}; {}", other_version, alive));
+        assert!(result.is_err());
+        assert_eq!(death_reason, None);
+
+        let (result, death_reason) = run_fake_checker(&alive);
+        assert!(result.is_err());
+        assert_eq!(death_reason, None);
+
+        let (result, _) = run_fake_checker(&format!("{}; {}", hello(), alive));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn given_checker_exe_is_used_instead_of_our_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let exe = dir.path().join("checker");
+        std::fs::write(&exe, "").unwrap();
+        std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let unknown = || Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+
+        assert_eq!(resolve_checker_exe(unknown(), Some(&exe)).unwrap(), exe);
+        assert!(resolve_checker_exe(unknown(), None).is_err());
+
+        let own = env::current_exe().unwrap();
+        assert_eq!(
+            resolve_checker_exe(Ok(own.clone()), Some(&exe)).unwrap(),
+            exe
+        );
+        assert_eq!(resolve_checker_exe(Ok(own.clone()), None).unwrap(), own);
+
+        // Our own executable was replaced by an upgrade.
+        let deleted = PathBuf::from(format!("{} (deleted)", own.display()));
+        assert_eq!(
+            resolve_checker_exe(Ok(deleted.clone()), Some(&exe)).unwrap(),
+            exe
+        );
+        assert!(resolve_checker_exe(Ok(deleted), None).is_err());
+        let missing = dir.path().join("crawler");
+        assert!(resolve_checker_exe(Ok(missing), None).is_err());
+
+        std::fs::set_permissions(&exe, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(resolve_checker_exe(unknown(), Some(&exe)).is_err());
+    }
+}
@@ -1,74 +1,497 @@
-//! Produce a JSON list of alive instances.
-use crate::{db, with_loc};
-use anyhow::Context;
+//! Produce JSON lists of alive instances.
+use crate::{db, orchestrator::Options, with_loc};
+use anyhow::{anyhow, Context};
+use rusqlite::Connection;
+use serde::Serialize;
 use slog::{info, Logger};
+use std::collections::BTreeMap;
 use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-/// Writes a JSON array of alive instances into _instances.json_.
-pub fn generate(logger: Logger) -> anyhow::Result<()> {
+/// zstd compression level for _instances.json.zst_. Levels above 19 need a lot of memory, and
+/// the gains over this one are small for a list of hostnames.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 19;
+
+/// Instances tagged with this are test or development instances; they're kept in the database
+/// but left out of the lists.
+const TEST_INSTANCE_TAG: &str = "test-instance";
+
+/// Top-level domains that are reserved for testing and local use (RFC 2606, RFC 6761, RFC 6762),
+/// so no public instance can live under them.
+const TEST_INSTANCE_TLDS: &[&str] = &["test", "localhost", "invalid", "example", "local"];
+
+/// Hostnames of the instances that go into the lists: alive ones, and those which were alive
+/// until recently, unless they asked to be hidden or are test instances. Instances that never
+/// told us whether they want to be hidden are listed.
+///
+/// Instances first seen alive after the timestamp in `?1` are left out. If we don't know when
+/// the instance was first seen alive, it was before we started keeping track, i.e. long ago.
+const LISTED_INSTANCES: &str = "SELECT hostname
+    FROM instances
+        LEFT JOIN hidden_instances ON instances.id = hidden_instances.instance
+    WHERE state = 1
+        AND coalesce(hide_from_list, 0) = 0
+        AND tag IS NULL
+        AND coalesce(first_alive_datetime, 0) <= ?1
+
+    UNION
+
+    SELECT hostname
+    FROM instances
+        JOIN dying_state_data ON instances.id = dying_state_data.instance
+        LEFT JOIN hidden_instances ON instances.id = hidden_instances.instance
+    WHERE state = 2
+        AND previous_state = 1
+        AND coalesce(hide_from_list, 0) = 0
+        AND tag IS NULL
+        AND coalesce(first_alive_datetime, 0) <= ?1
+
+    UNION
+
+    SELECT instances.hostname
+    FROM instances
+        JOIN moving_state_data ON instances.id = moving_state_data.instance
+        LEFT JOIN hidden_instances ON instances.id = hidden_instances.instance
+        JOIN instances AS moved_to_instance ON moving_state_data.moving_to = moved_to_instance.id
+    WHERE instances.state = 4
+        AND previous_state = 1
+        AND moved_to_instance.state != 1
+        AND coalesce(hide_from_list, 0) = 0
+        AND instances.tag IS NULL
+        AND coalesce(instances.first_alive_datetime, 0) <= ?1";
+
+/// Writes a JSON array of alive instances into _instances.json_, their details into
+/// _instances-detailed.json_, and how many of them run each software into _software.json_.
+///
+/// Instances that were first seen alive less than `min_alive_age` ago are not listed, and neither
+/// are test instances.
+pub fn generate(logger: Logger, options: &Options) -> anyhow::Result<()> {
+    let mut conn = db::open()?;
+    tag_test_instances(&logger, &mut conn, &options.test_instance_suffixes)?;
+    generate_into(&logger, &conn, Path::new("."), options)?;
+    if options.ndjson_list {
+        generate_ndjson_into(&logger, &conn, Path::new("."), options)?;
+    }
+    generate_detailed_into(&logger, &conn, Path::new("."), options.min_alive_age)?;
+    generate_software_into(&logger, &conn, Path::new("."), options.min_alive_age)?;
+    if options.states_list {
+        generate_states_into(&logger, &conn, Path::new("."))?;
+    }
+    Ok(())
+}
+
+/// Returns `true` if the hostname is under one of the reserved [`TEST_INSTANCE_TLDS`], or under
+/// one of the `extra_suffixes`.
+pub fn is_test_instance(hostname: &str, extra_suffixes: &[String]) -> bool {
+    let is_under = |suffix: &str| {
+        hostname == suffix
+            || hostname
+                .strip_suffix(suffix)
+                .is_some_and(|prefix| prefix.ends_with('.'))
+    };
+    TEST_INSTANCE_TLDS.iter().any(|tld| is_under(tld))
+        || extra_suffixes.iter().any(|suffix| is_under(suffix))
+}
+
+/// Tags the test instances with [`TEST_INSTANCE_TAG`]. Instances that no longer look like test
+/// instances, e.g. because a suffix was removed from the options, are untagged.
+fn tag_test_instances(
+    logger: &Logger,
+    conn: &mut Connection,
+    extra_suffixes: &[String],
+) -> anyhow::Result<()> {
+    // Unlike the rest of list generation, this writes, so it competes with the checks' writes.
+    let tagged = db::on_sqlite_busy_retry(&mut || {
+        db::retag_instances(conn, TEST_INSTANCE_TAG, |hostname| {
+            is_test_instance(hostname, extra_suffixes)
+        })
+    })
+    .context(with_loc!("Tagging test instances"))?;
+    info!(logger, "Tagged {} test instances", tagged);
+    Ok(())
+}
+
+/// The value for `?1` in [`LISTED_INSTANCES`].
+fn first_alive_cutoff(min_alive_age: Duration) -> anyhow::Result<i64> {
+    let cutoff = SystemTime::now()
+        .checked_sub(min_alive_age)
+        .ok_or_else(|| anyhow!("Minimum alive age is too large"))?
+        .duration_since(UNIX_EPOCH)
+        .context(with_loc!("Minimum alive age goes before the Unix epoch"))?;
+    i64::try_from(cutoff.as_secs()).context(with_loc!("Converting the cutoff into i64"))
+}
+
+/// An entry of _instances.json_ when it's generated with `--rich-list`.
+#[derive(Serialize)]
+struct RichInstance {
+    hostname: String,
+
+    /// When the instance was last seen alive, in ISO 8601 format and UTC. `None` for instances
+    /// that haven't been checked since we started keeping track.
+    last_alive: Option<String>,
+}
+
+/// An entry of _instances.json_ and _instances.ndjson_.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ListedInstance {
+    Hostname(String),
+    Rich(RichInstance),
+}
+
+/// Passes the entries of _instances.json_ to `write` as they're read from the database. They
+/// are [`RichInstance`]s with `--rich-list`, and bare hostnames otherwise.
+fn with_listed_instances(
+    conn: &Connection,
+    options: &Options,
+    write: impl FnOnce(&mut dyn Iterator<Item = anyhow::Result<ListedInstance>>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let cutoff = first_alive_cutoff(options.min_alive_age)?;
+    if options.rich_list {
+        let mut statement = conn
+            .prepare(&format!(
+                "SELECT hostname,
+                    strftime('%Y-%m-%dT%H:%M:%SZ', last_alive_datetime, 'unixepoch')
+                FROM instances
+                WHERE hostname IN ({})
+                ORDER BY hostname",
+                LISTED_INSTANCES
+            ))
+            .context(with_loc!("Preparing a SELECT"))?;
+        let mut rich_instances = statement
+            .query_map([cutoff], |row| {
+                Ok(ListedInstance::Rich(RichInstance {
+                    hostname: row.get(0)?,
+                    last_alive: row.get(1)?,
+                }))
+            })
+            .context(with_loc!("Executing the SELECT"))?
+            .map(|row| row.context(with_loc!("Getting a row")));
+        write(&mut rich_instances)
+    } else {
+        let mut statement = conn
+            .prepare(LISTED_INSTANCES)
+            .context(with_loc!("Preparing a SELECT"))?;
+        let mut hostnames = statement
+            .query_map([cutoff], |row| row.get(0).map(ListedInstance::Hostname))
+            .context(with_loc!("Executing the SELECT"))?
+            .map(|hostname| hostname.context(with_loc!("Getting `hostname`")));
+        write(&mut hostnames)
+    }
+}
+
+/// Writes a JSON array of alive instances into _instances.json_ in the given directory.
+///
+/// Each file is only written if its contents changed, so the files' modification times are
+/// preserved and consumers don't have to re-download them. The compressed versions are checked on
+/// their own, so one that's missing (e.g. because the previous run was interrupted) is written even
+/// if _instances.json_ didn't change.
+fn generate_into(
+    logger: &Logger,
+    conn: &Connection,
+    dir: &Path,
+    options: &Options,
+) -> anyhow::Result<()> {
     info!(logger, "Generating a list of instances");
 
-    let mut instances: Vec<String> = vec![];
+    // The hostnames are written straight into the JSON, so that we don't hold a copy of all of
+    // them in memory alongside the JSON and its gzipped version.
+    let mut instances = vec![];
+    with_listed_instances(conn, options, |entries| {
+        write_json_array(&mut instances, entries)
+    })
+    .context(with_loc!("Serializing instances list into JSON"))?;
+
+    write_if_changed(logger, dir, "instances.json", &instances)
+        .context(with_loc!("Writing instances.json"))?;
+
+    let gzipped_instances = gzip(&instances).context(with_loc!("Compressing instances list"))?;
+    write_if_changed(logger, dir, "instances.json.gz", &gzipped_instances)
+        .context(with_loc!("Writing instances.json.gz"))?;
+
+    #[cfg(feature = "zstd")]
+    {
+        let zstd_instances = zstd::bulk::compress(&instances, ZSTD_LEVEL)
+            .context(with_loc!("Compressing instances list with zstd"))?;
+        write_if_changed(logger, dir, "instances.json.zst", &zstd_instances)
+            .context(with_loc!("Writing instances.json.zst"))?;
+    }
+
+    Ok(())
+}
+
+/// Writes the entries of _instances.json_ one per line into _instances.ndjson_ in the given
+/// directory.
+///
+/// This reads the list from the database anew rather than reusing _instances.json_, so that only
+/// one copy of the list is held in memory at a time. Like _instances.json_, the files are only
+/// written if their contents changed.
+fn generate_ndjson_into(
+    logger: &Logger,
+    conn: &Connection,
+    dir: &Path,
+    options: &Options,
+) -> anyhow::Result<()> {
+    info!(logger, "Generating a newline-delimited list of instances");
 
-    let conn = db::open()?;
+    let mut lines = vec![];
+    with_listed_instances(conn, options, |entries| {
+        write_json_lines(&mut lines, entries)
+    })
+    .context(with_loc!("Serializing instances list into NDJSON"))?;
+
+    write_if_changed(logger, dir, "instances.ndjson", &lines)
+        .context(with_loc!("Writing instances.ndjson"))?;
+    let gzipped_lines = gzip(&lines).context(with_loc!("Compressing NDJSON list"))?;
+    write_if_changed(logger, dir, "instances.ndjson.gz", &gzipped_lines)
+        .context(with_loc!("Writing instances.ndjson.gz"))
+}
+
+fn gzip(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    use flate2::{write::GzEncoder, Compression};
+
+    let mut e = GzEncoder::new(Vec::new(), Compression::best());
+    e.write_all(data).context(with_loc!("Compressing data"))?;
+    e.finish().context(with_loc!("Finishing gzip stream"))
+}
+
+/// An entry of _instances-detailed.json_.
+#[derive(Serialize)]
+struct DetailedInstance {
+    hostname: String,
+    software: Option<String>,
+    title: Option<String>,
+    rules: Option<Vec<String>>,
+    contact: Option<String>,
+    thumbnail: Option<String>,
+}
+
+/// Writes a JSON array of listed instances and their metadata into _instances-detailed.json_ in
+/// the given directory.
+///
+/// Like _instances.json_, the file is only written if its contents changed.
+fn generate_detailed_into(
+    logger: &Logger,
+    conn: &Connection,
+    dir: &Path,
+    min_alive_age: Duration,
+) -> anyhow::Result<()> {
+    info!(logger, "Generating a detailed list of instances");
+
+    let cutoff = first_alive_cutoff(min_alive_age)?;
     let mut statement = conn
-        .prepare(
-            "SELECT hostname
+        .prepare(&format!(
+            "SELECT hostname, software, title, rules, contact, thumbnail
             FROM instances
-                JOIN hidden_instances ON instances.id = hidden_instances.instance
-            WHERE state = 1
-                AND hide_from_list = 0
+                LEFT JOIN instance_metadata ON instances.id = instance_metadata.instance
+            WHERE hostname IN ({})
+            ORDER BY hostname",
+            LISTED_INSTANCES
+        ))
+        .context(with_loc!("Preparing a SELECT"))?;
+    let instances = statement
+        .query_map([cutoff], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            ))
+        })
+        .context(with_loc!("Executing the SELECT"))?
+        .map(|row| {
+            let (hostname, software, title, rules, contact, thumbnail) =
+                row.context(with_loc!("Getting a row"))?;
+            let rules = rules
+                .map(|rules| serde_json::from_str(&rules))
+                .transpose()
+                .context(with_loc!("Parsing the stored rules"))?;
+            Ok(DetailedInstance {
+                hostname,
+                software,
+                title,
+                rules,
+                contact,
+                thumbnail,
+            })
+        });
+
+    let mut detailed = vec![];
+    write_json_array(&mut detailed, instances)
+        .context(with_loc!("Serializing detailed instances list into JSON"))?;
+
+    if is_unchanged(&dir.join("instances-detailed.json"), &detailed) {
+        info!(logger, "Detailed list unchanged, skipping write");
+        return Ok(());
+    }
+
+    write(dir, "instances-detailed.json", &detailed)
+        .context(with_loc!("Writing instances-detailed.json"))
+}
 
-            UNION
+/// Writes a JSON object mapping software families to the number of listed instances running them
+/// into _software.json_ and _software.json.gz_ in the given directory.
+///
+/// Instances whose software is unknown are not counted. Like _instances.json_, the files are only
+/// written if their contents changed.
+fn generate_software_into(
+    logger: &Logger,
+    conn: &Connection,
+    dir: &Path,
+    min_alive_age: Duration,
+) -> anyhow::Result<()> {
+    info!(logger, "Generating a software breakdown");
 
-            SELECT hostname
+    let cutoff = first_alive_cutoff(min_alive_age)?;
+    let mut statement = conn
+        .prepare(&format!(
+            "SELECT software, count(*)
             FROM instances
-                JOIN dying_state_data ON instances.id = dying_state_data.instance
-                JOIN hidden_instances ON instances.id = hidden_instances.instance
-            WHERE state = 2
-                AND previous_state = 1
-                AND hide_from_list = 0
+            WHERE hostname IN ({})
+                AND software IS NOT NULL
+            GROUP BY software",
+            LISTED_INSTANCES
+        ))
+        .context(with_loc!("Preparing a SELECT"))?;
+    let counts = statement
+        .query_map([cutoff], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?))
+        })
+        .context(with_loc!("Executing the SELECT"))?
+        .collect::<Result<BTreeMap<_, _>, _>>()
+        .context(with_loc!("Getting a row"))?;
 
-            UNION
+    let software = serde_json::to_vec(&counts)
+        .context(with_loc!("Serializing software breakdown into JSON"))?;
+
+    write_if_changed(logger, dir, "software.json", &software)
+        .context(with_loc!("Writing software.json"))?;
+    let gzipped_software = gzip(&software).context(with_loc!("Compressing software breakdown"))?;
+    write_if_changed(logger, dir, "software.json.gz", &gzipped_software)
+        .context(with_loc!("Writing software.json.gz"))
+}
 
-            SELECT instances.hostname
+/// Writes the items as a JSON array, without collecting them first.
+fn write_json_array(
+    mut writer: impl Write,
+    items: impl Iterator<Item = anyhow::Result<impl Serialize>>,
+) -> anyhow::Result<()> {
+    writer.write_all(b"[")?;
+    for (i, item) in items.enumerate() {
+        if i != 0 {
+            writer.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut writer, &item?)?;
+    }
+    writer.write_all(b"]")?;
+    Ok(())
+}
+
+/// Writes the items as newline-delimited JSON, without collecting them first.
+fn write_json_lines(
+    mut writer: impl Write,
+    items: impl Iterator<Item = anyhow::Result<impl Serialize>>,
+) -> anyhow::Result<()> {
+    for item in items {
+        serde_json::to_writer(&mut writer, &item?)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// The contents of _instances-states.json_: hostnames of the instances in each state.
+#[derive(Default, Serialize)]
+struct StatesList {
+    alive: Vec<String>,
+    dying: Vec<String>,
+    dead: Vec<String>,
+    moving: Vec<String>,
+    moved: Vec<String>,
+}
+
+/// Writes a JSON object with the hostnames of instances in each state into
+/// _instances-states.json_ in the given directory.
+///
+/// Hidden instances are left out of the "alive" bucket, and test instances are left out
+/// entirely. Like _instances.json_, the file is only written if its contents changed.
+fn generate_states_into(logger: &Logger, conn: &Connection, dir: &Path) -> anyhow::Result<()> {
+    info!(logger, "Generating a list of instances by state");
+
+    let mut statement = conn
+        .prepare(
+            "SELECT hostname, state
             FROM instances
-                JOIN moving_state_data ON instances.id = moving_state_data.instance
-                JOIN hidden_instances ON instances.id = hidden_instances.instance
-                JOIN instances AS moved_to_instance ON moving_state_data.moving_to = moved_to_instance.id
-            WHERE instances.state = 4
-                AND previous_state = 1
-                AND moved_to_instance.state != 1
-                AND hide_from_list = 0",
+                LEFT JOIN hidden_instances ON instances.id = hidden_instances.instance
+            WHERE tag IS NULL
+                AND (state != ?1 OR coalesce(hide_from_list, 0) = 0)
+            ORDER BY hostname",
         )
         .context(with_loc!("Preparing a SELECT"))?;
-    let mut ids = statement.query([])?;
-    while let Some(row) = ids.next()? {
-        let hostname: String = row.get(0).context(with_loc!("Getting `hostname`"))?;
-        instances.push(hostname);
+    let rows = statement
+        .query_map([db::InstanceState::Alive], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?))
+        })
+        .context(with_loc!("Executing the SELECT"))?;
+
+    let mut states = StatesList::default();
+    for row in rows {
+        let (hostname, state) = row.context(with_loc!("Getting a row"))?;
+        let bucket = match state {
+            db::InstanceState::Alive => &mut states.alive,
+            db::InstanceState::Dying => &mut states.dying,
+            db::InstanceState::Dead => &mut states.dead,
+            db::InstanceState::Moving => &mut states.moving,
+            db::InstanceState::Moved => &mut states.moved,
+            db::InstanceState::Discovered | db::InstanceState::Blocked => continue,
+        };
+        bucket.push(hostname);
     }
 
-    let instances = serde_json::to_string(&instances)
-        .context(with_loc!("Serializing instances list into JSON"))?;
-    write("instances.json", instances.as_bytes()).context(with_loc!("Writing instances.json"))?;
+    let states = serde_json::to_vec(&states)
+        .context(with_loc!("Serializing instances by state into JSON"))?;
 
-    let gzipped_instances = {
-        use flate2::{write::GzEncoder, Compression};
+    if is_unchanged(&dir.join("instances-states.json"), &states) {
+        info!(
+            logger,
+            "List of instances by state unchanged, skipping write"
+        );
+        return Ok(());
+    }
 
-        let mut e = GzEncoder::new(Vec::new(), Compression::best());
-        e.write_all(instances.as_bytes())
-            .context(with_loc!("Compressing instances list"))?;
-        e.finish().context(with_loc!("Finishing gzip stream"))?
-    };
-    write("instances.json.gz", &gzipped_instances)
-        .context(with_loc!("Writing instances.json.gz"))?;
+    write(dir, "instances-states.json", &states).context(with_loc!("Writing instances-states.json"))
+}
 
-    Ok(())
+/// Returns `true` if the file exists and contains exactly `data`.
+fn is_unchanged(path: &Path, data: &[u8]) -> bool {
+    match std::fs::read(path) {
+        Ok(contents) => contents == data,
+        Err(_) => false,
+    }
 }
 
-fn write(filename: &str, data: &[u8]) -> anyhow::Result<()> {
-    let mut file = tempfile::NamedTempFile::new_in(".")
-        .context(with_loc!("Creating a temporary file in current directory"))?;
+/// Writes `data` into `filename` in the given directory, unless the file already contains exactly
+/// that.
+fn write_if_changed(
+    logger: &Logger,
+    dir: &Path,
+    filename: &str,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    if is_unchanged(&dir.join(filename), data) {
+        info!(logger, "{} unchanged, skipping write", filename);
+        return Ok(());
+    }
+    write(dir, filename, data)
+}
+
+fn write(dir: &Path, filename: &str, data: &[u8]) -> anyhow::Result<()> {
+    let mut file = tempfile::NamedTempFile::new_in(dir).context(with_loc!(
+        "Creating a temporary file in the output directory"
+    ))?;
     file.write_all(data)
         .context(with_loc!("Writing data into a temporary file"))?;
 
@@ -85,7 +508,454 @@ fn write(filename: &str, data: &[u8]) -> anyhow::Result<()> {
             .context(with_loc!("Setting permissions for the temporary file"))?;
     }
 
-    file.persist(filename)
+    file.persist(dir.join(filename))
         .context(with_loc!("Renaming temporary file to the desired filename"))?;
     Ok(())
 }
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::domain::Domain;
+    use std::io::Read;
+    use std::os::unix::fs::MetadataExt;
+
+    fn open_in_memory() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        conn
+    }
+
+    fn inode(path: &Path) -> u64 {
+        std::fs::metadata(path).unwrap().ino()
+    }
+
+    #[test]
+    fn streamed_list_matches_serialized_vec() {
+        for hostnames in [
+            vec![],
+            vec!["example.com"],
+            vec![
+                "example.com",
+                "example.org",
+                "xn--80ak6aa92e.com",
+                "weird\"name",
+            ],
+        ] {
+            let hostnames: Vec<String> = hostnames.into_iter().map(String::from).collect();
+
+            let mut streamed = vec![];
+            write_json_array(&mut streamed, hostnames.iter().cloned().map(Ok)).unwrap();
+            let mut lines = vec![];
+            write_json_lines(&mut lines, hostnames.iter().cloned().map(Ok)).unwrap();
+
+            assert_eq!(streamed, serde_json::to_vec(&hostnames).unwrap());
+            let parsed: Vec<String> = lines
+                .split(|&b| b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| serde_json::from_slice(line).unwrap())
+                .collect();
+            assert_eq!(parsed, hostnames);
+            assert!(lines.is_empty() || lines.ends_with(b"\n"));
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_list_decompresses_to_json() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        db::mark_alive(&mut conn, &instance, false).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+
+        let json = std::fs::read(dir.path().join("instances.json")).unwrap();
+        let compressed = std::fs::File::open(dir.path().join("instances.json.zst")).unwrap();
+        assert_eq!(zstd::decode_all(compressed).unwrap(), json);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn writes_missing_zstd_list_even_if_json_is_unchanged() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        db::mark_alive(&mut conn, &instance, false).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+        let json = dir.path().join("instances.json");
+        let zstd = dir.path().join("instances.json.zst");
+        let json_inode = inode(&json);
+        // As if the list was generated by a build without zstd.
+        std::fs::remove_file(&zstd).unwrap();
+
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+        assert_eq!(inode(&json), json_inode);
+        let compressed = std::fs::File::open(&zstd).unwrap();
+        assert_eq!(
+            zstd::decode_all(compressed).unwrap(),
+            std::fs::read(&json).unwrap()
+        );
+        let zstd_inode = inode(&zstd);
+
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+        assert_eq!(inode(&zstd), zstd_inode);
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn no_zstd_list_without_the_feature() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let conn = open_in_memory();
+        let dir = tempfile::tempdir().unwrap();
+
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+
+        assert!(dir.path().join("instances.json").exists());
+        assert!(!dir.path().join("instances.json.zst").exists());
+    }
+
+    #[test]
+    fn does_not_rewrite_unchanged_list() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        db::mark_alive(&mut conn, &instance, false).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+        let json = dir.path().join("instances.json");
+        let gzipped = dir.path().join("instances.json.gz");
+        let (json_inode, gzipped_inode) = (inode(&json), inode(&gzipped));
+
+        // Files are written by renaming a temporary file over them, so a write changes the inode.
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+        assert_eq!(inode(&json), json_inode);
+        assert_eq!(inode(&gzipped), gzipped_inode);
+
+        let instance = Domain::from_str("example.org").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        db::mark_alive(&mut conn, &instance, false).unwrap();
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+        assert_ne!(inode(&json), json_inode);
+        assert_ne!(inode(&gzipped), gzipped_inode);
+    }
+
+    #[test]
+    fn writes_missing_gzipped_list_even_if_json_is_unchanged() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        db::mark_alive(&mut conn, &instance, false).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+        let json = dir.path().join("instances.json");
+        let gzipped = dir.path().join("instances.json.gz");
+        let json_inode = inode(&json);
+        std::fs::remove_file(&gzipped).unwrap();
+
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+        assert_eq!(inode(&json), json_inode);
+        let mut decompressed = vec![];
+        flate2::read::GzDecoder::new(std::fs::File::open(&gzipped).unwrap())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, std::fs::read(&json).unwrap());
+    }
+
+    #[test]
+    fn states_list_puts_instances_into_their_buckets() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        let set_state = |conn: &Connection, hostname: &str, state: db::InstanceState| {
+            let instance = Domain::from_str(hostname).unwrap();
+            db::add_instance(conn, &instance).unwrap();
+            conn.execute(
+                "UPDATE instances SET state = ?1 WHERE hostname = ?2",
+                rusqlite::params![state, hostname],
+            )
+            .unwrap();
+        };
+        set_state(&conn, "alive.example.com", db::InstanceState::Alive);
+        set_state(&conn, "dying.example.com", db::InstanceState::Dying);
+        set_state(&conn, "dead.example.com", db::InstanceState::Dead);
+        set_state(&conn, "moving.example.com", db::InstanceState::Moving);
+        set_state(&conn, "moved.example.com", db::InstanceState::Moved);
+        set_state(&conn, "blocked.example.com", db::InstanceState::Blocked);
+        set_state(&conn, "new.example.com", db::InstanceState::Discovered);
+        let hidden = Domain::from_str("hidden.example.com").unwrap();
+        db::add_instance(&conn, &hidden).unwrap();
+        db::mark_alive(&mut conn, &hidden, true).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        generate_states_into(&logger, &conn, dir.path()).unwrap();
+
+        let json = std::fs::read(dir.path().join("instances-states.json")).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "alive": ["alive.example.com"],
+                "dying": ["dying.example.com"],
+                "dead": ["dead.example.com"],
+                "moving": ["moving.example.com"],
+                "moved": ["moved.example.com"],
+            })
+        );
+    }
+
+    #[test]
+    fn detailed_list_includes_metadata() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        let described = Domain::from_str("example.com").unwrap();
+        let bare = Domain::from_str("example.org").unwrap();
+        let hidden = Domain::from_str("example.net").unwrap();
+        for instance in [&described, &bare, &hidden] {
+            db::add_instance(&conn, instance).unwrap();
+        }
+        db::mark_alive(&mut conn, &described, false).unwrap();
+        db::mark_alive(&mut conn, &bare, false).unwrap();
+        db::mark_alive(&mut conn, &hidden, true).unwrap();
+        let metadata = crate::ipc::Metadata {
+            software: Some("hometown".to_string()),
+            title: Some("Example".to_string()),
+            rules: Some(vec!["No spam".to_string()]),
+            contact: Some("admin@example.com".to_string()),
+            thumbnail: Some("https://example.com/thumbnail.png".to_string()),
+            ..Default::default()
+        };
+        db::set_metadata(&conn, &described, &metadata).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        generate_detailed_into(&logger, &conn, dir.path(), Duration::ZERO).unwrap();
+
+        let json = std::fs::read(dir.path().join("instances-detailed.json")).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {
+                    "hostname": "example.com",
+                    "software": "mastodon",
+                    "title": "Example",
+                    "rules": ["No spam"],
+                    "contact": "admin@example.com",
+                    "thumbnail": "https://example.com/thumbnail.png",
+                },
+                {
+                    "hostname": "example.org",
+                    "software": null,
+                    "title": null,
+                    "rules": null,
+                    "contact": null,
+                    "thumbnail": null,
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn rich_list_includes_last_alive_time() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        for hostname in ["example.org", "example.com"] {
+            let instance = Domain::from_str(hostname).unwrap();
+            db::add_instance(&conn, &instance).unwrap();
+            db::mark_alive(&mut conn, &instance, false).unwrap();
+        }
+        conn.execute(
+            "UPDATE instances SET last_alive_datetime = 1700000000 WHERE hostname = 'example.com'",
+            [],
+        )
+        .unwrap();
+        // Alive since before the time was tracked.
+        conn.execute(
+            "UPDATE instances SET last_alive_datetime = NULL WHERE hostname = 'example.org'",
+            [],
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        let options = Options {
+            rich_list: true,
+            ..Default::default()
+        };
+        generate_into(&logger, &conn, dir.path(), &options).unwrap();
+
+        let json = std::fs::read(dir.path().join("instances.json")).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"hostname": "example.com", "last_alive": "2023-11-14T22:13:20Z"},
+                {"hostname": "example.org", "last_alive": null},
+            ])
+        );
+    }
+
+    #[test]
+    fn ndjson_list_has_one_instance_per_line() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        for hostname in ["example.com", "example.org"] {
+            let instance = Domain::from_str(hostname).unwrap();
+            db::add_instance(&conn, &instance).unwrap();
+            db::mark_alive(&mut conn, &instance, false).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+
+        generate_ndjson_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+        let ndjson = std::fs::read_to_string(dir.path().join("instances.ndjson")).unwrap();
+        let mut lines: Vec<&str> = ndjson.lines().collect();
+        lines.sort_unstable();
+        assert_eq!(lines, ["\"example.com\"", "\"example.org\""]);
+
+        let gzipped = std::fs::File::open(dir.path().join("instances.ndjson.gz")).unwrap();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(gzipped)
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, ndjson);
+    }
+
+    #[test]
+    fn software_breakdown_counts_listed_instances() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        let add = |conn: &mut Connection, hostname: &str, software: Option<&str>, hidden: bool| {
+            let instance = Domain::from_str(hostname).unwrap();
+            db::add_instance(conn, &instance).unwrap();
+            db::mark_alive(conn, &instance, hidden).unwrap();
+            let metadata = crate::ipc::Metadata {
+                software: software.map(str::to_string),
+                ..Default::default()
+            };
+            db::set_metadata(conn, &instance, &metadata).unwrap();
+        };
+        add(&mut conn, "a.example.com", Some("mastodon"), false);
+        add(&mut conn, "b.example.com", Some("hometown"), false);
+        add(&mut conn, "c.example.com", Some("pleroma"), false);
+        add(&mut conn, "d.example.com", Some("pleroma"), true);
+        add(&mut conn, "e.example.com", None, false);
+        let dir = tempfile::tempdir().unwrap();
+
+        generate_software_into(&logger, &conn, dir.path(), Duration::ZERO).unwrap();
+
+        let json = std::fs::read(dir.path().join("software.json")).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!({"mastodon": 2, "pleroma": 1})
+        );
+
+        let gzipped = std::fs::File::open(dir.path().join("software.json.gz")).unwrap();
+        let mut decompressed = vec![];
+        std::io::Read::read_to_end(
+            &mut flate2::read::GzDecoder::new(gzipped),
+            &mut decompressed,
+        )
+        .unwrap();
+        assert_eq!(decompressed, json);
+    }
+
+    #[test]
+    fn test_instances_are_recognized() {
+        let no_suffixes: &[String] = &[];
+        assert!(is_test_instance("foo.test", no_suffixes));
+        assert!(is_test_instance("mastodon.localhost", no_suffixes));
+        assert!(is_test_instance("localhost", no_suffixes));
+        assert!(!is_test_instance("example.com", no_suffixes));
+        assert!(!is_test_instance("contest", no_suffixes));
+
+        let suffixes = ["staging.example.com".to_string()];
+        assert!(is_test_instance("staging.example.com", &suffixes));
+        assert!(is_test_instance("a.staging.example.com", &suffixes));
+        assert!(!is_test_instance("notstaging.example.com", &suffixes));
+    }
+
+    #[test]
+    fn test_instances_are_tagged_and_not_listed() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        let public = Domain::from_str("example.com").unwrap();
+        let test = Domain::from_str_with_policy(
+            "foo.test",
+            crate::domain::UnknownSuffixPolicy::AcceptPlausible,
+        )
+        .unwrap();
+        for instance in [&public, &test] {
+            db::add_instance(&conn, instance).unwrap();
+            db::mark_alive(&mut conn, instance, false).unwrap();
+        }
+        let dir = tempfile::tempdir().unwrap();
+
+        tag_test_instances(&logger, &mut conn, &[]).unwrap();
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+
+        let tag: Option<String> = conn
+            .query_row(
+                "SELECT tag FROM instances WHERE hostname = 'foo.test'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tag.as_deref(), Some(TEST_INSTANCE_TAG));
+        let json = std::fs::read(dir.path().join("instances.json")).unwrap();
+        let json: Vec<String> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json, vec!["example.com"]);
+
+        // The instance is tagged, not deleted.
+        assert!(db::get_instance_by_host(&conn, &test).unwrap().is_some());
+    }
+
+    #[test]
+    fn newly_alive_instances_are_held_back() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        db::mark_alive(&mut conn, &instance, false).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let read_list = || -> Vec<String> {
+            let json = std::fs::read(dir.path().join("instances.json")).unwrap();
+            serde_json::from_slice(&json).unwrap()
+        };
+
+        let week = Duration::from_secs(7 * 24 * 3600);
+        let options = Options {
+            min_alive_age: week,
+            ..Default::default()
+        };
+        generate_into(&logger, &conn, dir.path(), &options).unwrap();
+        assert!(read_list().is_empty());
+
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+        assert_eq!(read_list(), vec!["example.com".to_string()]);
+    }
+
+    #[test]
+    fn instances_without_hidden_flag_are_listed() {
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let mut conn = open_in_memory();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        db::mark_alive(&mut conn, &instance, false).unwrap();
+        conn.execute("DELETE FROM hidden_instances", []).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        generate_into(&logger, &conn, dir.path(), &Options::default()).unwrap();
+
+        let json = std::fs::read(dir.path().join("instances.json")).unwrap();
+        let list: Vec<String> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(list, vec!["example.com".to_string()]);
+    }
+}
@@ -0,0 +1,208 @@
+//! Metrics of the crawl in the Prometheus text format, served over HTTP.
+//!
+//! The server is deliberately tiny: it handles one connection at a time, and answers `GET
+//! /metrics` and nothing else. It's meant to be scraped every few seconds by a single Prometheus,
+//! not to face the Internet.
+use crate::{
+    db::{self, InstanceState},
+    with_loc,
+};
+use anyhow::Context;
+use slog::{error, info, Logger};
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a scraper has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests with headers larger than this are cut off. Prometheus sends a few hundred bytes.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Counters that are incremented as the crawl goes on.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    checks: AtomicU64,
+    checker_spawn_failures: AtomicU64,
+    list_generations: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a check whose checker was spawned, whatever the outcome.
+    pub fn record_check(&self) {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a check that didn't happen because the checker couldn't be spawned.
+    pub fn record_checker_spawn_failure(&self) {
+        self.checker_spawn_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a successful generation of the lists.
+    pub fn record_list_generation(&self) {
+        self.list_generations.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Serve the `metrics` on `addr` from a background thread. The number of instances in each state
+/// is looked up in the database on each request.
+///
+/// Returns an error if `addr` can't be listened on.
+pub fn serve(
+    logger: Logger,
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    pool: rusty_pool::ThreadPool,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("Failed to listen for metrics requests on {}", addr))?;
+    info!(logger, "Serving metrics on http://{}/metrics", addr);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .context(with_loc!("Accepting a metrics request"))
+                .and_then(|stream| respond(stream, &metrics, &pool));
+            if let Err(e) = result {
+                error!(logger, "Failed to serve metrics: {:?}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(
+    mut stream: TcpStream,
+    metrics: &Metrics,
+    pool: &rusty_pool::ThreadPool,
+) -> anyhow::Result<()> {
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .context(with_loc!("Setting the read timeout"))?;
+    // The response doesn't depend on the headers, but they're read anyway so that the scraper
+    // isn't cut off while it's still sending them.
+    let mut request = vec![];
+    let mut byte = [0];
+    while !request.ends_with(b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let read = stream
+            .read(&mut byte)
+            .context(with_loc!("Reading the metrics request"))?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&byte);
+    }
+
+    let (status, body) = if request.starts_with(b"GET /metrics ") {
+        let conn = db::open_read_only()?;
+        let states = db::on_sqlite_busy_retry(&mut || db::count_instances_by_state(&conn))
+            .context(with_loc!("Counting instances"))?;
+        let body = render(&states, metrics, pool.get_current_worker_count())
+            .context(with_loc!("Formatting the metrics"))?;
+        ("200 OK", body)
+    } else {
+        ("404 Not Found", String::new())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .context(with_loc!("Sending the metrics"))
+}
+
+fn render(
+    states: &[(InstanceState, u64)],
+    metrics: &Metrics,
+    pool_size: usize,
+) -> Result<String, std::fmt::Error> {
+    let mut out = String::new();
+
+    writeln!(
+        out,
+        "# HELP fediverse_crawler_instances Instances in the database, by state."
+    )?;
+    writeln!(out, "# TYPE fediverse_crawler_instances gauge")?;
+    for state in InstanceState::ALL {
+        let count = states
+            .iter()
+            .find(|(s, _)| *s == state)
+            .map_or(0, |(_, count)| *count);
+        writeln!(
+            out,
+            "fediverse_crawler_instances{{state=\"{}\"}} {}",
+            state.name(),
+            count
+        )?;
+    }
+
+    let counters = [
+        ("checks_total", "Checks performed.", &metrics.checks),
+        (
+            "checker_spawn_failures_total",
+            "Checks that failed because the checker couldn't be spawned.",
+            &metrics.checker_spawn_failures,
+        ),
+        (
+            "list_generations_total",
+            "Successful generations of the lists.",
+            &metrics.list_generations,
+        ),
+    ];
+    for (name, help, counter) in counters {
+        writeln!(out, "# HELP fediverse_crawler_{} {}", name, help)?;
+        writeln!(out, "# TYPE fediverse_crawler_{} counter", name)?;
+        writeln!(
+            out,
+            "fediverse_crawler_{} {}",
+            name,
+            counter.load(Ordering::Relaxed)
+        )?;
+    }
+
+    writeln!(
+        out,
+        "# HELP fediverse_crawler_pool_workers Threads in the checkers' pool."
+    )?;
+    writeln!(out, "# TYPE fediverse_crawler_pool_workers gauge")?;
+    writeln!(out, "fediverse_crawler_pool_workers {}", pool_size)?;
+
+    Ok(out)
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_every_state_and_counter() {
+        let metrics = Metrics::default();
+        metrics.record_check();
+        metrics.record_check();
+        metrics.record_checker_spawn_failure();
+
+        let text = render(
+            &[(InstanceState::Alive, 3), (InstanceState::Dead, 1)],
+            &metrics,
+            5,
+        )
+        .unwrap();
+        for line in [
+            "fediverse_crawler_instances{state=\"alive\"} 3",
+            "fediverse_crawler_instances{state=\"dead\"} 1",
+            "fediverse_crawler_instances{state=\"discovered\"} 0",
+            "fediverse_crawler_checks_total 2",
+            "fediverse_crawler_checker_spawn_failures_total 1",
+            "fediverse_crawler_list_generations_total 0",
+            "fediverse_crawler_pool_workers 5",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?}", line);
+        }
+        assert_eq!(text.lines().filter(|l| l.starts_with("# TYPE")).count(), 5);
+    }
+}
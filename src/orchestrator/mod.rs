@@ -1,14 +1,153 @@
-use crate::{db, with_loc};
-use anyhow::Context;
-use slog::{error, o, Logger};
+use crate::{checker::rate_limiter::RateLimiter, db, domain::Domain, with_loc};
+use anyhow::{anyhow, Context};
+use slog::{error, info, o, warn, Logger};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    Arc, Mutex,
 };
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
+mod checker_limits;
+mod dns_cache;
+pub mod hidden_recomputer;
 mod instance_checker;
+pub use instance_checker::ensure_executable;
 mod list_generator;
+mod metrics;
+mod pause;
+mod recently_checked;
+pub mod rechecker;
+mod retry_queue;
+
+/// Settings of the crawl.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Don't add peers that are more than this many peers lists away from the seeds (the
+    /// instances that were added manually).
+    pub max_depth: Option<u32>,
+
+    /// The executable to run checkers from instead of our own, e.g. because the path to ours
+    /// can't be determined, or because it was deleted by an upgrade.
+    pub checker_exe: Option<PathBuf>,
+
+    /// Resource limits of the checker processes.
+    pub checker_limits: checker_limits::CheckerLimits,
+
+    /// The size of the pool of threads that run the checkers.
+    pub workers: Workers,
+
+    /// Only list instances that were first seen alive at least this long ago. This keeps
+    /// short-lived test instances out of the list.
+    pub min_alive_age: Duration,
+
+    /// Don't generate the lists while the crawl is paused.
+    pub pause_list_generation: bool,
+
+    /// Also write _instances-states.json_, which lists the instances in each state.
+    pub states_list: bool,
+
+    /// Make _instances.json_ an array of objects with the hostname and the time the instance was
+    /// last seen alive, rather than an array of hostnames.
+    pub rich_list: bool,
+
+    /// Also write _instances.ndjson_, which has the entries of _instances.json_ one per line, for
+    /// consumers that want to process the list without loading all of it.
+    pub ndjson_list: bool,
+
+    /// Hostnames under these suffixes are treated as test instances, in addition to the reserved
+    /// ones like _.test_; see [`list_generator::is_test_instance`].
+    pub test_instance_suffixes: Vec<String>,
+
+    /// Check alive instances running these software families with the given period instead of
+    /// the usual one; see [`db::check_period_for`].
+    pub software_periods: Vec<(String, crate::time::Period)>,
+
+    /// Passed on to the checkers; see [`crate::checker::Options::peers_paths`].
+    pub peers_paths: Vec<(String, String)>,
+
+    /// Passed on to the checkers; see [`crate::checker::Options::strict_liveness`].
+    pub strict_liveness: bool,
+
+    /// Passed on to the checkers; see [`crate::checker::Options::tor_proxy`].
+    pub tor_proxy: Option<String>,
+
+    /// Passed on to the checkers; see [`crate::checker::Options::extra_ca_certs`].
+    pub extra_ca_certs: Vec<PathBuf>,
+
+    /// Make at most this many requests per second, all checkers and hosts taken together, on
+    /// average. The budget is shared by the checks: see [`Options::check_interval`] and
+    /// [`Options::checker_request_interval`].
+    pub max_rps: Option<u32>,
+
+    /// Passed on to the checkers; see [`crate::checker::Options::contact_url`].
+    pub contact_url: Option<String>,
+
+    /// The periods with which instances are checked and the lists are generated. They're saved in
+    /// the database, so that the other commands that schedule checks use them too.
+    pub periods: crate::time::Periods,
+
+    /// Schedule checks at exact times rather than randomized ones, and check instances that are
+    /// due at the same time in alphabetical order. Only meant for reproducible development runs.
+    pub deterministic: bool,
+
+    /// Only add peers whose hostnames resolve. Peers lists are full of long-gone domains, and
+    /// this keeps them out of the database at the cost of a DNS lookup per peer.
+    pub verify_dns_on_add: bool,
+
+    /// Check every instance that is due when the crawl starts, generate the lists, and exit. Meant
+    /// for CI and one-off audits. Overdue checks are done as they are, rather than spread over the
+    /// next "daily" period.
+    pub once: bool,
+
+    /// Run a single iteration of the main loop: generate the lists if they're due, check the next
+    /// instance if it's due within a few seconds, wait for that to finish, and exit. Meant for
+    /// testing the scheduling and for cron-style deployments.
+    pub single_iteration: bool,
+
+    /// Check instances as usual, but don't change the database or generate the lists; only log
+    /// what would've been done. Meant for trying out changes against a production database.
+    ///
+    /// Requires [`Options::once`]: nothing is rescheduled, so a crawl that kept going would idle
+    /// once it walked the schedule.
+    pub read_only: bool,
+
+    /// Serve Prometheus metrics over HTTP on this address.
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl Options {
+    /// How often a check may start, so that the checks together stay under [`Options::max_rps`].
+    ///
+    /// Each check takes [`REQUESTS_PER_CHECK`] out of the budget when it starts, no matter how many
+    /// checkers are running at the time. Slow checks don't run out of time because of this: they
+    /// merely start later.
+    pub fn check_interval(&self) -> Option<Duration> {
+        requests_interval(REQUESTS_PER_CHECK, self.max_rps?)
+    }
+
+    /// How often each checker may make a request. A single check never goes faster than the whole
+    /// [`Options::max_rps`]; this matters for instances with many pages of peers. Rounded up to
+    /// whole milliseconds, which is what the checkers accept.
+    pub fn checker_request_interval(&self) -> Option<Duration> {
+        requests_interval(1, self.max_rps?)
+    }
+}
+
+/// How many requests a typical check makes: robots.txt, NodeInfo pointer and document, the
+/// instance's description, its peers, and a few to spare for redirects and retries.
+const REQUESTS_PER_CHECK: u64 = 8;
+
+/// How long `requests` take at `max_rps`, rounded up to whole milliseconds.
+fn requests_interval(requests: u64, max_rps: u32) -> Option<Duration> {
+    let max_rps = u64::from(max_rps);
+    let millis = requests
+        .checked_mul(1000)
+        .and_then(|millis| millis.checked_next_multiple_of(max_rps))
+        .and_then(|millis| millis.checked_div(max_rps))?;
+    Some(Duration::from_millis(millis))
+}
 
 /// This has to be a large-ish number, so Orchestrator can out-starve any other thread
 const SQLITE_BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
@@ -22,32 +161,170 @@ const MAX_WORKERS: usize = 128;
 /// How long a worker will wait for work before shutting down its thread.
 const MAX_WORKER_IDLE_TIME: std::time::Duration = std::time::Duration::from_secs(3);
 
-pub fn main(logger: Logger) -> anyhow::Result<()> {
-    let mut conn = db::open()?;
-    conn.busy_timeout(SQLITE_BUSY_TIMEOUT)?;
-    db::init(&mut conn)?;
-    db::reschedule_missed_checks(&mut conn)?;
+/// The size of the pool of threads that run the checkers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Workers {
+    /// The number of threads that are always present, waiting for work or performing it.
+    pub min: usize,
 
-    let pool = rusty_pool::ThreadPool::new(CONSTANT_WORKERS, MAX_WORKERS, MAX_WORKER_IDLE_TIME);
+    /// The most checkers that can run at once. It's at least 1 and at least `min`.
+    pub max: usize,
 
+    /// How long a thread above `min` waits for work before shutting down.
+    pub idle_time: Duration,
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Self {
+            min: CONSTANT_WORKERS,
+            max: MAX_WORKERS,
+            idle_time: MAX_WORKER_IDLE_TIME,
+        }
+    }
+}
+
+pub fn main(logger: Logger, options: Options) -> anyhow::Result<()> {
     let terminate = Arc::new(AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGINT, terminate.clone())
         .context(with_loc!("Setting up a SIGINT hook"))?;
     signal_hook::flag::register(signal_hook::consts::SIGTERM, terminate.clone())
         .context(with_loc!("Setting up a SIGTERM hook"))?;
+    let pause = pause::PauseControl::register()?;
+    crawl(logger, options, &terminate, pause)
+}
+
+/// Crawl until `terminate` is set, or until the crawl is over if `options` say so.
+fn crawl(
+    logger: Logger,
+    options: Options,
+    terminate: &AtomicBool,
+    mut pause: pause::PauseControl,
+) -> anyhow::Result<()> {
+    if options.deterministic {
+        warn!(
+            logger,
+            "Running in deterministic mode: checks are NOT randomized, which hammers instances in lockstep. Never do this in production!"
+        );
+        crate::time::make_deterministic();
+    }
+    if options.periods != crate::time::Periods::default() {
+        info!(logger, "Using non-default periods"; "periods" => ?options.periods);
+    }
+    crate::time::set_periods(options.periods);
+    if let Some(interval) = options.check_interval() {
+        info!(
+            logger,
+            "Starting at most one check per {} ms",
+            interval.as_millis()
+        );
+    }
+
+    let mut conn = if options.read_only {
+        info!(logger, "Running in read-only mode");
+        db::open_read_only()?
+    } else {
+        db::open()?
+    };
+    conn.busy_timeout(SQLITE_BUSY_TIMEOUT)?;
+    if !options.read_only {
+        db::init(&mut conn)?;
+        db::on_sqlite_busy_retry_indefinitely(&mut || {
+            db::save_periods(&mut conn, &options.periods)
+        })
+        .context(with_loc!("Saving the periods"))?;
+        // With `--once`, the missed checks are exactly the ones that should be done now.
+        if !options.once {
+            let rescheduled = db::reschedule_missed_checks(&mut conn)?;
+            info!(logger, "Rescheduled {} missed checks", rescheduled);
+        }
+    }
+
+    let checker_exe = instance_checker::checker_exe(options.checker_exe.as_deref())
+        .context(with_loc!("Looking for the checker executable"))?;
+    info!(logger, "Running checkers from {}", checker_exe.display());
+    // Otherwise every checker would fail, and every instance would be marked dead.
+    crate::checker::load_extra_ca_certs(&options.extra_ca_certs)
+        .context(with_loc!("Loading the extra CA certificates"))?;
+    let options = Arc::new(options);
+
+    let pool = rusty_pool::ThreadPool::new(
+        options.workers.min,
+        options.workers.max,
+        options.workers.idle_time,
+    );
+    let shared = Arc::new(Shared {
+        dns: dns_cache::DnsCache::new(dns_cache::DEFAULT_TTL),
+        metrics: Arc::new(metrics::Metrics::default()),
+        check_budget: options
+            .check_interval()
+            .map(|interval| RateLimiter::new(interval, 1)),
+    });
+    if let Some(addr) = options.metrics_addr {
+        metrics::serve(logger.clone(), addr, shared.metrics.clone(), pool.clone())?;
+    }
+    let retry_queue = Arc::new(Mutex::new(retry_queue::RetryQueue::default()));
+    let mut recently_checked = recently_checked::RecentlyChecked::default();
 
     let mut time_to_generate_a_list = SystemTime::now();
+    // With `--once`, the checks that are due at this moment are all we do.
+    let pass_started = options.once.then(SystemTime::now);
+    let pass_is_over = AtomicBool::new(false);
+    let mut clock_jumps = crate::time::ClockJumpDetector::new(SystemTime::now(), Instant::now());
+    let mut schedule_needs_fixing = false;
+    // In read-only mode, checks can't be rescheduled, so instead we walk the schedule. This is
+    // the last instance that was checked.
+    let mut read_only_cursor = None;
 
     let mut iteration = || -> anyhow::Result<()> {
-        if time_to_generate_a_list < SystemTime::now() {
+        if let Some(jump) = clock_jumps.check(SystemTime::now(), Instant::now()) {
+            info!(logger, "System clock jumped {}", jump);
+            schedule_needs_fixing = true;
+        }
+        if pause.update() {
+            if pause.is_paused() {
+                info!(logger, "Paused the crawl");
+            } else {
+                info!(logger, "Resumed the crawl");
+                // Checks that were due during the pause shouldn't all happen at once.
+                schedule_needs_fixing = true;
+            }
+        }
+        if schedule_needs_fixing && options.read_only {
+            info!(
+                logger,
+                "Would reschedule missed checks, but running in read-only mode"
+            );
+            schedule_needs_fixing = false;
+        }
+        if schedule_needs_fixing && !pause.is_paused() {
+            let rescheduled = db::reschedule_missed_checks(&mut conn)
+                .context(with_loc!("Rescheduling missed checks"))?;
+            info!(
+                logger,
+                "Rescheduled {} checks that ended up outside of the schedule", rescheduled
+            );
+            schedule_needs_fixing = false;
+        }
+
+        let lists_paused = pause.is_paused() && options.pause_list_generation;
+        if time_to_generate_a_list < SystemTime::now() && options.read_only {
+            info!(
+                logger,
+                "Would generate the lists, but running in read-only mode"
+            );
+            time_to_generate_a_list = crate::time::in_about_six_hours()?;
+        }
+        if time_to_generate_a_list < SystemTime::now() && !lists_paused && !options.once {
             let logger = logger.new(o!("list_generation" => "true"));
+            let options = options.clone();
+            let metrics = shared.metrics.clone();
             pool.execute(move || {
                 let task = {
                     let logger = logger.clone();
-                    move || {
-                        if let Err(e) = list_generator::generate(logger.clone()) {
-                            error!(logger, "List generator error: {:?}", e);
-                        }
+                    move || match list_generator::generate(logger.clone(), &options) {
+                        Ok(()) => metrics.record_list_generation(),
+                        Err(e) => error!(logger, "List generator error: {:?}", e),
                     }
                 };
 
@@ -59,8 +336,51 @@ pub fn main(logger: Logger) -> anyhow::Result<()> {
             time_to_generate_a_list = crate::time::in_about_six_hours()?;
         }
 
-        let (instance, check_time) = db::pick_next_instance(&conn)
-            .context(with_loc!("Orchestrator picking next instance"))?;
+        if pause.is_paused() {
+            std::thread::sleep(Duration::from_secs(1));
+            return Ok(());
+        }
+
+        let retry = retry_queue
+            .lock()
+            .map_err(|_| anyhow!("Retry queue mutex is poisoned"))?
+            .pop_due(Instant::now());
+        if let Some((instance, attempt)) = retry {
+            info!(
+                logger,
+                "Retrying the check of {} (attempt {})", instance, attempt
+            );
+            // Same small wait as with the scheduled checks below.
+            std::thread::sleep(Duration::from_millis(100));
+            dispatch_check(
+                &pool,
+                &logger,
+                &options,
+                instance,
+                attempt,
+                &shared,
+                &retry_queue,
+            );
+            return Ok(());
+        }
+
+        let (instance, check_time) = if options.read_only {
+            let next = db::pick_next_instance_after(&conn, read_only_cursor.as_ref())
+                .context(with_loc!("Orchestrator picking next instance"))?;
+            let Some(next) = next else {
+                // Every instance was checked already. Read-only mode always comes with `--once`.
+                pass_is_over.store(true, Ordering::Relaxed);
+                return Ok(());
+            };
+            next
+        } else {
+            db::pick_next_instance(&conn)
+                .context(with_loc!("Orchestrator picking next instance"))?
+        };
+        if is_past_the_pass(check_time, pass_started) {
+            pass_is_over.store(true, Ordering::Relaxed);
+            return Ok(());
+        }
         let wait = check_time
             .duration_since(SystemTime::now())
             // If `check_time` has already passed, wait a bit and do the check. The small wait is
@@ -75,36 +395,341 @@ pub fn main(logger: Logger) -> anyhow::Result<()> {
         if wait > Duration::from_secs(0) {
             std::thread::sleep(wait);
         }
-        db::reschedule(&mut conn, &instance)
-            .context(with_loc!("Orchestrator rescheduling an instance"))?;
-
-        let logger = logger.new(o!("host" => instance.to_string()));
-        pool.execute(move || {
-            let task = {
-                let logger = logger.clone();
-                move || {
-                    if let Err(e) = instance_checker::run(logger.clone(), instance) {
-                        error!(logger, "Checker error: {:?}", e);
-                    }
-                }
+        let instance = if options.read_only {
+            info!(
+                logger,
+                "Would reschedule {}, but running in read-only mode", instance
+            );
+            read_only_cursor = Some((check_time, instance.clone()));
+            instance
+        } else {
+            // Another Orchestrator working on the same database might've claimed the instance
+            // while we were waiting, so we take whichever check is due now.
+            let claimed =
+                db::claim_next_instance(&mut conn, SystemTime::now(), &options.software_periods)
+                    .context(with_loc!("Orchestrator claiming an instance"))?;
+            let Some(instance) = claimed else {
+                return Ok(());
             };
+            instance
+        };
 
-            if let Err(e) = std::panic::catch_unwind(task) {
-                error!(logger, "Checker panicked: {:?}", e);
-            }
-        });
+        if !recently_checked.try_claim(&instance, Instant::now()) {
+            info!(
+                logger,
+                "{} was checked less than a minute ago, skipping", instance
+            );
+            return Ok(());
+        }
+
+        dispatch_check(&pool, &logger, &options, instance, 0, &shared, &retry_queue);
 
         Ok(())
     };
 
     loop {
         db::on_sqlite_busy_retry_indefinitely(&mut iteration)?;
+        if options.single_iteration {
+            println!("Ran a single iteration, shutting down...");
+            break;
+        }
         if terminate.load(Ordering::Relaxed) {
             println!("Shutting down gracefully...");
             break;
         }
+        if pass_is_over.load(Ordering::Relaxed) {
+            println!("Checked every instance that was due, shutting down...");
+            break;
+        }
     }
 
     pool.join();
+
+    if pass_is_over.load(Ordering::Relaxed) && !options.read_only {
+        list_generator::generate(logger.clone(), &options)
+            .context(with_loc!("Generating the lists"))?;
+    }
     Ok(())
 }
+
+/// Returns `true` if the check at `check_time` wasn't due yet when the `--once` pass started at
+/// `pass_started`. Without `--once`, there are no passes, so this is always `false`.
+fn is_past_the_pass(check_time: SystemTime, pass_started: Option<SystemTime>) -> bool {
+    pass_started.is_some_and(|started| check_time > started)
+}
+
+/// Caches and counters that are shared by all checks.
+struct Shared {
+    dns: dns_cache::DnsCache,
+    metrics: Arc<metrics::Metrics>,
+    /// Shares `--max-rps` between the checks.
+    check_budget: Option<RateLimiter>,
+}
+
+/// Check the instance on the thread pool. `attempt` is 0 for scheduled checks, and the number of
+/// the retry otherwise.
+///
+/// With `--max-rps`, this first waits until the check fits into the budget.
+fn dispatch_check(
+    pool: &rusty_pool::ThreadPool,
+    logger: &Logger,
+    options: &Arc<Options>,
+    instance: Domain,
+    attempt: u32,
+    shared: &Arc<Shared>,
+    retry_queue: &Arc<Mutex<retry_queue::RetryQueue>>,
+) {
+    if let Some(budget) = &shared.check_budget {
+        budget.acquire();
+    }
+    let logger = logger.new(o!("host" => instance.to_string()));
+    let options = options.clone();
+    let shared = shared.clone();
+    let retry_queue = retry_queue.clone();
+    pool.execute(move || {
+        let task = {
+            let logger = logger.clone();
+            move || {
+                if let Err(e) = instance_checker::run(
+                    logger.clone(),
+                    instance.clone(),
+                    &shared.dns,
+                    &shared.metrics,
+                    &options,
+                ) {
+                    on_check_error(&logger, &retry_queue, instance, attempt, e);
+                }
+            }
+        };
+
+        if let Err(e) = std::panic::catch_unwind(task) {
+            error!(logger, "Checker panicked: {:?}", e);
+        }
+    });
+}
+
+/// Log the error, and queue a retry if the check failed through no fault of the instance.
+fn on_check_error(
+    logger: &Logger,
+    retry_queue: &Mutex<retry_queue::RetryQueue>,
+    instance: Domain,
+    attempt: u32,
+    error: anyhow::Error,
+) {
+    error!(logger, "Checker error: {:?}", error);
+
+    if !instance_checker::is_orchestrator_side_failure(&error) {
+        return;
+    }
+    match retry_queue.lock() {
+        Ok(mut queue) => {
+            if queue.push(instance, attempt, Instant::now()) {
+                info!(
+                    logger,
+                    "Queued a retry of the check ({} retries waiting)",
+                    queue.len()
+                );
+            } else {
+                info!(
+                    logger,
+                    "Not retrying the check; it'll be done at its scheduled time"
+                );
+            }
+        }
+        Err(_) => error!(logger, "Retry queue mutex is poisoned"),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use std::path::Path;
+
+    /// The tests that run the crawl change the working directory, where the database is kept.
+    static WORKING_DIR: Mutex<()> = Mutex::new(());
+
+    /// Prepare a crawl in the working directory: a database with the `instances` due at the given
+    /// times, and a fake checker that reports every instance alive and logs its hostname to
+    /// _checked_. Returns the options to crawl with.
+    fn prepare_crawl(instances: &[(&str, SystemTime)]) -> Options {
+        use crate::ipc::{CheckerResponse, InstanceState, PROTOCOL_VERSION};
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut conn = db::open().unwrap();
+        db::init(&mut conn).unwrap();
+        // The seed instance isn't due any time soon.
+        conn.execute(
+            "UPDATE instances SET next_check_datetime = ?1",
+            [unix_secs(
+                SystemTime::now()
+                    .checked_add(Duration::from_secs(3600))
+                    .unwrap(),
+            )],
+        )
+        .unwrap();
+        for &(hostname, next_check) in instances {
+            db::add_instance(&conn, &Domain::from_str(hostname).unwrap()).unwrap();
+            conn.execute(
+                "UPDATE instances SET next_check_datetime = ?1 WHERE hostname = ?2",
+                rusqlite::params![unix_secs(next_check), hostname],
+            )
+            .unwrap();
+        }
+
+        let responses = [
+            CheckerResponse::Hello {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            CheckerResponse::State {
+                state: InstanceState::Alive {
+                    hide_from_list: false,
+                },
+            },
+            CheckerResponse::EndOfPeers,
+        ]
+        .map(|response| format!("echo '{}'\n", serde_json::to_string(&response).unwrap()));
+        let dir = std::env::current_dir().unwrap();
+        let checker = dir.join("checker");
+        std::fs::write(
+            &checker,
+            format!(
+                "#!/bin/sh\necho \"$2\" >> {}\n{}",
+                dir.join("checked").display(),
+                responses.concat()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&checker, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        Options {
+            checker_exe: Some(checker),
+            ..Default::default()
+        }
+    }
+
+    /// The hostnames that the fake checker of [`prepare_crawl`] in `dir` was run for.
+    fn checked(dir: &Path) -> Vec<String> {
+        let checked = std::fs::read_to_string(dir.join("checked")).unwrap_or_default();
+        let mut checked: Vec<String> = checked.lines().map(str::to_owned).collect();
+        checked.sort_unstable();
+        checked
+    }
+
+    fn unix_secs(time: SystemTime) -> u64 {
+        time.duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn once_checks_the_due_instances_and_exits() {
+        let _working_dir = WORKING_DIR.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let previous_dir = std::env::current_dir().unwrap();
+        std::env::set_current_dir(dir.path()).unwrap();
+
+        // Onion services aren't looked up in the DNS, so the test doesn't need the network.
+        let due = ["yzw45do3yrjfnbpr.onion", "abcdefghijklmnop.onion"];
+        let now = SystemTime::now();
+        let day_ago = now - Duration::from_secs(24 * 3600);
+        let options = Options {
+            once: true,
+            ..prepare_crawl(&[
+                (due[0], day_ago),
+                (due[1], day_ago),
+                ("later234567abcde.onion", now + Duration::from_secs(3600)),
+            ])
+        };
+        let result = main(Logger::root(slog::Discard, o!()), options);
+        let list_exists = Path::new("instances.json").exists();
+        std::env::set_current_dir(previous_dir).unwrap();
+        result.unwrap();
+
+        assert_eq!(
+            checked(dir.path()),
+            ["abcdefghijklmnop.onion", "yzw45do3yrjfnbpr.onion"]
+        );
+        assert!(list_exists);
+
+        let conn =
+            rusqlite::Connection::open(dir.path().join("minoru-fediverse-crawler.db")).unwrap();
+        for hostname in due {
+            let instance = db::get_instance_by_host(&conn, &Domain::from_str(hostname).unwrap())
+                .unwrap()
+                .unwrap();
+            assert_eq!(instance.state, db::InstanceState::Alive);
+            assert!(instance.next_check > now);
+        }
+    }
+
+    #[test]
+    fn paused_crawl_dispatches_nothing_until_resumed() {
+        let _working_dir = WORKING_DIR.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let previous_dir = std::env::current_dir().unwrap();
+        std::env::set_current_dir(dir.path()).unwrap();
+
+        let now = SystemTime::now();
+        let options = prepare_crawl(&[
+            ("yzw45do3yrjfnbpr.onion", now - Duration::from_secs(3600)),
+            ("abcdefghijklmnop.onion", now + Duration::from_secs(3)),
+        ]);
+        // The crawl is paused before it begins.
+        let toggle = Arc::new(AtomicBool::new(true));
+        let terminate = Arc::new(AtomicBool::new(false));
+        let crawl = {
+            let pause = pause::PauseControl::new(toggle.clone());
+            let terminate = terminate.clone();
+            std::thread::spawn(move || {
+                crawl(
+                    Logger::root(slog::Discard, o!()),
+                    options,
+                    &terminate,
+                    pause,
+                )
+            })
+        };
+
+        std::thread::sleep(Duration::from_secs(2));
+        let checked_while_paused = checked(dir.path());
+
+        toggle.store(true, Ordering::Relaxed);
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while checked(dir.path()).is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        terminate.store(true, Ordering::Relaxed);
+        let result = crawl.join().unwrap();
+        std::env::set_current_dir(previous_dir).unwrap();
+        result.unwrap();
+
+        assert!(checked_while_paused.is_empty());
+        // The check that was missed during the pause was moved to later, so that the missed
+        // checks don't all happen at once. The one that came due afterwards was done.
+        assert_eq!(checked(dir.path()), ["abcdefghijklmnop.onion"]);
+    }
+
+    #[test]
+    fn orchestrator_side_failure_is_retried() {
+        let logger = Logger::root(slog::Discard, o!());
+        let instance = Domain::from_str("example.com").unwrap();
+        let retry_queue = Mutex::new(retry_queue::RetryQueue::default());
+
+        let error =
+            anyhow!("Failed to spawn a checker").context(instance_checker::OrchestratorSideFailure);
+        on_check_error(&logger, &retry_queue, instance.clone(), 0, error);
+        assert_eq!(retry_queue.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn instance_side_failure_is_not_retried() {
+        let logger = Logger::root(slog::Discard, o!());
+        let instance = Domain::from_str("example.com").unwrap();
+        let retry_queue = Mutex::new(retry_queue::RetryQueue::default());
+
+        let error = anyhow!("Failed to deserialize checker's response");
+        on_check_error(&logger, &retry_queue, instance, 0, error);
+        assert_eq!(retry_queue.lock().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,70 @@
+//! Pausing and resuming the crawl without stopping the Orchestrator.
+//!
+//! Operators send SIGUSR1 to pause the checks, e.g. for the duration of maintenance, and send it
+//! again to resume them.
+use crate::with_loc;
+use anyhow::Context;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+pub struct PauseControl {
+    toggle_requested: Arc<AtomicBool>,
+    paused: bool,
+}
+
+impl PauseControl {
+    /// Start unpaused, and toggle the state whenever SIGUSR1 arrives.
+    pub fn register() -> anyhow::Result<Self> {
+        let toggle_requested = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGUSR1, toggle_requested.clone())
+            .context(with_loc!("Setting up a SIGUSR1 hook"))?;
+        Ok(Self::new(toggle_requested))
+    }
+
+    /// Start unpaused, and toggle the state whenever `toggle_requested` is set.
+    pub fn new(toggle_requested: Arc<AtomicBool>) -> Self {
+        Self {
+            toggle_requested,
+            paused: false,
+        }
+    }
+
+    /// Apply the toggle requested since the last call, if any. Returns `true` if the state
+    /// changed.
+    pub fn update(&mut self) -> bool {
+        let toggled = self.toggle_requested.swap(false, Ordering::Relaxed);
+        if toggled {
+            self.paused = !self.paused;
+        }
+        toggled
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn toggles_between_paused_and_running() {
+        let mut control = PauseControl::new(Arc::new(AtomicBool::new(false)));
+        assert!(!control.update());
+        assert!(!control.is_paused());
+
+        control.toggle_requested.store(true, Ordering::Relaxed);
+        assert!(control.update());
+        assert!(control.is_paused());
+        // The state sticks until the next toggle.
+        assert!(!control.update());
+        assert!(control.is_paused());
+
+        control.toggle_requested.store(true, Ordering::Relaxed);
+        assert!(control.update());
+        assert!(!control.is_paused());
+    }
+}
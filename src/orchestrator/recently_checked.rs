@@ -0,0 +1,50 @@
+//! Instances that were dispatched for a check a moment ago.
+//!
+//! The schedule in the database should already prevent back-to-back checks, but if something goes
+//! wrong with it (e.g. the Orchestrator is restarted in a loop), this guard makes sure that an
+//! instance is still checked at most once a minute.
+use crate::domain::Domain;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long an instance is considered recently checked.
+const GUARD_PERIOD: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub struct RecentlyChecked {
+    checked_at: HashMap<Domain, Instant>,
+}
+
+impl RecentlyChecked {
+    /// Note down that the instance is about to be checked.
+    ///
+    /// Returns `false` (and notes nothing) if the instance was already checked recently.
+    pub fn try_claim(&mut self, instance: &Domain, now: Instant) -> bool {
+        self.checked_at
+            .retain(|_, checked_at| now.saturating_duration_since(*checked_at) < GUARD_PERIOD);
+        if self.checked_at.contains_key(instance) {
+            return false;
+        }
+        self.checked_at.insert(instance.clone(), now);
+        true
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recently_checked_instance_is_not_checked_again() {
+        let instance = Domain::from_str("example.com").unwrap();
+        let other = Domain::from_str("example.org").unwrap();
+        let now = Instant::now();
+        let mut guard = RecentlyChecked::default();
+
+        assert!(guard.try_claim(&instance, now));
+        assert!(!guard.try_claim(&instance, now + Duration::from_secs(5)));
+        assert!(guard.try_claim(&other, now + Duration::from_secs(5)));
+        assert!(guard.try_claim(&instance, now + GUARD_PERIOD));
+    }
+}
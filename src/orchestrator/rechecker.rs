@@ -0,0 +1,216 @@
+//! Check a single instance right away, and show what that did to its state.
+//!
+//! This goes through the same steps as the Orchestrator does for every check, except that the
+//! checker runs in our own process, so its logs end up next to ours. Meant for finding out why an
+//! instance is stuck in some state.
+//!
+//! The check counts as a scheduled one: the next check is scheduled according to the new state.
+//! For it to be done the same way as in the crawl, the crawl's options that affect the checks
+//! have to be passed again.
+use crate::{
+    checker, db,
+    domain::Domain,
+    ipc,
+    orchestrator::{
+        dns_cache::{self, DnsCache},
+        instance_checker, Options,
+    },
+    with_loc,
+};
+use anyhow::{anyhow, Context};
+use rusqlite::Connection;
+use slog::{info, Logger};
+use std::time::Duration;
+
+/// A check can take a while, and the Orchestrator might be writing to the database all along.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub fn main(logger: Logger, host: &str, options: &Options) -> anyhow::Result<()> {
+    let instance = Domain::from_str(host)?;
+    info!(logger, "Re-checking {}", instance);
+
+    let mut conn = db::open()?;
+    conn.busy_timeout(BUSY_TIMEOUT)
+        .context(with_loc!("Setting the busy timeout"))?;
+    db::init(&mut conn)?;
+    db::use_saved_periods(&conn)?;
+
+    // Otherwise the check would fail, and the instance would be marked dead.
+    checker::load_extra_ca_certs(&options.extra_ca_certs)
+        .context(with_loc!("Loading the extra CA certificates"))?;
+    let checker_options = instance_checker::checker_options(options);
+    let (before, after) = recheck(&logger, &mut conn, &instance, options, |instance| {
+        let host = url::Host::Domain(instance.to_string());
+        checker::check_in_process(&logger, host, &checker_options)
+    })?;
+    println!(
+        "{}: {} -> {}",
+        instance,
+        before.state.name(),
+        after.state.name()
+    );
+    println!("Before: {}", before);
+    println!("After: {}", after);
+    Ok(())
+}
+
+/// Check the `instance` with `check`, and apply the responses to the database like the
+/// Orchestrator would with the given `options`. Returns the instance's records from before and
+/// after.
+fn recheck(
+    logger: &Logger,
+    conn: &mut Connection,
+    instance: &Domain,
+    options: &Options,
+    check: impl FnOnce(&Domain) -> anyhow::Result<Vec<ipc::CheckerResponse>>,
+) -> anyhow::Result<(db::InstanceRecord, db::InstanceRecord)> {
+    let before = record(conn, instance)?;
+
+    let responses = check(instance)?;
+    let lines = responses
+        .iter()
+        .map(|response| serde_json::to_string(response).map_err(std::io::Error::from));
+    let dns_cache = DnsCache::new(dns_cache::DEFAULT_TTL);
+    let reported = instance_checker::apply_checker_responses(
+        logger, conn, instance, lines, &dns_cache, options,
+    )?;
+    if !reported {
+        // That's what a checker process that failed without reporting anything amounts to.
+        instance_checker::mark_unreported_dead(
+            logger,
+            conn,
+            instance,
+            options,
+            db::DeathReason::NoResponse,
+        )?;
+    }
+    db::on_sqlite_busy_retry_indefinitely(&mut || {
+        db::reschedule_by_state(conn, instance, &options.software_periods)
+    })
+    .context(with_loc!("Scheduling the next check"))?;
+
+    let after = record(conn, instance)?;
+    Ok((before, after))
+}
+
+fn record(conn: &Connection, instance: &Domain) -> anyhow::Result<db::InstanceRecord> {
+    db::on_sqlite_busy_retry_indefinitely(&mut || db::get_instance_by_host(conn, instance))
+        .context(with_loc!("Looking up the instance"))?
+        .ok_or_else(|| {
+            anyhow!(
+                "{} is not in the database; add it with --add-instances first",
+                instance
+            )
+        })
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use slog::o;
+    use std::time::SystemTime;
+
+    #[test]
+    fn applies_the_check_and_reports_the_transition() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+
+        let options = Options::default();
+        let (before, after) = recheck(&logger, &mut conn, &instance, &options, |_| {
+            Ok(vec![
+                ipc::CheckerResponse::Hello {
+                    protocol_version: ipc::PROTOCOL_VERSION,
+                },
+                ipc::CheckerResponse::State {
+                    state: ipc::InstanceState::Alive {
+                        hide_from_list: false,
+                    },
+                },
+                ipc::CheckerResponse::Peer {
+                    peer: url::Host::Domain("example.org".to_string()),
+                },
+                ipc::CheckerResponse::EndOfPeers,
+            ])
+        })
+        .unwrap();
+        assert_eq!(before.state, db::InstanceState::Discovered);
+        assert_eq!(after.state, db::InstanceState::Alive);
+        assert!(after.next_check > SystemTime::now() + Duration::from_secs(12 * 3600));
+        let peer = Domain::from_str("example.org").unwrap();
+        assert!(db::get_instance_by_host(&conn, &peer).unwrap().is_some());
+
+        // A check that fails without saying anything is taken as the instance being dead.
+        let (before, after) = recheck(&logger, &mut conn, &instance, &options, |_| {
+            Ok(vec![ipc::CheckerResponse::Hello {
+                protocol_version: ipc::PROTOCOL_VERSION,
+            }])
+        })
+        .unwrap();
+        assert_eq!(before.state, db::InstanceState::Alive);
+        assert_eq!(after.state, db::InstanceState::Dying);
+        assert_eq!(after.death_reason, Some(db::DeathReason::NoResponse));
+    }
+
+    #[test]
+    fn crawl_options_apply() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+
+        // The instance's peers are too deep to be added, and its software is checked weekly.
+        let options = Options {
+            max_depth: Some(0),
+            software_periods: vec![("mastodon".to_string(), crate::time::Period::Weekly)],
+            ..Default::default()
+        };
+        let (_, after) = recheck(&logger, &mut conn, &instance, &options, |_| {
+            Ok(vec![
+                ipc::CheckerResponse::Hello {
+                    protocol_version: ipc::PROTOCOL_VERSION,
+                },
+                ipc::CheckerResponse::State {
+                    state: ipc::InstanceState::Alive {
+                        hide_from_list: false,
+                    },
+                },
+                ipc::CheckerResponse::Metadata {
+                    metadata: ipc::Metadata {
+                        software: Some("mastodon".to_string()),
+                        ..Default::default()
+                    },
+                },
+                ipc::CheckerResponse::Peer {
+                    peer: url::Host::Domain("example.org".to_string()),
+                },
+                ipc::CheckerResponse::EndOfPeers,
+            ])
+        })
+        .unwrap();
+        let peer = Domain::from_str("example.org").unwrap();
+        assert!(db::get_instance_by_host(&conn, &peer).unwrap().is_none());
+        // More than the "daily" period plus its randomness.
+        assert!(after.next_check > SystemTime::now() + Duration::from_secs(32 * 3600));
+    }
+
+    #[test]
+    fn unknown_instances_are_not_checked() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+
+        let mut checked = false;
+        let result = recheck(&logger, &mut conn, &instance, &Options::default(), |_| {
+            checked = true;
+            Ok(vec![])
+        });
+        assert!(result.is_err());
+        assert!(!checked);
+    }
+}
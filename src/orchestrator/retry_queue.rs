@@ -0,0 +1,121 @@
+//! Checks that failed because of a problem on our side, waiting to be retried.
+//!
+//! The queue lives in memory only: if the Orchestrator restarts, the instances will simply be
+//! checked at their next scheduled time.
+use crate::domain::Domain;
+use std::time::{Duration, Instant};
+
+/// Maximum number of checks waiting to be retried. If the Orchestrator is failing this much, the
+/// queue won't help anyway.
+const CAPACITY: usize = 1000;
+
+/// After this many retries, the check is abandoned until its next scheduled time.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry. Each subsequent retry waits twice as long as the previous one.
+const BASE_DELAY: Duration = Duration::from_secs(30);
+
+struct Entry {
+    instance: Domain,
+    attempt: u32,
+    due: Instant,
+}
+
+#[derive(Default)]
+pub struct RetryQueue {
+    entries: Vec<Entry>,
+}
+
+impl RetryQueue {
+    /// Schedule a retry of a check that failed on its `attempt`th retry (0 is the scheduled check).
+    ///
+    /// Returns `false` if the check won't be retried, because it failed too many times or because
+    /// the queue is full.
+    pub fn push(&mut self, instance: Domain, attempt: u32, now: Instant) -> bool {
+        let attempt = attempt.saturating_add(1);
+        if attempt > MAX_ATTEMPTS || self.entries.len() >= CAPACITY {
+            return false;
+        }
+        if self.entries.iter().any(|entry| entry.instance == instance) {
+            return true;
+        }
+
+        let delay = 2u32
+            .checked_pow(attempt.saturating_sub(1))
+            .and_then(|multiplier| BASE_DELAY.checked_mul(multiplier))
+            .unwrap_or(Duration::MAX);
+        let Some(due) = now.checked_add(delay) else {
+            return false;
+        };
+        self.entries.push(Entry {
+            instance,
+            attempt,
+            due,
+        });
+        true
+    }
+
+    /// Take the retry that's been due the longest, if any.
+    pub fn pop_due(&mut self, now: Instant) -> Option<(Domain, u32)> {
+        let (index, _) = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.due <= now)
+            .min_by_key(|(_, entry)| entry.due)?;
+        let entry = self.entries.swap_remove(index);
+        Some((entry.instance, entry.attempt))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retries_with_growing_delay() {
+        let instance = Domain::from_str("example.com").unwrap();
+        let now = Instant::now();
+        let mut queue = RetryQueue::default();
+
+        assert!(queue.push(instance.clone(), 0, now));
+        assert_eq!(queue.pop_due(now), None);
+        assert_eq!(queue.pop_due(now + BASE_DELAY), Some((instance.clone(), 1)));
+        assert_eq!(queue.len(), 0);
+
+        assert!(queue.push(instance.clone(), 1, now));
+        assert_eq!(queue.pop_due(now + BASE_DELAY), None);
+        assert_eq!(
+            queue.pop_due(now + BASE_DELAY * 2),
+            Some((instance.clone(), 2))
+        );
+    }
+
+    #[test]
+    fn gives_up_eventually() {
+        let instance = Domain::from_str("example.com").unwrap();
+        let mut queue = RetryQueue::default();
+
+        assert!(!queue.push(instance, MAX_ATTEMPTS, Instant::now()));
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn is_bounded() {
+        let now = Instant::now();
+        let mut queue = RetryQueue::default();
+        for i in 0..CAPACITY {
+            let instance = Domain::from_str(&format!("i{}.example.com", i)).unwrap();
+            assert!(queue.push(instance, 0, now));
+        }
+
+        let instance = Domain::from_str("one-too-many.example.com").unwrap();
+        assert!(!queue.push(instance, 0, now));
+        assert_eq!(queue.len(), CAPACITY);
+    }
+}
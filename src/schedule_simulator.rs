@@ -0,0 +1,118 @@
+//! Project the load that the scheduler is going to generate.
+//!
+//! This reads the current schedule from the database and plays it forward, assuming every check
+//! is rescheduled exactly one period later (i.e. ignoring randomization). The result is
+//! a histogram of checks per hour, which lets us confirm that the load is spread evenly as
+//! described in [`crate::time`].
+use crate::{db, orchestrator::Options, time, with_loc};
+use anyhow::Context;
+use slog::{info, Logger};
+use std::time::{Duration, SystemTime};
+
+/// How far into the future to simulate if the user didn't specify: one "week".
+const DEFAULT_WINDOW_HOURS: u64 = 167;
+
+const ONE_HOUR: Duration = Duration::from_secs(3600);
+
+/// The widest bar in the printed histogram.
+const MAX_BAR_WIDTH: f64 = 60.0;
+
+/// Only the `software_periods` of the `options` are used.
+pub fn main(logger: Logger, hours: Option<u64>, options: &Options) -> anyhow::Result<()> {
+    let hours = hours.unwrap_or(DEFAULT_WINDOW_HOURS);
+    info!(
+        logger,
+        "Simulating the schedule for the next {} hours", hours
+    );
+
+    let mut conn = db::open()?;
+    db::init(&mut conn)?;
+    db::use_saved_periods(&conn)?;
+    let schedule = db::get_schedule(&conn, &options.software_periods)
+        .context(with_loc!("Reading the schedule"))?;
+    let histogram = histogram(&schedule, SystemTime::now(), hours);
+
+    let peak = histogram.iter().copied().max().unwrap_or(0);
+    println!("Projected checks per hour over the next {} hours:", hours);
+    for (hour, count) in histogram.iter().enumerate() {
+        let bar_width = if peak == 0 {
+            0
+        } else {
+            (*count as f64 / peak as f64 * MAX_BAR_WIDTH).round() as usize
+        };
+        println!("+{:>4}h {:>8} {}", hour, count, "#".repeat(bar_width));
+    }
+    println!(
+        "Peak: {} checks per hour, or {:.3} checks per second",
+        peak,
+        peak as f64 / ONE_HOUR.as_secs_f64()
+    );
+
+    Ok(())
+}
+
+/// Count the checks that fall into each of the next `hours` hours after `now`.
+///
+/// `schedule` is a list of next check datetimes and the periods with which these checks repeat.
+/// Checks whose time has already passed are counted as happening right away.
+fn histogram(schedule: &[(SystemTime, time::Period)], now: SystemTime, hours: u64) -> Vec<u64> {
+    let mut buckets = vec![0u64; usize::try_from(hours).unwrap_or(usize::MAX)];
+    for (next_check, period) in schedule {
+        let mut next_check = *next_check.max(&now);
+        loop {
+            let offset = next_check.duration_since(now).unwrap_or(Duration::ZERO);
+            let bucket = offset
+                .as_secs()
+                .checked_div(ONE_HOUR.as_secs())
+                .and_then(|b| usize::try_from(b).ok())
+                .and_then(|b| buckets.get_mut(b));
+            match bucket {
+                Some(bucket) => *bucket = bucket.saturating_add(1),
+                None => break,
+            }
+
+            match next_check.checked_add(period.duration()) {
+                Some(t) => next_check = t,
+                None => break,
+            }
+        }
+    }
+    buckets
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used, clippy::indexing_slicing)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn counts_checks_per_hour() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        let schedule = [
+            (now, time::Period::Daily),
+            (now + minutes(30), time::Period::Daily),
+            (now + minutes(2 * 60 + 59), time::Period::Weekly),
+            // Overdue checks happen right away.
+            (now - minutes(60), time::Period::Daily),
+            // Too far in the future to be counted.
+            (now + minutes(31 * 60), time::Period::Daily),
+        ];
+
+        let histogram = histogram(&schedule, now, 30);
+
+        assert_eq!(histogram.len(), 30);
+        assert_eq!(histogram[0], 3);
+        assert_eq!(histogram[2], 1);
+        // Daily checks repeat 29 hours later; the weekly one doesn't.
+        assert_eq!(histogram[29], 3);
+        assert_eq!(histogram.iter().sum::<u64>(), 7);
+    }
+
+    #[test]
+    fn empty_schedule_produces_empty_buckets() {
+        let histogram = histogram(&[], SystemTime::now(), 5);
+        assert_eq!(histogram, vec![0; 5]);
+    }
+}
@@ -0,0 +1,51 @@
+//! Names of the software that Fediverse instances run.
+//!
+//! Forks and rebrands report different names for what is essentially the same codebase (e.g.
+//! Calckey became Firefish, which was forked into Sharkey and Iceshrimp). To group such instances
+//! together, we map each name to the "family" it belongs to.
+
+/// Known names of forks and rebrands, and the family that each belongs to. Names that aren't
+/// listed here are families of their own.
+const FAMILIES: &[(&str, &str)] = &[
+    ("akkoma", "pleroma"),
+    ("calckey", "misskey"),
+    ("catodon", "misskey"),
+    ("cherrypick", "misskey"),
+    ("firefish", "misskey"),
+    ("foundkey", "misskey"),
+    ("iceshrimp", "misskey"),
+    ("meisskey", "misskey"),
+    ("sharkey", "misskey"),
+    ("glitchsoc", "mastodon"),
+    ("hometown", "mastodon"),
+];
+
+/// The family of the software with the given name, as reported in NodeInfo.
+pub fn family(name: &str) -> String {
+    let name = name.trim().to_lowercase();
+    FAMILIES
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map(|(_, family)| family.to_string())
+        .unwrap_or(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn forks_belong_to_the_family_of_the_original() {
+        assert_eq!(family("calckey"), "misskey");
+        assert_eq!(family("firefish"), "misskey");
+        assert_eq!(family("Sharkey"), "misskey");
+        assert_eq!(family("akkoma"), "pleroma");
+        assert_eq!(family("hometown"), "mastodon");
+    }
+
+    #[test]
+    fn unknown_software_is_its_own_family() {
+        assert_eq!(family("mastodon"), "mastodon");
+        assert_eq!(family(" GoToSocial "), "gotosocial");
+    }
+}
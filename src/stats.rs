@@ -0,0 +1,381 @@
+//! Read-only views of the database for the operator.
+use crate::{
+    db::{self, InstanceState},
+    domain::Domain,
+    with_loc,
+};
+use anyhow::{bail, Context};
+use rusqlite::Connection;
+use serde::Serialize;
+use slog::{info, Logger};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How many of the most stable instances `--stats` prints.
+const MOST_STABLE_COUNT: u32 = 10;
+
+/// `--stats` lists alive instances whose certificates expire within this many days.
+const CERTIFICATE_WARNING_DAYS: u64 = 14;
+
+/// Print everything the database knows about `host`.
+pub fn show(logger: Logger, host: &str) -> anyhow::Result<()> {
+    let domain = Domain::from_str(host)?;
+    info!(logger, "Showing {}", domain);
+
+    let conn = db::open()?;
+    match db::on_sqlite_busy_retry_indefinitely(&mut || db::get_instance_by_host(&conn, &domain))
+        .context(with_loc!("Looking up the instance"))?
+    {
+        Some(record) => println!("{}: {}", domain, record),
+        None => println!("{} is not in the database", domain),
+    }
+    Ok(())
+}
+
+/// Print the instances that are in the `state`, along with how long they've been dying or moving.
+pub fn list_state(logger: Logger, state: InstanceState) -> anyhow::Result<()> {
+    info!(logger, "Listing {} instances", state.name());
+
+    let conn = db::open()?;
+    let instances =
+        db::on_sqlite_busy_retry_indefinitely(&mut || db::get_instances_in_state(&conn, state))
+            .context(with_loc!("Listing the instances"))?;
+    for (instance, transition) in instances {
+        println!("{}", describe(state, &instance, transition.as_ref()));
+    }
+    Ok(())
+}
+
+fn describe(
+    state: InstanceState,
+    instance: &Domain,
+    transition: Option<&db::Transition>,
+) -> String {
+    let Some(transition) = transition else {
+        return instance.to_string();
+    };
+    let since = hours_from_now(unix_timestamp(transition.since));
+    match &transition.moving_to {
+        Some(to) => format!(
+            "{}: moving to {} since {}, redirected {} times",
+            instance, to, since, transition.checks
+        ),
+        // Only dying instances have transitions without a destination.
+        None => format!(
+            "{}: {} since {}, failed {} checks",
+            instance,
+            state.name(),
+            since,
+            transition.checks
+        ),
+    }
+}
+
+/// How `--stats` prints the statistics.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// For humans.
+    #[default]
+    Text,
+
+    /// A single [`Stats`] object, for monitoring scripts.
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("Unknown format {}, expected \"text\" or \"json\"", s),
+        }
+    }
+}
+
+/// Statistics printed by `--stats`. In JSON, the fields and their names are stable; timestamps
+/// are seconds since the Unix epoch.
+#[derive(Debug, Serialize)]
+struct Stats {
+    /// The number of instances in each state, keyed by the state's name. Every state is present,
+    /// even if there are no instances in it.
+    states: BTreeMap<&'static str, u64>,
+
+    /// The number of instances in the database.
+    total: u64,
+
+    /// The earliest and the latest scheduled checks; `null` if there are no instances.
+    schedule: Option<ScheduleExtremes>,
+
+    /// Instances with the most consecutive alive checks, most stable first.
+    most_stable: Vec<StableInstance>,
+
+    /// Alive instances whose certificates expire within [`CERTIFICATE_WARNING_DAYS`], soonest
+    /// first.
+    expiring_certificates: Vec<ExpiringCertificate>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScheduleExtremes {
+    earliest_check: u64,
+    latest_check: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct StableInstance {
+    hostname: String,
+    consecutive_alive_checks: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct ExpiringCertificate {
+    hostname: String,
+    expires_at: u64,
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Print the number of instances in each state, the instances that stayed alive the longest, and
+/// certificates that are about to expire.
+pub fn main(logger: Logger, format: Format) -> anyhow::Result<()> {
+    info!(logger, "Printing database statistics");
+
+    let conn = db::open()?;
+    let stats = db::on_sqlite_busy_retry_indefinitely(&mut || collect(&conn, SystemTime::now()))?;
+    match format {
+        Format::Text => print!("{}", stats),
+        Format::Json => println!(
+            "{}",
+            serde_json::to_string(&stats).context(with_loc!("Serializing the statistics"))?
+        ),
+    }
+    Ok(())
+}
+
+fn collect(conn: &Connection, now: SystemTime) -> anyhow::Result<Stats> {
+    let mut states: BTreeMap<&'static str, u64> = InstanceState::ALL
+        .iter()
+        .map(|state| (state.name(), 0))
+        .collect();
+    let mut total: u64 = 0;
+    for (state, count) in
+        db::count_instances_by_state(conn).context(with_loc!("Counting instances"))?
+    {
+        states.insert(state.name(), count);
+        total = total.saturating_add(count);
+    }
+
+    let schedule = db::get_schedule_extremes(conn)
+        .context(with_loc!("Getting the schedule"))?
+        .map(|(earliest, latest)| ScheduleExtremes {
+            earliest_check: unix_timestamp(earliest),
+            latest_check: unix_timestamp(latest),
+        });
+
+    let most_stable = db::get_most_stable_instances(conn, MOST_STABLE_COUNT)
+        .context(with_loc!("Getting the most stable instances"))?
+        .into_iter()
+        .map(|(instance, checks)| StableInstance {
+            hostname: instance.to_string(),
+            consecutive_alive_checks: checks,
+        })
+        .collect();
+
+    let warning_period = Duration::from_secs(CERTIFICATE_WARNING_DAYS * 24 * 60 * 60);
+    let before = now.checked_add(warning_period).unwrap_or(now);
+    let expiring_certificates = db::get_expiring_certificates(conn, before)
+        .context(with_loc!("Getting the expiring certificates"))?
+        .into_iter()
+        .map(|(instance, expires_at)| ExpiringCertificate {
+            hostname: instance.to_string(),
+            expires_at: unix_timestamp(expires_at),
+        })
+        .collect();
+
+    Ok(Stats {
+        states,
+        total,
+        schedule,
+        most_stable,
+        expiring_certificates,
+    })
+}
+
+/// How far `timestamp` is from now, e.g. "in 1.5 hours" or "2.0 hours ago".
+fn hours_from_now(timestamp: u64) -> String {
+    let now = unix_timestamp(SystemTime::now());
+    let hours = |secs: u64| secs as f64 / 3600.0;
+    match timestamp.checked_sub(now) {
+        Some(wait) => format!("in {:.1} hours", hours(wait)),
+        None => format!("{:.1} hours ago", hours(now.saturating_sub(timestamp))),
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Instances by state:")?;
+        for state in InstanceState::ALL {
+            let count = self.states.get(state.name()).copied().unwrap_or(0);
+            writeln!(f, "  {:>10}: {}", state.name(), count)?;
+        }
+        writeln!(f, "  {:>10}: {}", "total", self.total)?;
+
+        if let Some(schedule) = &self.schedule {
+            writeln!(
+                f,
+                "Earliest scheduled check: {}",
+                hours_from_now(schedule.earliest_check)
+            )?;
+            writeln!(
+                f,
+                "Latest scheduled check: {}",
+                hours_from_now(schedule.latest_check)
+            )?;
+        }
+
+        writeln!(f, "Most stable instances (consecutive alive checks):")?;
+        for instance in &self.most_stable {
+            writeln!(
+                f,
+                "  {}: {}",
+                instance.hostname, instance.consecutive_alive_checks
+            )?;
+        }
+
+        writeln!(
+            f,
+            "Certificates expiring within {} days:",
+            CERTIFICATE_WARNING_DAYS
+        )?;
+        let now = unix_timestamp(SystemTime::now());
+        for certificate in &self.expiring_certificates {
+            match certificate.expires_at.checked_sub(now) {
+                Some(left) => writeln!(
+                    f,
+                    "  {}: in {:.1} days",
+                    certificate.hostname,
+                    left as f64 / (24.0 * 60.0 * 60.0)
+                )?,
+                None => writeln!(f, "  {}: already expired", certificate.hostname)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    fn stats_of_one_alive_instance() -> Stats {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db::init(&mut conn).unwrap();
+        let instance = Domain::from_str("example.com").unwrap();
+        db::add_instance(&conn, &instance).unwrap();
+        db::mark_alive(&mut conn, &instance, false).unwrap();
+        collect(&conn, SystemTime::now()).unwrap()
+    }
+
+    #[test]
+    fn json_has_a_stable_shape() {
+        let json = serde_json::to_value(stats_of_one_alive_instance()).unwrap();
+
+        let object = json.as_object().unwrap();
+        let mut keys: Vec<&str> = object.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            vec![
+                "expiring_certificates",
+                "most_stable",
+                "schedule",
+                "states",
+                "total"
+            ]
+        );
+        // The database is seeded with one instance, which is still in "discovered".
+        assert_eq!(json.pointer("/total"), Some(&serde_json::json!(2)));
+        assert_eq!(
+            json.pointer("/states"),
+            Some(&serde_json::json!({
+                "discovered": 1,
+                "alive": 1,
+                "dying": 0,
+                "dead": 0,
+                "moving": 0,
+                "moved": 0,
+                "blocked": 0,
+            }))
+        );
+        for check in ["/schedule/earliest_check", "/schedule/latest_check"] {
+            assert!(json.pointer(check).and_then(|t| t.as_u64()).unwrap() > 0);
+        }
+        assert_eq!(
+            json.pointer("/most_stable"),
+            Some(&serde_json::json!([
+                {"hostname": "example.com", "consecutive_alive_checks": 1}
+            ]))
+        );
+        assert_eq!(
+            json.pointer("/expiring_certificates"),
+            Some(&serde_json::json!([]))
+        );
+    }
+
+    #[test]
+    fn transitions_are_described() {
+        let instance = Domain::from_str("example.com").unwrap();
+        assert_eq!(
+            describe(InstanceState::Alive, &instance, None),
+            "example.com"
+        );
+
+        let since = SystemTime::now() - Duration::from_secs(2 * 3600);
+        let dying = db::Transition {
+            since,
+            checks: 3,
+            moving_to: None,
+        };
+        assert_eq!(
+            describe(InstanceState::Dying, &instance, Some(&dying)),
+            "example.com: dying since 2.0 hours ago, failed 3 checks"
+        );
+        let moving = db::Transition {
+            since,
+            checks: 2,
+            moving_to: Some(Domain::from_str("example.org").unwrap()),
+        };
+        assert_eq!(
+            describe(InstanceState::Moving, &instance, Some(&moving)),
+            "example.com: moving to example.org since 2.0 hours ago, redirected 2 times"
+        );
+    }
+
+    #[test]
+    fn text_has_labels() {
+        let text = stats_of_one_alive_instance().to_string();
+        for label in [
+            "Instances by state:",
+            "alive: 1",
+            "total: 2",
+            "Earliest scheduled check:",
+            "Latest scheduled check:",
+            "Most stable instances",
+            "example.com: 1",
+            "Certificates expiring within 14 days:",
+        ] {
+            assert!(
+                text.contains(label),
+                "{:?} is missing from:\n{}",
+                label,
+                text
+            );
+        }
+    }
+}
@@ -33,28 +33,256 @@
 //! "spread" we give to a "weekly" check.
 //!
 //! The functions that implement those techniques are [`about_a_day_from_now()`] and
-//! [`about_a_week_from_now()`].
+//! [`about_a_week_from_now()`]. The periods themselves are described by [`Period`].
 //!
 //! This module also has a [`in_about_six_hours()`] function, which is used when generating
 //! a list of "alive" instances. That task is periodic, and uses a slightly odd period of 6 hours
 //! and 6 minutes. Randomization adds or subtracts up to 5 minutes.
 //!
+//! All three periods can be changed with [`set_periods()`], e.g. to loop faster over a small
+//! private Fediverse, or slower over a huge one. The randomization ranges are then scaled by the
+//! same factor as their periods, so each spread stays the same fraction of its period and
+//! "daily" and "weekly" checks keep accumulating the same spread. Pick prime numbers of hours
+//! for the new "daily" and "weekly" periods, or the checks will overlap much more often.
+//!
 //! Finally, there is [`sometime_today()`], which is a helper we use when we schedule
 //! a check for a newly discovered instance. This is an initial check, so it's not periodic. We
 //! still employ randomness though, so when a bunch  of instances are added simultaneously, they
 //! won't all get scheduled onto the same time. The amount of randomness is bigger than with the
-//! other two functions; it's any number of seconds from 0 to 29 hours (both inclusive).
-use anyhow::anyhow;
-use std::ops::{RangeBounds, RangeInclusive};
-use std::time::{Duration, SystemTime};
+//! other two functions; it's any number of seconds from 0 to 29 hours (both inclusive). When
+//! there are too many checks to fit into 29 hours, [`spread_window()`] and [`sometime_within()`]
+//! stretch that range.
+//!
+//! For development and CI, the randomness can be turned off with [`make_deterministic()`], so that
+//! a run can be reproduced. Never do that in production: the randomness is what keeps us polite.
+//!
+//! All of the above rely on the system clock, which can jump (e.g. when NTP corrects it, or when
+//! a VM is resumed). [`ClockJumpDetector`] notices such jumps, so that the schedule can be
+//! brought back into the [`max_check_delay()`] window.
+use anyhow::{anyhow, bail};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
 const DAY_HOURS_IN_SECONDS: u64 = 29 * 3600;
+const WEEK_HOURS_IN_SECONDS: u64 = 167 * 3600;
+
+const TWO_HOURS_SECS: i64 = 2 * 60 * 60;
+const ELEVEN_AND_A_HALF_HOURS_SECS: i64 = (11 * 60 + 30) * 60;
+const FIVE_MINUTES_SECS: i64 = 5 * 60;
+const SIX_HOURS_SIX_MINUTES_SECS: u64 = (6 * 60 + 6) * 60;
+
+const DAY_RAND_RANGE: RangeInclusive<i64> = -TWO_HOURS_SECS..=TWO_HOURS_SECS;
+const WEEK_RAND_RANGE: RangeInclusive<i64> =
+    -ELEVEN_AND_A_HALF_HOURS_SECS..=ELEVEN_AND_A_HALF_HOURS_SECS;
+const TODAY_RAND_RANGE: RangeInclusive<i64> = 0..=(DAY_HOURS_IN_SECONDS as i64);
+const SIX_HOURS_RAND_RANGE: RangeInclusive<i64> = -FIVE_MINUTES_SECS..=FIVE_MINUTES_SECS;
+
+/// Set by [`make_deterministic()`].
+static DETERMINISTIC: AtomicBool = AtomicBool::new(false);
+
+/// Set by [`set_periods()`].
+static DAILY_PERIOD_SECS: AtomicU64 = AtomicU64::new(DAY_HOURS_IN_SECONDS);
+static WEEKLY_PERIOD_SECS: AtomicU64 = AtomicU64::new(WEEK_HOURS_IN_SECONDS);
+static LIST_GENERATION_PERIOD_SECS: AtomicU64 = AtomicU64::new(SIX_HOURS_SIX_MINUTES_SECS);
+
+/// Clock jumps smaller than this are ignored, as they can't disturb the schedule much.
+const CLOCK_JUMP_TOLERANCE: Duration = Duration::from_secs(5 * 60);
+
+/// The lengths of the periods, without any randomization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Periods {
+    /// The period of [`Period::Daily`] checks; 29 hours by default.
+    pub daily: Duration,
+
+    /// The period of [`Period::Weekly`] checks; 167 hours by default.
+    pub weekly: Duration,
+
+    /// How often the lists are generated; 6 hours and 6 minutes by default.
+    pub list_generation: Duration,
+}
+
+impl Default for Periods {
+    fn default() -> Self {
+        Self {
+            daily: Duration::from_secs(DAY_HOURS_IN_SECONDS),
+            weekly: Duration::from_secs(WEEK_HOURS_IN_SECONDS),
+            list_generation: Duration::from_secs(SIX_HOURS_SIX_MINUTES_SECS),
+        }
+    }
+}
+
+impl Periods {
+    /// Checks that all periods are at least a second long, and that the "daily" period is shorter
+    /// than the "weekly" one, randomization included.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, period) in [
+            ("daily", self.daily),
+            ("weekly", self.weekly),
+            ("list generation", self.list_generation),
+        ] {
+            if period.as_secs() == 0 {
+                bail!("The {} period should be positive", name);
+            }
+        }
+        if self.daily >= self.weekly {
+            bail!(
+                "The daily period ({} seconds) should be shorter than the weekly one ({} seconds)",
+                self.daily.as_secs(),
+                self.weekly.as_secs()
+            );
+        }
+        // Otherwise a "daily" check could be scheduled further away than `max_check_delay()`, and
+        // look like it was scheduled before the clock jumped back.
+        let latest_daily = latest_check(self.daily, DAY_RAND_RANGE, DAY_HOURS_IN_SECONDS)?;
+        let latest_weekly = latest_check(self.weekly, WEEK_RAND_RANGE, WEEK_HOURS_IN_SECONDS)?;
+        if latest_daily > latest_weekly {
+            bail!(
+                "A daily check can be up to {} seconds away, which is more than the {} seconds of a weekly one",
+                latest_daily.as_secs(),
+                latest_weekly.as_secs()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Use `periods` instead of the default ones for the rest of the process's life. They should be
+/// validated with [`Periods::validate()`] first.
+pub fn set_periods(periods: Periods) {
+    DAILY_PERIOD_SECS.store(periods.daily.as_secs(), Ordering::Relaxed);
+    WEEKLY_PERIOD_SECS.store(periods.weekly.as_secs(), Ordering::Relaxed);
+    LIST_GENERATION_PERIOD_SECS.store(periods.list_generation.as_secs(), Ordering::Relaxed);
+}
+
+/// The periods set by [`set_periods()`], or the default ones.
+pub fn periods() -> Periods {
+    Periods {
+        daily: Duration::from_secs(DAILY_PERIOD_SECS.load(Ordering::Relaxed)),
+        weekly: Duration::from_secs(WEEKLY_PERIOD_SECS.load(Ordering::Relaxed)),
+        list_generation: Duration::from_secs(LIST_GENERATION_PERIOD_SECS.load(Ordering::Relaxed)),
+    }
+}
+
+/// Scales `range`, which was picked for a period of `default_secs`, to `period`.
+fn scale_range(
+    range: RangeInclusive<i64>,
+    default_secs: u64,
+    period: Duration,
+) -> anyhow::Result<RangeInclusive<i64>> {
+    let scale = |bound: i64| {
+        i128::from(bound)
+            .checked_mul(i128::from(period.as_secs()))
+            .and_then(|bound| bound.checked_div(i128::from(default_secs)))
+            .and_then(|bound| i64::try_from(bound).ok())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Failed to scale {} seconds to a period of {} seconds",
+                    bound,
+                    period.as_secs()
+                )
+            })
+    };
+    Ok(scale(*range.start())?..=scale(*range.end())?)
+}
+
+/// The `period` plus its largest random offset, which is `range` scaled from `default_secs`.
+fn latest_check(
+    period: Duration,
+    range: RangeInclusive<i64>,
+    default_secs: u64,
+) -> anyhow::Result<Duration> {
+    let range = scale_range(range, default_secs, period)?;
+    period
+        .checked_add(Duration::from_secs(range.end().unsigned_abs()))
+        .ok_or_else(|| anyhow!("Failed to compute the maximum delay of a check"))
+}
+
+/// The furthest from now that a check can be scheduled: a "weekly" period plus its largest random
+/// offset. Checks scheduled further away than this were scheduled before the clock jumped back.
+pub fn max_check_delay() -> anyhow::Result<Duration> {
+    latest_check(periods().weekly, WEEK_RAND_RANGE, WEEK_HOURS_IN_SECONDS)
+}
+
+/// A period with which checks are repeated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    /// 29 hours, unless changed with [`set_periods()`].
+    Daily,
+    /// 167 hours, unless changed with [`set_periods()`].
+    Weekly,
+}
+
+impl Period {
+    /// The length of the period, without any randomization.
+    pub fn duration(self) -> Duration {
+        let periods = periods();
+        match self {
+            Period::Daily => periods.daily,
+            Period::Weekly => periods.weekly,
+        }
+    }
+
+    /// Random datetime about one period from now.
+    pub fn next_check(self) -> anyhow::Result<SystemTime> {
+        match self {
+            Period::Daily => about_a_day_from_now(),
+            Period::Weekly => about_a_week_from_now(),
+        }
+    }
+}
+
+impl std::str::FromStr for Period {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "daily" => Ok(Self::Daily),
+            "weekly" => Ok(Self::Weekly),
+            _ => Err(anyhow!(
+                "Unknown period {}, expected \"daily\" or \"weekly\"",
+                s
+            )),
+        }
+    }
+}
+
+/// Stop randomizing the times for the rest of the process's life: every function in this module
+/// will return exactly its fixed offset from now. Only meant for reproducible development runs.
+pub fn make_deterministic() {
+    DETERMINISTIC.store(true, Ordering::Relaxed);
+}
+
+/// Returns `true` if [`make_deterministic()`] was called.
+pub fn is_deterministic() -> bool {
+    DETERMINISTIC.load(Ordering::Relaxed)
+}
 
 fn now_plus_offset_plus_random_from_range(
     fixed_offset: Duration,
-    range: impl RangeBounds<i64>,
+    range: RangeInclusive<i64>,
 ) -> anyhow::Result<SystemTime> {
-    let random_offset = fastrand::i64(range);
+    let offset = offset_plus_random_from_range(fixed_offset, range, is_deterministic())?;
+    SystemTime::now().checked_add(offset).ok_or_else(|| {
+        anyhow!(
+            "Failed to add {} seconds to the current time, as it will lead to overflow",
+            offset.as_secs()
+        )
+    })
+}
+
+/// The `fixed_offset` plus a random number of seconds from the `range`, or just the
+/// `fixed_offset` if `deterministic` is `true`.
+fn offset_plus_random_from_range(
+    fixed_offset: Duration,
+    range: RangeInclusive<i64>,
+    deterministic: bool,
+) -> anyhow::Result<Duration> {
+    let random_offset = if deterministic {
+        0
+    } else {
+        fastrand::i64(range)
+    };
 
     // Convert fixed_offset to seconds and add the random offset
     let final_offset_seconds = if random_offset >= 0 {
@@ -71,51 +299,275 @@ fn now_plus_offset_plus_random_from_range(
             })?
     };
 
-    let now = SystemTime::now();
-
-    let final_time = now
-        .checked_add(Duration::from_secs(final_offset_seconds))
-        .ok_or_else(|| {
-            anyhow!(
-                "Failed to adjust {} seconds to the current time, as it will lead to overflow",
-                random_offset
-            )
-        })?;
-
-    Ok(final_time)
+    Ok(Duration::from_secs(final_offset_seconds))
 }
 
-/// Random datetime about a day from now (now + 29 hours ± 2 hours).
+/// Random datetime about a day from now (now + 29 hours ± 2 hours, by default).
 pub fn about_a_day_from_now() -> anyhow::Result<SystemTime> {
-    const TWO_HOURS_SECS: i64 = 2 * 60 * 60;
-    const RAND_RANGE: RangeInclusive<i64> = -TWO_HOURS_SECS..=TWO_HOURS_SECS;
-    let starting_point = Duration::from_secs(DAY_HOURS_IN_SECONDS);
-    now_plus_offset_plus_random_from_range(starting_point, RAND_RANGE)
+    let starting_point = periods().daily;
+    let range = scale_range(DAY_RAND_RANGE, DAY_HOURS_IN_SECONDS, starting_point)?;
+    now_plus_offset_plus_random_from_range(starting_point, range)
 }
 
-/// Random datetime about a week away from now (now + 167 hours ± 11.5 hours).
+/// Random datetime about a week away from now (now + 167 hours ± 11.5 hours, by default).
 pub fn about_a_week_from_now() -> anyhow::Result<SystemTime> {
-    const ELEVEN_AND_A_HALF_HOURS_SECS: i64 = (11 * 60 + 30) * 60;
-    const RAND_RANGE: RangeInclusive<i64> =
-        -ELEVEN_AND_A_HALF_HOURS_SECS..=ELEVEN_AND_A_HALF_HOURS_SECS;
-    let hours = 167;
-    let starting_point = Duration::from_secs(hours * 3600);
-    now_plus_offset_plus_random_from_range(starting_point, RAND_RANGE)
+    let starting_point = periods().weekly;
+    let range = scale_range(WEEK_RAND_RANGE, WEEK_HOURS_IN_SECONDS, starting_point)?;
+    now_plus_offset_plus_random_from_range(starting_point, range)
 }
 
-/// Random datetime no further than 29 hours from now.
+/// Random datetime no further than a "daily" period (29 hours, by default) from now.
 pub fn sometime_today() -> anyhow::Result<SystemTime> {
-    now_plus_offset_plus_random_from_range(
-        Duration::from_secs(0),
-        0..=(DAY_HOURS_IN_SECONDS as i64),
-    )
+    let range = scale_range(TODAY_RAND_RANGE, DAY_HOURS_IN_SECONDS, periods().daily)?;
+    now_plus_offset_plus_random_from_range(Duration::from_secs(0), range)
 }
 
-/// Random datetime about 6.1 hours from now (now + 6 hours 6 minutes ± 5 minutes).
+/// Random datetime no further than `window` from now.
+pub fn sometime_within(window: Duration) -> anyhow::Result<SystemTime> {
+    let window = i64::try_from(window.as_secs())
+        .map_err(|_| anyhow!("{} seconds is too long a window", window.as_secs()))?;
+    now_plus_offset_plus_random_from_range(Duration::from_secs(0), 0..=window)
+}
+
+/// How long it takes to do `count` checks at `checks_per_second`, but no less than the "daily"
+/// period of [`sometime_today()`], and no more than [`max_check_delay()`].
+pub fn spread_window(count: u64, checks_per_second: u64) -> anyhow::Result<Duration> {
+    let seconds = count.checked_div(checks_per_second).unwrap_or(count);
+    Ok(Duration::from_secs(seconds)
+        .max(periods().daily)
+        .min(max_check_delay()?))
+}
+
+/// Random datetime about one list generation period from now (now + 6 hours 6 minutes
+/// ± 5 minutes, by default).
 pub fn in_about_six_hours() -> anyhow::Result<SystemTime> {
-    const FIVE_MINUTES_SECS: i64 = 5 * 60;
-    const SIX_HOURS_SIX_MINUTES_SECS: u64 = (6 * 60 + 6) * 60;
-    let six_hours_six_minutes_duration = Duration::from_secs(SIX_HOURS_SIX_MINUTES_SECS);
-    const RAND_RANGE: RangeInclusive<i64> = -FIVE_MINUTES_SECS..=FIVE_MINUTES_SECS;
-    now_plus_offset_plus_random_from_range(six_hours_six_minutes_duration, RAND_RANGE)
+    let starting_point = periods().list_generation;
+    let range = scale_range(
+        SIX_HOURS_RAND_RANGE,
+        SIX_HOURS_SIX_MINUTES_SECS,
+        starting_point,
+    )?;
+    now_plus_offset_plus_random_from_range(starting_point, range)
+}
+
+/// A jump of the system clock, relative to the monotonic clock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockJump {
+    Forward(Duration),
+    Backward(Duration),
+}
+
+impl std::fmt::Display for ClockJump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClockJump::Forward(by) => write!(f, "forward by {} seconds", by.as_secs()),
+            ClockJump::Backward(by) => write!(f, "backward by {} seconds", by.as_secs()),
+        }
+    }
+}
+
+/// Compares the system clock against the monotonic one to find out if the former jumped.
+pub struct ClockJumpDetector {
+    wall: SystemTime,
+    monotonic: Instant,
+}
+
+impl ClockJumpDetector {
+    pub fn new(wall: SystemTime, monotonic: Instant) -> Self {
+        Self { wall, monotonic }
+    }
+
+    /// Returns the jump that happened since the previous call, if it's bigger than
+    /// [`CLOCK_JUMP_TOLERANCE`].
+    pub fn check(&mut self, wall: SystemTime, monotonic: Instant) -> Option<ClockJump> {
+        let elapsed = monotonic.saturating_duration_since(self.monotonic);
+        let expected = self.wall.checked_add(elapsed)?;
+        self.wall = wall;
+        self.monotonic = monotonic;
+
+        let jump = match wall.duration_since(expected) {
+            Ok(ahead) => ClockJump::Forward(ahead),
+            Err(e) => ClockJump::Backward(e.duration()),
+        };
+        match jump {
+            ClockJump::Forward(by) | ClockJump::Backward(by) if by > CLOCK_JUMP_TOLERANCE => {
+                Some(jump)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used, clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn deterministic_offsets_are_exactly_the_fixed_ones() {
+        for (fixed, range) in [
+            (DAY_HOURS_IN_SECONDS, DAY_RAND_RANGE),
+            (WEEK_HOURS_IN_SECONDS, WEEK_RAND_RANGE),
+            (0, TODAY_RAND_RANGE),
+            (SIX_HOURS_SIX_MINUTES_SECS, SIX_HOURS_RAND_RANGE),
+        ] {
+            let fixed = Duration::from_secs(fixed);
+            for _ in 0..100 {
+                assert_eq!(
+                    offset_plus_random_from_range(fixed, range.clone(), true).unwrap(),
+                    fixed
+                );
+
+                let random = offset_plus_random_from_range(fixed, range.clone(), false).unwrap();
+                let random = (random.as_secs() as i64)
+                    .checked_sub(fixed.as_secs() as i64)
+                    .unwrap();
+                assert!(range.contains(&random));
+            }
+        }
+    }
+
+    #[test]
+    fn default_periods_keep_the_documented_ranges() {
+        assert!(Periods::default().validate().is_ok());
+        for (range, default_secs) in [
+            (DAY_RAND_RANGE, DAY_HOURS_IN_SECONDS),
+            (WEEK_RAND_RANGE, WEEK_HOURS_IN_SECONDS),
+            (TODAY_RAND_RANGE, DAY_HOURS_IN_SECONDS),
+            (SIX_HOURS_RAND_RANGE, SIX_HOURS_SIX_MINUTES_SECS),
+        ] {
+            let period = Duration::from_secs(default_secs);
+            assert_eq!(
+                scale_range(range.clone(), default_secs, period).unwrap(),
+                range
+            );
+        }
+    }
+
+    #[test]
+    fn ranges_scale_with_their_periods() {
+        let daily = Duration::from_secs(DAY_HOURS_IN_SECONDS.checked_mul(2).unwrap());
+        assert_eq!(
+            scale_range(DAY_RAND_RANGE, DAY_HOURS_IN_SECONDS, daily).unwrap(),
+            -(4 * 3600)..=4 * 3600
+        );
+
+        // 58 minutes instead of 6 hours 6 minutes: 1/6.31 of the period, so about 47 seconds of
+        // spread rather than 5 minutes.
+        let list = Duration::from_secs(58 * 60);
+        assert_eq!(
+            scale_range(SIX_HOURS_RAND_RANGE, SIX_HOURS_SIX_MINUTES_SECS, list).unwrap(),
+            -47..=47
+        );
+    }
+
+    #[test]
+    fn periods_are_validated() {
+        let hours = |h: u64| Duration::from_secs(h.checked_mul(3600).unwrap());
+
+        let zero = Periods {
+            list_generation: Duration::ZERO,
+            ..Periods::default()
+        };
+        assert!(zero.validate().is_err());
+
+        let sub_second = Periods {
+            daily: Duration::from_millis(500),
+            ..Periods::default()
+        };
+        assert!(sub_second.validate().is_err());
+
+        let inverted = Periods {
+            daily: hours(167),
+            weekly: hours(29),
+            ..Periods::default()
+        };
+        assert!(inverted.validate().is_err());
+
+        // The "daily" randomization is a slightly larger fraction of its period than the "weekly"
+        // one, so a "daily" check could end up further away.
+        let close = Periods {
+            daily: Duration::from_secs(999_950),
+            weekly: Duration::from_secs(1_000_000),
+            ..Periods::default()
+        };
+        assert!(close.validate().is_err());
+
+        let small = Periods {
+            daily: hours(5),
+            weekly: hours(23),
+            list_generation: Duration::from_secs(58 * 60),
+        };
+        assert!(small.validate().is_ok());
+    }
+
+    #[test]
+    fn large_backlogs_are_spread_over_a_longer_window() {
+        let day = Duration::from_secs(DAY_HOURS_IN_SECONDS);
+        assert_eq!(spread_window(0, 1).unwrap(), day);
+        assert_eq!(spread_window(10_000, 1).unwrap(), day);
+        assert_eq!(spread_window(10_000, 0).unwrap(), day);
+
+        let backlog = DAY_HOURS_IN_SECONDS.checked_mul(4).unwrap();
+        assert_eq!(
+            spread_window(backlog, 1).unwrap(),
+            Duration::from_secs(backlog)
+        );
+        assert_eq!(
+            spread_window(backlog, 2).unwrap(),
+            Duration::from_secs(DAY_HOURS_IN_SECONDS.checked_mul(2).unwrap())
+        );
+        assert_eq!(spread_window(backlog, 4).unwrap(), day);
+        // Otherwise the checks would be rescheduled again as soon as the Orchestrator restarts.
+        assert_eq!(
+            spread_window(u64::MAX, 1).unwrap(),
+            max_check_delay().unwrap()
+        );
+
+        let window = spread_window(backlog, 1).unwrap();
+        let latest = SystemTime::now().checked_add(window).unwrap();
+        for _ in 0..100 {
+            assert!(sometime_within(window).unwrap() <= latest);
+        }
+    }
+
+    #[test]
+    fn steady_clock_is_not_a_jump() {
+        let (wall, monotonic) = (SystemTime::now(), Instant::now());
+        let mut detector = ClockJumpDetector::new(wall, monotonic);
+
+        let hour = Duration::from_secs(3600);
+        assert_eq!(detector.check(wall + hour, monotonic + hour), None);
+        // Small adjustments are tolerated.
+        assert_eq!(
+            detector.check(
+                wall + hour * 2 + Duration::from_secs(10),
+                monotonic + hour * 2
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn detects_forward_and_backward_jumps() {
+        let (wall, monotonic) = (SystemTime::now(), Instant::now());
+        let mut detector = ClockJumpDetector::new(wall, monotonic);
+
+        let day = Duration::from_secs(24 * 3600);
+        let second = Duration::from_secs(1);
+        assert_eq!(
+            detector.check(wall + day + second, monotonic + second),
+            Some(ClockJump::Forward(day))
+        );
+        // The detector now tracks the new time, so the jump is only reported once.
+        assert_eq!(
+            detector.check(wall + day + second * 2, monotonic + second * 2),
+            None
+        );
+
+        assert_eq!(
+            detector.check(wall + second * 3, monotonic + second * 3),
+            Some(ClockJump::Backward(day))
+        );
+    }
 }
@@ -0,0 +1,33 @@
+//! Compact the database.
+//!
+//! Months of state transitions and reschedules leave a lot of free pages in the database file.
+//! This rebuilds it, which can be done while the Orchestrator is running: it will just have to
+//! wait until the rebuild is over.
+use crate::{db, with_loc};
+use anyhow::Context;
+use slog::{info, Logger};
+use std::time::Duration;
+
+/// How long to wait for the Orchestrator to release the database. A rebuild needs exclusive
+/// access, and the Orchestrator only holds the lock for short transactions.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub fn main(logger: Logger) -> anyhow::Result<()> {
+    let mut conn = db::open()?;
+    conn.busy_timeout(BUSY_TIMEOUT)
+        .context(with_loc!("Setting the busy timeout"))?;
+    db::init(&mut conn)?;
+
+    let size_before = db::file_size()?;
+    info!(logger, "Vacuuming the database");
+    db::vacuum(&conn)?;
+    let size_after = db::file_size()?;
+
+    let msg = format!(
+        "Database size went from {} to {} bytes",
+        size_before, size_after
+    );
+    info!(logger, "{}", msg);
+    println!("{}", msg);
+    Ok(())
+}